@@ -114,12 +114,12 @@ impl Cli {
         file_path: &str,
     ) -> Result<VMMConfig, Box<dyn std::error::Error>> {
         // Open the YAML file
-        let mut file = File::open(file_path).unwrap();
+        let mut file = File::open(file_path)?;
         // Read the YAML file
         let mut yaml_content = String::new();
-        file.read_to_string(&mut yaml_content).unwrap();
+        file.read_to_string(&mut yaml_content)?;
         // Parse the YAML file
-        let vmm_config: VMMConfig = serde_yaml::from_str(&yaml_content).unwrap();
+        let vmm_config: VMMConfig = serde_yaml::from_str(&yaml_content)?;
         // Return the configuration
         Ok(vmm_config)
     }
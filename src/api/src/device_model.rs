@@ -5,7 +5,7 @@
 
 //! Bao device model.
 
-use crate::defines::{BAO_IO_ASK, BAO_IRQFD_FLAG_ASSIGN};
+use crate::defines::{BAO_IO_ASK, BAO_IRQFD_FLAG_ASSIGN, BAO_IRQFD_FLAG_DEASSIGN};
 use crate::error::{Error, Result};
 use crate::ioctl::*;
 use crate::types::{BaoDMInfo, BaoIoEventFd, BaoIoRequest, BaoIrqFd};
@@ -14,6 +14,72 @@ use std::os::fd::AsRawFd;
 use vmm_sys_util::errno;
 use vmm_sys_util::eventfd::EventFd;
 
+/// Trait to model the operations of a Bao device model, so the device model can be backed
+/// either by the Bao I/O dispatcher or by a different implementation (e.g. for testing).
+pub trait DeviceModelT: Send + Sync {
+    /// Get the device model information.
+    ///
+    /// # Returns
+    ///
+    /// The device model information (the `fd` field holds the file descriptor backing the
+    /// shared memory).
+    fn info(&self) -> BaoDMInfo;
+
+    /// Attach the I/O client to the VM.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    fn attach_io_client(&self) -> Result<BaoIoRequest>;
+
+    /// Notifies I/O request completion.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The BaoIoRequest to be notified.
+    ///
+    /// # Return
+    ///
+    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
+    fn notify_io_completed(&self, req: BaoIoRequest) -> Result<()>;
+
+    /// Registers an ioeventfd within the VM (guest to host interrupt)
+    ///
+    /// # Arguments
+    ///
+    /// * `kick` - The EventFd to be registered.
+    /// * `flags` - The flags to be used.
+    /// * `addr` - The address to be registered.
+    /// * `datamatch` - The data to be matched (index of the Virtqueue).
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    fn register_ioeventfd(&self, kick: u32, flags: u32, addr: u64, datamatch: u64) -> Result<()>;
+
+    /// Registers an irqfd within the VM (host to guest interrupt)
+    ///
+    /// # Arguments
+    ///
+    /// * `call` - The EventFd to be registered.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    fn register_irqfd(&self, call: &EventFd) -> Result<()>;
+
+    /// Unregisters an irqfd from the VM.
+    ///
+    /// # Arguments
+    ///
+    /// * `call` - The EventFd to be unregistered.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    fn unregister_irqfd(&self, call: &EventFd) -> Result<()>;
+}
+
 /// Bao Hypervisor Device Model.
 ///
 /// # Attributes
@@ -74,13 +140,20 @@ impl BaoDeviceModel {
 
         Ok(device_model)
     }
+}
+
+impl DeviceModelT for BaoDeviceModel {
+    fn info(&self) -> BaoDMInfo {
+        BaoDMInfo {
+            id: self.id as u32,
+            shmem_addr: self.shmem_addr,
+            shmem_size: self.shmem_size,
+            irq: self.irq,
+            fd: self.devmodel_fd,
+        }
+    }
 
-    /// Attach the I/O client to the VM.
-    ///
-    /// # Returns
-    ///
-    /// A `Result` containing the result of the operation.
-    pub fn attach_io_client(&self) -> Result<BaoIoRequest> {
+    fn attach_io_client(&self) -> Result<BaoIoRequest> {
         // Create a new I/O request
         let mut request = BaoIoRequest {
             dm_id: 0,
@@ -103,16 +176,7 @@ impl BaoDeviceModel {
         Ok(request)
     }
 
-    /// Notifies I/O request completion.
-    ///
-    /// # Arguments
-    ///
-    /// * `req` - The BaoIoRequest to be notified.
-    ///
-    /// # Return
-    ///
-    /// * `Result<()>` - A Result containing Ok(()) on success, or an Error on failure.
-    pub fn notify_io_completed(&self, req: BaoIoRequest) -> Result<()> {
+    fn notify_io_completed(&self, req: BaoIoRequest) -> Result<()> {
         // Notify I/O request completion
         unsafe {
             let ret = ioctl(
@@ -133,25 +197,7 @@ impl BaoDeviceModel {
         Ok(())
     }
 
-    /// Registers an ioeventfd within the VM (guest to host interrupt)
-    ///
-    /// # Arguments
-    ///
-    /// * `kick` - The EventFd to be registered.
-    /// * `flags` - The flags to be used.
-    /// * `addr` - The address to be registered.
-    /// * `datamatch` - The data to be matched (index of the Virtqueue).
-    ///
-    /// # Returns
-    ///
-    /// A `Result` containing the result of the operation.
-    pub fn register_ioeventfd(
-        &self,
-        kick: u32,
-        flags: u32,
-        addr: u64,
-        datamatch: u64,
-    ) -> Result<()> {
+    fn register_ioeventfd(&self, kick: u32, flags: u32, addr: u64, datamatch: u64) -> Result<()> {
         // Create a BaoIoEventFd struct.
         let ioeventfd = BaoIoEventFd {
             fd: kick,
@@ -173,16 +219,7 @@ impl BaoDeviceModel {
         Ok(())
     }
 
-    /// Registers an irqfd within the VM (host to guest interrupt)
-    ///
-    /// # Arguments
-    ///
-    /// * `call` - The EventFd to be registered.
-    ///
-    /// # Returns
-    ///
-    /// A `Result` containing the result of the operation.
-    pub fn register_irqfd(&self, call: &EventFd) -> Result<()> {
+    fn register_irqfd(&self, call: &EventFd) -> Result<()> {
         // Create a BaoIrqFd struct.
         let irqfd = BaoIrqFd {
             fd: call.as_raw_fd() as i32,
@@ -199,4 +236,22 @@ impl BaoDeviceModel {
         }
         Ok(())
     }
+
+    fn unregister_irqfd(&self, call: &EventFd) -> Result<()> {
+        // Create a BaoIrqFd struct.
+        let irqfd = BaoIrqFd {
+            fd: call.as_raw_fd() as i32,
+            flags: BAO_IRQFD_FLAG_DEASSIGN, // Deassign the Irqfd
+        };
+
+        // Call the ioctl to unregister the irqfd.
+        unsafe {
+            let ret = ioctl(self.devmodel_fd, BAO_IOCTL_IRQFD(), &irqfd);
+
+            if ret < 0 {
+                return Err(Error::RegisterIrqfd(errno::Error::last()));
+            }
+        }
+        Ok(())
+    }
 }
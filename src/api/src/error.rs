@@ -57,8 +57,10 @@ pub enum Error {
     InvalidIoReqDirection(u64),
     #[error("HandleIoEventFailed")]
     HandleIoEventFailed,
-    #[error("Device not found")]
-    DeviceNotFound,
+    #[error("No device at MMIO address {1:#x} of VM {0}")]
+    DeviceNotFound(u32, u64),
+    #[error("No VM {0}")]
+    VmNotFound(u32),
     #[error("Mmap guest memory failed")]
     MmapGuestMemoryFailed,
     #[error("Failed to create event manager: {0:?}")]
@@ -69,6 +71,8 @@ pub enum Error {
     RegisterIrqfd(errno::Error),
     #[error("Failed to register the Mmio")]
     MmioConfig,
+    #[error("The device manager is still held by a device")]
+    DeviceManagerInUse,
     #[error("Invalid MMIO {0:?} Operation")]
     InvalidMmioOperation(&'static str),
     #[error("Device not found: {0:?} - {1:?}")]
@@ -89,4 +93,50 @@ pub enum Error {
     NetOpenTun(IoError),
     #[error("Ioctl error: {0:?}")]
     IoctlError(IoError),
+    #[error("Invalid I/O request access width: {0}")]
+    InvalidAccessWidth(u64),
+    #[error("Invalid shared memory slice for the device at MMIO address {0:#x}")]
+    InvalidShmemSlice(u64),
+    #[error("Missing device configuration field: {0}")]
+    MissingConfigField(&'static str),
+    #[error("Invalid device configuration field {0}: {1:?}")]
+    InvalidConfigField(&'static str, String),
+    #[error("Failed to create the EventFd: {0:?}")]
+    EventFdCreateFailed(io::Error),
+    #[error("Failed to create the virtqueues")]
+    QueueCreateFailed,
+    #[error("Failed to access the disk image: {0:?}")]
+    DiskImage(io::Error),
+    #[error("Disk image {0} is locked by another device")]
+    DiskImageLocked(String),
+    #[error("Failed to resize the disk image: {0}")]
+    DiskResize(String),
+    #[error("Failed to snapshot the disk image: {0}")]
+    DiskSnapshot(String),
+    #[error("Failed to load the encryption key: {0:?}")]
+    EncryptionKey(io::Error),
+    #[error("Failed to create the block backend: {0}")]
+    BlockBackend(String),
+    #[error("Vhost backend error: {0:?}")]
+    VhostBackend(vhost::Error),
+    #[error("Failed to open the pty: {0:?}")]
+    PtyOpenFailed(io::Error),
+    #[error("The device requires an event manager")]
+    EventManagerNotFound,
+    #[error("Failed to spawn the thread {0:?}: {1:?}")]
+    ThreadSpawnFailed(String, io::Error),
+    #[error("The thread {0:?} panicked")]
+    ThreadJoinFailed(String),
+    #[error("Invalid configuration space access at offset {0} ({1} bytes)")]
+    InvalidConfigSpaceAccess(usize, usize),
+    #[error("Packed virtqueues are not supported")]
+    PackedRingNotSupported,
+    #[error("Failed to create the TimerFd: {0:?}")]
+    TimerFdCreateFailed(io::Error),
+    #[error("Failed to create the control socket: {0:?}")]
+    ControlSocket(io::Error),
+    #[error("Invalid control request: {0}")]
+    InvalidControlRequest(String),
+    #[error("Invalid VMM configuration: {0}")]
+    InvalidVmmConfig(clap::Error),
 }
@@ -3,4 +3,6 @@ pub mod defines;
 pub mod device_model;
 pub mod error;
 pub mod ioctl;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod types;
@@ -0,0 +1,520 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Mock Bao device model.
+//!
+//! In-process replacement for the Bao I/O dispatcher, allowing to bring up and exercise
+//! the device model on a plain Linux host (e.g. for testing).
+
+use crate::defines::{
+    BAO_IOEVENTFD_FLAG_DATAMATCH, BAO_IOEVENTFD_FLAG_DEASSIGN, BAO_IO_READ, BAO_IO_WRITE,
+};
+use crate::device_model::DeviceModelT;
+use crate::error::{Error, Result};
+use crate::types::{BaoDMInfo, BaoIoRequest};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use vmm_sys_util::errno;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Maximum time to wait for the completion of an I/O request.
+pub const MOCK_IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Mock I/O request queues.
+///
+/// # Attributes
+///
+/// * `pending` - The I/O requests waiting to be attached by the I/O client.
+/// * `completed` - The I/O requests notified as completed by the I/O client.
+/// * `next_request_id` - The ID of the next injected I/O request.
+/// * `shutdown` - Whether the mock device model has been shut down.
+struct MockState {
+    pending: VecDeque<BaoIoRequest>,
+    completed: Vec<BaoIoRequest>,
+    next_request_id: u64,
+    shutdown: bool,
+}
+
+/// Mock ioeventfd registration.
+///
+/// # Attributes
+///
+/// * `fd` - The registered EventFd.
+/// * `addr` - The registered address.
+/// * `datamatch` - The data to be matched (if any).
+struct MockIoEventFd {
+    fd: EventFd,
+    addr: u64,
+    datamatch: Option<u64>,
+}
+
+/// Mock Bao Device Model.
+///
+/// # Attributes
+///
+/// * `info` - The device model information.
+/// * `shmem` - The memfd backing the guest shared memory.
+/// * `state` - The I/O request queues.
+/// * `cond` - The condition variable signalled when the I/O request queues change.
+/// * `ioeventfds` - The registered ioeventfds.
+/// * `irqfds` - The registered irqfds, along with the file descriptors they were registered with.
+/// * `irq_signals` - The number of irqfd signals raised by the devices so far.
+pub struct MockDeviceModel {
+    info: BaoDMInfo,
+    shmem: File,
+    state: Mutex<MockState>,
+    cond: Condvar,
+    ioeventfds: Mutex<Vec<MockIoEventFd>>,
+    irqfds: Mutex<Vec<(RawFd, EventFd)>>,
+    irq_signals: AtomicU64,
+}
+
+impl MockDeviceModel {
+    /// Create a new mock device model.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The ID of the device model.
+    /// * `shmem_addr` - The guest address of the shared memory.
+    /// * `shmem_size` - The size of the shared memory.
+    /// * `irq` - The device model interrupt.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    pub fn new(id: u16, shmem_addr: u64, shmem_size: u64, irq: u32) -> Result<Self> {
+        // Create the memfd backing the shared memory.
+        let fd = unsafe { libc::memfd_create(c"bao-mock-shmem".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::OpenFdFailed("memfd", io::Error::last_os_error()));
+        }
+        let shmem = unsafe { File::from_raw_fd(fd) };
+        shmem
+            .set_len(shmem_size)
+            .map_err(|e| Error::OpenFdFailed("memfd", e))?;
+
+        Ok(MockDeviceModel {
+            info: BaoDMInfo {
+                id: id as u32,
+                shmem_addr,
+                shmem_size,
+                irq,
+                fd: shmem.as_raw_fd(),
+            },
+            shmem,
+            state: Mutex::new(MockState {
+                pending: VecDeque::new(),
+                completed: Vec::new(),
+                next_request_id: 0,
+                shutdown: false,
+            }),
+            cond: Condvar::new(),
+            ioeventfds: Mutex::new(Vec::new()),
+            irqfds: Mutex::new(Vec::new()),
+            irq_signals: AtomicU64::new(0),
+        })
+    }
+
+    /// Inject an I/O request, as if the guest accessed the given address.
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - The operation (`BAO_IO_WRITE` or `BAO_IO_READ`).
+    /// * `addr` - The accessed address.
+    /// * `access_width` - The access width in bytes.
+    /// * `value` - The written value (ignored on reads).
+    ///
+    /// # Returns
+    ///
+    /// The ID of the injected I/O request.
+    ///
+    /// # Note
+    ///
+    /// Writes matching a registered ioeventfd signal it and complete right away, without
+    /// reaching the I/O client.
+    pub fn inject_request(&self, op: u64, addr: u64, access_width: u64, value: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+
+        let req = BaoIoRequest {
+            dm_id: self.info.id as u64,
+            addr,
+            op,
+            value,
+            access_width,
+            request_id: state.next_request_id,
+        };
+        state.next_request_id += 1;
+
+        if op == BAO_IO_WRITE && self.ioeventfd_write(addr, value) {
+            state.completed.push(req);
+        } else {
+            state.pending.push_back(req);
+        }
+        self.cond.notify_all();
+
+        req.request_id
+    }
+
+    /// Wait for the completion of an I/O request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request_id` - The ID of the I/O request.
+    /// * `timeout` - The maximum time to wait.
+    ///
+    /// # Returns
+    ///
+    /// The completed I/O request, or `None` if it did not complete in time.
+    pub fn wait_completion(&self, request_id: u64, timeout: Duration) -> Option<BaoIoRequest> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some(pos) = state
+                .completed
+                .iter()
+                .position(|req| req.request_id == request_id)
+            {
+                return Some(state.completed.remove(pos));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Perform a guest MMIO read and wait for its completion.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The accessed address.
+    /// * `access_width` - The access width in bytes.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the read value.
+    pub fn mmio_read(&self, addr: u64, access_width: u64) -> Result<u64> {
+        let id = self.inject_request(BAO_IO_READ, addr, access_width, 0);
+        self.wait_completion(id, MOCK_IO_TIMEOUT)
+            .map(|req| req.value)
+            .ok_or_else(Self::timed_out)
+    }
+
+    /// Perform a guest MMIO write and wait for its completion.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The accessed address.
+    /// * `access_width` - The access width in bytes.
+    /// * `value` - The written value.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    pub fn mmio_write(&self, addr: u64, access_width: u64, value: u64) -> Result<()> {
+        let id = self.inject_request(BAO_IO_WRITE, addr, access_width, value);
+        self.wait_completion(id, MOCK_IO_TIMEOUT)
+            .map(|_| ())
+            .ok_or_else(Self::timed_out)
+    }
+
+    /// Shut down the mock device model, making the I/O client fail to attach.
+    pub fn shutdown(&self) {
+        self.state.lock().unwrap().shutdown = true;
+        self.cond.notify_all();
+    }
+
+    /// Signal the ioeventfds matching a guest write.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The written address.
+    /// * `value` - The written value.
+    ///
+    /// # Returns
+    ///
+    /// Whether any ioeventfd matched the write.
+    pub fn ioeventfd_write(&self, addr: u64, value: u64) -> bool {
+        let mut matched = false;
+        for ioeventfd in self.ioeventfds.lock().unwrap().iter() {
+            if ioeventfd.addr == addr && ioeventfd.datamatch.is_none_or(|data| data == value) {
+                ioeventfd.fd.write(1).unwrap();
+                matched = true;
+            }
+        }
+        matched
+    }
+
+    /// Wait for any of the registered irqfds to be signalled.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The maximum time to wait.
+    ///
+    /// # Returns
+    ///
+    /// Whether any irqfd was signalled.
+    pub fn wait_irq(&self, timeout: Duration) -> bool {
+        let irqfds = self.irqfds.lock().unwrap();
+
+        let mut fds = irqfds
+            .iter()
+            .map(|(_, fd)| libc::pollfd {
+                fd: fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect::<Vec<_>>();
+
+        let ret = unsafe {
+            libc::poll(
+                fds.as_mut_ptr(),
+                fds.len() as libc::nfds_t,
+                timeout.as_millis() as i32,
+            )
+        };
+        if ret <= 0 {
+            return false;
+        }
+
+        // Consume the signals, so each one is only recorded once.
+        for ((_, fd), pollfd) in irqfds.iter().zip(fds.iter()) {
+            if pollfd.revents & libc::POLLIN != 0 {
+                if let Ok(count) = fd.read() {
+                    self.irq_signals.fetch_add(count, Ordering::SeqCst);
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Get the number of irqfd signals raised by the devices so far.
+    ///
+    /// # Returns
+    ///
+    /// The number of irqfd signals.
+    pub fn irq_signals(&self) -> u64 {
+        // Collect any signal not consumed yet.
+        self.wait_irq(Duration::ZERO);
+        self.irq_signals.load(Ordering::SeqCst)
+    }
+
+    /// Get the number of registered irqfds.
+    pub fn num_irqfds(&self) -> usize {
+        self.irqfds.lock().unwrap().len()
+    }
+
+    /// Get the memfd backing the guest shared memory.
+    pub fn shmem_file(&self) -> &File {
+        &self.shmem
+    }
+
+    /// Read from the guest shared memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The guest address.
+    /// * `buf` - The buffer to fill.
+    pub fn read_guest(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.shmem
+            .read_exact_at(buf, self.guest_offset(addr, buf.len())?)
+    }
+
+    /// Write to the guest shared memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The guest address.
+    /// * `data` - The data to write.
+    pub fn write_guest(&self, addr: u64, data: &[u8]) -> io::Result<()> {
+        self.shmem
+            .write_all_at(data, self.guest_offset(addr, data.len())?)
+    }
+
+    /// Translate a guest address range into an offset of the shared memory.
+    fn guest_offset(&self, addr: u64, len: usize) -> io::Result<u64> {
+        let offset = addr.wrapping_sub(self.info.shmem_addr);
+        if addr < self.info.shmem_addr || offset + len as u64 > self.info.shmem_size {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        Ok(offset)
+    }
+
+    fn timed_out() -> Error {
+        Error::BaoIoctlError(
+            io::Error::from(io::ErrorKind::TimedOut),
+            std::any::type_name::<Self>(),
+        )
+    }
+}
+
+impl DeviceModelT for MockDeviceModel {
+    fn info(&self) -> BaoDMInfo {
+        self.info
+    }
+
+    fn attach_io_client(&self) -> Result<BaoIoRequest> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if state.shutdown {
+                return Err(Error::BaoIoctlError(
+                    io::Error::from(io::ErrorKind::BrokenPipe),
+                    std::any::type_name::<Self>(),
+                ));
+            }
+
+            if let Some(req) = state.pending.pop_front() {
+                return Ok(req);
+            }
+
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+
+    fn notify_io_completed(&self, req: BaoIoRequest) -> Result<()> {
+        self.state.lock().unwrap().completed.push(req);
+        self.cond.notify_all();
+        Ok(())
+    }
+
+    fn register_ioeventfd(&self, kick: u32, flags: u32, addr: u64, datamatch: u64) -> Result<()> {
+        let datamatch = if flags & BAO_IOEVENTFD_FLAG_DATAMATCH != 0 {
+            Some(datamatch)
+        } else {
+            None
+        };
+
+        let mut ioeventfds = self.ioeventfds.lock().unwrap();
+
+        // Deassign the ioeventfd.
+        if flags & BAO_IOEVENTFD_FLAG_DEASSIGN != 0 {
+            ioeventfds
+                .retain(|ioeventfd| ioeventfd.addr != addr || ioeventfd.datamatch != datamatch);
+            return Ok(());
+        }
+
+        // Keep our own copy of the file descriptor, as the kernel would.
+        let fd = unsafe { libc::dup(kick as i32) };
+        if fd < 0 {
+            return Err(Error::RegisterIoevent(errno::Error::last()));
+        }
+
+        ioeventfds.push(MockIoEventFd {
+            fd: unsafe { EventFd::from_raw_fd(fd) },
+            addr,
+            datamatch,
+        });
+
+        Ok(())
+    }
+
+    fn register_irqfd(&self, call: &EventFd) -> Result<()> {
+        let fd = call
+            .try_clone()
+            .map_err(|_| Error::RegisterIrqfd(errno::Error::last()))?;
+        self.irqfds.lock().unwrap().push((call.as_raw_fd(), fd));
+        Ok(())
+    }
+
+    fn unregister_irqfd(&self, call: &EventFd) -> Result<()> {
+        self.irqfds
+            .lock()
+            .unwrap()
+            .retain(|(fd, _)| *fd != call.as_raw_fd());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use vmm_sys_util::eventfd::EFD_NONBLOCK;
+
+    #[test]
+    fn test_mock_io_request() {
+        let dm = Arc::new(MockDeviceModel::new(0, 0x1000_0000, 0x10000, 47).unwrap());
+
+        // Serve a single read request, as the I/O client would.
+        let client = dm.clone();
+        let handle = thread::spawn(move || {
+            let mut req = client.attach_io_client().unwrap();
+            assert_eq!(req.op, BAO_IO_READ);
+            assert_eq!(req.access_width, 2);
+            req.value = 0xbeef;
+            client.notify_io_completed(req).unwrap();
+        });
+
+        assert_eq!(dm.mmio_read(0xa003e00, 2).unwrap(), 0xbeef);
+        handle.join().unwrap();
+
+        // The I/O client fails to attach once the device model is shut down.
+        dm.shutdown();
+        assert!(dm.attach_io_client().is_err());
+    }
+
+    #[test]
+    fn test_mock_ioeventfd_and_irqfd() {
+        let dm = MockDeviceModel::new(0, 0x1000_0000, 0x10000, 47).unwrap();
+
+        // Writes matching the ioeventfd do not reach the I/O client.
+        let kick = EventFd::new(EFD_NONBLOCK).unwrap();
+        dm.register_ioeventfd(
+            kick.as_raw_fd() as u32,
+            BAO_IOEVENTFD_FLAG_DATAMATCH,
+            0xa003e50,
+            1,
+        )
+        .unwrap();
+        dm.mmio_write(0xa003e50, 4, 1).unwrap();
+        assert_eq!(kick.read().unwrap(), 1);
+
+        // Writes not matching the data are forwarded to the I/O client.
+        dm.inject_request(BAO_IO_WRITE, 0xa003e50, 4, 0);
+        assert_eq!(dm.attach_io_client().unwrap().value, 0);
+
+        // The deassigned ioeventfd is no longer signalled.
+        dm.register_ioeventfd(
+            kick.as_raw_fd() as u32,
+            BAO_IOEVENTFD_FLAG_DATAMATCH | BAO_IOEVENTFD_FLAG_DEASSIGN,
+            0xa003e50,
+            1,
+        )
+        .unwrap();
+        assert!(!dm.ioeventfd_write(0xa003e50, 1));
+
+        // Signals raised through the irqfd are recorded.
+        let call = EventFd::new(0).unwrap();
+        dm.register_irqfd(&call).unwrap();
+        call.write(1).unwrap();
+        assert!(dm.wait_irq(MOCK_IO_TIMEOUT));
+        call.write(1).unwrap();
+        assert_eq!(dm.irq_signals(), 2);
+
+        // The unregistered irqfd is no longer tracked.
+        dm.unregister_irqfd(&call).unwrap();
+        assert_eq!(dm.num_irqfds(), 0);
+
+        // The guest memory is backed by the memfd.
+        dm.write_guest(0x1000_0010, &[1, 2, 3, 4]).unwrap();
+        let mut buf = [0u8; 4];
+        dm.read_guest(0x1000_0010, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert!(dm.read_guest(0x1000_fffe, &mut buf).is_err());
+    }
+}
@@ -7,6 +7,7 @@
 
 #![allow(dead_code)]
 
+use super::error::{Error, Result};
 use serde::{Deserialize, Serialize};
 
 /// Struct representing a Bao I/O request.
@@ -20,7 +21,7 @@ use serde::{Deserialize, Serialize};
 /// * `access_width` - Access width.
 /// * `request_id` - Request ID.
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct BaoIoRequest {
     pub dm_id: u64,
     pub addr: u64,
@@ -72,6 +73,7 @@ pub struct BaoIrqFd {
 /// * `irq` - Device model interrupt.
 /// * `fd` - Device model file descriptor.
 #[repr(C)]
+#[derive(Debug, Clone, Copy)]
 pub struct BaoDMInfo {
     pub id: u32,
     pub shmem_addr: u64,
@@ -85,18 +87,45 @@ pub struct BaoDMInfo {
 ///
 /// # Attributes
 ///
-/// * `id` - Device ID.
+/// * `id` - Frontend VM ID (devices sharing the same ID are served by the same VM).
 /// * `type` - Device type.
-/// * `shmem_addr` - Shared memory address.
-/// * `shmem_size` - Shared memory size.
+/// * `shmem_addr` - Shared memory address (slice of the frontend shared memory used by the device).
+/// * `shmem_size` - Shared memory size (slice of the frontend shared memory used by the device).
 /// * `shmem_path` - Shared memory path.
 /// * `mmio_addr` - MMIO address.
-/// * `irq` - Device interrupt.
+/// * `irq` - Device interrupt, which must match the frontend device model one, since the Bao irqfd
+///   interface cannot select another (defaults to it). All the devices of a frontend VM therefore
+///   share one interrupt, and tell their notifications apart through their interrupt status.
 /// * `data_plane` - Data plane type.
-/// * `file_path` - File path (Block device specific option).
+/// * `num_queues` - Number of virtqueues (defaults to the device type one).
+/// * `queue_size` - Maximum size of each virtqueue (defaults to the device type one).
+/// * `file_path` - File path, or NBD export URI (Block device specific option).
 /// * `read_only` - Read only (Block device specific option).
 /// * `root_device` - Root device (Block device specific option).
-/// * `advertise_flush` - Advertise flush (Block device specific option).
+/// * `advertise_flush` - Advertise flush, which must match the cache mode (Block device specific
+///   option).
+/// * `io_engine` - I/O engine, either `sync` or `io_uring` (Block device specific option).
+/// * `image_format` - Image format, either `raw` or `qcow2` (Block device specific option).
+/// * `logical_block_size` - Logical block size, defaults to the backing storage one (Block device
+///   specific option).
+/// * `physical_block_size` - Physical block size, defaults to the backing storage one (Block device
+///   specific option).
+/// * `queue_threads` - Serve every queue on its own event manager thread (Block device specific
+///   option).
+/// * `serial` - Serial number, up to 20 characters, defaults to one derived from the file path
+///   and device ID (Block device specific option).
+/// * `rate_limiter` - I/O rate limiter, unlimited if omitted (Block device specific option).
+/// * `cache` - Host cache mode, either `none`, `writeback`, `writethrough` or `unsafe` (Block
+///   device specific option).
+/// * `shared` - Share the disk image with other writers (e.g. cluster file systems), instead of
+///   locking it exclusively when writable (Block device specific option).
+/// * `overlay` - Path of the copy-on-write delta file (a qcow2 image created on top of `file_path`
+///   if missing), which takes the writes while the base image is never written (Block device
+///   specific option).
+/// * `zoned` - Expose a host-managed zoned device, either passing through the host zoned block
+///   device or emulating zones on top of the disk image (Block device specific option).
+/// * `encryption` - Encrypt the disk image with AES-XTS, the image starting with the encryption
+///   header (Block device specific option).
 /// * `tap_name` - TAP name (Network device specific option).
 /// * `mac_addr` - MAC address (Network device specific option).
 /// * `guest_cid` - Guest context ID (Vsock device specific option).
@@ -107,11 +136,30 @@ pub struct DeviceConfig {
     pub device_type: String,
     pub mmio_addr: u64,
     pub data_plane: String,
+    // Shared memory and interrupt fields (defaults to the frontend device model ones)
+    pub shmem_addr: Option<u64>,
+    pub shmem_size: Option<u64>,
+    pub irq: Option<u32>,
+    // Virtqueue fields (defaults to the device type ones)
+    pub num_queues: Option<usize>,
+    pub queue_size: Option<u16>,
     // Block device specific fields
     pub file_path: Option<String>,
     pub read_only: Option<bool>,
     pub root_device: Option<bool>,
     pub advertise_flush: Option<bool>,
+    pub io_engine: Option<String>,
+    pub image_format: Option<String>,
+    pub logical_block_size: Option<u32>,
+    pub physical_block_size: Option<u32>,
+    pub queue_threads: Option<bool>,
+    pub serial: Option<String>,
+    pub rate_limiter: Option<RateLimiterConfig>,
+    pub cache: Option<String>,
+    pub shared: Option<bool>,
+    pub overlay: Option<String>,
+    pub zoned: Option<ZonedConfig>,
+    pub encryption: Option<EncryptionConfig>,
     // Network device specific fields
     pub tap_name: Option<String>,
     pub mac_addr: Option<String>,
@@ -123,14 +171,148 @@ pub struct DeviceConfig {
     pub pty_alias: Option<String>,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+/// Struct representing a token bucket configuration.
+///
+/// # Attributes
+///
+/// * `size` - Bucket size (in bytes or operations).
+/// * `one_time_burst` - Initial extra budget, which is not refilled (defaults to 0).
+/// * `refill_time` - Time to refill the bucket from empty (in milliseconds).
+pub struct TokenBucketConfig {
+    pub size: u64,
+    pub one_time_burst: Option<u64>,
+    pub refill_time: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+/// Struct representing a rate limiter configuration.
+///
+/// # Attributes
+///
+/// * `bandwidth` - Bandwidth bucket, in bytes (unlimited if omitted).
+/// * `ops` - Operations bucket, in requests (unlimited if omitted).
+pub struct RateLimiterConfig {
+    pub bandwidth: Option<TokenBucketConfig>,
+    pub ops: Option<TokenBucketConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+/// Struct representing a zoned block device configuration.
+///
+/// # Attributes
+///
+/// * `zone_size` - Zone size (in bytes), a power of 2 dividing the disk size (defaults to 256 MiB).
+/// * `conventional_zones` - Number of leading conventional zones (defaults to 0).
+/// * `max_open_zones` - Maximum number of open zones (unlimited if omitted).
+/// * `max_active_zones` - Maximum number of active zones (unlimited if omitted).
+///
+/// # Note
+///
+/// The zones of a host zoned block device are passed through as is, so none of the attributes
+/// may be set.
+pub struct ZonedConfig {
+    pub zone_size: Option<u64>,
+    pub conventional_zones: Option<u32>,
+    pub max_open_zones: Option<u32>,
+    pub max_active_zones: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+/// Struct representing a block device encryption configuration.
+///
+/// # Attributes
+///
+/// * `key_file` - Path of the key file, holding the raw 32 or 64 byte key.
+/// * `keyring` - Description of a `user` key in the kernel keyrings, whose payload is the raw key.
+///
+/// # Note
+///
+/// Exactly one of the key sources must be set.
+pub struct EncryptionConfig {
+    pub key_file: Option<String>,
+    pub keyring: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 /// Struct representing the VMM configuration.
 ///
 /// # Attributes
 ///
 /// * `devices` - List of devices.
+/// * `io_workers` - Number of threads dispatching the I/O requests of each VM (defaults to 1).
+/// * `control_socket` - Path of the Unix socket serving the control requests (disabled if omitted).
 pub struct VMMConfig {
     pub devices: Vec<DeviceConfig>,
+    pub io_workers: Option<usize>,
+    pub control_socket: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+/// Enum representing a control request, targeting the device at `mmio_addr` of the frontend VM `id`.
+pub enum ControlRequest {
+    /// Resize a block device to `size` bytes, or pick up the size of its disk image if omitted.
+    BlockResize {
+        id: u32,
+        mmio_addr: u64,
+        size: Option<u64>,
+    },
+    /// Snapshot the overlay of a block device: the current delta file is moved to `path`, where it
+    /// keeps the disk content at this point in time, and the device switches to a fresh delta.
+    BlockSnapshot {
+        id: u32,
+        mmio_addr: u64,
+        path: String,
+    },
+    /// Dump the I/O statistics of a block device, as a single line JSON object.
+    BlockMetrics { id: u32, mmio_addr: u64 },
+    /// Update the size of a console device.
+    ConsoleResize {
+        id: u32,
+        mmio_addr: u64,
+        cols: u16,
+        rows: u16,
+    },
+    /// Update the link status of a network device.
+    NetLink { id: u32, mmio_addr: u64, up: bool },
+}
+
+impl ControlRequest {
+    /// Parse a control request.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The control request, as a YAML (or JSON) mapping.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the control request.
+    pub fn parse(line: &str) -> Result<Self> {
+        serde_yaml::from_str(line).map_err(|e| Error::InvalidControlRequest(e.to_string()))
+    }
+
+    /// Get the ID of the targeted frontend VM.
+    pub fn id(&self) -> u32 {
+        match self {
+            ControlRequest::BlockResize { id, .. }
+            | ControlRequest::BlockSnapshot { id, .. }
+            | ControlRequest::BlockMetrics { id, .. }
+            | ControlRequest::ConsoleResize { id, .. }
+            | ControlRequest::NetLink { id, .. } => *id,
+        }
+    }
+
+    /// Get the MMIO address of the targeted device.
+    pub fn mmio_addr(&self) -> u64 {
+        match self {
+            ControlRequest::BlockResize { mmio_addr, .. }
+            | ControlRequest::BlockSnapshot { mmio_addr, .. }
+            | ControlRequest::BlockMetrics { mmio_addr, .. }
+            | ControlRequest::ConsoleResize { mmio_addr, .. }
+            | ControlRequest::NetLink { mmio_addr, .. } => *mmio_addr,
+        }
+    }
 }
 
 /// An address either in programmable I/O space or in memory mapped I/O space.
@@ -0,0 +1,133 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Offline provisioning of encrypted block images.
+
+use clap::{App, Arg, ArgGroup};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use virtio::block::disk::crypt::{self, CryptImage, EncryptionKey, CRYPT_HEADER_SIZE};
+use virtio::block::disk::raw::RawImage;
+use virtio::block::disk::DiskImage;
+
+/// Size of the chunks copied from the plaintext image.
+const COPY_CHUNK_SIZE: usize = 1 << 20;
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("bao-crypt-format: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Format an encrypted block image, and optionally fill its payload with a plaintext image.
+///
+/// # Returns
+///
+/// An `io::Result` containing the result of the operation.
+fn run() -> io::Result<()> {
+    let matches = App::new("Bao Encrypted Block Image Format")
+        .arg(
+            Arg::with_name("image")
+                .short('i')
+                .long("image")
+                .value_name("FILE")
+                .help("Sets the encrypted image to format")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("key-file")
+                .short('k')
+                .long("key-file")
+                .value_name("FILE")
+                .help("Sets the key file, holding the raw 32 or 64 byte key")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("keyring")
+                .long("keyring")
+                .value_name("DESCRIPTION")
+                .help("Sets the description of the user key holding the raw key")
+                .takes_value(true),
+        )
+        .group(
+            ArgGroup::with_name("key")
+                .args(&["key-file", "keyring"])
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("size")
+                .short('s')
+                .long("size")
+                .value_name("BYTES")
+                .help("Sets the payload size, creating or resizing the image")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("input")
+                .long("input")
+                .value_name("FILE")
+                .help("Sets a plaintext image, encrypted into the payload")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    // Load the key.
+    let key = match matches.value_of("key-file") {
+        Some(key_file) => EncryptionKey::from_file(key_file)?,
+        None => EncryptionKey::from_keyring(matches.value_of("keyring").unwrap())?,
+    };
+
+    // Size the payload, from the given size or else from the plaintext image (if any).
+    let input = matches.value_of("input").map(File::open).transpose()?;
+    let input_size = input.as_ref().map(|f| f.metadata()).transpose()?.map(|m| m.len());
+    let size = match matches.value_of("size") {
+        Some(size) => Some(
+            size.parse::<u64>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+        ),
+        None => input_size,
+    };
+    if let (Some(size), Some(input_size)) = (size, input_size) {
+        if input_size > size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the plaintext image does not fit the payload",
+            ));
+        }
+    }
+
+    // Open the image, which is created when its size is known.
+    let image = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(size.is_some())
+        .truncate(false)
+        .open(matches.value_of("image").unwrap())?;
+    if let Some(size) = size {
+        image.set_len(CRYPT_HEADER_SIZE + size)?;
+    }
+
+    // Write the encryption header.
+    crypt::format(&mut RawImage::new(image.try_clone()?)?, &key)?;
+
+    // Encrypt the plaintext image into the payload.
+    if let Some(input) = input {
+        let mut disk = CryptImage::new(Box::new(RawImage::new(image)?), &key)?;
+        let mut buf = vec![0u8; COPY_CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < input_size.unwrap() {
+            let len = COPY_CHUNK_SIZE.min((input_size.unwrap() - offset) as usize);
+            input.read_exact_at(&mut buf[..len], offset)?;
+            disk.write_at(&buf[..len], offset)?;
+            offset += len as u64;
+        }
+        disk.flush()?;
+    }
+
+    Ok(())
+}
@@ -1,16 +1,29 @@
 use api::cli::Cli;
+use api::error::{Error, Result};
 use vmm::vmm::Vmm;
 
 fn main() {
+    if let Err(e) = run() {
+        eprintln!("bao-virtio-dm: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Parse the configuration file, create the VMM and run it.
+///
+/// # Returns
+///
+/// A `Result` containing the result of the operation.
+fn run() -> Result<()> {
     // Create a new CLI object.
     let cli = Cli::new();
 
     // Launch the CI to parse the configuration file.
-    let vmm_config = cli.launch().unwrap();
+    let vmm_config = cli.launch().map_err(Error::InvalidVmmConfig)?;
 
     // Create a new VMM.
-    let vmm = Vmm::try_from(vmm_config).unwrap();
+    let vmm = Vmm::try_from(vmm_config)?;
 
     // Run the VMM.
-    vmm.run().unwrap();
+    vmm.run()
 }
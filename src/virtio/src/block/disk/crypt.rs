@@ -0,0 +1,727 @@
+use super::{DiskImage, SECTOR_SHIFT};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::ptr;
+
+/// Magic of the encryption header.
+pub const CRYPT_MAGIC: [u8; 8] = *b"BAOCRYPT";
+
+/// Size of the encryption header written by `format`, which is followed by the payload (in bytes).
+pub const CRYPT_HEADER_SIZE: u64 = 4096;
+
+// Version of the encryption header.
+const CRYPT_VERSION: u32 = 1;
+
+// Cipher of the payload, named after its dm-crypt specification.
+const CRYPT_CIPHER: &[u8] = b"aes-xts-plain64";
+
+// Layout of the encryption header (see the block README).
+const VERSION_OFFSET: usize = 8;
+const KEY_SIZE_OFFSET: usize = 12;
+const PAYLOAD_OFFSET_OFFSET: usize = 16;
+const CIPHER_OFFSET: usize = 24;
+const CIPHER_SIZE: usize = 32;
+const KEY_CHECK_OFFSET: usize = 56;
+const KEY_CHECK_SIZE: usize = 32;
+
+// Sector encrypted to check the key, which is beyond any payload.
+const KEY_CHECK_SECTOR: u64 = u64::MAX;
+
+// Size of an AES block, which is also the size of the XTS IV.
+const AES_BLOCK_SIZE: usize = 16;
+
+// Key sizes of AES-128-XTS and AES-256-XTS (two AES keys each).
+const KEY_SIZES: [usize; 2] = [32, 64];
+
+// Keyctl operation reading the payload of a key (see `linux/keyctl.h`).
+const KEYCTL_READ: libc::c_long = 11;
+
+const SECTOR_SIZE: u64 = 1 << SECTOR_SHIFT;
+
+/// Encryption key, zeroed once dropped.
+pub struct EncryptionKey(Vec<u8>);
+
+impl EncryptionKey {
+    /// Create a new encryption key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The raw key, either 32 bytes (AES-128-XTS) or 64 bytes (AES-256-XTS).
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the encryption key.
+    pub fn new(key: Vec<u8>) -> io::Result<Self> {
+        let key = EncryptionKey(key);
+        if !KEY_SIZES.contains(&key.0.len()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid {} byte key (expected {} or {} bytes)",
+                    key.0.len(),
+                    KEY_SIZES[0],
+                    KEY_SIZES[1]
+                ),
+            ));
+        }
+
+        // IEEE 1619 requires the data key and the tweak key to differ.
+        let (data_key, tweak_key) = key.0.split_at(key.0.len() / 2);
+        if data_key == tweak_key {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the two halves of the key are equal",
+            ));
+        }
+
+        Ok(key)
+    }
+
+    /// Read an encryption key from a key file, which holds the raw key.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the key file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(fs::read(path)?)
+    }
+
+    /// Read an encryption key from the kernel keyrings, as the payload of a `user` key.
+    ///
+    /// # Arguments
+    ///
+    /// * `description` - The key description (e.g. as added by `keyctl padd user <description> @u`).
+    pub fn from_keyring(description: &str) -> io::Result<Self> {
+        let key_type = CString::new("user").unwrap();
+        let description = CString::new(description)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        // Search the keyrings of the process.
+        // SAFETY: Called with valid C strings, and we check the return.
+        let serial = unsafe {
+            libc::syscall(
+                libc::SYS_request_key,
+                key_type.as_ptr(),
+                description.as_ptr(),
+                ptr::null::<libc::c_char>(),
+                0,
+            )
+        };
+        if serial < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Read the key payload, which is truncated to the buffer, while its full length is
+        // returned.
+        let mut key = EncryptionKey(vec![0u8; KEY_SIZES[1] + 1]);
+        // SAFETY: Called with a buffer of the given length, and we check the return.
+        let len = unsafe {
+            libc::syscall(
+                libc::SYS_keyctl,
+                KEYCTL_READ,
+                serial,
+                key.0.as_mut_ptr(),
+                key.0.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        key.0.truncate(len as usize);
+
+        Self::new(mem::take(&mut key.0))
+    }
+}
+
+impl Drop for EncryptionKey {
+    fn drop(&mut self) {
+        zero(&mut self.0);
+    }
+}
+
+/// Plaintext sectors, zeroed once dropped.
+struct PlainSectors(Vec<u8>);
+
+impl Deref for PlainSectors {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for PlainSectors {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl Drop for PlainSectors {
+    fn drop(&mut self) {
+        zero(&mut self.0);
+    }
+}
+
+/// Zero a buffer holding secrets, before it is freed.
+fn zero(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        // SAFETY: The byte is a valid reference. The volatile write is not optimized out.
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+}
+
+/// AES-XTS cipher of the host kernel crypto API (`xts(aes)`), reached through an `AF_ALG`
+/// operation socket.
+struct XtsCipher(OwnedFd);
+
+impl XtsCipher {
+    /// Create a new AES-XTS cipher.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The encryption key.
+    fn new(key: &EncryptionKey) -> io::Result<Self> {
+        xts_socket(&key.0).map(XtsCipher)
+    }
+
+    /// Encrypt or decrypt whole sectors in place, each one with its own IV.
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - The operation (`ALG_OP_ENCRYPT` or `ALG_OP_DECRYPT`).
+    /// * `sector` - The number of the first sector.
+    /// * `buf` - The sectors.
+    fn crypt_sectors(&self, op: libc::c_int, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        // The control messages select the operation and the IV (`struct af_alg_iv`).
+        let op_len = mem::size_of::<u32>() as libc::c_uint;
+        let iv_len = (mem::size_of::<u32>() + AES_BLOCK_SIZE) as libc::c_uint;
+        // SAFETY: Computing the control message sizes has no side effect.
+        let space = unsafe { libc::CMSG_SPACE(op_len) + libc::CMSG_SPACE(iv_len) } as usize;
+        // The buffer is made of `u64`s, so the control message headers are aligned.
+        let mut control = vec![0u64; space.div_ceil(mem::size_of::<u64>())];
+
+        // Each sector is a data unit, ciphered by an operation of its own.
+        for (index, data) in buf.chunks_exact_mut(SECTOR_SIZE as usize).enumerate() {
+            // The IV of a sector is its little endian number, zero padded (`plain64`).
+            let mut iv = [0u8; AES_BLOCK_SIZE];
+            iv[..8].copy_from_slice(&sector.wrapping_add(index as u64).to_le_bytes());
+
+            let mut iov = libc::iovec {
+                iov_base: data.as_mut_ptr() as *mut libc::c_void,
+                iov_len: data.len(),
+            };
+            // SAFETY: `msghdr` is a plain C structure, for which zero is a valid value.
+            let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = space as _;
+
+            // SAFETY: The control buffer is large enough for both control messages, which are
+            // written within their data.
+            unsafe {
+                let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                (*cmsg).cmsg_level = libc::SOL_ALG;
+                (*cmsg).cmsg_type = libc::ALG_SET_OP;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(op_len) as _;
+                ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u32, op as u32);
+
+                let cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+                (*cmsg).cmsg_level = libc::SOL_ALG;
+                (*cmsg).cmsg_type = libc::ALG_SET_IV;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(iv_len) as _;
+                let iv_data = libc::CMSG_DATA(cmsg);
+                ptr::write_unaligned(iv_data as *mut u32, AES_BLOCK_SIZE as u32);
+                ptr::copy_nonoverlapping(
+                    iv.as_ptr(),
+                    iv_data.add(mem::size_of::<u32>()),
+                    AES_BLOCK_SIZE,
+                );
+            }
+
+            // Send the input, then read the output back into the same buffer.
+            // SAFETY: Called with a valid fd and message, and we check the return.
+            let sent = unsafe { libc::sendmsg(self.0.as_raw_fd(), &msg, 0) };
+            if sent < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: Called with a valid fd and buffer, and we check the return.
+            let received = unsafe {
+                libc::read(
+                    self.0.as_raw_fd(),
+                    data.as_mut_ptr() as *mut libc::c_void,
+                    data.len(),
+                )
+            };
+            if received < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if sent as usize != data.len() || received as usize != data.len() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compute the key check value, from a sector beyond any payload.
+    fn key_check(&self) -> io::Result<[u8; KEY_CHECK_SIZE]> {
+        let mut sector = [0u8; SECTOR_SIZE as usize];
+        self.crypt_sectors(libc::ALG_OP_ENCRYPT, KEY_CHECK_SECTOR, &mut sector)?;
+
+        let mut key_check = [0u8; KEY_CHECK_SIZE];
+        key_check.copy_from_slice(&sector[..KEY_CHECK_SIZE]);
+        Ok(key_check)
+    }
+}
+
+/// Create an AES-XTS operation socket of the host kernel crypto API.
+///
+/// # Arguments
+///
+/// * `key` - The AES-XTS key (32 or 64 bytes).
+///
+/// # Returns
+///
+/// An `io::Result` containing the operation socket.
+fn xts_socket(key: &[u8]) -> io::Result<OwnedFd> {
+    // SAFETY: We check the return, and the fd is owned from there on.
+    let tfm = unsafe { libc::socket(libc::AF_ALG, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0) };
+    if tfm < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let tfm = unsafe { OwnedFd::from_raw_fd(tfm) };
+
+    // Select the cipher.
+    // SAFETY: `sockaddr_alg` is a plain C structure, for which zero is a valid value.
+    let mut addr: libc::sockaddr_alg = unsafe { mem::zeroed() };
+    addr.salg_family = libc::AF_ALG as libc::sa_family_t;
+    addr.salg_type[..8].copy_from_slice(b"skcipher");
+    addr.salg_name[..8].copy_from_slice(b"xts(aes)");
+    // SAFETY: Called with a valid fd and address, and we check the return.
+    if unsafe {
+        libc::bind(
+            tfm.as_raw_fd(),
+            &addr as *const libc::sockaddr_alg as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_alg>() as libc::socklen_t,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Set the key, which stays within the kernel from there on.
+    // SAFETY: Called with a valid fd and key, and we check the return.
+    if unsafe {
+        libc::setsockopt(
+            tfm.as_raw_fd(),
+            libc::SOL_ALG,
+            libc::ALG_SET_KEY,
+            key.as_ptr() as *const libc::c_void,
+            key.len() as libc::socklen_t,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: Called with a valid fd, and we check the return.
+    let op = unsafe {
+        libc::accept4(
+            tfm.as_raw_fd(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            libc::SOCK_CLOEXEC,
+        )
+    };
+    if op < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: The fd was just accepted, and is owned from there on.
+    Ok(unsafe { OwnedFd::from_raw_fd(op) })
+}
+
+/// Check if the host kernel crypto API provides the AES-XTS cipher.
+///
+/// # Returns
+///
+/// An `io::Result` containing the result of the operation.
+pub fn probe() -> io::Result<()> {
+    XtsCipher::new(&EncryptionKey::new((0..KEY_SIZES[1] as u8).collect())?).map(|_| ())
+}
+
+/// Encrypted disk image, which encrypts the sectors of an inner disk image with AES-XTS.
+///
+/// The inner image starts with the encryption header, followed by the payload, whose sectors are
+/// encrypted with `aes-xts-plain64` (as dm-crypt does), the IV being the sector number within the
+/// payload.
+///
+/// # Attributes
+///
+/// * `disk` - The inner disk image.
+/// * `cipher` - The AES-XTS cipher.
+/// * `payload_offset` - The inner image offset of the payload (in bytes).
+pub struct CryptImage {
+    disk: Box<dyn DiskImage>,
+    cipher: XtsCipher,
+    payload_offset: u64,
+}
+
+impl CryptImage {
+    /// Create a new encrypted disk image.
+    ///
+    /// # Arguments
+    ///
+    /// * `disk` - The inner disk image, which starts with the encryption header.
+    /// * `key` - The encryption key.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the encrypted disk image.
+    pub fn new(mut disk: Box<dyn DiskImage>, key: &EncryptionKey) -> io::Result<Self> {
+        let mut header = [0u8; SECTOR_SIZE as usize];
+        disk.read_at(&mut header, 0)?;
+        if header[..CRYPT_MAGIC.len()] != CRYPT_MAGIC {
+            return Err(invalid_data("missing encryption header"));
+        }
+
+        let version = u32::from_le_bytes(header[VERSION_OFFSET..][..4].try_into().unwrap());
+        let cipher = &header[CIPHER_OFFSET..][..CIPHER_SIZE];
+        if version != CRYPT_VERSION || cipher != cipher_name() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported encryption header version {}", version),
+            ));
+        }
+
+        let key_size = u32::from_le_bytes(header[KEY_SIZE_OFFSET..][..4].try_into().unwrap());
+        if key_size as usize != key.0.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the image takes a {} byte key", key_size),
+            ));
+        }
+
+        let payload_offset =
+            u64::from_le_bytes(header[PAYLOAD_OFFSET_OFFSET..][..8].try_into().unwrap());
+        if payload_offset < SECTOR_SIZE
+            || payload_offset & (SECTOR_SIZE - 1) != 0
+            || payload_offset > disk.size()
+        {
+            return Err(invalid_data("invalid encryption payload offset"));
+        }
+
+        // Check the key, rather than serving garbage.
+        let cipher = XtsCipher::new(key)?;
+        if cipher.key_check()? != header[KEY_CHECK_OFFSET..][..KEY_CHECK_SIZE] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "wrong encryption key",
+            ));
+        }
+
+        Ok(CryptImage {
+            disk,
+            cipher,
+            payload_offset,
+        })
+    }
+
+    /// Get the sector range covering a byte range of the payload.
+    ///
+    /// # Returns
+    ///
+    /// The payload offset and length of the sector range.
+    fn sector_range(offset: u64, len: usize) -> (u64, usize) {
+        let start = offset & !(SECTOR_SIZE - 1);
+        let end = (offset + len as u64 + SECTOR_SIZE - 1) & !(SECTOR_SIZE - 1);
+        (start, (end - start) as usize)
+    }
+
+    /// Read and decrypt whole sectors of the payload.
+    fn read_sectors(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.disk.read_at(buf, self.payload_offset + offset)?;
+        self.cipher
+            .crypt_sectors(libc::ALG_OP_DECRYPT, offset >> SECTOR_SHIFT, buf)
+    }
+}
+
+impl DiskImage for CryptImage {
+    fn size(&self) -> u64 {
+        self.disk.size() - self.payload_offset
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let (start, len) = Self::sector_range(offset, buf.len());
+        if start == offset && len == buf.len() {
+            return self.read_sectors(buf, offset);
+        }
+
+        // Partial sectors are decrypted as a whole.
+        let mut sectors = PlainSectors(vec![0u8; len]);
+        self.read_sectors(&mut sectors, start)?;
+        let skip = (offset - start) as usize;
+        buf.copy_from_slice(&sectors[skip..skip + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let (start, len) = Self::sector_range(offset, buf.len());
+
+        // Partial sectors are read, updated and encrypted as a whole.
+        let mut sectors = PlainSectors(vec![0u8; len]);
+        if start != offset || len != buf.len() {
+            self.read_sectors(&mut sectors, start)?;
+        }
+        let skip = (offset - start) as usize;
+        sectors[skip..skip + buf.len()].copy_from_slice(buf);
+
+        self.cipher
+            .crypt_sectors(libc::ALG_OP_ENCRYPT, start >> SECTOR_SHIFT, &mut sectors)?;
+        self.disk.write_at(&sectors, self.payload_offset + start)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.disk.flush()
+    }
+
+    // Discarding is left to the default implementation, which keeps the data, as releasing the
+    // backing storage would reveal which sectors are in use.
+
+    fn block_sizes(&self) -> io::Result<(u32, u32)> {
+        self.disk.block_sizes()
+    }
+}
+
+/// Write the encryption header to the start of a disk image.
+///
+/// # Arguments
+///
+/// * `disk` - The disk image, whose first `CRYPT_HEADER_SIZE` bytes hold the header.
+/// * `key` - The encryption key.
+///
+/// # Returns
+///
+/// An `io::Result` containing the result of the operation.
+///
+/// # Note
+///
+/// The payload is left as is, so it reads as random data until written through the encrypted
+/// disk image.
+pub fn format(disk: &mut dyn DiskImage, key: &EncryptionKey) -> io::Result<()> {
+    if disk.size() < CRYPT_HEADER_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the disk image is smaller than the encryption header",
+        ));
+    }
+
+    let cipher = XtsCipher::new(key)?;
+    let mut header = vec![0u8; CRYPT_HEADER_SIZE as usize];
+    header[..CRYPT_MAGIC.len()].copy_from_slice(&CRYPT_MAGIC);
+    header[VERSION_OFFSET..][..4].copy_from_slice(&CRYPT_VERSION.to_le_bytes());
+    header[KEY_SIZE_OFFSET..][..4].copy_from_slice(&(key.0.len() as u32).to_le_bytes());
+    header[PAYLOAD_OFFSET_OFFSET..][..8].copy_from_slice(&CRYPT_HEADER_SIZE.to_le_bytes());
+    header[CIPHER_OFFSET..][..CIPHER_SIZE].copy_from_slice(&cipher_name());
+    header[KEY_CHECK_OFFSET..][..KEY_CHECK_SIZE].copy_from_slice(&cipher.key_check()?);
+
+    disk.write_at(&header, 0)?;
+    disk.flush()
+}
+
+/// Get the cipher name, as stored in the encryption header (zero padded).
+fn cipher_name() -> [u8; CIPHER_SIZE] {
+    let mut name = [0u8; CIPHER_SIZE];
+    name[..CRYPT_CIPHER.len()].copy_from_slice(CRYPT_CIPHER);
+    name
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::disk::raw::RawImage;
+    use std::os::unix::fs::FileExt;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const DISK_SIZE: u64 = 0x10_0000;
+
+    fn raw_image(file: &TempFile) -> Box<dyn DiskImage> {
+        Box::new(RawImage::new(file.as_file().try_clone().unwrap()).unwrap())
+    }
+
+    #[test]
+    #[ignore = "requires the AF_ALG xts(aes) cipher of the host kernel"]
+    fn test_crypt_xts_vector() {
+        // IEEE 1619 vectors 2 and 3 (XTS-AES-128, whose 32 bytes are the start of a sector) and
+        // 10 (XTS-AES-256, a whole sector).
+        let vector_10: Vec<u8> = (0..=255).chain(0..=255).collect();
+        let vectors = [
+            (
+                [[0x11; 16], [0x22; 16]].concat(),
+                0x33_3333_3333,
+                vec![0x44; 32],
+                [
+                    0xc4, 0x54, 0x18, 0x5e, 0x6a, 0x16, 0x93, 0x6e, 0x39, 0x33, 0x40, 0x38, 0xac,
+                    0xef, 0x83, 0x8b, 0xfb, 0x18, 0x6f, 0xff, 0x74, 0x80, 0xad, 0xc4, 0x28, 0x93,
+                    0x82, 0xec, 0xd6, 0xd3, 0x94, 0xf0,
+                ],
+            ),
+            (
+                (0..16).map(|i| 0xff - i).chain([0x22; 16]).collect(),
+                0x33_3333_3333,
+                vec![0x44; 32],
+                [
+                    0xaf, 0x85, 0x33, 0x6b, 0x59, 0x7a, 0xfc, 0x1a, 0x90, 0x0b, 0x2e, 0xb2, 0x1e,
+                    0xc9, 0x49, 0xd2, 0x92, 0xdf, 0x4c, 0x04, 0x7e, 0x0b, 0x21, 0x53, 0x21, 0x86,
+                    0xa5, 0x97, 0x1a, 0x22, 0x7a, 0x89,
+                ],
+            ),
+            (
+                [
+                    0x27, 0x18, 0x28, 0x18, 0x28, 0x45, 0x90, 0x45, 0x23, 0x53, 0x60, 0x28, 0x74,
+                    0x71, 0x35, 0x26, 0x62, 0x49, 0x77, 0x57, 0x24, 0x70, 0x93, 0x69, 0x99, 0x59,
+                    0x57, 0x49, 0x66, 0x96, 0x76, 0x27, 0x31, 0x41, 0x59, 0x26, 0x53, 0x58, 0x97,
+                    0x93, 0x23, 0x84, 0x62, 0x64, 0x33, 0x83, 0x27, 0x95, 0x02, 0x88, 0x41, 0x97,
+                    0x16, 0x93, 0x99, 0x37, 0x51, 0x05, 0x82, 0x09, 0x74, 0x94, 0x45, 0x92,
+                ]
+                .to_vec(),
+                0xff,
+                vector_10,
+                [
+                    0x1c, 0x3b, 0x3a, 0x10, 0x2f, 0x77, 0x03, 0x86, 0xe4, 0x83, 0x6c, 0x99, 0xe3,
+                    0x70, 0xcf, 0x9b, 0xea, 0x00, 0x80, 0x3f, 0x5e, 0x48, 0x23, 0x57, 0xa4, 0xae,
+                    0x12, 0xd4, 0x14, 0xa3, 0xe6, 0x3b,
+                ],
+            ),
+        ];
+
+        for (key, sector, plain, cipher_start) in vectors {
+            let cipher = XtsCipher::new(&EncryptionKey::new(key).unwrap()).unwrap();
+
+            let mut buf = [0u8; SECTOR_SIZE as usize];
+            buf[..plain.len()].copy_from_slice(&plain);
+            cipher
+                .crypt_sectors(libc::ALG_OP_ENCRYPT, sector, &mut buf)
+                .unwrap();
+            assert_eq!(buf[..32], cipher_start);
+            cipher
+                .crypt_sectors(libc::ALG_OP_DECRYPT, sector, &mut buf)
+                .unwrap();
+            assert_eq!(buf[..plain.len()], plain);
+        }
+    }
+
+    #[test]
+    #[ignore = "requires the AF_ALG xts(aes) cipher of the host kernel"]
+    fn test_crypt_batch() {
+        let cipher = XtsCipher::new(&EncryptionKey::new((0..64).collect()).unwrap()).unwrap();
+        let plain: Vec<u8> = (0..4 * SECTOR_SIZE as usize).map(|i| i as u8).collect();
+
+        // A request spanning several sectors is ciphered as its sectors would be one by one.
+        let mut batch = plain.clone();
+        cipher
+            .crypt_sectors(libc::ALG_OP_ENCRYPT, 7, &mut batch)
+            .unwrap();
+        for (index, sector) in plain.chunks(SECTOR_SIZE as usize).enumerate() {
+            let mut sector = sector.to_vec();
+            cipher
+                .crypt_sectors(libc::ALG_OP_ENCRYPT, 7 + index as u64, &mut sector)
+                .unwrap();
+            assert_eq!(
+                sector,
+                batch[index * SECTOR_SIZE as usize..][..sector.len()]
+            );
+        }
+
+        cipher
+            .crypt_sectors(libc::ALG_OP_DECRYPT, 7, &mut batch)
+            .unwrap();
+        assert_eq!(batch, plain);
+    }
+
+    #[test]
+    #[ignore = "requires the AF_ALG xts(aes) cipher of the host kernel"]
+    fn test_crypt_read_write() {
+        let file = TempFile::new().unwrap();
+        file.as_file()
+            .set_len(CRYPT_HEADER_SIZE + DISK_SIZE)
+            .unwrap();
+        let key = EncryptionKey::new((0..64).collect()).unwrap();
+        format(raw_image(&file).as_mut(), &key).unwrap();
+
+        let mut disk = CryptImage::new(raw_image(&file), &key).unwrap();
+        assert_eq!(disk.size(), DISK_SIZE);
+
+        // Identical sectors are encrypted differently.
+        let pattern = vec![0xaau8; 0x400];
+        disk.write_at(&pattern, 0x1000).unwrap();
+        let mut buf = vec![0u8; 0x400];
+        disk.read_at(&mut buf, 0x1000).unwrap();
+        assert_eq!(buf, pattern);
+        file.as_file()
+            .read_exact_at(&mut buf, CRYPT_HEADER_SIZE + 0x1000)
+            .unwrap();
+        assert_ne!(buf[..0x200], pattern[..0x200]);
+        assert_ne!(buf[..0x200], buf[0x200..]);
+
+        // Partial sectors are updated in place.
+        disk.write_at(&[0x55; 4], 0x11fe).unwrap();
+        let mut buf = [0u8; 8];
+        disk.read_at(&mut buf, 0x11fc).unwrap();
+        assert_eq!(buf, [0xaa, 0xaa, 0x55, 0x55, 0x55, 0x55, 0xaa, 0xaa]);
+    }
+
+    #[test]
+    fn test_crypt_key_sizes() {
+        // Only AES-128-XTS and AES-256-XTS keys are taken.
+        assert!(EncryptionKey::new((0..48).collect()).is_err());
+        assert!(EncryptionKey::new((0..32).collect()).is_ok());
+        assert!(EncryptionKey::new((0..64).collect()).is_ok());
+
+        // So are the keys whose data and tweak keys are equal (e.g. IEEE 1619 vector 1).
+        assert!(EncryptionKey::new(vec![0; 32]).is_err());
+        assert!(EncryptionKey::new([[1; 32], [1; 32]].concat()).is_err());
+    }
+
+    #[test]
+    #[ignore = "requires the AF_ALG xts(aes) cipher of the host kernel"]
+    fn test_crypt_invalid_key() {
+        let file = TempFile::new().unwrap();
+        file.as_file()
+            .set_len(CRYPT_HEADER_SIZE + DISK_SIZE)
+            .unwrap();
+
+        // Images without the header are refused.
+        let key = EncryptionKey::new((0..64).collect()).unwrap();
+        assert_eq!(
+            CryptImage::new(raw_image(&file), &key)
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // So are the wrong keys.
+        format(raw_image(&file).as_mut(), &key).unwrap();
+        for key in [(64..128).collect(), (0..32).collect()] {
+            let key = EncryptionKey::new(key).unwrap();
+            assert_eq!(
+                CryptImage::new(raw_image(&file), &key)
+                    .err()
+                    .unwrap()
+                    .kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+    }
+}
@@ -0,0 +1,643 @@
+pub mod crypt;
+pub mod nbd;
+pub mod qcow2;
+pub mod raw;
+pub mod zoned;
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr};
+
+use qcow2::Qcow2Image;
+use raw::RawImage;
+
+/// The sector size is 512 bytes (1 << 9).
+pub const SECTOR_SHIFT: u8 = 9;
+
+// Size of the zeroed buffer used to write zeroes.
+const ZERO_BUFFER_SIZE: usize = 64 << 10;
+
+/// Maximum length of a backing file chain.
+pub const MAX_BACKING_DEPTH: u32 = 16;
+
+/// Maximum physical block size.
+pub const MAX_PHYSICAL_BLOCK_SIZE: u32 = 64 << 10;
+
+// Block device ioctls (see `linux/fs.h`).
+ioctl_io_nr!(BLKSSZGET, 0x12, 104);
+ioctl_io_nr!(BLKPBSZGET, 0x12, 123);
+
+/// Disk image.
+///
+/// The block devices access their backing storage through this trait, which exposes the guest
+/// visible (virtual) disk regardless of how it is laid out on the host.
+pub trait DiskImage: Send {
+    /// Get the virtual disk size (in bytes).
+    fn size(&self) -> u64;
+
+    /// Read from the virtual disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - The buffer to fill.
+    /// * `offset` - The virtual disk offset.
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Write to the virtual disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - The data to write.
+    /// * `offset` - The virtual disk offset.
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Flush the written data to the backing storage.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Discard a range of the virtual disk, releasing the backing storage where possible.
+    ///
+    /// The content of the discarded range is undefined afterwards, so the default implementation
+    /// does nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The virtual disk offset.
+    /// * `len` - The range length (in bytes).
+    fn discard(&mut self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Write zeroes to a range of the virtual disk.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The virtual disk offset.
+    /// * `len` - The range length (in bytes).
+    /// * `unmap` - Whether the backing storage of the range may be released.
+    fn write_zeroes(&mut self, offset: u64, len: u64, _unmap: bool) -> io::Result<()> {
+        write_zero_buffers(self, offset, len)
+    }
+
+    /// Get the granularity at which discarding releases the backing storage (in bytes).
+    fn discard_alignment(&self) -> u64 {
+        1 << SECTOR_SHIFT
+    }
+
+    /// Get the logical and physical block sizes of the backing storage (in bytes).
+    fn block_sizes(&self) -> io::Result<(u32, u32)> {
+        Ok((1 << SECTOR_SHIFT, 1 << SECTOR_SHIFT))
+    }
+
+    /// Get the host file backing the virtual disk one to one (if any).
+    ///
+    /// # Returns
+    ///
+    /// The host file, if the virtual disk offsets are also the host file offsets.
+    fn raw_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+/// Disk image shared by several queue handlers.
+///
+/// The image metadata (e.g. the qcow2 tables) must not be updated concurrently, so every
+/// access to the inner image is serialized.
+#[derive(Clone)]
+pub struct SharedImage(Arc<Mutex<Box<dyn DiskImage>>>);
+
+impl SharedImage {
+    /// Create a new shared disk image.
+    ///
+    /// # Arguments
+    ///
+    /// * `disk` - The disk image to share.
+    ///
+    /// # Returns
+    ///
+    /// The shared disk image, which is cloned for every user.
+    pub fn new(disk: Box<dyn DiskImage>) -> Self {
+        SharedImage(Arc::new(Mutex::new(disk)))
+    }
+}
+
+impl DiskImage for SharedImage {
+    fn size(&self) -> u64 {
+        self.0.lock().unwrap().size()
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.0.lock().unwrap().read_at(buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.0.lock().unwrap().write_at(buf, offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.0.lock().unwrap().discard(offset, len)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        self.0.lock().unwrap().write_zeroes(offset, len, unmap)
+    }
+
+    fn discard_alignment(&self) -> u64 {
+        self.0.lock().unwrap().discard_alignment()
+    }
+
+    fn block_sizes(&self) -> io::Result<(u32, u32)> {
+        self.0.lock().unwrap().block_sizes()
+    }
+}
+
+/// Write zeroes to a range of a disk image, through zeroed buffers.
+///
+/// # Arguments
+///
+/// * `disk` - The disk image.
+/// * `offset` - The virtual disk offset.
+/// * `len` - The range length (in bytes).
+pub fn write_zero_buffers<D: DiskImage + ?Sized>(
+    disk: &mut D,
+    offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    let zeroes = vec![0u8; cmp::min(len, ZERO_BUFFER_SIZE as u64) as usize];
+    let mut done = 0;
+
+    while done < len {
+        let chunk = cmp::min(len - done, zeroes.len() as u64) as usize;
+        disk.write_at(&zeroes[..chunk], offset + done)?;
+        done += chunk as u64;
+    }
+
+    Ok(())
+}
+
+/// Deallocate or zero a range of a host file through `fallocate`.
+///
+/// # Arguments
+///
+/// * `file` - The host file.
+/// * `mode` - The `fallocate` mode (`FALLOC_FL_PUNCH_HOLE` or `FALLOC_FL_ZERO_RANGE`).
+/// * `offset` - The host file offset.
+/// * `len` - The range length (in bytes).
+pub(crate) fn fallocate(file: &File, mode: libc::c_int, offset: u64, len: u64) -> io::Result<()> {
+    // The file size is never changed.
+    let ret = unsafe {
+        libc::fallocate64(
+            file.as_raw_fd(),
+            mode | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off64_t,
+            len as libc::off64_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Get the logical and physical block sizes of a host file.
+///
+/// Block devices report their own block sizes. For regular files, the logical block size is the
+/// sector size and the physical block size is the preferred I/O size of the file system.
+///
+/// # Arguments
+///
+/// * `file` - The host file.
+///
+/// # Returns
+///
+/// An `io::Result` containing the logical and physical block sizes (in bytes).
+pub fn host_block_sizes(file: &File) -> io::Result<(u32, u32)> {
+    let metadata = file.metadata()?;
+
+    if metadata.file_type().is_block_device() {
+        let mut logical: libc::c_int = 0;
+        let mut physical: libc::c_uint = 0;
+
+        // The ioctls are safe. Called with a valid block device fd, and we check the return.
+        if unsafe { ioctl_with_mut_ref(file, BLKSSZGET(), &mut logical) } < 0
+            || unsafe { ioctl_with_mut_ref(file, BLKPBSZGET(), &mut physical) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        return Ok((logical as u32, physical));
+    }
+
+    // Only trust power of 2 preferred I/O sizes, within the physical block size limits.
+    let logical = 1u32 << SECTOR_SHIFT;
+    let physical = match metadata.blksize() {
+        size if size.is_power_of_two()
+            && (logical as u64..=MAX_PHYSICAL_BLOCK_SIZE as u64).contains(&size) =>
+        {
+            size as u32
+        }
+        _ => logical,
+    };
+
+    Ok((logical, physical))
+}
+
+/// Add status flags (e.g. `O_DIRECT`) to an open host file.
+///
+/// # Arguments
+///
+/// * `file` - The host file.
+/// * `flags` - The flags to add.
+pub(crate) fn set_status_flags(file: &File, flags: libc::c_int) -> io::Result<()> {
+    // The fcntls are safe. Called with a valid fd, and we check the return.
+    let current = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    if current < 0 || unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, current | flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Check if an open host file bypasses the host page cache (`O_DIRECT`).
+///
+/// # Arguments
+///
+/// * `file` - The host file.
+pub(crate) fn is_direct(file: &File) -> io::Result<bool> {
+    // The fcntl is safe. Called with a valid fd, and we check the return.
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(flags & libc::O_DIRECT != 0)
+}
+
+/// Disk image formats.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageFormat {
+    Raw,
+    Qcow2,
+}
+
+impl ImageFormat {
+    /// Parse an image format name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The image format name (`raw` or `qcow2`).
+    ///
+    /// # Returns
+    ///
+    /// The image format, or `None` if the name is unknown.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "raw" => Some(ImageFormat::Raw),
+            "qcow2" => Some(ImageFormat::Qcow2),
+            _ => None,
+        }
+    }
+
+    /// Detect the format of a disk image from its content.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The disk image.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the image format (`Raw` unless a known header is found).
+    pub fn detect(file: &File) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        match file.read_exact_at(&mut magic, 0) {
+            Ok(()) if u32::from_be_bytes(magic) == qcow2::QCOW2_MAGIC => Ok(ImageFormat::Qcow2),
+            Ok(()) => Ok(ImageFormat::Raw),
+            // Images smaller than any header are raw.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(ImageFormat::Raw),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Host cache modes of a disk image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CacheMode {
+    /// Direct I/O, bypassing the host page cache. The writes are durable after a flush.
+    None,
+    /// Buffered I/O. The writes are durable after a flush.
+    Writeback,
+    /// Buffered I/O. The writes are durable as soon as they complete.
+    Writethrough,
+    /// Buffered I/O. The flushes are ignored, so the writes are never guaranteed to be durable.
+    Unsafe,
+}
+
+impl CacheMode {
+    /// Parse a cache mode name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The cache mode name (`none`, `writeback`, `writethrough` or `unsafe`).
+    ///
+    /// # Returns
+    ///
+    /// The cache mode, or `None` if the name is unknown.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(CacheMode::None),
+            "writeback" => Some(CacheMode::Writeback),
+            "writethrough" => Some(CacheMode::Writethrough),
+            "unsafe" => Some(CacheMode::Unsafe),
+            _ => None,
+        }
+    }
+
+    /// Check if the writes are held in a volatile cache, which the driver has to flush.
+    pub fn has_write_cache(&self) -> bool {
+        *self != CacheMode::Writethrough
+    }
+
+    /// Get the flags the disk image file is opened with.
+    pub fn open_flags(&self) -> libc::c_int {
+        match self {
+            CacheMode::None => libc::O_DIRECT,
+            CacheMode::Writethrough => libc::O_DSYNC,
+            CacheMode::Writeback | CacheMode::Unsafe => 0,
+        }
+    }
+}
+
+/// Open a disk image.
+///
+/// # Arguments
+///
+/// * `path` - The path to the disk image.
+/// * `format` - The image format (detected from the image content if `None`).
+/// * `read_only` - Whether the disk image is opened read-only.
+///
+/// # Returns
+///
+/// An `io::Result` containing the disk image.
+pub fn open<P: AsRef<Path>>(
+    path: P,
+    format: Option<ImageFormat>,
+    read_only: bool,
+) -> io::Result<Box<dyn DiskImage>> {
+    open_chain(path.as_ref(), format, read_only, CacheMode::Writeback, 0)
+}
+
+/// Open a disk image with a given host cache mode.
+///
+/// # Arguments
+///
+/// * `path` - The path to the disk image.
+/// * `format` - The image format (detected from the image content if `None`).
+/// * `read_only` - Whether the disk image is opened read-only.
+/// * `cache` - The host cache mode.
+///
+/// # Returns
+///
+/// An `io::Result` containing the disk image.
+///
+/// # Note
+///
+/// Direct I/O requires the buffers, offsets and lengths to be aligned to the logical block size
+/// of the backing storage, so the `None` cache mode only serves raw images.
+pub fn open_with_cache<P: AsRef<Path>>(
+    path: P,
+    format: Option<ImageFormat>,
+    read_only: bool,
+    cache: CacheMode,
+) -> io::Result<Box<dyn DiskImage>> {
+    open_chain(path.as_ref(), format, read_only, cache, 0)
+}
+
+/// Open a disk image, which may be part of a backing file chain.
+///
+/// # Arguments
+///
+/// * `path` - The path to the disk image.
+/// * `format` - The image format (detected from the image content if `None`).
+/// * `read_only` - Whether the disk image is opened read-only.
+/// * `cache` - The host cache mode.
+/// * `depth` - The number of images above this one in the chain.
+///
+/// # Returns
+///
+/// An `io::Result` containing the disk image.
+pub(crate) fn open_chain(
+    path: &Path,
+    format: Option<ImageFormat>,
+    read_only: bool,
+    cache: CacheMode,
+    depth: u32,
+) -> io::Result<Box<dyn DiskImage>> {
+    if depth >= MAX_BACKING_DEPTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "backing file chain is too long",
+        ));
+    }
+
+    // NBD exports are raw disks, cached by the server.
+    if nbd::is_uri(path) {
+        if format.is_some_and(|format| format != ImageFormat::Raw)
+            || cache.open_flags() & libc::O_DIRECT != 0
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "NBD exports are raw images, without direct I/O",
+            ));
+        }
+        let uri = nbd::NbdUri::parse(&path.to_string_lossy())?;
+        return Ok(Box::new(nbd::NbdImage::connect(uri, read_only)?));
+    }
+
+    // Direct I/O is only enabled once the format is known, as detecting it reads a few bytes.
+    let flags = cache.open_flags();
+    let file = OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .custom_flags(flags & !libc::O_DIRECT)
+        .open(path)?;
+
+    let format = match format {
+        Some(format) => format,
+        None => ImageFormat::detect(&file)?,
+    };
+
+    if flags & libc::O_DIRECT != 0 {
+        if format != ImageFormat::Raw {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "direct I/O only serves raw images",
+            ));
+        }
+        set_status_flags(&file, libc::O_DIRECT)?;
+    }
+
+    Ok(match format {
+        ImageFormat::Raw => Box::new(RawImage::new(file)?),
+        ImageFormat::Qcow2 => Box::new(Qcow2Image::new(file, path, read_only, depth)?),
+    })
+}
+
+/// Lock a disk image, so other users are not able to write it concurrently.
+///
+/// The lock is an open file description (OFD) lock, so it conflicts with the locks taken by other
+/// processes as well as with the ones taken through other descriptions within this process, and
+/// is held until the returned file is closed.
+///
+/// # Arguments
+///
+/// * `path` - The path to the disk image.
+/// * `read_only` - Whether the disk image is only read.
+/// * `exclusive` - Whether the lock excludes every other user. Otherwise, the lock is shared with
+///   the other users not taking an exclusive one.
+///
+/// # Returns
+///
+/// An `io::Result` containing the file holding the lock. A conflicting lock is reported as
+/// `io::ErrorKind::WouldBlock`.
+pub fn lock_image<P: AsRef<Path>>(path: P, read_only: bool, exclusive: bool) -> io::Result<File> {
+    // Exclusive locks can only be taken on files opened for writing.
+    let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+
+    // The whole file is locked.
+    // SAFETY: `flock` is a plain C structure, for which zero is a valid value.
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = if exclusive {
+        libc::F_WRLCK
+    } else {
+        libc::F_RDLCK
+    } as libc::c_short;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+
+    // The fcntl is safe. Called with a valid fd and lock description, and we check the return.
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_SETLK, &flock) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_image_format() {
+        assert_eq!(ImageFormat::from_name("raw"), Some(ImageFormat::Raw));
+        assert_eq!(ImageFormat::from_name("qcow2"), Some(ImageFormat::Qcow2));
+        assert_eq!(ImageFormat::from_name("vmdk"), None);
+
+        // Empty and headerless images are raw.
+        let image = TempFile::new().unwrap();
+        assert_eq!(
+            ImageFormat::detect(image.as_file()).unwrap(),
+            ImageFormat::Raw
+        );
+        image.as_file().set_len(0x1000).unwrap();
+        assert_eq!(
+            ImageFormat::detect(image.as_file()).unwrap(),
+            ImageFormat::Raw
+        );
+
+        // qcow2 images are recognized by their magic.
+        qcow2::create(image.as_file(), 0x10_0000, None).unwrap();
+        assert_eq!(
+            ImageFormat::detect(image.as_file()).unwrap(),
+            ImageFormat::Qcow2
+        );
+        let disk = open(image.as_path(), None, false).unwrap();
+        assert_eq!(disk.size(), 0x10_0000);
+        assert!(disk.raw_file().is_none());
+    }
+
+    #[test]
+    fn test_shared_image() {
+        let image = TempFile::new().unwrap();
+        qcow2::create(image.as_file(), 0x10_0000, None).unwrap();
+        let mut a = SharedImage::new(open(image.as_path(), None, false).unwrap());
+        let mut b = a.clone();
+
+        // The writes through one user are seen by the other ones.
+        a.write_at(&[0xaa; 0x200], 0x1000).unwrap();
+        let mut buf = [0u8; 0x200];
+        b.read_at(&mut buf, 0x1000).unwrap();
+        assert_eq!(buf, [0xaa; 0x200]);
+        assert_eq!(b.size(), 0x10_0000);
+        assert!(b.raw_file().is_none());
+    }
+
+    #[test]
+    fn test_cache_modes() {
+        assert_eq!(CacheMode::from_name("none"), Some(CacheMode::None));
+        assert_eq!(CacheMode::from_name("unsafe"), Some(CacheMode::Unsafe));
+        assert_eq!(CacheMode::from_name("directsync"), None);
+        assert!(!CacheMode::Writethrough.has_write_cache());
+
+        let image = TempFile::new().unwrap();
+        image.as_file().set_len(0x10_0000).unwrap();
+
+        // Writes through unaligned buffers are bounced for direct I/O.
+        match open_with_cache(image.as_path(), None, false, CacheMode::None) {
+            Ok(mut disk) => {
+                assert!(is_direct(disk.raw_file().unwrap()).unwrap());
+                let data = vec![0xaau8; 0x1001];
+                disk.write_at(&data[1..], 0x1000).unwrap();
+                disk.flush().unwrap();
+                let mut buf = vec![0u8; 0x1001];
+                disk.read_at(&mut buf[1..], 0x1000).unwrap();
+                assert_eq!(buf[1..], data[1..]);
+            }
+            // Some file systems (e.g. older tmpfs) do not support direct I/O.
+            Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EINVAL)),
+        }
+
+        // Direct I/O only serves raw images.
+        qcow2::create(image.as_file(), 0x10_0000, None).unwrap();
+        assert!(open_with_cache(image.as_path(), None, false, CacheMode::None).is_err());
+        let disk = open_with_cache(image.as_path(), None, false, CacheMode::Writethrough).unwrap();
+        assert_eq!(disk.size(), 0x10_0000);
+    }
+
+    #[test]
+    fn test_lock_image() {
+        let image = TempFile::new().unwrap();
+        let is_locked =
+            |result: io::Result<File>| result.unwrap_err().kind() == io::ErrorKind::WouldBlock;
+
+        // Writers exclude every other user, even within the same process.
+        let lock = lock_image(image.as_path(), false, true).unwrap();
+        assert!(is_locked(lock_image(image.as_path(), false, true)));
+        assert!(is_locked(lock_image(image.as_path(), true, false)));
+        drop(lock);
+
+        // Readers and shared writers coexist, but keep exclusive writers out.
+        let _reader = lock_image(image.as_path(), true, false).unwrap();
+        let _writer = lock_image(image.as_path(), false, false).unwrap();
+        assert!(is_locked(lock_image(image.as_path(), false, true)));
+    }
+
+    #[test]
+    fn test_host_block_sizes() {
+        // Image files have 512 bytes logical blocks, on power of 2 physical blocks.
+        let image = TempFile::new().unwrap();
+        let (logical, physical) = host_block_sizes(image.as_file()).unwrap();
+        assert_eq!(logical, 1 << SECTOR_SHIFT);
+        assert!(physical.is_power_of_two());
+        assert!((logical..=MAX_PHYSICAL_BLOCK_SIZE).contains(&physical));
+    }
+}
@@ -0,0 +1,1234 @@
+use super::{write_zero_buffers, DiskImage, MAX_PHYSICAL_BLOCK_SIZE, SECTOR_SHIFT};
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Default port of the NBD servers.
+pub const NBD_DEFAULT_PORT: u16 = 10809;
+
+// Handshake magics ("NBDMAGIC" and "IHAVEOPT").
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const NBD_OPTS_MAGIC: u64 = 0x4948_4156_454f_5054;
+const NBD_REP_MAGIC: u64 = 0x0003_e889_0455_65a9;
+
+// Handshake flags, of the server and of the client.
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+// Options.
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_GO: u32 = 7;
+const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
+
+// Option replies.
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+const NBD_REP_ERR_UNSUP: u32 = NBD_REP_FLAG_ERROR | 1;
+
+// Export information.
+const NBD_INFO_EXPORT: u16 = 0;
+const NBD_INFO_BLOCK_SIZE: u16 = 3;
+
+// Transmission flags.
+const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+// Requests.
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+// Replies.
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
+const NBD_REPLY_TYPE_NONE: u16 = 0;
+const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const NBD_REPLY_TYPE_ERROR: u16 = 1 << 15;
+
+// Largest option reply and error chunk payloads, beyond which the server is not trusted.
+const MAX_REPLY_PAYLOAD: u32 = 64 << 10;
+
+// Largest read and write payload, which the servers are required to accept.
+const MAX_PAYLOAD_SIZE: u32 = 32 << 20;
+
+// Largest trim and write zeroes range.
+const MAX_RANGE_SIZE: u64 = 1 << 30;
+
+// Timeout of the socket I/O, after which the connection is considered dropped.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Delay between the reconnections to an unreachable server, with exponential backoff.
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Check if a disk image path is an NBD URI.
+///
+/// # Arguments
+///
+/// * `path` - The path to the disk image.
+pub fn is_uri<P: AsRef<Path>>(path: P) -> bool {
+    matches!(path.as_ref().to_str(), Some(path) if path.starts_with("nbd://") || path.starts_with("nbd+unix://"))
+}
+
+/// NBD server address.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NbdAddress {
+    /// TCP server, as `host:port`.
+    Tcp(String),
+    /// Unix socket server.
+    Unix(PathBuf),
+}
+
+/// NBD export location.
+///
+/// # Attributes
+///
+/// * `address` - The server address.
+/// * `export` - The export name (the default export if empty).
+#[derive(Clone, Debug, PartialEq)]
+pub struct NbdUri {
+    pub address: NbdAddress,
+    pub export: String,
+}
+
+impl NbdUri {
+    /// Parse an NBD URI, either `nbd://host[:port]/export` or `nbd+unix:///export?socket=path`.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The NBD URI.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the NBD export location.
+    pub fn parse(uri: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid NBD URI {}", uri),
+            )
+        };
+
+        if let Some(rest) = uri.strip_prefix("nbd+unix://") {
+            // The host is empty, and the socket path is the only supported query.
+            let (export, query) = rest
+                .strip_prefix('/')
+                .and_then(|rest| rest.split_once('?'))
+                .ok_or_else(invalid)?;
+            let socket = query
+                .split('&')
+                .find_map(|param| param.strip_prefix("socket="))
+                .filter(|socket| !socket.is_empty())
+                .ok_or_else(invalid)?;
+
+            return Ok(NbdUri {
+                address: NbdAddress::Unix(PathBuf::from(socket)),
+                export: export.to_string(),
+            });
+        }
+
+        let rest = uri.strip_prefix("nbd://").ok_or_else(invalid)?;
+        let (host, export) = rest.split_once('/').unwrap_or((rest, ""));
+        if host.is_empty() || export.contains('?') {
+            return Err(invalid());
+        }
+
+        // IPv6 hosts are bracketed, so their port follows the closing bracket.
+        let has_port = match host.rfind(']') {
+            Some(end) => host[end..].contains(':'),
+            None => host.contains(':'),
+        };
+        let address = if has_port {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, NBD_DEFAULT_PORT)
+        };
+
+        Ok(NbdUri {
+            address: NbdAddress::Tcp(address),
+            export: export.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for NbdUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.address {
+            NbdAddress::Tcp(address) => write!(f, "nbd://{}/{}", address, self.export),
+            NbdAddress::Unix(socket) => {
+                write!(f, "nbd+unix:///{}?socket={}", self.export, socket.display())
+            }
+        }
+    }
+}
+
+/// Connection stream (TCP or Unix socket).
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Export characteristics, negotiated during the handshake.
+///
+/// # Attributes
+///
+/// * `size` - The export size (in bytes).
+/// * `flags` - The transmission flags.
+/// * `min_block` - The minimum block size (in bytes).
+/// * `preferred_block` - The preferred block size (in bytes).
+/// * `max_payload` - The largest read and write payload (in bytes).
+#[derive(Clone, Copy, Debug)]
+struct Export {
+    size: u64,
+    flags: u16,
+    min_block: u32,
+    preferred_block: u32,
+    max_payload: u32,
+}
+
+impl Export {
+    /// Create the characteristics of an export, before its block sizes are known.
+    fn new(size: u64, flags: u16) -> Self {
+        Export {
+            size,
+            // The flags are only meaningful if the server sets them.
+            flags: if flags & NBD_FLAG_HAS_FLAGS != 0 {
+                flags
+            } else {
+                0
+            },
+            min_block: 1,
+            preferred_block: 1 << SECTOR_SHIFT,
+            max_payload: MAX_PAYLOAD_SIZE,
+        }
+    }
+
+    fn has(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+/// Connection to an NBD server, in the transmission phase.
+///
+/// # Attributes
+///
+/// * `stream` - The connection stream.
+/// * `structured_replies` - Whether the server sends structured replies.
+struct Session {
+    stream: Box<dyn Stream>,
+    structured_replies: bool,
+}
+
+impl Session {
+    /// Connect to an NBD server, and select the export.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The NBD export location.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the session and the export characteristics.
+    fn connect(uri: &NbdUri) -> io::Result<(Self, Export)> {
+        let mut stream: Box<dyn Stream> = match &uri.address {
+            NbdAddress::Tcp(address) => {
+                let stream = connect_tcp(address)?;
+                stream.set_nodelay(true)?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                Box::new(stream)
+            }
+            NbdAddress::Unix(socket) => {
+                let stream = UnixStream::connect(socket)?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                Box::new(stream)
+            }
+        };
+
+        // Only the fixed newstyle negotiation is supported.
+        let header: [u8; 18] = read_array(stream.as_mut())?;
+        let flags = be_u16(&header[16..]);
+        if be_u64(&header[..8]) != NBD_MAGIC
+            || be_u64(&header[8..16]) != NBD_OPTS_MAGIC
+            || flags & NBD_FLAG_FIXED_NEWSTYLE == 0
+        {
+            return Err(invalid_data(
+                "the NBD server does not support the fixed newstyle negotiation",
+            ));
+        }
+        let no_zeroes = flags & NBD_FLAG_NO_ZEROES != 0;
+        let client_flags =
+            NBD_FLAG_C_FIXED_NEWSTYLE | if no_zeroes { NBD_FLAG_C_NO_ZEROES } else { 0 };
+        stream.write_all(&client_flags.to_be_bytes())?;
+
+        // Structured replies are used if the server supports them.
+        send_option(stream.as_mut(), NBD_OPT_STRUCTURED_REPLY, &[])?;
+        let (reply, _) = read_option_reply(stream.as_mut(), NBD_OPT_STRUCTURED_REPLY)?;
+        let structured_replies = reply == NBD_REP_ACK;
+
+        let mut session = Session {
+            stream,
+            structured_replies,
+        };
+        let export = session.go(&uri.export, no_zeroes)?;
+        Ok((session, export))
+    }
+
+    /// Select the export, asking for its block size constraints.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The export name.
+    /// * `no_zeroes` - Whether the server omits the padding of the export name reply.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the export characteristics.
+    fn go(&mut self, name: &str, no_zeroes: bool) -> io::Result<Export> {
+        let mut data = Vec::with_capacity(name.len() + 8);
+        data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&NBD_INFO_BLOCK_SIZE.to_be_bytes());
+        send_option(self.stream.as_mut(), NBD_OPT_GO, &data)?;
+
+        let mut export = None;
+        let mut block_sizes = None;
+        loop {
+            let (reply, data) = read_option_reply(self.stream.as_mut(), NBD_OPT_GO)?;
+            match reply {
+                NBD_REP_INFO if data.len() >= 2 => match be_u16(&data) {
+                    NBD_INFO_EXPORT if data.len() == 12 => {
+                        export = Some((be_u64(&data[2..]), be_u16(&data[10..])));
+                    }
+                    NBD_INFO_BLOCK_SIZE if data.len() == 14 => {
+                        block_sizes =
+                            Some((be_u32(&data[2..]), be_u32(&data[6..]), be_u32(&data[10..])));
+                    }
+                    // Unrequested information is ignored.
+                    _ => {}
+                },
+                NBD_REP_ACK => break,
+                // Old servers only select exports by name.
+                NBD_REP_ERR_UNSUP => return self.export_name(name, no_zeroes),
+                reply if reply & NBD_REP_FLAG_ERROR != 0 => {
+                    return Err(io::Error::other(format!(
+                        "the NBD server refused the export {:?}: {}",
+                        name,
+                        String::from_utf8_lossy(&data)
+                    )));
+                }
+                _ => return Err(invalid_data("unexpected NBD option reply")),
+            }
+        }
+
+        let (size, flags) = export.ok_or_else(|| invalid_data("missing NBD export information"))?;
+        let mut export = Export::new(size, flags);
+        if let Some((min, preferred, max)) = block_sizes {
+            if !min.is_power_of_two() || !preferred.is_power_of_two() || max < min {
+                return Err(invalid_data("invalid NBD block size constraints"));
+            }
+            export.min_block = min;
+            export.preferred_block = preferred;
+            // The largest payload stays a multiple of the minimum block size.
+            export.max_payload = cmp::min(max, MAX_PAYLOAD_SIZE) & !(min - 1);
+        }
+
+        Ok(export)
+    }
+
+    /// Select the export by name, as the servers without the `NBD_OPT_GO` option do.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The export name.
+    /// * `no_zeroes` - Whether the server omits the padding of the reply.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the export characteristics.
+    fn export_name(&mut self, name: &str, no_zeroes: bool) -> io::Result<Export> {
+        // The server closes the connection if the export does not exist.
+        send_option(self.stream.as_mut(), NBD_OPT_EXPORT_NAME, name.as_bytes())?;
+        let reply: [u8; 10] = read_array(self.stream.as_mut())?;
+        if !no_zeroes {
+            let _: [u8; 124] = read_array(self.stream.as_mut())?;
+        }
+
+        // The structured replies were not negotiated either.
+        self.structured_replies = false;
+        Ok(Export::new(be_u64(&reply), be_u16(&reply[8..])))
+    }
+
+    /// Send a request, and receive its reply.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request.
+    /// * `read` - The buffer filled by a read request.
+    /// * `write` - The data sent by a write request.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the error returned by the server (0 on success). A transport or
+    /// protocol failure is returned as an error, as the connection cannot be used anymore.
+    fn transmit(
+        &mut self,
+        request: &Request,
+        read: Option<&mut [u8]>,
+        write: Option<&[u8]>,
+    ) -> io::Result<u32> {
+        self.stream.write_all(&request.to_bytes())?;
+        if let Some(data) = write {
+            self.stream.write_all(data)?;
+        }
+        self.stream.flush()?;
+
+        let magic = be_u32(&read_array::<4>(self.stream.as_mut())?);
+        match magic {
+            NBD_SIMPLE_REPLY_MAGIC => self.simple_reply(request, read),
+            NBD_STRUCTURED_REPLY_MAGIC if self.structured_replies => {
+                self.structured_reply(request, read)
+            }
+            _ => Err(invalid_data("invalid NBD reply magic")),
+        }
+    }
+
+    /// Receive a simple reply, following its magic.
+    fn simple_reply(&mut self, request: &Request, read: Option<&mut [u8]>) -> io::Result<u32> {
+        // Read replies are structured, once negotiated.
+        if self.structured_replies && request.command == NBD_CMD_READ {
+            return Err(invalid_data("unexpected NBD simple reply"));
+        }
+
+        let reply: [u8; 12] = read_array(self.stream.as_mut())?;
+        if be_u64(&reply[4..]) != request.cookie {
+            return Err(invalid_data("unexpected NBD reply cookie"));
+        }
+
+        let error = be_u32(&reply);
+        if error == 0 {
+            if let Some(buf) = read {
+                self.stream.read_exact(buf)?;
+            }
+        }
+
+        Ok(error)
+    }
+
+    /// Receive the chunks of a structured reply, following the magic of the first one.
+    fn structured_reply(
+        &mut self,
+        request: &Request,
+        mut read: Option<&mut [u8]>,
+    ) -> io::Result<u32> {
+        let mut error = 0;
+        let mut first = true;
+
+        loop {
+            if !first
+                && be_u32(&read_array::<4>(self.stream.as_mut())?) != NBD_STRUCTURED_REPLY_MAGIC
+            {
+                return Err(invalid_data("invalid NBD reply magic"));
+            }
+            first = false;
+
+            let header: [u8; 16] = read_array(self.stream.as_mut())?;
+            let flags = be_u16(&header);
+            let chunk_type = be_u16(&header[2..]);
+            let length = be_u32(&header[12..]);
+            if be_u64(&header[4..]) != request.cookie {
+                return Err(invalid_data("unexpected NBD reply cookie"));
+            }
+
+            match chunk_type {
+                NBD_REPLY_TYPE_NONE if length == 0 => {}
+                NBD_REPLY_TYPE_OFFSET_DATA | NBD_REPLY_TYPE_OFFSET_HOLE if length >= 8 => {
+                    let offset = be_u64(&read_array::<8>(self.stream.as_mut())?);
+                    let len = if chunk_type == NBD_REPLY_TYPE_OFFSET_DATA {
+                        length - 8
+                    } else if length == 12 {
+                        be_u32(&read_array::<4>(self.stream.as_mut())?)
+                    } else {
+                        return Err(invalid_data("invalid NBD hole chunk"));
+                    };
+
+                    // The chunk must lie within the read buffer.
+                    let buf = read
+                        .as_deref_mut()
+                        .and_then(|buf| {
+                            let start = offset.checked_sub(request.offset)? as usize;
+                            buf.get_mut(start..start.checked_add(len as usize)?)
+                        })
+                        .ok_or_else(|| invalid_data("invalid NBD read chunk"))?;
+                    if chunk_type == NBD_REPLY_TYPE_OFFSET_DATA {
+                        self.stream.read_exact(buf)?;
+                    } else {
+                        buf.fill(0);
+                    }
+                }
+                chunk_type
+                    if chunk_type & NBD_REPLY_TYPE_ERROR != 0
+                        && (6..=MAX_REPLY_PAYLOAD).contains(&length) =>
+                {
+                    // The message (and offset) of the error are skipped.
+                    let mut payload = vec![0u8; length as usize];
+                    self.stream.read_exact(&mut payload)?;
+                    if error == 0 {
+                        // A zero error is invalid, but still a failure.
+                        error = match be_u32(&payload) {
+                            0 => libc::EIO as u32,
+                            e => e,
+                        };
+                    }
+                }
+                _ => return Err(invalid_data("invalid NBD reply chunk")),
+            }
+
+            if flags & NBD_REPLY_FLAG_DONE != 0 {
+                return Ok(error);
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Disconnect cleanly, if the connection is still up.
+        let request = Request {
+            command: NBD_CMD_DISC,
+            flags: 0,
+            cookie: 0,
+            offset: 0,
+            len: 0,
+        };
+        let _ = self.stream.write_all(&request.to_bytes());
+    }
+}
+
+/// NBD request header.
+struct Request {
+    command: u16,
+    flags: u16,
+    cookie: u64,
+    offset: u64,
+    len: u32,
+}
+
+impl Request {
+    fn to_bytes(&self) -> [u8; 28] {
+        let mut bytes = [0u8; 28];
+        bytes[..4].copy_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+        bytes[4..6].copy_from_slice(&self.flags.to_be_bytes());
+        bytes[6..8].copy_from_slice(&self.command.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.cookie.to_be_bytes());
+        bytes[16..24].copy_from_slice(&self.offset.to_be_bytes());
+        bytes[24..].copy_from_slice(&self.len.to_be_bytes());
+        bytes
+    }
+}
+
+/// NBD export, accessed as a raw disk image.
+///
+/// The requests are sent one at a time. When the connection drops, the client reconnects and
+/// sends the request again, as every request is idempotent. The client never sleeps while
+/// reconnecting, since the queues sharing the image wait for it: while the server is unreachable,
+/// the requests fail until the backoff delay elapsed.
+///
+/// # Attributes
+///
+/// * `uri` - The NBD export location.
+/// * `export` - The export characteristics, negotiated by the first connection.
+/// * `session` - The connection to the server (if connected).
+/// * `cookie` - The cookie of the last request.
+/// * `unflushed` - Whether the session acknowledged writes not flushed yet.
+/// * `lost_writes` - Whether a dropped session acknowledged writes not flushed, which the next
+///   flush reports.
+/// * `reconnect_delay` - The delay before the next reconnection, after a failed one.
+/// * `reconnect_at` - The earliest time of the next reconnection (if the server is unreachable).
+pub struct NbdImage {
+    uri: NbdUri,
+    export: Export,
+    session: Option<Session>,
+    cookie: u64,
+    unflushed: bool,
+    lost_writes: bool,
+    reconnect_delay: Duration,
+    reconnect_at: Option<Instant>,
+}
+
+impl NbdImage {
+    /// Connect to an NBD export.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The NBD export location.
+    /// * `read_only` - Whether the export is only read.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the NBD disk image.
+    pub fn connect(uri: NbdUri, read_only: bool) -> io::Result<Self> {
+        let (session, export) = Session::connect(&uri)?;
+        if !read_only && export.has(NBD_FLAG_READ_ONLY) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("the NBD export {} is read-only", uri),
+            ));
+        }
+
+        Ok(NbdImage {
+            uri,
+            export,
+            session: Some(session),
+            cookie: 0,
+            unflushed: false,
+            lost_writes: false,
+            reconnect_delay: RECONNECT_DELAY,
+            reconnect_at: None,
+        })
+    }
+
+    /// Get the connection to the server, reconnecting if needed.
+    fn session(&mut self) -> io::Result<&mut Session> {
+        if self.session.is_none() {
+            // An unreachable server is not tried again before the backoff delay elapsed.
+            if self.reconnect_at.is_some_and(|at| Instant::now() < at) {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("the NBD export {} is unreachable", self.uri),
+                ));
+            }
+            let (session, export) = match Session::connect(&self.uri) {
+                Ok(connection) => connection,
+                Err(e) => {
+                    self.reconnect_at = Some(Instant::now() + self.reconnect_delay);
+                    self.reconnect_delay = cmp::min(self.reconnect_delay * 2, MAX_RECONNECT_DELAY);
+                    return Err(e);
+                }
+            };
+
+            // The export must still serve the whole disk.
+            if export.size < self.export.size
+                || export.flags & NBD_FLAG_READ_ONLY > self.export.flags & NBD_FLAG_READ_ONLY
+            {
+                return Err(io::Error::other(format!(
+                    "the NBD export {} changed across connections",
+                    self.uri
+                )));
+            }
+            self.session = Some(session);
+            self.reconnect_delay = RECONNECT_DELAY;
+            self.reconnect_at = None;
+        }
+
+        Ok(self.session.as_mut().unwrap())
+    }
+
+    /// Send a request, reconnecting once when the connection drops.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The request command.
+    /// * `flags` - The request flags.
+    /// * `offset` - The export offset.
+    /// * `len` - The request length (in bytes).
+    /// * `read` - The buffer filled by a read request.
+    /// * `write` - The data sent by a write request.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the result of the operation. The errors returned by the server
+    /// are reported as the matching OS errors.
+    fn request(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        mut read: Option<&mut [u8]>,
+        write: Option<&[u8]>,
+    ) -> io::Result<()> {
+        let mut retried = false;
+
+        loop {
+            self.cookie = self.cookie.wrapping_add(1);
+            let request = Request {
+                command,
+                flags,
+                cookie: self.cookie,
+                offset,
+                len,
+            };
+
+            match self
+                .session()
+                .and_then(|session| session.transmit(&request, read.as_deref_mut(), write))
+            {
+                Ok(0) => {
+                    match command {
+                        NBD_CMD_READ => (),
+                        NBD_CMD_FLUSH => self.unflushed = false,
+                        _ => self.unflushed = true,
+                    }
+                    return Ok(());
+                }
+                // The NBD errors are the Linux errno values.
+                Ok(error) => return Err(io::Error::from_raw_os_error(error as i32)),
+                Err(e) => {
+                    // The connection is dropped, as it may be out of sync. The writes it
+                    // acknowledged may be lost along with the server cache.
+                    let dropped = self.session.take().is_some();
+                    self.lost_writes |= std::mem::take(&mut self.unflushed);
+                    if !dropped || retried {
+                        return Err(e);
+                    }
+                    retried = true;
+                    println!(
+                        "NBD connection to {} failed, reconnecting: {:?}",
+                        self.uri, e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Send a request without payload over a range, split into requests of at most
+    /// `MAX_RANGE_SIZE` bytes.
+    fn request_range(&mut self, command: u16, flags: u16, offset: u64, len: u64) -> io::Result<()> {
+        let mut done = 0;
+
+        while done < len {
+            let chunk = cmp::min(len - done, MAX_RANGE_SIZE);
+            self.request(command, flags, offset + done, chunk as u32, None, None)?;
+            done += chunk;
+        }
+
+        Ok(())
+    }
+}
+
+impl DiskImage for NbdImage {
+    fn size(&self) -> u64 {
+        self.export.size
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let max_payload = self.export.max_payload as usize;
+        for (index, chunk) in buf.chunks_mut(max_payload).enumerate() {
+            let chunk_offset = offset + (index * max_payload) as u64;
+            let len = chunk.len() as u32;
+            self.request(NBD_CMD_READ, 0, chunk_offset, len, Some(chunk), None)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let max_payload = self.export.max_payload as usize;
+        for (index, chunk) in buf.chunks(max_payload).enumerate() {
+            let chunk_offset = offset + (index * max_payload) as u64;
+            let len = chunk.len() as u32;
+            self.request(NBD_CMD_WRITE, 0, chunk_offset, len, None, Some(chunk))?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Servers without a write cache do not take flushes.
+        if !self.export.has(NBD_FLAG_SEND_FLUSH) {
+            return Ok(());
+        }
+
+        self.request(NBD_CMD_FLUSH, 0, 0, 0, None, None)?;
+
+        // The writes acknowledged by a dropped connection may not have reached the disk.
+        if std::mem::take(&mut self.lost_writes) {
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+
+        Ok(())
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if !self.export.has(NBD_FLAG_SEND_TRIM) {
+            return Ok(());
+        }
+
+        self.request_range(NBD_CMD_TRIM, 0, offset, len)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        if !self.export.has(NBD_FLAG_SEND_WRITE_ZEROES) {
+            return write_zero_buffers(self, offset, len);
+        }
+
+        // The server may punch holes, unless the range must stay allocated.
+        let flags = if unmap { 0 } else { NBD_CMD_FLAG_NO_HOLE };
+        self.request_range(NBD_CMD_WRITE_ZEROES, flags, offset, len)
+    }
+
+    fn discard_alignment(&self) -> u64 {
+        cmp::max(self.export.min_block as u64, 1 << SECTOR_SHIFT)
+    }
+
+    fn block_sizes(&self) -> io::Result<(u32, u32)> {
+        // Only the block sizes within the limits of the virtio block devices are trusted.
+        let logical = match self.export.min_block {
+            size if (1 << SECTOR_SHIFT..=4096).contains(&size) => size,
+            _ => 1 << SECTOR_SHIFT,
+        };
+        let physical = match self.export.preferred_block {
+            size if (logical..=MAX_PHYSICAL_BLOCK_SIZE).contains(&size) => size,
+            _ => logical,
+        };
+
+        Ok((logical, physical))
+    }
+}
+
+/// Connect to a TCP server, trying each of its addresses.
+fn connect_tcp(address: &str) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} has no address", address),
+    );
+
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, IO_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Send a handshake option.
+fn send_option(stream: &mut dyn Stream, option: u32, data: &[u8]) -> io::Result<()> {
+    let mut header = [0u8; 16];
+    header[..8].copy_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+    header[8..12].copy_from_slice(&option.to_be_bytes());
+    header[12..].copy_from_slice(&(data.len() as u32).to_be_bytes());
+    stream.write_all(&header)?;
+    stream.write_all(data)?;
+    stream.flush()
+}
+
+/// Receive a handshake option reply.
+///
+/// # Returns
+///
+/// An `io::Result` containing the reply type and data.
+fn read_option_reply(stream: &mut dyn Stream, option: u32) -> io::Result<(u32, Vec<u8>)> {
+    let header: [u8; 20] = read_array(stream)?;
+    let length = be_u32(&header[16..]);
+    if be_u64(&header) != NBD_REP_MAGIC
+        || be_u32(&header[8..]) != option
+        || length > MAX_REPLY_PAYLOAD
+    {
+        return Err(invalid_data("invalid NBD option reply"));
+    }
+
+    let mut data = vec![0u8; length as usize];
+    stream.read_exact(&mut data)?;
+    Ok((be_u32(&header[12..]), data))
+}
+
+fn read_array<const N: usize>(stream: &mut (impl Read + ?Sized)) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    stream.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn be_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes(bytes[..2].try_into().unwrap())
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes[..8].try_into().unwrap())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::{fs, thread};
+    use vmm_sys_util::tempdir::TempDir;
+
+    const EXPORT_SIZE: usize = 0x10_0000;
+
+    /// In-memory NBD server, serving one connection at a time.
+    ///
+    /// # Attributes
+    ///
+    /// * `dir` - The directory of the server socket.
+    /// * `commands` - The commands received.
+    /// * `connections` - The number of connections accepted.
+    struct MockServer {
+        dir: TempDir,
+        commands: Arc<Mutex<Vec<u16>>>,
+        connections: Arc<AtomicUsize>,
+    }
+
+    impl MockServer {
+        /// Start a new server.
+        ///
+        /// # Arguments
+        ///
+        /// * `structured_replies` - Whether the server supports structured replies.
+        /// * `drop_after` - The request on which the first connection is dropped (if any).
+        fn new(structured_replies: bool, drop_after: Option<usize>) -> Self {
+            let dir = TempDir::new().unwrap();
+            let listener = UnixListener::bind(dir.as_path().join("nbd.sock")).unwrap();
+            let disk = Arc::new(Mutex::new(vec![0u8; EXPORT_SIZE]));
+            let commands = Arc::new(Mutex::new(Vec::new()));
+            let connections = Arc::new(AtomicUsize::new(0));
+
+            let server_commands = commands.clone();
+            let server_connections = connections.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else {
+                        return;
+                    };
+                    let drop_after = match server_connections.fetch_add(1, Ordering::SeqCst) {
+                        0 => drop_after,
+                        _ => None,
+                    };
+                    let _ = serve(
+                        stream,
+                        structured_replies,
+                        drop_after,
+                        &disk,
+                        &server_commands,
+                    );
+                }
+            });
+
+            MockServer {
+                dir,
+                commands,
+                connections,
+            }
+        }
+
+        fn uri(&self) -> NbdUri {
+            NbdUri::parse(&format!(
+                "nbd+unix:///disk?socket={}",
+                self.dir.as_path().join("nbd.sock").display()
+            ))
+            .unwrap()
+        }
+    }
+
+    fn serve(
+        mut stream: UnixStream,
+        structured: bool,
+        drop_after: Option<usize>,
+        disk: &Mutex<Vec<u8>>,
+        commands: &Mutex<Vec<u16>>,
+    ) -> io::Result<()> {
+        // Handshake.
+        stream.write_all(&NBD_MAGIC.to_be_bytes())?;
+        stream.write_all(&NBD_OPTS_MAGIC.to_be_bytes())?;
+        stream.write_all(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes())?;
+        let _: [u8; 4] = read_array(&mut stream)?;
+        let mut structured_replies = false;
+        loop {
+            let header: [u8; 16] = read_array(&mut stream)?;
+            let option = be_u32(&header[8..]);
+            let mut data = vec![0u8; be_u32(&header[12..]) as usize];
+            stream.read_exact(&mut data)?;
+
+            match option {
+                NBD_OPT_STRUCTURED_REPLY if structured => {
+                    structured_replies = true;
+                    option_reply(&mut stream, option, NBD_REP_ACK, &[])?;
+                }
+                NBD_OPT_GO => {
+                    let flags = NBD_FLAG_HAS_FLAGS
+                        | NBD_FLAG_SEND_FLUSH
+                        | NBD_FLAG_SEND_TRIM
+                        | NBD_FLAG_SEND_WRITE_ZEROES;
+                    let mut info = NBD_INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend_from_slice(&(EXPORT_SIZE as u64).to_be_bytes());
+                    info.extend_from_slice(&flags.to_be_bytes());
+                    option_reply(&mut stream, option, NBD_REP_INFO, &info)?;
+                    option_reply(&mut stream, option, NBD_REP_ACK, &[])?;
+                    break;
+                }
+                _ => option_reply(&mut stream, option, NBD_REP_ERR_UNSUP, &[])?,
+            }
+        }
+
+        // Transmission.
+        let mut requests = 0;
+        loop {
+            let request: [u8; 28] = read_array(&mut stream)?;
+            let command = be_u16(&request[6..]);
+            let cookie = be_u64(&request[8..]);
+            let offset = be_u64(&request[16..]) as usize;
+            let len = be_u32(&request[24..]) as usize;
+            if command == NBD_CMD_DISC {
+                return Ok(());
+            }
+            let mut data = vec![0u8; if command == NBD_CMD_WRITE { len } else { 0 }];
+            stream.read_exact(&mut data)?;
+
+            // Drop the connection without replying, as a crashed server does.
+            requests += 1;
+            if Some(requests) == drop_after {
+                return Ok(());
+            }
+            commands.lock().unwrap().push(command);
+
+            let mut disk = disk.lock().unwrap();
+            if offset + len > disk.len() {
+                reply(&mut stream, structured_replies, cookie, libc::EINVAL as u32)?;
+                continue;
+            }
+            let range = offset..offset + len;
+            match command {
+                NBD_CMD_READ if structured_replies => {
+                    // The first half is sent as data, and the second one as a hole if zeroed.
+                    let half = offset + len / 2;
+                    let mut payload = (offset as u64).to_be_bytes().to_vec();
+                    payload.extend_from_slice(&disk[offset..half]);
+                    chunk(&mut stream, 0, NBD_REPLY_TYPE_OFFSET_DATA, cookie, &payload)?;
+                    let mut payload = (half as u64).to_be_bytes().to_vec();
+                    if disk[half..range.end].iter().all(|&b| b == 0) {
+                        payload.extend_from_slice(&((range.end - half) as u32).to_be_bytes());
+                        chunk(&mut stream, 0, NBD_REPLY_TYPE_OFFSET_HOLE, cookie, &payload)?;
+                    } else {
+                        payload.extend_from_slice(&disk[half..range.end]);
+                        chunk(&mut stream, 0, NBD_REPLY_TYPE_OFFSET_DATA, cookie, &payload)?;
+                    }
+                    reply(&mut stream, true, cookie, 0)?;
+                }
+                NBD_CMD_READ => {
+                    reply(&mut stream, false, cookie, 0)?;
+                    stream.write_all(&disk[range])?;
+                }
+                NBD_CMD_WRITE => {
+                    disk[range].copy_from_slice(&data);
+                    reply(&mut stream, structured_replies, cookie, 0)?;
+                }
+                NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES => {
+                    disk[range].fill(0);
+                    reply(&mut stream, structured_replies, cookie, 0)?;
+                }
+                NBD_CMD_FLUSH => reply(&mut stream, structured_replies, cookie, 0)?,
+                _ => reply(&mut stream, structured_replies, cookie, libc::EINVAL as u32)?,
+            }
+        }
+    }
+
+    fn option_reply(
+        stream: &mut UnixStream,
+        option: u32,
+        reply: u32,
+        data: &[u8],
+    ) -> io::Result<()> {
+        stream.write_all(&NBD_REP_MAGIC.to_be_bytes())?;
+        stream.write_all(&option.to_be_bytes())?;
+        stream.write_all(&reply.to_be_bytes())?;
+        stream.write_all(&(data.len() as u32).to_be_bytes())?;
+        stream.write_all(data)
+    }
+
+    /// Send the final reply of a request, without data.
+    fn reply(stream: &mut UnixStream, structured: bool, cookie: u64, error: u32) -> io::Result<()> {
+        if !structured {
+            stream.write_all(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes())?;
+            stream.write_all(&error.to_be_bytes())?;
+            return stream.write_all(&cookie.to_be_bytes());
+        }
+
+        if error == 0 {
+            return chunk(
+                stream,
+                NBD_REPLY_FLAG_DONE,
+                NBD_REPLY_TYPE_NONE,
+                cookie,
+                &[],
+            );
+        }
+        let mut payload = error.to_be_bytes().to_vec();
+        payload.extend_from_slice(&0u16.to_be_bytes());
+        chunk(
+            stream,
+            NBD_REPLY_FLAG_DONE,
+            NBD_REPLY_TYPE_ERROR | 1,
+            cookie,
+            &payload,
+        )
+    }
+
+    fn chunk(
+        stream: &mut UnixStream,
+        flags: u16,
+        chunk_type: u16,
+        cookie: u64,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        stream.write_all(&NBD_STRUCTURED_REPLY_MAGIC.to_be_bytes())?;
+        stream.write_all(&flags.to_be_bytes())?;
+        stream.write_all(&chunk_type.to_be_bytes())?;
+        stream.write_all(&cookie.to_be_bytes())?;
+        stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+        stream.write_all(payload)
+    }
+
+    #[test]
+    fn test_nbd_uri() {
+        assert_eq!(
+            NbdUri::parse("nbd://storage:10810/disk0").unwrap(),
+            NbdUri {
+                address: NbdAddress::Tcp("storage:10810".to_string()),
+                export: "disk0".to_string(),
+            }
+        );
+        assert_eq!(
+            NbdUri::parse("nbd://[::1]").unwrap(),
+            NbdUri {
+                address: NbdAddress::Tcp("[::1]:10809".to_string()),
+                export: String::new(),
+            }
+        );
+        assert_eq!(
+            NbdUri::parse("nbd+unix:///disk0?socket=/run/nbd.sock").unwrap(),
+            NbdUri {
+                address: NbdAddress::Unix(PathBuf::from("/run/nbd.sock")),
+                export: "disk0".to_string(),
+            }
+        );
+
+        for uri in [
+            "nbd:///disk0",
+            "nbd+unix:///disk0",
+            "nbd+unix://host/disk0?socket=/run/nbd.sock",
+            "/var/disk0.img",
+        ] {
+            assert!(NbdUri::parse(uri).is_err());
+        }
+        assert!(is_uri("nbd+unix:///disk0?socket=/run/nbd.sock"));
+        assert!(!is_uri("/var/disk0.img"));
+    }
+
+    #[test]
+    fn test_nbd_requests() {
+        for structured_replies in [true, false] {
+            let server = MockServer::new(structured_replies, None);
+            let mut disk = NbdImage::connect(server.uri(), false).unwrap();
+            assert_eq!(disk.size(), EXPORT_SIZE as u64);
+            assert_eq!(
+                disk.session.as_ref().unwrap().structured_replies,
+                structured_replies
+            );
+
+            // The unwritten ranges are read as zeroes (holes).
+            disk.write_at(&[0xaa; 0x400], 0x1000).unwrap();
+            let mut buf = [0xffu8; 0x800];
+            disk.read_at(&mut buf, 0x1000).unwrap();
+            assert!(buf[..0x400].iter().all(|&b| b == 0xaa));
+            assert!(buf[0x400..].iter().all(|&b| b == 0));
+
+            // Flush, discard and write zeroes are passed through.
+            disk.flush().unwrap();
+            disk.discard(0x1000, 0x200).unwrap();
+            disk.write_zeroes(0x1200, 0x200, false).unwrap();
+            disk.read_at(&mut buf, 0x1000).unwrap();
+            assert!(buf.iter().all(|&b| b == 0));
+            let commands = server.commands.lock().unwrap().clone();
+            for command in [NBD_CMD_FLUSH, NBD_CMD_TRIM, NBD_CMD_WRITE_ZEROES] {
+                assert!(commands.contains(&command));
+            }
+
+            // The server errors are reported, without dropping the connection.
+            assert_eq!(
+                disk.read_at(&mut buf, EXPORT_SIZE as u64)
+                    .unwrap_err()
+                    .raw_os_error(),
+                Some(libc::EINVAL)
+            );
+            disk.read_at(&mut buf, 0).unwrap();
+            assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[test]
+    fn test_nbd_reconnect() {
+        // The connection drops on the second request, which is then sent again.
+        let server = MockServer::new(true, Some(2));
+        let mut disk = NbdImage::connect(server.uri(), false).unwrap();
+        disk.write_at(&[0x55; 0x200], 0).unwrap();
+        let mut buf = [0u8; 0x200];
+        disk.read_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&b| b == 0x55));
+        assert_eq!(server.connections.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            *server.commands.lock().unwrap(),
+            vec![NBD_CMD_WRITE, NBD_CMD_READ]
+        );
+
+        // The write acknowledged by the dropped connection was not flushed, which the next flush
+        // reports.
+        assert_eq!(disk.flush().unwrap_err().raw_os_error(), Some(libc::EIO));
+        disk.flush().unwrap();
+    }
+
+    #[test]
+    fn test_nbd_unreachable() {
+        // The connection drops on the second request, once the server stopped listening.
+        let server = MockServer::new(true, Some(2));
+        let mut disk = NbdImage::connect(server.uri(), false).unwrap();
+        let mut buf = [0u8; 0x200];
+        disk.read_at(&mut buf, 0).unwrap();
+        fs::remove_file(server.dir.as_path().join("nbd.sock")).unwrap();
+
+        // The request fails after a single reconnection, and the next ones fail without
+        // reconnecting until the backoff delay elapsed.
+        assert_eq!(
+            disk.read_at(&mut buf, 0).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            disk.read_at(&mut buf, 0).unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+        thread::sleep(RECONNECT_DELAY);
+        assert_eq!(
+            disk.read_at(&mut buf, 0).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
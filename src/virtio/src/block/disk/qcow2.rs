@@ -0,0 +1,967 @@
+use super::{fallocate, host_block_sizes, open_chain, CacheMode, DiskImage, ImageFormat};
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// The qcow2 magic ("QFI\xfb").
+pub const QCOW2_MAGIC: u32 = 0x5146_49fb;
+
+// Header lengths (version 2 and version 3).
+const HEADER_V2_LEN: usize = 72;
+const HEADER_V3_LEN: usize = 104;
+
+// Cluster size limits (in bits).
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+
+// Limits of the tables loaded in memory (in bytes).
+const MAX_L1_TABLE_SIZE: u64 = 32 << 20;
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 << 20;
+
+// Maximum length of the backing file name.
+const MAX_BACKING_FILE_SIZE: u32 = 1023;
+
+// Layout of the created images: 64 KiB clusters and 16-bit refcounts.
+const DEFAULT_CLUSTER_BITS: u32 = 16;
+const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+
+// Header extension types.
+const EXT_END: u32 = 0;
+const EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+// Table entry masks and flags.
+const L1_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFCOUNT_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+const QCOW_OFLAG_COPIED: u64 = 1 << 63;
+const QCOW_OFLAG_COMPRESSED: u64 = 1 << 62;
+const QCOW_OFLAG_ZERO: u64 = 1;
+
+// Number of L2 tables cached in memory.
+const L2_CACHE_SIZE: usize = 64;
+
+/// qcow2 header (the fields not used by the image layer are skipped).
+struct Header {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    crypt_method: u32,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+    nb_snapshots: u32,
+    incompatible_features: u64,
+    refcount_order: u32,
+    header_length: u32,
+}
+
+impl Header {
+    /// Read the header of a qcow2 image.
+    fn read(file: &File) -> io::Result<Self> {
+        let mut buf = [0u8; HEADER_V3_LEN];
+        file.read_exact_at(&mut buf[..HEADER_V2_LEN], 0)?;
+
+        if be32(&buf, 0) != QCOW2_MAGIC {
+            return Err(invalid_data("not a qcow2 image"));
+        }
+
+        // Version 3 extends the version 2 header.
+        let version = be32(&buf, 4);
+        match version {
+            2 => {}
+            3 => file.read_exact_at(&mut buf[HEADER_V2_LEN..], HEADER_V2_LEN as u64)?,
+            _ => return Err(unsupported(format!("qcow2 version {} images", version))),
+        }
+
+        Ok(Header {
+            version,
+            backing_file_offset: be64(&buf, 8),
+            backing_file_size: be32(&buf, 16),
+            cluster_bits: be32(&buf, 20),
+            size: be64(&buf, 24),
+            crypt_method: be32(&buf, 32),
+            l1_size: be32(&buf, 36),
+            l1_table_offset: be64(&buf, 40),
+            refcount_table_offset: be64(&buf, 48),
+            refcount_table_clusters: be32(&buf, 56),
+            nb_snapshots: be32(&buf, 60),
+            incompatible_features: if version == 3 { be64(&buf, 72) } else { 0 },
+            refcount_order: if version == 3 { be32(&buf, 96) } else { 4 },
+            header_length: if version == 3 {
+                be32(&buf, 100)
+            } else {
+                HEADER_V2_LEN as u32
+            },
+        })
+    }
+
+    /// Read the backing file format from the header extensions.
+    fn backing_format(&self, file: &File) -> io::Result<Option<ImageFormat>> {
+        // The header extensions follow the header, within the first cluster.
+        let cluster_size = 1u64 << self.cluster_bits;
+        let mut offset = self.header_length as u64;
+
+        while offset + 8 <= cluster_size {
+            let mut ext = [0u8; 8];
+            file.read_exact_at(&mut ext, offset)?;
+            let (ext_type, ext_len) = (be32(&ext, 0), be32(&ext, 4) as u64);
+
+            match ext_type {
+                EXT_END => break,
+                EXT_BACKING_FORMAT => {
+                    let mut name = vec![0u8; ext_len as usize];
+                    file.read_exact_at(&mut name, offset + 8)?;
+                    let name = String::from_utf8_lossy(&name);
+                    return ImageFormat::from_name(&name)
+                        .map(Some)
+                        .ok_or_else(|| unsupported(format!("backing file format {}", name)));
+                }
+                _ => {}
+            }
+
+            // The extension data is padded to 8 bytes.
+            offset += 8 + ext_len.next_multiple_of(8);
+        }
+
+        Ok(None)
+    }
+}
+
+/// qcow2 disk image.
+///
+/// The clusters are allocated on the first write, at the end of the host file. Unallocated
+/// clusters are read from the backing file, if any, or as zeros.
+///
+/// # Attributes
+///
+/// * `file` - The host file.
+/// * `size` - The virtual disk size (in bytes).
+/// * `cluster_bits` - The cluster size (in bits).
+/// * `zero_clusters` - Whether the L2 entries can flag clusters as zeroed (version 3).
+/// * `l2_bits` - The number of entries of an L2 table (in bits).
+/// * `l1_table_offset` - The host offset of the L1 table.
+/// * `l1_table` - The L1 table.
+/// * `refcount_table_offset` - The host offset of the refcount table.
+/// * `refcount_table` - The refcount table.
+/// * `refcount_bytes` - The width of the refcount entries (in bytes).
+/// * `next_cluster` - The host offset of the next allocated cluster.
+/// * `l2_cache` - The cached L2 tables, indexed by their host offset.
+/// * `backing` - The backing image (if any).
+pub struct Qcow2Image {
+    file: File,
+    size: u64,
+    cluster_bits: u32,
+    zero_clusters: bool,
+    l2_bits: u32,
+    l1_table_offset: u64,
+    l1_table: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    refcount_bytes: u64,
+    next_cluster: u64,
+    l2_cache: HashMap<u64, Vec<u64>>,
+    backing: Option<Box<dyn DiskImage>>,
+}
+
+impl Qcow2Image {
+    /// Open a qcow2 disk image.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The host file.
+    /// * `path` - The path to the host file (relative backing file names are resolved from it).
+    /// * `read_only` - Whether the disk image is opened read-only.
+    /// * `depth` - The number of images above this one in the backing file chain.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the qcow2 disk image.
+    pub fn new(file: File, path: &Path, read_only: bool, depth: u32) -> io::Result<Self> {
+        let header = Header::read(&file)?;
+
+        // Check the header.
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&header.cluster_bits) {
+            return Err(invalid_data("invalid qcow2 cluster size"));
+        }
+        if header.crypt_method != 0 {
+            return Err(unsupported("encrypted qcow2 images".to_string()));
+        }
+        if header.incompatible_features != 0 {
+            return Err(unsupported(format!(
+                "qcow2 incompatible features {:#x}",
+                header.incompatible_features
+            )));
+        }
+        if header.version == 3 && (header.header_length as usize) < HEADER_V3_LEN {
+            return Err(invalid_data("invalid qcow2 header length"));
+        }
+        if !read_only && header.nb_snapshots != 0 {
+            return Err(unsupported(
+                "writing to qcow2 images with internal snapshots".to_string(),
+            ));
+        }
+        if !read_only && !(3..=6).contains(&header.refcount_order) {
+            return Err(unsupported(format!(
+                "writing to qcow2 images with {}-bit refcounts",
+                1u32 << header.refcount_order.min(31)
+            )));
+        }
+
+        let cluster_size = 1u64 << header.cluster_bits;
+        let l2_bits = header.cluster_bits - 3;
+
+        // The L1 table must cover the whole virtual disk.
+        let l1_size = header.l1_size as u64;
+        if l1_size * 8 > MAX_L1_TABLE_SIZE
+            || l1_size < header.size.div_ceil(cluster_size << l2_bits)
+        {
+            return Err(invalid_data("invalid qcow2 L1 table size"));
+        }
+        let l1_table = read_table(&file, header.l1_table_offset, l1_size)?;
+
+        let refcount_table_size = header.refcount_table_clusters as u64 * cluster_size;
+        if refcount_table_size > MAX_REFCOUNT_TABLE_SIZE {
+            return Err(invalid_data("invalid qcow2 refcount table size"));
+        }
+        let refcount_table =
+            read_table(&file, header.refcount_table_offset, refcount_table_size / 8)?;
+
+        // Open the backing file, which is never written.
+        let backing = if header.backing_file_offset != 0 {
+            if header.backing_file_size > MAX_BACKING_FILE_SIZE {
+                return Err(invalid_data("invalid qcow2 backing file name"));
+            }
+            let mut name = vec![0u8; header.backing_file_size as usize];
+            file.read_exact_at(&mut name, header.backing_file_offset)?;
+            let name = String::from_utf8(name)
+                .map_err(|_| invalid_data("invalid qcow2 backing file name"))?;
+
+            // Relative backing file names are relative to the image directory.
+            let backing_path = path.parent().unwrap_or(Path::new("")).join(name);
+            let format = header.backing_format(&file)?;
+            // Backing files are only read, so they go through the host page cache.
+            Some(open_chain(
+                &backing_path,
+                format,
+                true,
+                CacheMode::Writeback,
+                depth + 1,
+            )?)
+        } else {
+            None
+        };
+
+        // New clusters are appended to the host file.
+        let next_cluster = file.metadata()?.len().next_multiple_of(cluster_size);
+
+        Ok(Qcow2Image {
+            file,
+            size: header.size,
+            cluster_bits: header.cluster_bits,
+            zero_clusters: header.version >= 3,
+            l2_bits,
+            l1_table_offset: header.l1_table_offset,
+            l1_table,
+            refcount_table_offset: header.refcount_table_offset,
+            refcount_table,
+            refcount_bytes: 1 << (header.refcount_order.clamp(3, 6) - 3),
+            next_cluster,
+            l2_cache: HashMap::new(),
+            backing,
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn l1_index(&self, offset: u64) -> usize {
+        (offset >> (self.cluster_bits + self.l2_bits)) as usize
+    }
+
+    fn l2_index(&self, offset: u64) -> usize {
+        ((offset >> self.cluster_bits) & ((1 << self.l2_bits) - 1)) as usize
+    }
+
+    /// Check if a range lies within the virtual disk.
+    fn check_range(&self, offset: u64, len: usize) -> io::Result<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    /// Load an L2 table into the cache.
+    fn load_l2_table(&mut self, l2_offset: u64) -> io::Result<()> {
+        if self.l2_cache.contains_key(&l2_offset) {
+            return Ok(());
+        }
+
+        // The cached tables are never dirty, so any of them can be evicted.
+        if self.l2_cache.len() >= L2_CACHE_SIZE {
+            if let Some(&evicted) = self.l2_cache.keys().next() {
+                self.l2_cache.remove(&evicted);
+            }
+        }
+
+        let table = read_table(&self.file, l2_offset, 1 << self.l2_bits)?;
+        self.l2_cache.insert(l2_offset, table);
+
+        Ok(())
+    }
+
+    /// Get the L2 entry mapping a virtual disk offset.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the L2 entry (0 if no L2 table is allocated).
+    fn l2_entry(&mut self, offset: u64) -> io::Result<u64> {
+        let l2_offset = self.l1_table[self.l1_index(offset)] & L1_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+
+        self.load_l2_table(l2_offset)?;
+        Ok(self.l2_cache[&l2_offset][self.l2_index(offset)])
+    }
+
+    /// Get the L2 table mapping a virtual disk offset, allocating it if needed.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the host offset of the L2 table.
+    fn l2_table_for_write(&mut self, offset: u64) -> io::Result<u64> {
+        let l1_index = self.l1_index(offset);
+        let entry = self.l1_table[l1_index];
+
+        let l2_offset = entry & L1_OFFSET_MASK;
+        if l2_offset != 0 {
+            // L2 tables shared with other references are never modified in place.
+            if entry & QCOW_OFLAG_COPIED == 0 {
+                return Err(unsupported("writing to shared qcow2 L2 tables".to_string()));
+            }
+            return Ok(l2_offset);
+        }
+
+        // Allocate an empty L2 table, and only then link it from the L1 table.
+        let l2_offset = self.allocate_cluster()?;
+        self.file
+            .write_all_at(&vec![0u8; self.cluster_size() as usize], l2_offset)?;
+
+        let entry = l2_offset | QCOW_OFLAG_COPIED;
+        self.file.write_all_at(
+            &entry.to_be_bytes(),
+            self.l1_table_offset + l1_index as u64 * 8,
+        )?;
+        self.l1_table[l1_index] = entry;
+
+        Ok(l2_offset)
+    }
+
+    /// Allocate a host cluster.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the host offset of the cluster.
+    fn allocate_cluster(&mut self) -> io::Result<u64> {
+        let offset = self.next_cluster;
+        self.next_cluster += self.cluster_size();
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    /// Set the refcount of a host cluster, allocating a refcount block if needed.
+    fn set_refcount(&mut self, offset: u64, refcount: u64) -> io::Result<()> {
+        let cluster_size = self.cluster_size();
+        let entries_per_block = cluster_size / self.refcount_bytes;
+        let width = self.refcount_bytes as usize;
+
+        // Find the refcount block covering the cluster.
+        let cluster_index = offset >> self.cluster_bits;
+        let table_index = (cluster_index / entries_per_block) as usize;
+        if table_index >= self.refcount_table.len() {
+            return Err(io::Error::other("qcow2 refcount table is full"));
+        }
+
+        let mut block_offset = self.refcount_table[table_index] & REFCOUNT_OFFSET_MASK;
+        if block_offset == 0 {
+            // Allocate a new refcount block.
+            block_offset = self.next_cluster;
+            self.next_cluster += cluster_size;
+
+            // The new block may account for itself.
+            let mut block = vec![0u8; cluster_size as usize];
+            let block_index = block_offset >> self.cluster_bits;
+            let self_accounted = block_index / entries_per_block == table_index as u64;
+            if self_accounted {
+                let pos = (block_index % entries_per_block) as usize * width;
+                block[pos..pos + width].copy_from_slice(&1u64.to_be_bytes()[8 - width..]);
+            }
+            self.file.write_all_at(&block, block_offset)?;
+
+            // Link the block from the refcount table.
+            self.file.write_all_at(
+                &block_offset.to_be_bytes(),
+                self.refcount_table_offset + table_index as u64 * 8,
+            )?;
+            self.refcount_table[table_index] = block_offset;
+
+            if !self_accounted {
+                self.set_refcount(block_offset, 1)?;
+            }
+        }
+
+        let pos = block_offset + (cluster_index % entries_per_block) * self.refcount_bytes;
+        self.file
+            .write_all_at(&refcount.to_be_bytes()[8 - width..], pos)
+    }
+
+    /// Read from the backing image, or zeros beyond its end.
+    fn read_backing(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let len = match &self.backing {
+            Some(backing) => cmp::min(buf.len() as u64, backing.size().saturating_sub(offset)),
+            None => 0,
+        } as usize;
+
+        if let Some(backing) = self.backing.as_mut().filter(|_| len > 0) {
+            backing.read_at(&mut buf[..len], offset)?;
+        }
+        buf[len..].fill(0);
+
+        Ok(())
+    }
+
+    /// Write within a single cluster.
+    fn write_cluster(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let cluster_size = self.cluster_size();
+        let in_cluster = offset & (cluster_size - 1);
+
+        let l2_offset = self.l2_table_for_write(offset)?;
+        let l2_index = self.l2_index(offset);
+        self.load_l2_table(l2_offset)?;
+        let entry = self.l2_cache[&l2_offset][l2_index];
+        let host_offset = entry & L2_OFFSET_MASK;
+
+        if entry & QCOW_OFLAG_COMPRESSED != 0 {
+            return Err(unsupported(
+                "writing to compressed qcow2 clusters".to_string(),
+            ));
+        }
+        if host_offset != 0 && entry & QCOW_OFLAG_COPIED == 0 {
+            return Err(unsupported("writing to shared qcow2 clusters".to_string()));
+        }
+
+        // Allocated clusters are written in place.
+        if host_offset != 0 && entry & QCOW_OFLAG_ZERO == 0 {
+            return self.file.write_all_at(buf, host_offset + in_cluster);
+        }
+
+        // Otherwise, fill the whole cluster with its current content first.
+        let cluster_offset = offset - in_cluster;
+        let mut cluster = vec![0u8; cluster_size as usize];
+        if entry & QCOW_OFLAG_ZERO == 0 {
+            self.read_backing(&mut cluster, cluster_offset)?;
+        }
+        cluster[in_cluster as usize..in_cluster as usize + buf.len()].copy_from_slice(buf);
+
+        // Write the cluster, and only then map it from the L2 table.
+        let host_offset = match host_offset {
+            0 => self.allocate_cluster()?,
+            host_offset => host_offset,
+        };
+        self.file.write_all_at(&cluster, host_offset)?;
+
+        let entry = host_offset | QCOW_OFLAG_COPIED;
+        self.file
+            .write_all_at(&entry.to_be_bytes(), l2_offset + l2_index as u64 * 8)?;
+        if let Some(table) = self.l2_cache.get_mut(&l2_offset) {
+            table[l2_index] = entry;
+        }
+
+        Ok(())
+    }
+
+    /// Remap a whole cluster as zeroed or unallocated, releasing its host cluster if not kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The virtual disk offset of the cluster.
+    /// * `zero` - Whether the cluster is flagged as zeroed (instead of reading from the backing
+    ///   file).
+    /// * `unmap` - Whether the host cluster may be released.
+    fn remap_cluster(&mut self, offset: u64, zero: bool, unmap: bool) -> io::Result<()> {
+        let entry = self.l2_entry(offset)?;
+        let host_offset = entry & L2_OFFSET_MASK;
+
+        // Only the host clusters solely referenced by this entry can be kept or released.
+        let owned = host_offset != 0
+            && entry & QCOW_OFLAG_COMPRESSED == 0
+            && entry & QCOW_OFLAG_COPIED != 0;
+        let keep = owned && !unmap;
+
+        let mut new_entry = if keep {
+            host_offset | QCOW_OFLAG_COPIED
+        } else {
+            0
+        };
+        if zero {
+            new_entry |= QCOW_OFLAG_ZERO;
+        }
+        if new_entry == entry {
+            return Ok(());
+        }
+
+        // Update the L2 entry first, so a crash can only leak the host cluster.
+        let l2_offset = self.l2_table_for_write(offset)?;
+        let l2_index = self.l2_index(offset);
+        self.file
+            .write_all_at(&new_entry.to_be_bytes(), l2_offset + l2_index as u64 * 8)?;
+        if let Some(table) = self.l2_cache.get_mut(&l2_offset) {
+            table[l2_index] = new_entry;
+        }
+
+        if owned && !keep {
+            self.set_refcount(host_offset, 0)?;
+            // The freed cluster is never reused, so give its storage back to the host.
+            let _ = fallocate(
+                &self.file,
+                libc::FALLOC_FL_PUNCH_HOLE,
+                host_offset,
+                self.cluster_size(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Check if a chunk covers a whole cluster (the last one may be cut by the disk end).
+    fn is_whole_cluster(&self, offset: u64, len: usize) -> bool {
+        offset & (self.cluster_size() - 1) == 0
+            && (len as u64 == self.cluster_size() || offset + len as u64 == self.size)
+    }
+
+    /// Split a virtual disk range into chunks that do not cross cluster boundaries.
+    ///
+    /// # Returns
+    ///
+    /// An iterator over the (buffer offset, virtual disk offset, length) of each chunk.
+    fn chunks(&self, offset: u64, len: usize) -> impl Iterator<Item = (usize, u64, usize)> {
+        let cluster_size = self.cluster_size();
+        let mut done = 0;
+
+        std::iter::from_fn(move || {
+            if done == len {
+                return None;
+            }
+            let pos = offset + done as u64;
+            let chunk = cmp::min(
+                len - done,
+                (cluster_size - (pos & (cluster_size - 1))) as usize,
+            );
+            let item = (done, pos, chunk);
+            done += chunk;
+            Some(item)
+        })
+    }
+}
+
+impl DiskImage for Qcow2Image {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.check_range(offset, buf.len())?;
+
+        for (done, pos, len) in self.chunks(offset, buf.len()).collect::<Vec<_>>() {
+            let buf = &mut buf[done..done + len];
+            let entry = self.l2_entry(pos)?;
+            let host_offset = entry & L2_OFFSET_MASK;
+
+            if entry & QCOW_OFLAG_COMPRESSED != 0 {
+                return Err(unsupported("compressed qcow2 clusters".to_string()));
+            } else if entry & QCOW_OFLAG_ZERO != 0 {
+                buf.fill(0);
+            } else if host_offset != 0 {
+                let in_cluster = pos & (self.cluster_size() - 1);
+                self.file.read_exact_at(buf, host_offset + in_cluster)?;
+            } else {
+                self.read_backing(buf, pos)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.check_range(offset, buf.len())?;
+
+        for (done, pos, len) in self.chunks(offset, buf.len()).collect::<Vec<_>>() {
+            self.write_cluster(&buf[done..done + len], pos)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // The metadata is written through, so syncing the host file data is enough (the file
+        // size is also synced when the image grows).
+        self.file.sync_data()
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.check_range(offset, len as usize)?;
+
+        // Only whole clusters can be released. Zeroed clusters hide the backing file data, which
+        // would otherwise show up again.
+        let zero = self.zero_clusters && self.backing.is_some();
+        for (_, pos, len) in self.chunks(offset, len as usize).collect::<Vec<_>>() {
+            if self.is_whole_cluster(pos, len) {
+                self.remap_cluster(pos, zero, true)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        self.check_range(offset, len as usize)?;
+
+        // Without zeroed clusters (version 2), only unallocated clusters without a backing file
+        // are known to read as zeroes.
+        let remap = self.zero_clusters || (unmap && self.backing.is_none());
+        for (_, pos, len) in self.chunks(offset, len as usize).collect::<Vec<_>>() {
+            if remap && self.is_whole_cluster(pos, len) {
+                self.remap_cluster(pos, self.zero_clusters, unmap)?;
+            } else {
+                self.write_cluster(&vec![0u8; len], pos)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn discard_alignment(&self) -> u64 {
+        self.cluster_size()
+    }
+
+    fn block_sizes(&self) -> io::Result<(u32, u32)> {
+        // The clusters are laid out on the host file, so its block sizes still apply.
+        host_block_sizes(&self.file)
+    }
+}
+
+/// Create an empty qcow2 (version 3) disk image.
+///
+/// # Arguments
+///
+/// * `file` - The host file (its content is discarded).
+/// * `size` - The virtual disk size (in bytes).
+/// * `backing` - The backing file name and format (if any).
+///
+/// # Returns
+///
+/// An `io::Result` containing the result of the operation.
+pub fn create(file: &File, size: u64, backing: Option<(&str, ImageFormat)>) -> io::Result<()> {
+    let cluster_size = 1u64 << DEFAULT_CLUSTER_BITS;
+    let l2_bits = DEFAULT_CLUSTER_BITS - 3;
+
+    // Layout: header, refcount table, refcount block and L1 table.
+    let refcount_table_offset = cluster_size;
+    let refcount_block_offset = 2 * cluster_size;
+    let l1_table_offset = 3 * cluster_size;
+    let l1_size = size.div_ceil(cluster_size << l2_bits);
+    let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+    if l1_size * 8 > MAX_L1_TABLE_SIZE {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    // The refcount block accounts for the metadata clusters.
+    let refcount_bytes = 1u64 << (DEFAULT_REFCOUNT_ORDER - 3);
+    let metadata_clusters = 3 + l1_clusters;
+    if metadata_clusters > cluster_size / refcount_bytes {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    let mut header = vec![0u8; cluster_size as usize];
+    put32(&mut header, 0, QCOW2_MAGIC);
+    put32(&mut header, 4, 3);
+    put32(&mut header, 20, DEFAULT_CLUSTER_BITS);
+    put64(&mut header, 24, size);
+    put32(&mut header, 36, l1_size as u32);
+    put64(&mut header, 40, l1_table_offset);
+    put64(&mut header, 48, refcount_table_offset);
+    put32(&mut header, 56, 1);
+    put32(&mut header, 96, DEFAULT_REFCOUNT_ORDER);
+    put32(&mut header, 100, HEADER_V3_LEN as u32);
+
+    // The backing file format extension is followed by the end extension, and then by the
+    // backing file name.
+    if let Some((name, format)) = backing {
+        let format = match format {
+            ImageFormat::Raw => "raw",
+            ImageFormat::Qcow2 => "qcow2",
+        };
+        let ext_len = format.len().next_multiple_of(8);
+        let name_offset = HEADER_V3_LEN + 8 + ext_len + 8;
+        if name.len() > MAX_BACKING_FILE_SIZE as usize {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        put32(&mut header, HEADER_V3_LEN, EXT_BACKING_FORMAT);
+        put32(&mut header, HEADER_V3_LEN + 4, format.len() as u32);
+        header[HEADER_V3_LEN + 8..HEADER_V3_LEN + 8 + format.len()]
+            .copy_from_slice(format.as_bytes());
+        header[name_offset..name_offset + name.len()].copy_from_slice(name.as_bytes());
+        put64(&mut header, 8, name_offset as u64);
+        put32(&mut header, 16, name.len() as u32);
+    }
+
+    let mut refcount_table = vec![0u8; cluster_size as usize];
+    put64(&mut refcount_table, 0, refcount_block_offset);
+
+    let mut refcount_block = vec![0u8; cluster_size as usize];
+    for cluster in 0..metadata_clusters as usize {
+        refcount_block[cluster * 2..cluster * 2 + 2].copy_from_slice(&1u16.to_be_bytes());
+    }
+
+    // Start from an empty file, so the L1 table is zeroed.
+    file.set_len(0)?;
+    file.write_all_at(&header, 0)?;
+    file.write_all_at(&refcount_table, refcount_table_offset)?;
+    file.write_all_at(&refcount_block, refcount_block_offset)?;
+    file.set_len(l1_table_offset + l1_clusters * cluster_size)?;
+
+    file.sync_all()
+}
+
+/// Read a table of big-endian 64-bit entries.
+fn read_table(file: &File, offset: u64, entries: u64) -> io::Result<Vec<u64>> {
+    let mut buf = vec![0u8; (entries * 8) as usize];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf
+        .chunks_exact(8)
+        .map(|entry| u64::from_be_bytes(entry.try_into().unwrap()))
+        .collect())
+}
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn put32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn put64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].copy_from_slice(&value.to_be_bytes());
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn unsupported(what: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} are not supported", what),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const DISK_SIZE: u64 = 16 << 20;
+
+    fn open_image(path: &Path, read_only: bool) -> io::Result<Qcow2Image> {
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        Qcow2Image::new(file, path, read_only, 0)
+    }
+
+    /// Read the refcount of a host cluster.
+    fn refcount(image: &Qcow2Image, offset: u64) -> u64 {
+        let entries_per_block = image.cluster_size() / image.refcount_bytes;
+        let cluster_index = offset >> image.cluster_bits;
+        let block_offset = image.refcount_table[(cluster_index / entries_per_block) as usize];
+        assert_ne!(block_offset, 0);
+
+        let mut buf = [0u8; 8];
+        let width = image.refcount_bytes as usize;
+        image
+            .file
+            .read_exact_at(
+                &mut buf[8 - width..],
+                block_offset + (cluster_index % entries_per_block) * image.refcount_bytes,
+            )
+            .unwrap();
+        u64::from_be_bytes(buf)
+    }
+
+    #[test]
+    fn test_qcow2_read_write() {
+        let file = TempFile::new().unwrap();
+        create(file.as_file(), DISK_SIZE, None).unwrap();
+
+        let mut image = open_image(file.as_path(), false).unwrap();
+        assert_eq!(image.size(), DISK_SIZE);
+
+        // Unallocated clusters read as zeros.
+        let mut buf = vec![0xffu8; 0x1000];
+        image.read_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // Write across a cluster boundary, and near the end of the disk.
+        let pattern = (0..0x1000).map(|i| i as u8).collect::<Vec<u8>>();
+        let offsets = [image.cluster_size() - 0x800, DISK_SIZE - 0x1000];
+        for offset in offsets {
+            image.write_at(&pattern, offset).unwrap();
+        }
+        assert!(image.write_at(&pattern, DISK_SIZE - 0x800).is_err());
+        image.flush().unwrap();
+
+        // The data survives reopening the image, which stays sparse.
+        let mut image = open_image(file.as_path(), true).unwrap();
+        for offset in offsets {
+            image.read_at(&mut buf, offset).unwrap();
+            assert_eq!(buf, pattern);
+        }
+        image.read_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        assert!(file.as_file().metadata().unwrap().len() < DISK_SIZE / 2);
+
+        // Every allocated cluster is accounted for.
+        for offset in offsets {
+            let l2_offset = image.l1_table[image.l1_index(offset)] & L1_OFFSET_MASK;
+            assert_eq!(refcount(&image, l2_offset), 1);
+            let host_offset = image.l2_entry(offset).unwrap() & L2_OFFSET_MASK;
+            assert_eq!(refcount(&image, host_offset), 1);
+        }
+    }
+
+    #[test]
+    fn test_qcow2_backing_file() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.as_path().join("base.raw");
+        let overlay_path = dir.as_path().join("overlay.qcow2");
+
+        // The base image is smaller than the overlay.
+        let base = create_file(&base_path);
+        base.write_all_at(&vec![0xaau8; 0x10000], 0).unwrap();
+        let overlay = create_file(&overlay_path);
+        create(&overlay, 0x20000, Some(("base.raw", ImageFormat::Raw))).unwrap();
+
+        let mut image = open_image(&overlay_path, false).unwrap();
+
+        // Write a single sector, which copies the rest of the cluster from the base image.
+        image.write_at(&[0x55u8; 0x200], 0x1000).unwrap();
+        let mut buf = vec![0u8; 0x2000];
+        image.read_at(&mut buf, 0).unwrap();
+        assert!(buf[..0x1000].iter().all(|&b| b == 0xaa));
+        assert!(buf[0x1000..0x1200].iter().all(|&b| b == 0x55));
+        assert!(buf[0x1200..].iter().all(|&b| b == 0xaa));
+
+        // The base image is left untouched, and reads beyond its end return zeros.
+        let mut base_buf = vec![0u8; 0x200];
+        base.read_exact_at(&mut base_buf, 0x1000).unwrap();
+        assert!(base_buf.iter().all(|&b| b == 0xaa));
+        image.read_at(&mut buf, 0x18000).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_qcow2_discard_write_zeroes() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.as_path().join("base.raw");
+        let overlay_path = dir.as_path().join("overlay.qcow2");
+
+        let base = create_file(&base_path);
+        base.write_all_at(&vec![0xaau8; 0x40000], 0).unwrap();
+        let overlay = create_file(&overlay_path);
+        create(&overlay, 0x40000, Some(("base.raw", ImageFormat::Raw))).unwrap();
+
+        let mut image = open_image(&overlay_path, false).unwrap();
+        assert_eq!(image.discard_alignment(), 0x10000);
+        image.write_at(&vec![0x55u8; 0x20000], 0).unwrap();
+        let host_offset = image.l2_entry(0).unwrap() & L2_OFFSET_MASK;
+        assert_eq!(refcount(&image, host_offset), 1);
+
+        // Discarded clusters are released, and do not expose the backing file.
+        image.discard(0, 0x10000).unwrap();
+        assert_eq!(refcount(&image, host_offset), 0);
+        let mut buf = vec![0xffu8; 0x10000];
+        image.read_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // Partially discarded clusters are left untouched.
+        image.discard(0x30000, 0x800).unwrap();
+        image.read_at(&mut buf, 0x30000).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xaa));
+
+        // Zeroes are written to whole clusters through the L2 entries, and to partial ones
+        // through the data.
+        image.write_zeroes(0x20000, 0x10000, false).unwrap();
+        image.write_zeroes(0x10200, 0x200, false).unwrap();
+        assert_eq!(image.l2_entry(0x20000).unwrap(), QCOW_OFLAG_ZERO);
+        image.read_at(&mut buf, 0x20000).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        image.read_at(&mut buf, 0x10000).unwrap();
+        assert!(buf[..0x200].iter().all(|&b| b == 0x55));
+        assert!(buf[0x200..0x400].iter().all(|&b| b == 0));
+        assert!(buf[0x400..].iter().all(|&b| b == 0x55));
+
+        // Writing to a zeroed cluster allocates it again.
+        image.write_at(&[0x11u8; 0x200], 0x20000).unwrap();
+        image.read_at(&mut buf, 0x20000).unwrap();
+        assert!(buf[..0x200].iter().all(|&b| b == 0x11));
+        assert!(buf[0x200..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_qcow2_unsupported() {
+        let file = TempFile::new().unwrap();
+
+        // Encrypted images.
+        create(file.as_file(), DISK_SIZE, None).unwrap();
+        put_header(&file, 32, &1u32.to_be_bytes());
+        let err = open_image(file.as_path(), false).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        // Unknown versions.
+        create(file.as_file(), DISK_SIZE, None).unwrap();
+        put_header(&file, 4, &4u32.to_be_bytes());
+        assert!(open_image(file.as_path(), false).is_err());
+
+        // Images with internal snapshots can only be read.
+        create(file.as_file(), DISK_SIZE, None).unwrap();
+        put_header(&file, 60, &1u32.to_be_bytes());
+        assert!(open_image(file.as_path(), false).is_err());
+        assert!(open_image(file.as_path(), true).is_ok());
+    }
+
+    fn create_file(path: &Path) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .unwrap()
+    }
+
+    fn put_header(file: &TempFile, offset: u64, data: &[u8]) {
+        file.as_file().write_all_at(data, offset).unwrap();
+    }
+}
@@ -0,0 +1,193 @@
+use super::{fallocate, host_block_sizes, is_direct, write_zero_buffers, DiskImage};
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::ptr::NonNull;
+use std::slice;
+
+/// Memory alignment of the direct I/O buffers, which covers the alignment required by any
+/// backing storage.
+const DIRECT_IO_ALIGN: usize = 4096;
+
+/// Raw disk image, where the virtual disk is the host file itself.
+///
+/// # Attributes
+///
+/// * `file` - The host file.
+/// * `size` - The virtual disk size (in bytes).
+/// * `direct` - Whether the host file bypasses the host page cache (`O_DIRECT`).
+pub struct RawImage {
+    file: File,
+    size: u64,
+    direct: bool,
+}
+
+impl RawImage {
+    /// Create a new raw disk image.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The host file.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the raw disk image.
+    pub fn new(file: File) -> io::Result<Self> {
+        // Seek to the end, as the metadata of block devices does not carry their size.
+        let size = (&file).seek(SeekFrom::End(0))?;
+        let direct = is_direct(&file)?;
+        Ok(RawImage { file, size, direct })
+    }
+
+    /// Get the host file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Check if a buffer has to be bounced through an aligned one for direct I/O.
+    fn needs_bounce(&self, buf: &[u8]) -> bool {
+        self.direct && buf.as_ptr().align_offset(DIRECT_IO_ALIGN) != 0
+    }
+}
+
+/// Zeroed heap buffer, aligned for direct I/O.
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    /// Allocate a new aligned buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `len` - The buffer length (in bytes), which must not be zero.
+    fn new(len: usize) -> io::Result<Self> {
+        let layout = Layout::from_size_align(len, DIRECT_IO_ALIGN)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        // The allocation is safe, as the layout has a non-zero size.
+        let ptr = NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+            .ok_or_else(|| io::Error::from(io::ErrorKind::OutOfMemory))?;
+
+        Ok(AlignedBuffer { ptr, layout })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // The buffer is valid for `layout.size()` initialized bytes.
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // The buffer is valid for `layout.size()` initialized bytes, and borrowed mutably.
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // The buffer was allocated with the same layout.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+impl DiskImage for RawImage {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        if buf.is_empty() || !self.needs_bounce(buf) {
+            return self.file.read_exact_at(buf, offset);
+        }
+
+        // Direct I/O reads into aligned memory only.
+        let mut bounce = AlignedBuffer::new(buf.len())?;
+        self.file.read_exact_at(bounce.as_mut_slice(), offset)?;
+        buf.copy_from_slice(bounce.as_slice());
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if buf.is_empty() || !self.needs_bounce(buf) {
+            return self.file.write_all_at(buf, offset);
+        }
+
+        // Direct I/O writes from aligned memory only.
+        let mut bounce = AlignedBuffer::new(buf.len())?;
+        bounce.as_mut_slice().copy_from_slice(buf);
+        self.file.write_all_at(bounce.as_slice(), offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Only the file data and the metadata needed to read it back have to be durable.
+        self.file.sync_data()
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        // Discarding is only a hint, so file systems without hole punching keep the data.
+        match fallocate(&self.file, libc::FALLOC_FL_PUNCH_HOLE, offset, len) {
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        // Punched holes also read as zeroes.
+        let mode = if unmap {
+            libc::FALLOC_FL_PUNCH_HOLE
+        } else {
+            libc::FALLOC_FL_ZERO_RANGE
+        };
+
+        match fallocate(&self.file, mode, offset, len) {
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                write_zero_buffers(self, offset, len)
+            }
+            result => result,
+        }
+    }
+
+    fn block_sizes(&self) -> io::Result<(u32, u32)> {
+        host_block_sizes(&self.file)
+    }
+
+    fn raw_file(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_raw_discard_write_zeroes() {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(0x10_0000).unwrap();
+        let mut image = RawImage::new(file.as_file().try_clone().unwrap()).unwrap();
+        assert_eq!(image.size(), 0x10_0000);
+
+        image.write_at(&vec![0xaau8; 0x10_0000], 0).unwrap();
+        image.flush().unwrap();
+        let blocks = file.as_file().metadata().unwrap().blocks();
+
+        // Write zeroes, with and without releasing the storage.
+        image.write_zeroes(0x1000, 0x1000, false).unwrap();
+        image.write_zeroes(0x4000, 0x1000, true).unwrap();
+        let mut buf = vec![0xffu8; 0x1000];
+        for offset in [0x1000, 0x4000] {
+            image.read_at(&mut buf, offset).unwrap();
+            assert!(buf.iter().all(|&b| b == 0));
+        }
+        image.read_at(&mut buf, 0x2000).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xaa));
+
+        // Discarding the whole image shrinks it on file systems supporting holes.
+        image.discard(0, 0x10_0000).unwrap();
+        assert!(file.as_file().metadata().unwrap().blocks() <= blocks);
+    }
+}
@@ -0,0 +1,896 @@
+use super::{DiskImage, SECTOR_SHIFT};
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+use vmm_sys_util::{ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr};
+
+/// Maximum number of zones fetched by a single host zone report.
+const HOST_REPORT_ZONES: usize = 256;
+
+/// The zone capacity of the host zone report is valid (`BLK_ZONE_REP_CAPACITY`).
+const BLK_ZONE_REP_CAPACITY: u32 = 1;
+
+// Zoned block device ioctls (see `linux/blkzoned.h`).
+ioctl_iowr_nr!(BLKREPORTZONE, 0x12, 130, BlkZoneReport);
+ioctl_iow_nr!(BLKRESETZONE, 0x12, 131, BlkZoneRange);
+ioctl_ior_nr!(BLKGETZONESZ, 0x12, 132, u32);
+ioctl_iow_nr!(BLKOPENZONE, 0x12, 134, BlkZoneRange);
+ioctl_iow_nr!(BLKCLOSEZONE, 0x12, 135, BlkZoneRange);
+ioctl_iow_nr!(BLKFINISHZONE, 0x12, 136, BlkZoneRange);
+
+/// Header of a host zone report (`struct blk_zone_report`).
+#[repr(C)]
+#[derive(Default)]
+struct BlkZoneReport {
+    sector: u64,
+    nr_zones: u32,
+    flags: u32,
+}
+
+/// Host zone descriptor (`struct blk_zone`).
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct BlkZone {
+    start: u64,
+    len: u64,
+    wp: u64,
+    zone_type: u8,
+    cond: u8,
+    non_seq: u8,
+    reset: u8,
+    resv: [u8; 4],
+    capacity: u64,
+    reserved: [u8; 24],
+}
+
+/// Host zone report, with room for `HOST_REPORT_ZONES` zones.
+#[repr(C)]
+struct BlkZoneReportBuffer {
+    header: BlkZoneReport,
+    zones: [BlkZone; HOST_REPORT_ZONES],
+}
+
+/// Host zone range (`struct blk_zone_range`).
+#[repr(C)]
+struct BlkZoneRange {
+    sector: u64,
+    nr_sectors: u64,
+}
+
+/// Zone types. The values are the ones of both Virtio (`VIRTIO_BLK_ZT_*`) and Linux
+/// (`BLK_ZONE_TYPE_*`).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ZoneType {
+    /// Zone accepting random writes.
+    Conventional = 1,
+    /// Zone only accepting writes at its write pointer.
+    SequentialWriteRequired = 2,
+    /// Zone expecting writes at its write pointer (host-aware devices).
+    SequentialWritePreferred = 3,
+}
+
+impl ZoneType {
+    fn from_raw(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(ZoneType::Conventional),
+            2 => Some(ZoneType::SequentialWriteRequired),
+            3 => Some(ZoneType::SequentialWritePreferred),
+            _ => None,
+        }
+    }
+}
+
+/// Zone conditions. The values are the ones of both Virtio (`VIRTIO_BLK_ZS_*`) and Linux
+/// (`BLK_ZONE_COND_*`).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ZoneCondition {
+    NotWritePointer = 0,
+    Empty = 1,
+    ImplicitOpen = 2,
+    ExplicitOpen = 3,
+    Closed = 4,
+    ReadOnly = 0xd,
+    Full = 0xe,
+    Offline = 0xf,
+}
+
+impl ZoneCondition {
+    fn from_raw(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ZoneCondition::NotWritePointer),
+            1 => Some(ZoneCondition::Empty),
+            2 => Some(ZoneCondition::ImplicitOpen),
+            3 => Some(ZoneCondition::ExplicitOpen),
+            4 => Some(ZoneCondition::Closed),
+            0xd => Some(ZoneCondition::ReadOnly),
+            0xe => Some(ZoneCondition::Full),
+            0xf => Some(ZoneCondition::Offline),
+            _ => None,
+        }
+    }
+}
+
+/// Zone descriptor.
+///
+/// # Attributes
+///
+/// * `start` - The first sector of the zone.
+/// * `len` - The zone size (in sectors).
+/// * `capacity` - The number of writable sectors of the zone.
+/// * `wp` - The write pointer (sector).
+/// * `zone_type` - The zone type.
+/// * `condition` - The zone condition.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Zone {
+    pub start: u64,
+    pub len: u64,
+    pub capacity: u64,
+    pub wp: u64,
+    pub zone_type: ZoneType,
+    pub condition: ZoneCondition,
+}
+
+impl Zone {
+    /// Get the sector following the writable sectors of the zone.
+    fn end(&self) -> u64 {
+        self.start + self.capacity
+    }
+
+    /// Check if the zone is written sequentially.
+    fn is_sequential(&self) -> bool {
+        self.zone_type != ZoneType::Conventional
+    }
+
+    /// Check if the zone is open, which takes an open zone resource.
+    fn is_open(&self) -> bool {
+        matches!(
+            self.condition,
+            ZoneCondition::ImplicitOpen | ZoneCondition::ExplicitOpen
+        )
+    }
+
+    /// Check if the zone is active, which takes an active zone resource.
+    fn is_active(&self) -> bool {
+        self.is_open() || self.condition == ZoneCondition::Closed
+    }
+
+    /// Check if the zone is neither read-only nor offline.
+    fn is_usable(&self) -> bool {
+        !matches!(
+            self.condition,
+            ZoneCondition::ReadOnly | ZoneCondition::Offline
+        )
+    }
+
+    /// Get the condition of a zone that is not open, after its write pointer.
+    fn idle_condition(&self) -> ZoneCondition {
+        match self.wp {
+            wp if wp == self.start => ZoneCondition::Empty,
+            wp if wp >= self.end() => ZoneCondition::Full,
+            _ => ZoneCondition::Closed,
+        }
+    }
+
+    /// Convert a host zone descriptor.
+    fn from_host(zone: &BlkZone, flags: u32) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "unknown host zone");
+
+        Ok(Zone {
+            start: zone.start,
+            len: zone.len,
+            capacity: if flags & BLK_ZONE_REP_CAPACITY != 0 {
+                zone.capacity
+            } else {
+                zone.len
+            },
+            wp: zone.wp,
+            zone_type: ZoneType::from_raw(zone.zone_type).ok_or_else(invalid)?,
+            condition: ZoneCondition::from_raw(zone.cond).ok_or_else(invalid)?,
+        })
+    }
+}
+
+/// Zone management operations.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ZoneOp {
+    /// Explicitly open the zone.
+    Open,
+    /// Close the zone, releasing its open zone resource.
+    Close,
+    /// Move the write pointer to the end of the zone.
+    Finish,
+    /// Move the write pointer back to the start of the zone, discarding its data.
+    Reset,
+}
+
+impl ZoneOp {
+    /// Get the host ioctl performing the operation.
+    fn host_ioctl(&self) -> libc::c_ulong {
+        match self {
+            ZoneOp::Open => BLKOPENZONE(),
+            ZoneOp::Close => BLKCLOSEZONE(),
+            ZoneOp::Finish => BLKFINISHZONE(),
+            ZoneOp::Reset => BLKRESETZONE(),
+        }
+    }
+}
+
+/// Zone errors, each one matching a Virtio zone status.
+#[derive(Debug)]
+pub enum ZoneError {
+    /// The request does not apply to the zone (`VIRTIO_BLK_S_ZONE_INVALID_CMD`).
+    InvalidCommand,
+    /// The write does not start at the write pointer (`VIRTIO_BLK_S_ZONE_UNALIGNED_WP`).
+    UnalignedWritePointer,
+    /// Too many zones are open (`VIRTIO_BLK_S_ZONE_OPEN_RESOURCE`).
+    OpenResource,
+    /// Too many zones are active (`VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE`).
+    ActiveResource,
+    /// The backing storage failed (`VIRTIO_BLK_S_IOERR`).
+    Io(io::Error),
+}
+
+/// Zoned disk image, which tracks the zones of a host-managed zoned disk.
+///
+/// The zones either pass through the ones of a host zoned block device, or are emulated on top
+/// of a regular disk image. In both cases, the zone state is kept here and checked before the
+/// requests reach the backing storage, which is only accessed through this device model while
+/// its image is locked.
+///
+/// # Attributes
+///
+/// * `zones` - The zones, in sector order.
+/// * `zone_sectors` - The zone size (in sectors).
+/// * `max_open_zones` - The maximum number of open zones (0 if unlimited).
+/// * `max_active_zones` - The maximum number of active zones (0 if unlimited).
+/// * `host` - The host zoned block device, which performs the zone operations (if any).
+pub struct ZonedImage {
+    zones: Vec<Zone>,
+    zone_sectors: u64,
+    max_open_zones: u32,
+    max_active_zones: u32,
+    host: Option<File>,
+}
+
+impl ZonedImage {
+    /// Emulate zones on top of a raw disk image.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The host file of the raw disk image.
+    /// * `zone_size` - The zone size (in bytes), a power of 2 evenly dividing the disk size.
+    /// * `conventional_zones` - The number of leading conventional zones.
+    /// * `max_open_zones` - The maximum number of open zones (0 if unlimited).
+    /// * `max_active_zones` - The maximum number of active zones (0 if unlimited).
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the zoned disk image.
+    ///
+    /// # Note
+    ///
+    /// The zone state is not stored alongside the image. Instead, the write pointer of each zone
+    /// is recovered from the allocated ranges of the file, as the zones are written sequentially
+    /// and reset by punching holes. The zones found partially written are closed.
+    pub fn emulated(
+        file: &File,
+        zone_size: u64,
+        conventional_zones: u32,
+        max_open_zones: u32,
+        max_active_zones: u32,
+    ) -> io::Result<Self> {
+        // Seek to the end, as the metadata of block devices does not carry their size.
+        let size = (&*file).seek(SeekFrom::End(0))?;
+        if !zone_size.is_power_of_two()
+            || zone_size < 1 << SECTOR_SHIFT
+            || size & (zone_size - 1) != 0
+        {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let zone_sectors = zone_size >> SECTOR_SHIFT;
+        let mut zones = Vec::new();
+        for index in 0..size / zone_size {
+            let start = index * zone_sectors;
+            let mut zone = Zone {
+                start,
+                len: zone_sectors,
+                capacity: zone_sectors,
+                wp: start,
+                zone_type: ZoneType::Conventional,
+                condition: ZoneCondition::NotWritePointer,
+            };
+
+            if index >= conventional_zones as u64 {
+                let wp = allocated_end(
+                    file,
+                    start << SECTOR_SHIFT,
+                    (start + zone_sectors) << SECTOR_SHIFT,
+                )?;
+                // Partially allocated sectors were partially written, so they cannot be rewritten.
+                zone.wp = wp.div_ceil(1 << SECTOR_SHIFT);
+                zone.zone_type = ZoneType::SequentialWriteRequired;
+                zone.condition = zone.idle_condition();
+            }
+            zones.push(zone);
+        }
+
+        Ok(ZonedImage {
+            zones,
+            zone_sectors,
+            max_open_zones,
+            max_active_zones,
+            host: None,
+        })
+    }
+
+    /// Pass through the zones of a host zoned block device.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The host zoned block device.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the zoned disk image.
+    pub fn host(file: &File) -> io::Result<Self> {
+        let zone_sectors = host_zone_sectors(file)?;
+        if zone_sectors == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a zoned block device",
+            ));
+        }
+
+        // The zone resources are reported through sysfs.
+        let rdev = file.metadata()?.rdev();
+        let limit = |name: &str| {
+            fs::read_to_string(format!(
+                "/sys/dev/block/{}:{}/queue/{}",
+                libc::major(rdev),
+                libc::minor(rdev),
+                name
+            ))
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(0)
+        };
+
+        Ok(ZonedImage {
+            zones: host_zones(file)?,
+            zone_sectors: zone_sectors as u64,
+            max_open_zones: limit("max_open_zones"),
+            max_active_zones: limit("max_active_zones"),
+            host: Some(file.try_clone()?),
+        })
+    }
+
+    /// Get the zone size (in sectors).
+    pub fn zone_sectors(&self) -> u64 {
+        self.zone_sectors
+    }
+
+    /// Get the maximum number of open zones (0 if unlimited).
+    pub fn max_open_zones(&self) -> u32 {
+        self.max_open_zones
+    }
+
+    /// Get the maximum number of active zones (0 if unlimited).
+    pub fn max_active_zones(&self) -> u32 {
+        self.max_active_zones
+    }
+
+    /// Check if the zones pass through the ones of a host zoned block device.
+    pub fn is_host(&self) -> bool {
+        self.host.is_some()
+    }
+
+    /// Get the zones, starting from the one holding a sector.
+    ///
+    /// # Arguments
+    ///
+    /// * `sector` - The sector.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the zones, or `ZoneError::InvalidCommand` if the sector is beyond
+    /// the last zone.
+    pub fn zones_from(&self, sector: u64) -> Result<&[Zone], ZoneError> {
+        Ok(&self.zones[self.zone_index(sector)?..])
+    }
+
+    /// Get the index of the zone holding a sector.
+    fn zone_index(&self, sector: u64) -> Result<usize, ZoneError> {
+        let index = (sector / self.zone_sectors) as usize;
+        if index >= self.zones.len() {
+            return Err(ZoneError::InvalidCommand);
+        }
+
+        Ok(index)
+    }
+
+    /// Get the index of a sequential zone, from its first sector.
+    fn sequential_zone(&self, sector: u64) -> Result<usize, ZoneError> {
+        let index = self.zone_index(sector)?;
+        let zone = &self.zones[index];
+        if zone.start != sector || !zone.is_sequential() || !zone.is_usable() {
+            return Err(ZoneError::InvalidCommand);
+        }
+
+        Ok(index)
+    }
+
+    /// Take the resources needed to open a zone.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The zone index.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    ///
+    /// # Note
+    ///
+    /// If all the open zone resources are taken, one of the implicitly open zones is closed.
+    fn reserve(&mut self, index: usize) -> Result<(), ZoneError> {
+        if self.zones[index].is_open() {
+            return Ok(());
+        }
+
+        // An empty zone becomes active.
+        if self.zones[index].condition == ZoneCondition::Empty
+            && self.max_active_zones != 0
+            && self.zones.iter().filter(|zone| zone.is_active()).count()
+                >= self.max_active_zones as usize
+        {
+            return Err(ZoneError::ActiveResource);
+        }
+
+        if self.max_open_zones != 0
+            && self.zones.iter().filter(|zone| zone.is_open()).count()
+                >= self.max_open_zones as usize
+        {
+            // Host devices close their implicitly open zones by themselves.
+            let zone = self
+                .zones
+                .iter_mut()
+                .find(|zone| zone.condition == ZoneCondition::ImplicitOpen)
+                .ok_or(ZoneError::OpenResource)?;
+            zone.condition = ZoneCondition::Closed;
+        }
+
+        Ok(())
+    }
+
+    /// Write to a zone, checking and advancing its write pointer.
+    ///
+    /// # Arguments
+    ///
+    /// * `sector` - The first sector to write, or the first sector of the zone to append to.
+    /// * `num_sectors` - The number of sectors to write.
+    /// * `append` - Whether the data is appended at the write pointer.
+    /// * `write` - The function writing the data to the backing storage, from a sector.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the first written sector.
+    pub fn write<F>(
+        &mut self,
+        sector: u64,
+        num_sectors: u64,
+        append: bool,
+        write: F,
+    ) -> Result<u64, ZoneError>
+    where
+        F: FnOnce(u64) -> io::Result<()>,
+    {
+        let index = self.zone_index(sector)?;
+        let zone = self.zones[index];
+
+        // Conventional zones take random writes, but no appends.
+        if !zone.is_sequential() {
+            if append || sector + num_sectors > zone.start + zone.len {
+                return Err(ZoneError::InvalidCommand);
+            }
+            write(sector).map_err(ZoneError::Io)?;
+            return Ok(sector);
+        }
+
+        if (append && sector != zone.start)
+            || !zone.is_usable()
+            || zone.condition == ZoneCondition::Full
+        {
+            return Err(ZoneError::InvalidCommand);
+        }
+        if !append && sector != zone.wp {
+            return Err(ZoneError::UnalignedWritePointer);
+        }
+        if zone.wp + num_sectors > zone.end() {
+            return Err(ZoneError::InvalidCommand);
+        }
+
+        // Writing implicitly opens the zone.
+        self.reserve(index)?;
+        if !self.zones[index].is_open() {
+            self.zones[index].condition = ZoneCondition::ImplicitOpen;
+        }
+
+        write(zone.wp).map_err(ZoneError::Io)?;
+
+        let zone = &mut self.zones[index];
+        zone.wp += num_sectors;
+        if zone.wp == zone.end() {
+            zone.condition = ZoneCondition::Full;
+        }
+
+        Ok(zone.wp - num_sectors)
+    }
+
+    /// Manage a sequential zone.
+    ///
+    /// # Arguments
+    ///
+    /// * `disk` - The disk image, whose zone data is discarded on reset.
+    /// * `op` - The operation.
+    /// * `sector` - The first sector of the zone.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    pub fn manage(
+        &mut self,
+        disk: &mut dyn DiskImage,
+        op: ZoneOp,
+        sector: u64,
+    ) -> Result<(), ZoneError> {
+        let index = self.sequential_zone(sector)?;
+        let zone = self.zones[index];
+
+        // Compute the new zone state, or return early if there is nothing to do.
+        let (wp, condition) = match op {
+            ZoneOp::Open => {
+                if matches!(
+                    zone.condition,
+                    ZoneCondition::ExplicitOpen | ZoneCondition::Full
+                ) {
+                    return Ok(());
+                }
+                self.reserve(index)?;
+                (zone.wp, ZoneCondition::ExplicitOpen)
+            }
+            ZoneOp::Close if zone.is_open() => (zone.wp, zone.idle_condition()),
+            ZoneOp::Finish if zone.condition != ZoneCondition::Full => {
+                (zone.end(), ZoneCondition::Full)
+            }
+            ZoneOp::Reset if zone.condition != ZoneCondition::Empty => {
+                (zone.start, ZoneCondition::Empty)
+            }
+            _ => return Ok(()),
+        };
+
+        // Let the host device perform the operation, or discard the data of the emulated zone.
+        match self.host.as_ref() {
+            Some(host) => {
+                let range = BlkZoneRange {
+                    sector: zone.start,
+                    nr_sectors: zone.len,
+                };
+                // The ioctl is safe. Called with a valid zone range, and we check the return.
+                if unsafe { ioctl_with_ref(host, op.host_ioctl(), &range) } < 0 {
+                    return Err(ZoneError::Io(io::Error::last_os_error()));
+                }
+            }
+            None if op == ZoneOp::Reset => disk
+                .discard(zone.start << SECTOR_SHIFT, zone.len << SECTOR_SHIFT)
+                .map_err(ZoneError::Io)?,
+            None => (),
+        }
+
+        let zone = &mut self.zones[index];
+        zone.wp = wp;
+        zone.condition = condition;
+
+        Ok(())
+    }
+
+    /// Reset all the sequential zones.
+    ///
+    /// # Arguments
+    ///
+    /// * `disk` - The disk image, whose zone data is discarded.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    pub fn reset_all(&mut self, disk: &mut dyn DiskImage) -> Result<(), ZoneError> {
+        for index in 0..self.zones.len() {
+            let zone = self.zones[index];
+            if zone.is_sequential() && zone.is_usable() && zone.condition != ZoneCondition::Empty {
+                self.manage(disk, ZoneOp::Reset, zone.start)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check if a host file is a zoned block device.
+///
+/// # Arguments
+///
+/// * `file` - The host file.
+///
+/// # Returns
+///
+/// An `io::Result` containing whether the file is a zoned block device.
+pub fn is_host_zoned(file: &File) -> io::Result<bool> {
+    if !file.metadata()?.file_type().is_block_device() {
+        return Ok(false);
+    }
+
+    // Kernels without zoned block device support do not know the ioctl.
+    match host_zone_sectors(file) {
+        Ok(zone_sectors) => Ok(zone_sectors != 0),
+        Err(e) if e.raw_os_error() == Some(libc::ENOTTY) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Get the zone size of a host block device (in sectors, 0 if the device is not zoned).
+fn host_zone_sectors(file: &File) -> io::Result<u32> {
+    let mut zone_sectors: u32 = 0;
+
+    // The ioctl is safe. Called with a valid block device fd, and we check the return.
+    if unsafe { ioctl_with_mut_ref(file, BLKGETZONESZ(), &mut zone_sectors) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(zone_sectors)
+}
+
+/// Get all the zones of a host zoned block device.
+fn host_zones(file: &File) -> io::Result<Vec<Zone>> {
+    let mut report = Box::new(BlkZoneReportBuffer {
+        header: BlkZoneReport::default(),
+        zones: [BlkZone::default(); HOST_REPORT_ZONES],
+    });
+    let mut zones = Vec::new();
+
+    loop {
+        // Report the zones following the ones already reported.
+        report.header = BlkZoneReport {
+            sector: zones.last().map_or(0, |zone: &Zone| zone.start + zone.len),
+            nr_zones: HOST_REPORT_ZONES as u32,
+            flags: 0,
+        };
+        // The ioctl is safe. Called with a report holding `nr_zones` zones, and we check the return.
+        if unsafe { ioctl_with_mut_ref(file, BLKREPORTZONE(), report.as_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let count = report.header.nr_zones as usize;
+        if count == 0 {
+            return Ok(zones);
+        }
+        for zone in &report.zones[..count.min(HOST_REPORT_ZONES)] {
+            zones.push(Zone::from_host(zone, report.header.flags)?);
+        }
+    }
+}
+
+/// Find the end of the allocated ranges of a host file, within a range.
+///
+/// # Arguments
+///
+/// * `file` - The host file.
+/// * `start` - The range start (in bytes).
+/// * `end` - The range end (in bytes).
+///
+/// # Returns
+///
+/// An `io::Result` containing the end of the last allocated range, or `start` if the whole
+/// range is a hole. File systems without hole support report the whole file as allocated.
+fn allocated_end(file: &File, start: u64, end: u64) -> io::Result<u64> {
+    let seek = |offset: u64, whence: libc::c_int| {
+        // The lseek is safe. Called with a valid fd, and we check the return.
+        match unsafe { libc::lseek64(file.as_raw_fd(), offset as libc::off64_t, whence) } {
+            ret if ret < 0 => Err(io::Error::last_os_error()),
+            ret => Ok(ret as u64),
+        }
+    };
+
+    let mut allocated = start;
+    let mut offset = start;
+    while offset < end {
+        let data = match seek(offset, libc::SEEK_DATA) {
+            Ok(data) if data < end => data,
+            // There is no data left within the range.
+            Ok(_) => break,
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) => return Err(e),
+        };
+        offset = seek(data, libc::SEEK_HOLE)?;
+        allocated = offset.min(end);
+    }
+
+    Ok(allocated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::disk::raw::RawImage;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const ZONE_SIZE: u64 = 0x10_0000;
+    const ZONE_SECTORS: u64 = ZONE_SIZE >> SECTOR_SHIFT;
+
+    #[test]
+    fn test_zoned_layout() {
+        let image = TempFile::new().unwrap();
+        image.as_file().set_len(4 * ZONE_SIZE).unwrap();
+
+        // The zone size must evenly divide the disk.
+        assert!(ZonedImage::emulated(image.as_file(), 3 * ZONE_SIZE, 0, 0, 0).is_err());
+        assert!(ZonedImage::emulated(image.as_file(), 0, 0, 0, 0).is_err());
+
+        let zoned = ZonedImage::emulated(image.as_file(), ZONE_SIZE, 1, 0, 0).unwrap();
+        assert!(!zoned.is_host());
+        assert_eq!(zoned.zone_sectors(), ZONE_SECTORS);
+        let zones = zoned.zones_from(0).unwrap();
+        assert_eq!(zones.len(), 4);
+        assert_eq!(zones[0].zone_type, ZoneType::Conventional);
+        assert_eq!(zones[0].condition, ZoneCondition::NotWritePointer);
+        assert_eq!(zones[1].zone_type, ZoneType::SequentialWriteRequired);
+        assert_eq!(zones[1].condition, ZoneCondition::Empty);
+        assert_eq!(zones[3].start, 3 * ZONE_SECTORS);
+        assert_eq!(zoned.zones_from(3 * ZONE_SECTORS + 1).unwrap().len(), 1);
+        assert!(matches!(
+            zoned.zones_from(4 * ZONE_SECTORS),
+            Err(ZoneError::InvalidCommand)
+        ));
+
+        // Regular files are not host zoned devices.
+        assert!(!is_host_zoned(image.as_file()).unwrap());
+        assert!(ZonedImage::host(image.as_file()).is_err());
+    }
+
+    #[test]
+    fn test_zoned_writes() {
+        let image = TempFile::new().unwrap();
+        image.as_file().set_len(5 * ZONE_SIZE).unwrap();
+        let mut disk = RawImage::new(image.as_file().try_clone().unwrap()).unwrap();
+        let mut zoned = ZonedImage::emulated(image.as_file(), ZONE_SIZE, 1, 2, 3).unwrap();
+        let write = |zoned: &mut ZonedImage, sector, num_sectors, append| {
+            zoned.write(sector, num_sectors, append, |_| Ok(()))
+        };
+
+        // Conventional zones take random writes, but no appends.
+        assert_eq!(write(&mut zoned, 8, 8, false).unwrap(), 8);
+        assert!(matches!(
+            write(&mut zoned, 0, 8, true),
+            Err(ZoneError::InvalidCommand)
+        ));
+
+        // Sequential zones only take writes at their write pointer.
+        let zone = ZONE_SECTORS;
+        assert!(matches!(
+            write(&mut zoned, zone + 8, 8, false),
+            Err(ZoneError::UnalignedWritePointer)
+        ));
+        assert_eq!(write(&mut zoned, zone, 8, false).unwrap(), zone);
+        assert_eq!(write(&mut zoned, zone, 8, true).unwrap(), zone + 8);
+        let state = zoned.zones_from(zone).unwrap()[0];
+        assert_eq!(state.wp, zone + 16);
+        assert_eq!(state.condition, ZoneCondition::ImplicitOpen);
+
+        // Writes cannot cross the zone end.
+        assert!(matches!(
+            write(&mut zoned, zone, ZONE_SECTORS, true),
+            Err(ZoneError::InvalidCommand)
+        ));
+
+        // Filling the zone releases its resources.
+        write(&mut zoned, zone, ZONE_SECTORS - 16, true).unwrap();
+        assert_eq!(
+            zoned.zones_from(zone).unwrap()[0].condition,
+            ZoneCondition::Full
+        );
+        assert!(matches!(
+            write(&mut zoned, zone, 8, true),
+            Err(ZoneError::InvalidCommand)
+        ));
+
+        // Explicitly open two zones, which takes all the open zone resources.
+        zoned
+            .manage(&mut disk, ZoneOp::Open, 2 * ZONE_SECTORS)
+            .unwrap();
+        zoned
+            .manage(&mut disk, ZoneOp::Open, 3 * ZONE_SECTORS)
+            .unwrap();
+        assert!(matches!(
+            zoned.manage(&mut disk, ZoneOp::Open, zone + 8),
+            Err(ZoneError::InvalidCommand)
+        ));
+        zoned.manage(&mut disk, ZoneOp::Reset, zone).unwrap();
+        assert!(matches!(
+            write(&mut zoned, zone, 8, true),
+            Err(ZoneError::OpenResource)
+        ));
+
+        // Closed zones stay active, while closing an empty zone releases all its resources.
+        write(&mut zoned, 2 * ZONE_SECTORS, 8, false).unwrap();
+        zoned
+            .manage(&mut disk, ZoneOp::Close, 2 * ZONE_SECTORS)
+            .unwrap();
+        zoned
+            .manage(&mut disk, ZoneOp::Close, 3 * ZONE_SECTORS)
+            .unwrap();
+        let zones = zoned.zones_from(2 * ZONE_SECTORS).unwrap();
+        assert_eq!(zones[0].condition, ZoneCondition::Closed);
+        assert_eq!(zones[1].condition, ZoneCondition::Empty);
+
+        // With three active zones, no other zone can be opened.
+        write(&mut zoned, 3 * ZONE_SECTORS, 8, false).unwrap();
+        zoned
+            .manage(&mut disk, ZoneOp::Open, 4 * ZONE_SECTORS)
+            .unwrap();
+        assert!(matches!(
+            write(&mut zoned, zone, 8, true),
+            Err(ZoneError::ActiveResource)
+        ));
+
+        // Finishing a zone releases its resources, and the implicitly open zone is closed to
+        // make room for another one.
+        zoned
+            .manage(&mut disk, ZoneOp::Finish, 2 * ZONE_SECTORS)
+            .unwrap();
+        assert_eq!(write(&mut zoned, zone, 8, true).unwrap(), zone);
+        assert_eq!(
+            zoned.zones_from(3 * ZONE_SECTORS).unwrap()[0].condition,
+            ZoneCondition::Closed
+        );
+
+        // Resetting all the zones empties the sequential ones.
+        zoned.reset_all(&mut disk).unwrap();
+        assert!(zoned
+            .zones_from(zone)
+            .unwrap()
+            .iter()
+            .all(|zone| zone.condition == ZoneCondition::Empty && zone.wp == zone.start));
+    }
+
+    #[test]
+    fn test_zoned_write_pointer_recovery() {
+        let image = TempFile::new().unwrap();
+        image.as_file().set_len(3 * ZONE_SIZE).unwrap();
+        let mut disk = RawImage::new(image.as_file().try_clone().unwrap()).unwrap();
+        let mut zoned = ZonedImage::emulated(image.as_file(), ZONE_SIZE, 0, 0, 0).unwrap();
+
+        // Partially write the first zone and fill the second one.
+        let data = vec![0xaau8; 0x8000];
+        zoned
+            .write(0, 0x8000 >> SECTOR_SHIFT, false, |sector| {
+                disk.write_at(&data, sector << SECTOR_SHIFT)
+            })
+            .unwrap();
+        let data = vec![0xaau8; ZONE_SIZE as usize];
+        zoned
+            .write(ZONE_SECTORS, ZONE_SECTORS, true, |sector| {
+                disk.write_at(&data, sector << SECTOR_SHIFT)
+            })
+            .unwrap();
+
+        // The write pointers are recovered on file systems supporting holes.
+        let zoned = ZonedImage::emulated(image.as_file(), ZONE_SIZE, 0, 0, 0).unwrap();
+        let zones = zoned.zones_from(0).unwrap();
+        assert_eq!(zones[1].condition, ZoneCondition::Full);
+        if zones[2].condition == ZoneCondition::Empty {
+            assert_eq!(zones[0].condition, ZoneCondition::Closed);
+            assert_eq!(zones[0].wp, 0x8000 >> SECTOR_SHIFT);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+/// Maximum size of a data segment (in bytes).
+pub const MAX_SEGMENT_SIZE: u32 = 1 << 20;
+
+/// Number of heads of the emulated geometry.
+pub const GEOMETRY_HEADS: u8 = 16;
+/// Number of sectors per track of the emulated geometry.
+pub const GEOMETRY_SECTORS: u8 = 63;
+
+/// Maximum number of sectors of a discard or write zeroes segment.
+pub const MAX_DISCARD_SECTORS: u32 = 1 << 22;
+/// Maximum number of segments of a discard or write zeroes request.
+pub const MAX_DISCARD_SEG: u32 = 32;
+
+/// Offset of the `capacity` field.
+pub const CAPACITY_OFFSET: usize = 0;
+/// Offset of the `writeback` field, the only one the driver may write.
+pub const WRITEBACK_OFFSET: usize = 32;
+/// Offset of the zoned characteristics (`struct virtio_blk_zoned_characteristics`).
+pub const ZONED_OFFSET: usize = 72;
+
+/// Size of the configuration space.
+pub const CONFIG_SPACE_SIZE: usize = 96;
+
+/// Virtio block configuration space (`struct virtio_blk_config` in the Virtio specification).
+///
+/// The driver only interprets the fields of the negotiated features, so the ones of the
+/// features not offered by the device are left zeroed.
+///
+/// # Attributes
+///
+/// * `capacity` - The disk size (in sectors).
+/// * `size_max` - The maximum size of a data segment (`VIRTIO_BLK_F_SIZE_MAX`).
+/// * `seg_max` - The maximum number of data segments of a request (`VIRTIO_BLK_F_SEG_MAX`).
+/// * `cylinders` - The number of cylinders (`VIRTIO_BLK_F_GEOMETRY`).
+/// * `heads` - The number of heads (`VIRTIO_BLK_F_GEOMETRY`).
+/// * `sectors` - The number of sectors per track (`VIRTIO_BLK_F_GEOMETRY`).
+/// * `blk_size` - The logical block size (`VIRTIO_BLK_F_BLK_SIZE`).
+/// * `physical_block_exp` - The number of logical blocks per physical block, as a power of two
+///   (`VIRTIO_BLK_F_TOPOLOGY`).
+/// * `alignment_offset` - The offset of the first aligned logical block (`VIRTIO_BLK_F_TOPOLOGY`).
+/// * `min_io_size` - The suggested minimum I/O size, in logical blocks (`VIRTIO_BLK_F_TOPOLOGY`).
+/// * `opt_io_size` - The optimal I/O size, in logical blocks (`VIRTIO_BLK_F_TOPOLOGY`).
+/// * `writeback` - The cache mode, 1 for writeback (`VIRTIO_BLK_F_CONFIG_WCE`).
+/// * `num_queues` - The number of request queues (`VIRTIO_BLK_F_MQ`).
+/// * `max_discard_sectors` - The maximum number of sectors of a discard segment
+///   (`VIRTIO_BLK_F_DISCARD`).
+/// * `max_discard_seg` - The maximum number of discard segments (`VIRTIO_BLK_F_DISCARD`).
+/// * `discard_sector_alignment` - The discard alignment, in sectors (`VIRTIO_BLK_F_DISCARD`).
+/// * `max_write_zeroes_sectors` - The maximum number of sectors of a write zeroes segment
+///   (`VIRTIO_BLK_F_WRITE_ZEROES`).
+/// * `max_write_zeroes_seg` - The maximum number of write zeroes segments
+///   (`VIRTIO_BLK_F_WRITE_ZEROES`).
+/// * `write_zeroes_may_unmap` - Whether write zeroes requests may release the storage
+///   (`VIRTIO_BLK_F_WRITE_ZEROES`).
+/// * `zone_sectors` - The zone size, in sectors (`VIRTIO_BLK_F_ZONED`).
+/// * `max_open_zones` - The maximum number of open zones, 0 if unlimited (`VIRTIO_BLK_F_ZONED`).
+/// * `max_active_zones` - The maximum number of active zones, 0 if unlimited
+///   (`VIRTIO_BLK_F_ZONED`).
+/// * `max_append_sectors` - The maximum number of sectors of a zone append request
+///   (`VIRTIO_BLK_F_ZONED`).
+/// * `write_granularity` - The alignment of the writes, in bytes (`VIRTIO_BLK_F_ZONED`).
+/// * `zoned_model` - The zoned model (`VIRTIO_BLK_F_ZONED`).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlockConfigSpace {
+    pub capacity: u64,
+    pub size_max: u32,
+    pub seg_max: u32,
+    pub cylinders: u16,
+    pub heads: u8,
+    pub sectors: u8,
+    pub blk_size: u32,
+    pub physical_block_exp: u8,
+    pub alignment_offset: u8,
+    pub min_io_size: u16,
+    pub opt_io_size: u32,
+    pub writeback: u8,
+    pub num_queues: u16,
+    pub max_discard_sectors: u32,
+    pub max_discard_seg: u32,
+    pub discard_sector_alignment: u32,
+    pub max_write_zeroes_sectors: u32,
+    pub max_write_zeroes_seg: u32,
+    pub write_zeroes_may_unmap: u8,
+    pub zone_sectors: u32,
+    pub max_open_zones: u32,
+    pub max_active_zones: u32,
+    pub max_append_sectors: u32,
+    pub write_granularity: u32,
+    pub zoned_model: u8,
+}
+
+impl BlockConfigSpace {
+    /// Set the emulated disk geometry, used by legacy partitioning tools.
+    ///
+    /// The number of cylinders is derived from the capacity, and saturates for disks larger
+    /// than the geometry is able to describe.
+    pub fn set_geometry(&mut self) {
+        let cylinder_sectors = GEOMETRY_HEADS as u64 * GEOMETRY_SECTORS as u64;
+
+        self.heads = GEOMETRY_HEADS;
+        self.sectors = GEOMETRY_SECTORS;
+        self.cylinders = (self.capacity / cylinder_sectors).min(u16::MAX as u64) as u16;
+    }
+
+    /// Set the block size and topology fields.
+    ///
+    /// # Arguments
+    ///
+    /// * `logical` - The logical block size (in bytes).
+    /// * `physical` - The physical block size (in bytes), a power of 2 multiple of `logical`.
+    pub fn set_block_sizes(&mut self, logical: u32, physical: u32) {
+        let blocks_per_physical = physical / logical;
+
+        self.blk_size = logical;
+        self.physical_block_exp = blocks_per_physical.trailing_zeros() as u8;
+        // The virtual disk starts at the beginning of the backing storage, so it is aligned.
+        self.alignment_offset = 0;
+        // Smaller I/O leads to read-modify-write cycles on the backing storage.
+        self.min_io_size = blocks_per_physical as u16;
+        self.opt_io_size = blocks_per_physical;
+    }
+
+    /// Serialize the configuration space.
+    ///
+    /// # Returns
+    ///
+    /// The configuration space bytes (little-endian, as required by the Virtio specification).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(CONFIG_SPACE_SIZE);
+
+        bytes.extend_from_slice(&self.capacity.to_le_bytes());
+        bytes.extend_from_slice(&self.size_max.to_le_bytes());
+        bytes.extend_from_slice(&self.seg_max.to_le_bytes());
+        bytes.extend_from_slice(&self.cylinders.to_le_bytes());
+        bytes.push(self.heads);
+        bytes.push(self.sectors);
+        bytes.extend_from_slice(&self.blk_size.to_le_bytes());
+        bytes.push(self.physical_block_exp);
+        bytes.push(self.alignment_offset);
+        bytes.extend_from_slice(&self.min_io_size.to_le_bytes());
+        bytes.extend_from_slice(&self.opt_io_size.to_le_bytes());
+        bytes.push(self.writeback);
+        // Unused.
+        bytes.push(0);
+        bytes.extend_from_slice(&self.num_queues.to_le_bytes());
+        bytes.extend_from_slice(&self.max_discard_sectors.to_le_bytes());
+        bytes.extend_from_slice(&self.max_discard_seg.to_le_bytes());
+        bytes.extend_from_slice(&self.discard_sector_alignment.to_le_bytes());
+        bytes.extend_from_slice(&self.max_write_zeroes_sectors.to_le_bytes());
+        bytes.extend_from_slice(&self.max_write_zeroes_seg.to_le_bytes());
+        bytes.push(self.write_zeroes_may_unmap);
+        // Unused.
+        bytes.extend_from_slice(&[0; 3]);
+        // Secure erase is not supported.
+        bytes.extend_from_slice(&[0; 12]);
+        bytes.extend_from_slice(&self.zone_sectors.to_le_bytes());
+        bytes.extend_from_slice(&self.max_open_zones.to_le_bytes());
+        bytes.extend_from_slice(&self.max_active_zones.to_le_bytes());
+        bytes.extend_from_slice(&self.max_append_sectors.to_le_bytes());
+        bytes.extend_from_slice(&self.write_granularity.to_le_bytes());
+        bytes.push(self.zoned_model);
+        // Unused.
+        bytes.extend_from_slice(&[0; 3]);
+
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_config_space_layout() {
+        let config_space = BlockConfigSpace {
+            capacity: 0x0102_0304_0506_0708,
+            max_discard_sectors: 0x11,
+            discard_sector_alignment: 0x22,
+            writeback: 1,
+            write_zeroes_may_unmap: 1,
+            zone_sectors: 0x33,
+            write_granularity: 0x44,
+            zoned_model: 1,
+            ..Default::default()
+        };
+
+        // The field offsets follow `struct virtio_blk_config`.
+        let bytes = config_space.to_bytes();
+        assert_eq!(bytes.len(), CONFIG_SPACE_SIZE);
+        assert_eq!(
+            bytes[CAPACITY_OFFSET..8],
+            0x0102_0304_0506_0708u64.to_le_bytes()
+        );
+        assert_eq!(bytes[WRITEBACK_OFFSET], 1);
+        assert_eq!(bytes[36..40], 0x11u32.to_le_bytes());
+        assert_eq!(bytes[44..48], 0x22u32.to_le_bytes());
+        assert_eq!(bytes[56], 1);
+        assert_eq!(bytes[ZONED_OFFSET..ZONED_OFFSET + 4], 0x33u32.to_le_bytes());
+        assert_eq!(bytes[88..92], 0x44u32.to_le_bytes());
+        assert_eq!(bytes[92], 1);
+    }
+
+    #[test]
+    fn test_block_config_space_topology() {
+        let mut config_space = BlockConfigSpace {
+            capacity: 0x10_0000,
+            ..Default::default()
+        };
+        config_space.set_geometry();
+        config_space.set_block_sizes(4096, 4096);
+
+        let bytes = config_space.to_bytes();
+        assert_eq!(bytes[16..18], 1040u16.to_le_bytes());
+        assert_eq!(bytes[18], GEOMETRY_HEADS);
+        assert_eq!(bytes[19], GEOMETRY_SECTORS);
+        assert_eq!(bytes[20..24], 4096u32.to_le_bytes());
+        assert_eq!(bytes[24], 0);
+        assert_eq!(bytes[26..28], 1u16.to_le_bytes());
+
+        // 512 bytes logical blocks on 4K physical blocks.
+        config_space.set_block_sizes(512, 4096);
+        let bytes = config_space.to_bytes();
+        assert_eq!(bytes[20..24], 512u32.to_le_bytes());
+        assert_eq!(bytes[24], 3);
+        assert_eq!(bytes[26..28], 8u16.to_le_bytes());
+        assert_eq!(bytes[28..32], 8u32.to_le_bytes());
+
+        // The cylinders saturate on large disks.
+        config_space.capacity = u64::MAX;
+        config_space.set_geometry();
+        assert_eq!(config_space.cylinders, u16::MAX);
+    }
+}
@@ -1,30 +1,79 @@
-use crate::device::{VirtioDevType, VirtioDeviceCommon};
-use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom};
-use std::path::PathBuf;
-
-use super::inorder_handler::InOrderQueueHandler;
-use super::queue_handler::QueueHandler;
-use crate::device::{SingleFdSignalQueue, Subscriber, VirtioDeviceT};
-use api::device_model::BaoDeviceModel;
+use super::config_space::{
+    BlockConfigSpace, CAPACITY_OFFSET, MAX_DISCARD_SECTORS, MAX_DISCARD_SEG, MAX_SEGMENT_SIZE,
+    WRITEBACK_OFFSET,
+};
+use super::zoned::{max_append_sectors, DEFAULT_ZONE_SIZE, VIRTIO_BLK_F_ZONED, VIRTIO_BLK_Z_HM};
+use crate::block::disk::crypt::{CryptImage, EncryptionKey};
+use crate::block::disk::nbd;
+use crate::block::disk::raw::RawImage;
+use crate::block::disk::zoned::{self, ZonedImage};
+use crate::block::disk::{
+    self, qcow2, CacheMode, DiskImage, ImageFormat, SharedImage, MAX_PHYSICAL_BLOCK_SIZE,
+    SECTOR_SHIFT,
+};
+use crate::device::{queue_config, VirtioDevType, VirtioDeviceCommon};
+use crate::rate_limiter::RateLimiter;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use super::inorder_handler::{DeviceId, InOrderQueueHandler, WriteCache};
+use super::io_uring_handler::{self, IoUringQueueHandler};
+use super::metrics::{BlockMetrics, BlockMetricsSnapshot};
+use super::queue_handler::{IoUringHandler, QueueHandler};
+use crate::device::{clone_queue, Subscriber, VirtioDeviceT};
+use api::device_model::DeviceModelT;
 use api::error::{Error, Result};
 use api::types::DeviceConfig;
 use event_manager::{
     EventManager, MutEventSubscriber, RemoteEndpoint, Result as EvmgrResult, SubscriberId,
 };
 use std::borrow::{Borrow, BorrowMut};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
-use virtio_bindings::virtio_blk::{VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO};
+use std::thread::{Builder, JoinHandle};
+use virtio_bindings::virtio_blk::{
+    VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_CONFIG_WCE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH,
+    VIRTIO_BLK_F_GEOMETRY, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX,
+    VIRTIO_BLK_F_SIZE_MAX, VIRTIO_BLK_F_TOPOLOGY, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_ID_BYTES,
+};
 use virtio_bindings::virtio_config::VIRTIO_F_IN_ORDER;
-use virtio_blk::stdio_executor::StdIoBackend;
 use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
-use virtio_queue::Queue;
+use virtio_queue::{Queue, QueueT};
 use vm_device::bus::MmioAddress;
 use vm_device::device_manager::{IoManager, MmioManager};
 use vm_device::MutDeviceMmio;
 
-// The sector size is 512 bytes (1 << 9).
-const SECTOR_SHIFT: u8 = 9;
+/// Block I/O engines.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IoEngine {
+    /// Synchronous I/O, served in order on the event manager thread.
+    Sync,
+    /// Asynchronous I/O through io_uring, completed out of order.
+    IoUring,
+}
+
+impl IoEngine {
+    /// Extract the I/O engine from the device configuration (defaults to `Sync`).
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The device configuration.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the I/O engine.
+    pub fn from_config(config: &DeviceConfig) -> Result<Self> {
+        match config.io_engine.as_deref() {
+            None | Some("sync") => Ok(IoEngine::Sync),
+            Some("io_uring") => Ok(IoEngine::IoUring),
+            Some(engine) => Err(Error::InvalidConfigField("io_engine", engine.to_string())),
+        }
+    }
+}
 
 /// Virtio block device.
 ///
@@ -32,17 +81,50 @@ const SECTOR_SHIFT: u8 = 9;
 ///
 /// * `common` - Virtio common device.
 /// * `endpoint` - The remote subscriber endpoint.
+/// * `queue_threads` - The threads running the per queue event managers (if any).
+/// * `sub_ids` - The queue indexes and the IDs of the subscribers registered within the
+///   `EventManager`.
 /// * `file_path` - Path to the block device file or disk partition.
 /// * `read_only` - Whether the block device is read-only.
 /// * `root_device` - Whether the block device is the root device.
 /// * `advertise_flush` - Whether the block device advertises the flush feature.
+/// * `io_engine` - The I/O engine serving the requests.
+/// * `image_format` - The format of the disk image.
+/// * `serial` - The serial number, returned to the driver as the device ID.
+/// * `rate_limiter` - The rate limiter shared by the queues (if any).
+/// * `write_cache` - The write cache state shared by the queues.
+/// * `image_lock` - The file holding the disk image lock (if the file system supports locks).
+/// * `capacity` - The disk capacity (in sectors), shared with the queue handlers.
+/// * `overlay` - Whether the block device serves the copy-on-write delta file of an overlay, in
+///   which case `file_path` is the delta file.
+/// * `base_lock` - The file holding the shared lock of the overlay base image (if any).
+/// * `queue_handlers` - The handlers of the synchronous I/O engine queues, quiesced by snapshots.
+/// * `zoned` - The zoned disk image shared by the queues, serializing the zone writes (if the
+///   device is zoned).
+/// * `encryption_key` - The key decrypting the disk image (if the disk image is encrypted).
+/// * `metrics` - The I/O statistics shared by the queues, kept across the device resets.
 pub struct VirtioBlock {
     pub common: VirtioDeviceCommon,
     pub endpoint: RemoteEndpoint<Subscriber>,
+    pub queue_threads: Vec<QueueThread>,
+    pub sub_ids: Vec<(usize, SubscriberId)>,
     pub file_path: PathBuf,
     pub read_only: bool,
     pub root_device: bool,
     pub advertise_flush: bool,
+    pub io_engine: IoEngine,
+    pub image_format: ImageFormat,
+    pub serial: String,
+    pub rate_limiter: Option<RateLimiter>,
+    pub write_cache: WriteCache,
+    pub image_lock: Option<File>,
+    pub capacity: Arc<AtomicU64>,
+    pub overlay: bool,
+    pub base_lock: Option<File>,
+    queue_handlers: Vec<Arc<Mutex<QueueHandler>>>,
+    pub zoned: Option<Arc<Mutex<ZonedImage>>>,
+    encryption_key: Option<EncryptionKey>,
+    pub metrics: Arc<BlockMetrics>,
 }
 
 impl VirtioDeviceT for VirtioBlock {
@@ -50,84 +132,1127 @@ impl VirtioDeviceT for VirtioBlock {
         config: &DeviceConfig,
         device_manager: Arc<Mutex<IoManager>>,
         event_manager: Option<Arc<Mutex<EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>>>>,
-        device_model: Arc<Mutex<BaoDeviceModel>>,
+        device_model: Arc<dyn DeviceModelT>,
     ) -> Result<Arc<Mutex<Self>>> {
+        // In overlay mode, the device serves the delta file, on top of the base image.
+        let overlay = overlay_config(config)?;
+        let base_path = overlay.as_ref().and(config.file_path.clone());
+        let config = overlay.as_ref().unwrap_or(config);
+
+        // Check if the I/O engine is available and able to serve the disk image.
+        let io_engine = IoEngine::from_config(config)?;
+        let image_format = image_format(config)?;
+        let cache = cache_mode(config)?;
+        let serial = serial(config)?;
+        let rate_limiter = config
+            .rate_limiter
+            .as_ref()
+            .map(RateLimiter::from_config)
+            .transpose()?;
+        if io_engine == IoEngine::IoUring {
+            io_uring_handler::probe()
+                .map_err(|e| Error::BlockBackend(format!("io_uring is not available: {:?}", e)))?;
+            if image_format != ImageFormat::Raw
+                || config.encryption.is_some()
+                || config.file_path.as_ref().is_some_and(nbd::is_uri)
+            {
+                return Err(Error::BlockBackend(
+                    "the io_uring engine only serves local unencrypted raw images".to_string(),
+                ));
+            }
+        }
+        if cache == CacheMode::None && image_format != ImageFormat::Raw {
+            return Err(Error::BlockBackend(
+                "the none cache mode only serves raw images".to_string(),
+            ));
+        }
+
+        // Lock the disk image for the lifetime of the device, so it is not attached twice. The
+        // overlay base image is only read, so it is shared with the other readers.
+        let image_lock = lock_image(config)?;
+        let base_lock = match base_path {
+            Some(base_path) => lock_file(&base_path, true, false)?,
+            None => None,
+        };
+
+        // The queue handlers check the requests against the current capacity, which changes
+        // when the disk is resized.
+        let file_path: PathBuf = config
+            .file_path
+            .clone()
+            .ok_or(Error::MissingConfigField("file_path"))?
+            .into();
+        let encryption_key = encryption_key(config)?;
+
+        // Open the disk image once, to size the device and fill its configuration space.
+        let disk = open_disk(
+            &file_path,
+            image_format,
+            true,
+            CacheMode::Writeback,
+            encryption_key.as_ref(),
+        )?;
+        let capacity = Arc::new(AtomicU64::new(disk_sectors(disk.as_ref())));
+
+        // Set up the zones, once the disk image is locked.
+        let zoned = zoned_image(config)?;
+
+        // Create a remote endpoint object, that allows interacting with the VM EventManager from a different thread.
+        let remote_endpoint = event_manager
+            .ok_or(Error::EventManagerNotFound)?
+            .lock()
+            .unwrap()
+            .remote_endpoint();
+
         // Extract the generic features and queues.
-        let (common_features, queues) = Self::initialize(&config).unwrap();
+        let (common_features, queues) = Self::initialize(&config)?;
 
         // Update the device features.
-        let device_features = common_features | Self::device_features(&config).unwrap();
+        let device_features = common_features | Self::device_features(&config)?;
 
-        // Update the configuration space.
-        let config_space = Self::config_space(&config).unwrap();
+        // Update the configuration space, from the disk image and zones set up above.
+        let config_space = Self::build_config_space(config, disk.as_ref(), zoned.as_ref())?;
+        let zoned = zoned.map(|zoned| Arc::new(Mutex::new(zoned)));
 
         // Create a VirtioConfig object.
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
-        // Create the generic device.
-        let common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg).unwrap();
+        // Create the per queue event managers, each one running on its own thread.
+        // The threads spawned so far are stopped if the device creation fails later on.
+        let mut queue_threads = Vec::new();
+        if config.queue_threads.unwrap_or(false) {
+            for index in 0..queue_config(config, VirtioDevType::Block)?.0 {
+                queue_threads.push(QueueThread::spawn(format!(
+                    "block_{}_queue_{}",
+                    config.id, index
+                ))?);
+            }
+        }
+
+        // Create the generic device, once the configuration is validated and the disk image locked.
+        let common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg)?;
 
-        // Create a remote endpoint object, that allows interacting with the VM EventManager from a different thread.
-        let remote_endpoint = event_manager.unwrap().lock().unwrap().remote_endpoint();
+        // Extract the VirtioDeviceCommon MMIO range.
+        let range = common_device.mmio.range;
 
         // Create the block device.
         let block = Arc::new(Mutex::new(VirtioBlock {
             common: common_device,
             endpoint: remote_endpoint,
-            file_path: config.file_path.clone().unwrap().into(),
-            read_only: config.read_only.unwrap(),
-            root_device: config.root_device.unwrap(),
-            advertise_flush: config.advertise_flush.unwrap(),
+            queue_threads,
+            sub_ids: Vec::new(),
+            file_path,
+            read_only: config.read_only.unwrap_or(false),
+            root_device: config.root_device.unwrap_or(false),
+            advertise_flush: cache.has_write_cache(),
+            io_engine,
+            image_format,
+            serial,
+            rate_limiter,
+            write_cache: WriteCache::new(cache),
+            image_lock,
+            capacity,
+            overlay: overlay.is_some(),
+            base_lock,
+            queue_handlers: Vec::new(),
+            zoned,
+            encryption_key,
+            metrics: Arc::new(BlockMetrics::default()),
         }));
 
         // Register the MMIO device within the device manager with the specified range.
         device_manager
             .lock()
             .unwrap()
-            .register_mmio(
-                block.clone().lock().unwrap().common.mmio.range,
-                block.clone(),
-            )
-            .unwrap();
+            .register_mmio(range, block.clone())
+            .map_err(|_| Error::MmioConfig)?;
 
         // Return the block device.
         Ok(block)
     }
 
     fn device_features(config: &DeviceConfig) -> Result<u64> {
-        let mut features = 1 << VIRTIO_F_IN_ORDER;
+        // The request limits, geometry, block size and topology are always reported.
+        let mut features = (1 << VIRTIO_BLK_F_SIZE_MAX)
+            | (1 << VIRTIO_BLK_F_SEG_MAX)
+            | (1 << VIRTIO_BLK_F_GEOMETRY)
+            | (1 << VIRTIO_BLK_F_BLK_SIZE)
+            | (1 << VIRTIO_BLK_F_TOPOLOGY)
+            | (1 << VIRTIO_BLK_F_MQ);
+
+        // The requests are only completed in order by the synchronous I/O engine.
+        if IoEngine::from_config(config)? == IoEngine::Sync {
+            features |= 1 << VIRTIO_F_IN_ORDER;
+        }
 
         // Set the read-only feature.
-        if config.read_only.unwrap() {
+        if config.read_only.unwrap_or(false) {
             features |= 1 << VIRTIO_BLK_F_RO;
         }
 
-        // Set the flush feature.
-        if config.advertise_flush.unwrap() {
-            features |= 1 << VIRTIO_BLK_F_FLUSH;
+        // Set the flush and cache mode features, if the writes are cached.
+        if cache_mode(config)?.has_write_cache() {
+            features |= (1 << VIRTIO_BLK_F_FLUSH) | (1 << VIRTIO_BLK_F_CONFIG_WCE);
+        }
+
+        // Set the zoned feature, or the discard and write zeroes ones (the zones are only reset
+        // through zone management requests).
+        if config.zoned.is_some() {
+            features |= 1 << VIRTIO_BLK_F_ZONED;
+        } else if !config.read_only.unwrap_or(false) {
+            features |= (1 << VIRTIO_BLK_F_DISCARD) | (1 << VIRTIO_BLK_F_WRITE_ZEROES);
         }
 
         Ok(features)
     }
 
     fn config_space(config: &DeviceConfig) -> Result<Vec<u8>> {
-        // TODO: right now, the file size is computed by the StdioBackend as well. Maybe we should
-        // create the backend as early as possible, and get the size information from there.
-        let file_size = File::open(config.file_path.clone().unwrap())
-            .unwrap()
-            .seek(SeekFrom::End(0))
-            .unwrap();
+        let file_path = config
+            .file_path
+            .as_ref()
+            .ok_or(Error::MissingConfigField("file_path"))?;
+        let disk = open_disk(
+            file_path,
+            image_format(config)?,
+            true,
+            CacheMode::Writeback,
+            encryption_key(config)?.as_ref(),
+        )?;
+        Self::build_config_space(config, disk.as_ref(), zoned_image(config)?.as_ref())
+    }
+}
 
-        // If the file size is actually not a multiple of sector size, then data at the very end
-        // will be ignored.
-        let num_sectors = file_size >> SECTOR_SHIFT;
+impl VirtioBlock {
+    /// Build the configuration space of the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The device configuration.
+    /// * `disk` - The disk image, already opened (and decrypted if encrypted).
+    /// * `zoned` - The zoned disk image, already set up (if the device is zoned).
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the configuration space.
+    fn build_config_space(
+        config: &DeviceConfig,
+        disk: &dyn DiskImage,
+        zoned: Option<&ZonedImage>,
+    ) -> Result<Vec<u8>> {
+        let mut config_space = BlockConfigSpace {
+            capacity: disk_sectors(disk),
+            ..Default::default()
+        };
+
+        // Set the request limits. The request header and status take two descriptors.
+        let (queue_num, queue_size) = queue_config(config, VirtioDevType::Block)?;
+        config_space.num_queues = queue_num as u16;
+        config_space.size_max = MAX_SEGMENT_SIZE;
+        config_space.seg_max = (queue_size as u32).saturating_sub(2).max(1);
+
+        // Set the geometry, block size and topology.
+        let (logical, physical) = block_sizes(config, disk)?;
+        config_space.set_geometry();
+        config_space.set_block_sizes(logical, physical);
+
+        // Set the cache mode the driver starts with.
+        config_space.writeback = cache_mode(config)?.has_write_cache() as u8;
+
+        // Set the zoned characteristics, or the discard and write zeroes limits.
+        if let Some(zoned) = zoned {
+            config_space.zone_sectors = zoned.zone_sectors() as u32;
+            config_space.max_open_zones = zoned.max_open_zones();
+            config_space.max_active_zones = zoned.max_active_zones();
+            config_space.max_append_sectors = max_append_sectors(zoned) as u32;
+            config_space.write_granularity = logical;
+            config_space.zoned_model = VIRTIO_BLK_Z_HM;
+        } else if !config.read_only.unwrap_or(false) {
+            config_space.max_discard_sectors = MAX_DISCARD_SECTORS;
+            config_space.max_discard_seg = MAX_DISCARD_SEG;
+            config_space.discard_sector_alignment =
+                (disk.discard_alignment() >> SECTOR_SHIFT) as u32;
+            config_space.max_write_zeroes_sectors = MAX_DISCARD_SECTORS;
+            config_space.max_write_zeroes_seg = MAX_DISCARD_SEG;
+            config_space.write_zeroes_may_unmap = 1;
+        }
 
         // Update the configuration space.
-        // This must be little-endian according to the Virtio specification.
-        Ok(num_sectors.to_le_bytes().to_vec())
+        Ok(config_space.to_bytes())
+    }
+
+    /// Open the disk image for every queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `queue_num` - The number of queues.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing one disk image per queue.
+    ///
+    /// # Note
+    ///
+    /// Raw images are served in parallel, each queue using its own duplicate of the block device
+    /// file. The other formats update their metadata on writes, so the queues share one image.
+    fn queue_disks(&self, queue_num: usize) -> Result<Vec<Box<dyn DiskImage>>> {
+        let disk = open_disk(
+            &self.file_path,
+            self.image_format,
+            self.read_only,
+            self.write_cache.mode,
+            self.encryption_key.as_ref(),
+        )?;
+
+        // A single queue takes the disk image as is.
+        if queue_num == 1 {
+            return Ok(vec![disk]);
+        }
+
+        let Some(file) = disk.raw_file() else {
+            let shared = SharedImage::new(disk);
+            return Ok((0..queue_num)
+                .map(|_| Box::new(shared.clone()) as Box<dyn DiskImage>)
+                .collect());
+        };
+
+        (0..queue_num)
+            .map(|_| {
+                file.try_clone()
+                    .and_then(RawImage::new)
+                    .map(|disk| Box::new(disk) as Box<dyn DiskImage>)
+                    .map_err(Error::DiskImage)
+            })
+            .collect()
+    }
+
+    /// Get the device ID returned to the driver, which holds the serial number.
+    fn device_id(&self) -> DeviceId {
+        // Shorter serial numbers are zero padded, while a full length one is not terminated.
+        let mut device_id = DeviceId::default();
+        device_id[..self.serial.len()].copy_from_slice(self.serial.as_bytes());
+        device_id
+    }
+
+    /// Get the remote endpoint of the event manager serving a queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The queue index.
+    ///
+    /// # Returns
+    ///
+    /// The queue event manager endpoint, or the VM one if the queues share its thread.
+    fn queue_endpoint(&self, index: usize) -> &RemoteEndpoint<Subscriber> {
+        self.queue_threads
+            .get(index)
+            .map_or(&self.endpoint, |thread| &thread.endpoint)
+    }
+
+    /// Get a snapshot of the I/O statistics of the device.
+    pub fn metrics(&self) -> BlockMetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Update the capacity reported to the driver, after the backing file was resized.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the new capacity (in sectors).
+    pub fn update_capacity(&mut self) -> Result<u64> {
+        if self.zoned.is_some() {
+            return Err(Error::DiskResize(
+                "zoned devices cannot be resized".to_string(),
+            ));
+        }
+
+        let disk = open_disk(
+            &self.file_path,
+            self.image_format,
+            true,
+            CacheMode::Writeback,
+            self.encryption_key.as_ref(),
+        )?;
+        let num_sectors = disk_sectors(disk.as_ref());
+
+        // Let the queue handlers serve the new sectors, then update the capacity field of the
+        // configuration space.
+        self.capacity.store(num_sectors, Ordering::Release);
+        self.common
+            .update_config_space(CAPACITY_OFFSET, &num_sectors.to_le_bytes())?;
+
+        Ok(num_sectors)
+    }
+
+    /// Grow the disk image, and notify the driver about its new capacity.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The new disk size (in bytes), a multiple of the sector size.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the new capacity (in sectors).
+    ///
+    /// # Note
+    ///
+    /// Only writable raw image files of non-zoned devices are resized. Shrinking the disk is refused, since the guest
+    /// may still hold data beyond the new size. Encrypted images are grown on the host instead,
+    /// since their payload starts after the encryption header.
+    pub fn resize(&mut self, size: u64) -> Result<u64> {
+        if self.read_only || self.image_format != ImageFormat::Raw {
+            return Err(Error::DiskResize(
+                "only writable raw images can be resized".to_string(),
+            ));
+        }
+        if self.zoned.is_some() {
+            return Err(Error::DiskResize(
+                "zoned devices cannot be resized".to_string(),
+            ));
+        }
+        if self.encryption_key.is_some() {
+            return Err(Error::DiskResize(
+                "encrypted images are grown on the host, then their capacity updated".to_string(),
+            ));
+        }
+        if nbd::is_uri(&self.file_path) {
+            return Err(Error::DiskResize(
+                "NBD exports are grown on the server, then their capacity updated".to_string(),
+            ));
+        }
+        if size % (1 << SECTOR_SHIFT) != 0 {
+            return Err(Error::DiskResize(format!(
+                "{} is not a multiple of the sector size",
+                size
+            )));
+        }
+        if size >> SECTOR_SHIFT < self.capacity.load(Ordering::Acquire) {
+            return Err(Error::DiskResize(format!(
+                "shrinking the disk to {} bytes is not supported",
+                size
+            )));
+        }
+
+        // Grow the image file (block devices have to be resized on the host instead).
+        OpenOptions::new()
+            .write(true)
+            .open(&self.file_path)
+            .and_then(|file| file.set_len(size))
+            .map_err(Error::DiskImage)?;
+
+        self.update_capacity()
+    }
+
+    /// Take a point-in-time snapshot of the overlay, and switch to a fresh delta file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path the current delta file is moved to, which must not exist.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    ///
+    /// # Note
+    ///
+    /// The queues are quiesced while the delta files are switched, so the snapshot holds every
+    /// completed write and none of the later ones. It remains in the backing file chain of the
+    /// fresh delta file, so it must not be modified.
+    pub fn snapshot(&mut self, path: &str) -> Result<()> {
+        if !self.overlay {
+            return Err(Error::DiskSnapshot(
+                "only overlays can be snapshotted".to_string(),
+            ));
+        }
+        let snapshot = Path::new(path);
+
+        // Quiesce the queues, by holding their handlers between two requests.
+        let handlers = self.queue_handlers.clone();
+        let mut handlers: Vec<_> = handlers
+            .iter()
+            .map(|handler| handler.lock().unwrap())
+            .collect();
+
+        // Flush the delta file, so the completed writes reach the snapshot.
+        for handler in handlers.iter_mut() {
+            handler.inner.disk.flush().map_err(Error::DiskImage)?;
+        }
+
+        // Move the delta file to the snapshot, and create a fresh one on top of it.
+        rename_noreplace(&self.file_path, snapshot).map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => Error::DiskSnapshot(format!("{} already exists", path)),
+            _ => Error::DiskImage(e),
+        })?;
+        let (image_lock, disks) = match self.switch_delta(snapshot, handlers.len()) {
+            Ok(delta) => delta,
+            Err(e) => {
+                // Restore the delta file, which the queues keep serving.
+                let _ = fs::remove_file(&self.file_path);
+                let _ = fs::rename(snapshot, &self.file_path);
+                return Err(Error::DiskSnapshot(e.to_string()));
+            }
+        };
+
+        // Serve the pending requests from the fresh delta file, which is now locked instead of
+        // the snapshot.
+        for (handler, disk) in handlers.iter_mut().zip(disks) {
+            handler.inner.disk = disk;
+        }
+        self.image_lock = image_lock;
+
+        Ok(())
+    }
+
+    /// Create and open a fresh delta file, on top of a snapshot.
+    ///
+    /// # Arguments
+    ///
+    /// * `snapshot` - The path to the snapshot.
+    /// * `queue_num` - The number of queue handlers.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the lock of the fresh delta file, and one disk image per queue
+    /// handler.
+    fn switch_delta(
+        &self,
+        snapshot: &Path,
+        queue_num: usize,
+    ) -> Result<(Option<File>, Vec<Box<dyn DiskImage>>)> {
+        let backing = fs::canonicalize(snapshot).map_err(Error::DiskImage)?;
+        create_delta(&self.file_path, &backing, ImageFormat::Qcow2)?;
+
+        let file_path = self
+            .file_path
+            .to_str()
+            .ok_or_else(|| Error::DiskImage(io::Error::from(io::ErrorKind::InvalidInput)))?;
+        let image_lock = lock_file(file_path, false, true)?;
+
+        Ok((image_lock, self.queue_disks(queue_num)?))
+    }
+}
+
+/// Extract the disk image format from the device configuration.
+///
+/// # Arguments
+///
+/// * `config` - The device configuration.
+///
+/// # Returns
+///
+/// A `Result` containing the image format (detected from the disk image if not configured).
+fn image_format(config: &DeviceConfig) -> Result<ImageFormat> {
+    if let Some(name) = config.image_format.as_ref() {
+        return ImageFormat::from_name(name)
+            .ok_or_else(|| Error::InvalidConfigField("image_format", name.clone()));
+    }
+
+    let file_path = config
+        .file_path
+        .as_ref()
+        .ok_or(Error::MissingConfigField("file_path"))?;
+
+    // NBD exports are raw disks.
+    if nbd::is_uri(file_path) {
+        return Ok(ImageFormat::Raw);
+    }
+
+    File::open(file_path)
+        .and_then(|file| ImageFormat::detect(&file))
+        .map_err(Error::DiskImage)
+}
+
+/// Extract the host cache mode from the device configuration.
+///
+/// # Arguments
+///
+/// * `config` - The device configuration.
+///
+/// # Returns
+///
+/// A `Result` containing the cache mode (defaults to `Writeback`, or to `Writethrough` if the
+/// flush feature is disabled).
+fn cache_mode(config: &DeviceConfig) -> Result<CacheMode> {
+    let cache = match config.cache.as_ref() {
+        Some(name) => CacheMode::from_name(name)
+            .ok_or_else(|| Error::InvalidConfigField("cache", name.clone()))?,
+        None if config.advertise_flush == Some(false) => CacheMode::Writethrough,
+        None => CacheMode::Writeback,
+    };
+
+    // The flush feature is only advertised if the writes are cached.
+    if let Some(flush) = config.advertise_flush {
+        if flush != cache.has_write_cache() {
+            return Err(Error::InvalidConfigField(
+                "advertise_flush",
+                format!("{} (does not match the {:?} cache mode)", flush, cache),
+            ));
+        }
+    }
+
+    Ok(cache)
+}
+
+/// Lock the disk image of the device configuration.
+///
+/// # Arguments
+///
+/// * `config` - The device configuration.
+///
+/// # Returns
+///
+/// A `Result` containing the file holding the lock, or `None` if the file system does not
+/// support locks.
+///
+/// # Note
+///
+/// Writable images are locked exclusively, unless shared (e.g. by cluster file systems), while
+/// read-only images are shared with the other readers.
+fn lock_image(config: &DeviceConfig) -> Result<Option<File>> {
+    let file_path = config
+        .file_path
+        .as_ref()
+        .ok_or(Error::MissingConfigField("file_path"))?;
+    let read_only = config.read_only.unwrap_or(false);
+    let exclusive = !read_only && !config.shared.unwrap_or(false);
+
+    // The NBD server controls the access to its exports.
+    if nbd::is_uri(file_path) {
+        return Ok(None);
+    }
+
+    lock_file(file_path, read_only, exclusive)
+}
+
+/// Lock a disk image file.
+///
+/// # Arguments
+///
+/// * `file_path` - The path to the disk image.
+/// * `read_only` - Whether the disk image is only read.
+/// * `exclusive` - Whether the lock is exclusive (otherwise shared).
+///
+/// # Returns
+///
+/// A `Result` containing the file holding the lock, or `None` if the file system does not
+/// support locks.
+fn lock_file(file_path: &str, read_only: bool, exclusive: bool) -> Result<Option<File>> {
+    match disk::lock_image(file_path, read_only, exclusive) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+            Err(Error::DiskImageLocked(file_path.to_string()))
+        }
+        // Some file systems (e.g. NFS without a lock manager) do not support locks.
+        Err(e) if e.raw_os_error() == Some(libc::ENOLCK) => {
+            println!("Failed to lock the disk image {}: {:?}", file_path, e);
+            Ok(None)
+        }
+        Err(e) => Err(Error::DiskImage(e)),
+    }
+}
+
+/// Build the device configuration of the overlay mode, creating the delta file if missing.
+///
+/// # Arguments
+///
+/// * `config` - The device configuration.
+///
+/// # Returns
+///
+/// A `Result` containing the configuration serving the delta file, or `None` if the overlay mode
+/// is disabled.
+///
+/// # Note
+///
+/// The delta file is a qcow2 image backed by the base image, so the written clusters are
+/// allocated in the delta file, while the other ones are read from the base image.
+fn overlay_config(config: &DeviceConfig) -> Result<Option<DeviceConfig>> {
+    let Some(overlay) = config.overlay.as_ref() else {
+        return Ok(None);
+    };
+    let file_path = config
+        .file_path
+        .as_ref()
+        .ok_or(Error::MissingConfigField("file_path"))?;
+
+    // The delta file belongs to a single writable device, and refers to a local base image.
+    if config.read_only.unwrap_or(false) || config.shared.unwrap_or(false) {
+        return Err(Error::InvalidConfigField(
+            "overlay",
+            format!("{} (overlays cannot be read-only or shared)", overlay),
+        ));
+    }
+    if nbd::is_uri(file_path) {
+        return Err(Error::InvalidConfigField(
+            "overlay",
+            format!("{} (overlays require a local base image)", overlay),
+        ));
+    }
+
+    // Create the delta file on the first run, backed by the absolute path of the base image.
+    if fs::symlink_metadata(overlay).is_err() {
+        let base = fs::canonicalize(file_path).map_err(Error::DiskImage)?;
+        create_delta(Path::new(overlay), &base, image_format(config)?)?;
+    }
+
+    Ok(Some(DeviceConfig {
+        file_path: Some(overlay.clone()),
+        image_format: Some("qcow2".to_string()),
+        overlay: None,
+        ..config.clone()
+    }))
+}
+
+/// Create a delta file, backed by another disk image.
+///
+/// # Arguments
+///
+/// * `path` - The path to the delta file, which must not exist.
+/// * `backing` - The path to the backing image.
+/// * `format` - The format of the backing image.
+///
+/// # Returns
+///
+/// A `Result` containing the result of the operation.
+fn create_delta(path: &Path, backing: &Path, format: ImageFormat) -> Result<()> {
+    let size = disk::open(backing, Some(format), true)
+        .map(|disk| disk.size())
+        .map_err(Error::DiskImage)?;
+    let backing_name = backing
+        .to_str()
+        .ok_or_else(|| Error::DiskImage(io::Error::from(io::ErrorKind::InvalidInput)))?;
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(Error::DiskImage)?;
+    qcow2::create(&file, size, Some((backing_name, format))).map_err(|e| {
+        let _ = fs::remove_file(path);
+        Error::DiskImage(e)
+    })
+}
+
+/// Rename a file, failing if the destination already exists.
+///
+/// # Arguments
+///
+/// * `from` - The path to the file.
+/// * `to` - The new path of the file, which must not exist.
+///
+/// # Returns
+///
+/// An `io::Result` containing the result of the operation.
+///
+/// # Note
+///
+/// Unlike `fs::rename`, this never replaces a file created at the destination in the meantime.
+fn rename_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    let from = CString::new(from.as_os_str().as_bytes())?;
+    let to = CString::new(to.as_os_str().as_bytes())?;
+
+    // SAFETY: both paths are valid NUL-terminated strings.
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Extract the serial number from the device configuration.
+///
+/// # Arguments
+///
+/// * `config` - The device configuration.
+///
+/// # Returns
+///
+/// A `Result` containing the serial number.
+///
+/// # Note
+///
+/// If no serial number is configured, a stable one is derived from the file path and the device
+/// ID, so the guest keeps identifying the disk across restarts.
+fn serial(config: &DeviceConfig) -> Result<String> {
+    if let Some(serial) = config.serial.as_ref() {
+        if serial.len() > VIRTIO_BLK_ID_BYTES as usize
+            || !serial.bytes().all(|b| b.is_ascii_graphic())
+        {
+            return Err(Error::InvalidConfigField(
+                "serial",
+                format!(
+                    "{} (expected up to {} printable ASCII characters)",
+                    serial, VIRTIO_BLK_ID_BYTES
+                ),
+            ));
+        }
+        return Ok(serial.clone());
+    }
+
+    let file_path = config
+        .file_path
+        .as_ref()
+        .ok_or(Error::MissingConfigField("file_path"))?;
+
+    // Hash the file path with 64-bit FNV-1a, which does not change across builds.
+    let hash = file_path.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+
+    // The hash and the device ID fill the 20 characters of the serial number.
+    Ok(format!("{:016x}{:04x}", hash, config.id as u16))
+}
+
+/// Extract the logical and physical block sizes from the device configuration.
+///
+/// # Arguments
+///
+/// * `config` - The device configuration.
+/// * `disk` - The disk image.
+///
+/// # Returns
+///
+/// A `Result` containing the logical and physical block sizes (defaults to the backing storage
+/// ones).
+fn block_sizes(config: &DeviceConfig, disk: &dyn DiskImage) -> Result<(u32, u32)> {
+    let (default_logical, default_physical) = disk.block_sizes().map_err(Error::DiskImage)?;
+    let logical = config.logical_block_size.unwrap_or(default_logical);
+    // A larger logical block size also makes the physical block size larger.
+    let physical = config
+        .physical_block_size
+        .unwrap_or(default_physical.max(logical));
+
+    // The logical block size must be a power of 2, from the sector size up to the page size.
+    if !logical.is_power_of_two() || !(1 << SECTOR_SHIFT..=4096).contains(&logical) {
+        return Err(Error::InvalidConfigField(
+            "logical_block_size",
+            format!("{} (expected a power of 2 from 512 to 4096)", logical),
+        ));
+    }
+
+    // The physical block size must be a power of 2 multiple of the logical block size.
+    if !physical.is_power_of_two() || !(logical..=MAX_PHYSICAL_BLOCK_SIZE).contains(&physical) {
+        return Err(Error::InvalidConfigField(
+            "physical_block_size",
+            format!(
+                "{} (expected a power of 2 from {} to {})",
+                physical, logical, MAX_PHYSICAL_BLOCK_SIZE
+            ),
+        ));
+    }
+
+    // Direct I/O cannot transfer less than a logical block of the backing storage.
+    if cache_mode(config)? == CacheMode::None && logical < default_logical {
+        return Err(Error::InvalidConfigField(
+            "logical_block_size",
+            format!(
+                "{} (the none cache mode requires at least {})",
+                logical, default_logical
+            ),
+        ));
+    }
+
+    Ok((logical, physical))
+}
+
+/// Set up the zones of the device configuration.
+///
+/// # Arguments
+///
+/// * `config` - The device configuration.
+///
+/// # Returns
+///
+/// A `Result` containing the zoned disk image, or `None` if the device is not zoned.
+///
+/// # Note
+///
+/// The zones of a host zoned block device are passed through, while the ones of the other raw
+/// images are emulated.
+fn zoned_image(config: &DeviceConfig) -> Result<Option<ZonedImage>> {
+    let file_path = config
+        .file_path
+        .as_ref()
+        .ok_or(Error::MissingConfigField("file_path"))?;
+
+    // NBD exports are not zoned, and their zones cannot be emulated.
+    if nbd::is_uri(file_path) {
+        if config.zoned.is_some() {
+            return Err(Error::BlockBackend(
+                "zoned devices require a local raw image".to_string(),
+            ));
+        }
+        return Ok(None);
+    }
+
+    let file = File::open(file_path).map_err(Error::DiskImage)?;
+    let host_zoned = zoned::is_host_zoned(&file).map_err(Error::DiskImage)?;
+
+    let Some(zoned) = config.zoned.as_ref() else {
+        // Host zoned block devices only take sequential writes, which plain devices do not issue.
+        if host_zoned {
+            return Err(Error::BlockBackend(format!(
+                "{} is a zoned block device, which requires the zoned option",
+                file_path
+            )));
+        }
+        return Ok(None);
+    };
+
+    // The zone state is shared by the synchronous queue handlers, which serialize the writes.
+    if config.read_only.unwrap_or(false)
+        || image_format(config)? != ImageFormat::Raw
+        || config.encryption.is_some()
+        || IoEngine::from_config(config)? != IoEngine::Sync
+    {
+        return Err(Error::BlockBackend(
+            "zoned devices require a writable unencrypted raw image and the sync engine"
+                .to_string(),
+        ));
+    }
+
+    if host_zoned {
+        if zoned.zone_size.is_some()
+            || zoned.conventional_zones.is_some()
+            || zoned.max_open_zones.is_some()
+            || zoned.max_active_zones.is_some()
+        {
+            return Err(Error::InvalidConfigField(
+                "zoned",
+                format!("{:?} (the host zones are passed through as is)", zoned),
+            ));
+        }
+        // The page cache may reorder the writes, which must reach the zones sequentially.
+        if cache_mode(config)? != CacheMode::None {
+            return Err(Error::BlockBackend(
+                "host zoned block devices require the none cache mode".to_string(),
+            ));
+        }
+
+        // The zone management ioctls require write access.
+        return OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(file_path)
+            .and_then(|file| ZonedImage::host(&file))
+            .map(Some)
+            .map_err(Error::DiskImage);
+    }
+
+    // The zone resources must fit within each other.
+    let max_open_zones = zoned.max_open_zones.unwrap_or(0);
+    let max_active_zones = zoned.max_active_zones.unwrap_or(0);
+    if max_active_zones != 0 && (max_open_zones == 0 || max_open_zones > max_active_zones) {
+        return Err(Error::InvalidConfigField(
+            "zoned",
+            format!(
+                "max_open_zones {} (expected from 1 to max_active_zones {})",
+                max_open_zones, max_active_zones
+            ),
+        ));
+    }
+
+    // The zones must hold whole logical blocks.
+    let zone_size = zoned.zone_size.unwrap_or(DEFAULT_ZONE_SIZE);
+    let invalid_zone_size = || {
+        Error::InvalidConfigField(
+            "zoned",
+            format!(
+                "zone_size {} (expected a power of 2 from 4096, dividing the disk size)",
+                zone_size
+            ),
+        )
+    };
+    if zone_size < 4096 {
+        return Err(invalid_zone_size());
+    }
+
+    ZonedImage::emulated(
+        &file,
+        zone_size,
+        zoned.conventional_zones.unwrap_or(0),
+        max_open_zones,
+        max_active_zones,
+    )
+    .map(Some)
+    .map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidInput => invalid_zone_size(),
+        _ => Error::DiskImage(e),
+    })
+}
+
+/// Thread running the event manager dedicated to a queue.
+///
+/// # Attributes
+///
+/// * `endpoint` - The remote endpoint of the event manager.
+/// * `exit` - Whether the thread should stop running the event manager.
+/// * `handle` - The thread handle, joined when the thread is stopped.
+pub struct QueueThread {
+    pub endpoint: RemoteEndpoint<Subscriber>,
+    exit: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl QueueThread {
+    /// Spawn a thread running a dedicated event manager.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The thread name.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the queue thread.
+    fn spawn(name: String) -> Result<Self> {
+        let (sender, receiver) = channel();
+        let exit = Arc::new(AtomicBool::new(false));
+
+        // The event manager is created on its own thread, which hands out its endpoint and keeps
+        // running it until the thread is stopped. The subscribers left are dropped along with it.
+        let thread_exit = exit.clone();
+        let handle = Builder::new()
+            .name(name.clone())
+            .spawn(move || {
+                let mut event_manager = match EventManager::<Subscriber>::new() {
+                    Ok(event_manager) => event_manager,
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = sender.send(Ok(event_manager.remote_endpoint()));
+
+                while !thread_exit.load(Ordering::Acquire) {
+                    if let Err(e) = event_manager.run() {
+                        println!("Block queue event manager failed: {:?}", e);
+                        return;
+                    }
+                }
+            })
+            .map_err(|e| Error::ThreadSpawnFailed(name, e))?;
+
+        // The thread is joined even if the event manager could not be created.
+        let endpoint = match receiver.recv() {
+            Ok(Ok(endpoint)) => endpoint,
+            Ok(Err(e)) => {
+                let _ = handle.join();
+                return Err(Error::EventManager(e));
+            }
+            Err(_) => {
+                let _ = handle.join();
+                return Err(Error::EventManager(event_manager::Error::ChannelRecv));
+            }
+        };
+
+        Ok(QueueThread {
+            endpoint,
+            exit,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for QueueThread {
+    fn drop(&mut self) {
+        // Raise the exit flag, and wake up the event manager with an empty remote call so the
+        // thread observes it. The call fails if the event manager already stopped on an error.
+        self.exit.store(true, Ordering::Release);
+        let _ = self
+            .endpoint
+            .call_blocking(|_| -> EvmgrResult<()> { Ok(()) });
+
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                println!("Block queue thread panicked");
+            }
+        }
     }
 }
 
+/// Load the encryption key of the device configuration.
+///
+/// # Arguments
+///
+/// * `config` - The device configuration.
+///
+/// # Returns
+///
+/// A `Result` containing the encryption key, or `None` if the disk image is not encrypted.
+fn encryption_key(config: &DeviceConfig) -> Result<Option<EncryptionKey>> {
+    let Some(encryption) = config.encryption.as_ref() else {
+        return Ok(None);
+    };
+
+    let key = match (&encryption.key_file, &encryption.keyring) {
+        (Some(key_file), None) => EncryptionKey::from_file(key_file),
+        (None, Some(keyring)) => EncryptionKey::from_keyring(keyring),
+        _ => {
+            return Err(Error::InvalidConfigField(
+                "encryption",
+                format!("{:?} (exactly one key source must be set)", encryption),
+            ))
+        }
+    };
+
+    key.map(Some).map_err(Error::EncryptionKey)
+}
+
+/// Open a disk image, decrypting it if encrypted.
+///
+/// # Arguments
+///
+/// * `path` - The path to the disk image.
+/// * `format` - The format of the disk image.
+/// * `read_only` - Whether the disk image is only read.
+/// * `cache` - The host cache mode.
+/// * `key` - The encryption key (if the disk image is encrypted).
+///
+/// # Returns
+///
+/// A `Result` containing the disk image.
+fn open_disk<P: AsRef<Path>>(
+    path: P,
+    format: ImageFormat,
+    read_only: bool,
+    cache: CacheMode,
+    key: Option<&EncryptionKey>,
+) -> Result<Box<dyn DiskImage>> {
+    let disk =
+        disk::open_with_cache(path, Some(format), read_only, cache).map_err(Error::DiskImage)?;
+
+    match key {
+        Some(key) => CryptImage::new(disk, key)
+            .map(|disk| Box::new(disk) as Box<dyn DiskImage>)
+            .map_err(Error::DiskImage),
+        None => Ok(disk),
+    }
+}
+
+/// Compute the number of sectors of a disk image.
+///
+/// # Arguments
+///
+/// * `disk` - The disk image.
+///
+/// # Returns
+///
+/// The number of sectors.
+fn disk_sectors(disk: &dyn DiskImage) -> u64 {
+    // If the disk size is actually not a multiple of sector size, then data at the very end
+    // will be ignored.
+    disk.size() >> SECTOR_SHIFT
+}
+
 impl Borrow<VirtioConfig<Queue>> for VirtioBlock {
     fn borrow(&self) -> &VirtioConfig<Queue> {
         &self.common.config
@@ -151,49 +1276,115 @@ impl VirtioDeviceActions for VirtioBlock {
     type E = Error;
 
     fn activate(&mut self) -> Result<()> {
-        // Open the block device file.
-        let file = OpenOptions::new()
-            .read(true)
-            .write(!self.read_only)
-            .open(&self.file_path)
-            .unwrap();
+        // Extract the guest memory.
+        let mem = self.common.mem()?;
 
-        // Create the backend.
-        // TODO: Create the backend earlier (as part of `VirtioBlock::new`)?
-        let disk = StdIoBackend::new(file, self.common.config.driver_features).unwrap();
+        // A driver unaware of the zones would issue random writes.
+        if self.zoned.is_some()
+            && self.common.config.driver_features & (1 << VIRTIO_BLK_F_ZONED) == 0
+        {
+            return Err(Error::BlockBackend(
+                "the driver did not negotiate the zoned feature".to_string(),
+            ));
+        }
 
-        // Create the driver notify object.
-        let driver_notify = SingleFdSignalQueue {
-            irqfd: self.common.irqfd.try_clone().unwrap(),
-            interrupt_status: self.common.config.interrupt_status.clone(),
-        };
+        // Open the disk image for every queue.
+        let mut disks = self
+            .queue_disks(self.common.config.queues.len())?
+            .into_iter();
 
         // Prepare the activation by calling the generic `prepare_activate` method.
-        let mut ioevents = self.common.prepare_activate().unwrap();
-
-        // Create the inner handler.
-        let inner = InOrderQueueHandler {
-            driver_notify,
-            mem: self.common.mem(),
-            queue: self.common.config.queues.remove(0),
-            disk,
-        };
+        let ioevents = self.common.prepare_activate()?;
 
-        // Create the queue handler.
-        let handler = Arc::new(Mutex::new(QueueHandler {
-            inner,
-            ioeventfd: ioevents.remove(0),
-        }));
+        // The writes are only cached if the driver is able to flush them, and did not switch the
+        // cache to writethrough.
+        let flush = self.common.config.driver_features & (1 << VIRTIO_BLK_F_FLUSH) != 0;
+        self.write_cache
+            .set_enabled(flush && self.common.config.config_space[WRITEBACK_OFFSET] != 0);
 
-        // Register the queue handler with the `EventManager`. We could record the `sub_id`
-        // (and/or keep a handler clone) for further interaction (i.e. to remove the subscriber at
-        // a later time, retrieve state, etc).
-        let _sub_id = self
-            .endpoint
-            .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
-                Ok(mgr.add_subscriber(handler))
-            })
-            .unwrap();
+        for (index, ioeventfd) in ioevents.into_iter().enumerate() {
+            let disk = disks.next().unwrap();
+
+            // The driver only sets up the extra queues if it negotiated `VIRTIO_BLK_F_MQ`.
+            if !self.common.config.queues[index].ready() {
+                continue;
+            }
+
+            // Create the driver notify object and extract the request queue.
+            let driver_notify = self.common.driver_notify()?;
+            let queue = clone_queue(&self.common.config.queues[index]);
+
+            // The queues draw from the same rate limiter buckets, each one with its own timer.
+            let rate_limiter = self
+                .rate_limiter
+                .as_ref()
+                .map(RateLimiter::try_clone)
+                .transpose()
+                .map_err(Error::TimerFdCreateFailed)?;
+
+            // Create the queue handler for the selected I/O engine.
+            let handler: Subscriber = match self.io_engine {
+                IoEngine::Sync => {
+                    // Create the inner handler.
+                    let inner = InOrderQueueHandler {
+                        driver_notify,
+                        mem: mem.clone(),
+                        queue,
+                        disk,
+                        device_id: self.device_id(),
+                        rate_limiter,
+                        write_cache: self.write_cache.clone(),
+                        capacity: self.capacity.clone(),
+                        zoned: self.zoned.clone(),
+                        metrics: self.metrics.clone(),
+                    };
+
+                    // Keep the handler, so snapshots can quiesce it.
+                    let handler = Arc::new(Mutex::new(QueueHandler { inner, ioeventfd }));
+                    self.queue_handlers.push(handler.clone());
+                    handler
+                }
+                IoEngine::IoUring => {
+                    // The io_uring engine accesses the block device file directly.
+                    let disk = disk
+                        .raw_file()
+                        .ok_or_else(|| {
+                            Error::BlockBackend(
+                                "the io_uring engine only serves raw images".to_string(),
+                            )
+                        })?
+                        .try_clone()
+                        .and_then(RawImage::new)
+                        .map_err(Error::DiskImage)?;
+
+                    // Create the inner handler, along with the io_uring instance.
+                    let inner = IoUringQueueHandler::new(
+                        driver_notify,
+                        mem.clone(),
+                        queue,
+                        disk,
+                        self.device_id(),
+                        rate_limiter,
+                        self.write_cache.clone(),
+                        self.capacity.clone(),
+                        self.metrics.clone(),
+                    )
+                    .map_err(|e| Error::BlockBackend(format!("{:?}", e)))?;
+
+                    Arc::new(Mutex::new(IoUringHandler { inner, ioeventfd }))
+                }
+            };
+
+            // Register the queue handler with the `EventManager` serving the queue. We record
+            // the `sub_id` to remove the subscriber when the device is reset.
+            let sub_id = self
+                .queue_endpoint(index)
+                .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
+                    Ok(mgr.add_subscriber(handler))
+                })
+                .map_err(Error::EventManager)?;
+            self.sub_ids.push((index, sub_id));
+        }
 
         // Set the device as activated.
         self.common.config.device_activated = true;
@@ -202,8 +1393,41 @@ impl VirtioDeviceActions for VirtioBlock {
     }
 
     fn reset(&mut self) -> Result<()> {
-        // Not implemented for now.
-        Ok(())
+        // Remove the queue handlers from the `EventManager`s.
+        for (index, sub_id) in std::mem::take(&mut self.sub_ids) {
+            self.queue_endpoint(index)
+                .call_blocking(move |mgr| -> EvmgrResult<()> {
+                    mgr.remove_subscriber(sub_id).map(|_| ())
+                })
+                .map_err(Error::EventManager)?;
+        }
+        self.queue_handlers.clear();
+
+        // Restore the cache mode the device started with.
+        let writeback = self.write_cache.mode.has_write_cache();
+        self.write_cache.set_enabled(writeback);
+        self.common.config.config_space[WRITEBACK_OFFSET] = writeback as u8;
+
+        // Reset the generic device.
+        self.common.reset()
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        // The driver may only switch the cache mode, once it negotiated the feature.
+        if offset != WRITEBACK_OFFSET
+            || data.len() != 1
+            || self.common.config.driver_features & (1 << VIRTIO_BLK_F_CONFIG_WCE) == 0
+        {
+            println!(
+                "Ignoring block configuration space write at offset {}",
+                offset
+            );
+            return;
+        }
+
+        let writeback = data[0] != 0;
+        self.write_cache.set_enabled(writeback);
+        self.common.config.config_space[WRITEBACK_OFFSET] = writeback as u8;
     }
 }
 
@@ -218,10 +1442,1070 @@ impl VirtioMmioDevice for VirtioBlock {
 /// Otherwise we could not register the device within the device manager.
 impl MutDeviceMmio for VirtioBlock {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.common.update_device_status();
         self.read(offset, data);
     }
 
     fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
         self.write(offset, data);
+        self.common.update_device_status();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::disk::crypt::{self, CRYPT_HEADER_SIZE};
+    use crate::block::virtio::zoned::{
+        VIRTIO_BLK_S_ZONE_INVALID_CMD, VIRTIO_BLK_S_ZONE_UNALIGNED_WP, VIRTIO_BLK_T_ZONE_APPEND,
+        VIRTIO_BLK_T_ZONE_OPEN, VIRTIO_BLK_T_ZONE_REPORT, VIRTIO_BLK_T_ZONE_RESET,
+    };
+    use crate::device::UNSUPPORTED_FEATURES;
+    use crate::mmio::{VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING};
+    use crate::test_utils::{
+        block_config, block_device, disk_image, new_block, VirtioMmioDriver, DATA_ADDR, DISK_SIZE,
+    };
+    use api::mock::MOCK_IO_TIMEOUT;
+    use api::types::{EncryptionConfig, RateLimiterConfig, TokenBucketConfig, ZonedConfig};
+    use std::os::unix::fs::FileExt;
+    use std::time::{Duration, Instant};
+    use virtio_bindings::virtio_blk::{
+        VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH,
+        VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES,
+        VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+    };
+    use virtio_bindings::virtio_config::VIRTIO_CONFIG_S_NEEDS_RESET;
+    use virtio_bindings::virtio_mmio::{
+        VIRTIO_MMIO_CONFIG_GENERATION, VIRTIO_MMIO_DEVICE_ID, VIRTIO_MMIO_STATUS,
+    };
+    use vm_memory::{Bytes, GuestAddress};
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    /// Write two sectors and read them back through the given I/O engine.
+    fn check_requests(io_engine: &str, image_format: ImageFormat) {
+        // Create the backing file.
+        let image = TempFile::new().unwrap();
+        match image_format {
+            ImageFormat::Raw => image.as_file().set_len(DISK_SIZE).unwrap(),
+            ImageFormat::Qcow2 => disk::qcow2::create(image.as_file(), DISK_SIZE, None).unwrap(),
+        }
+        let mut config = block_config(&image);
+        config.io_engine = Some(io_engine.to_string());
+        config.serial = Some("bao-disk-0".to_string());
+
+        // Create the device and bring it up.
+        let (mut driver, block, features) = block_device(&config);
+        assert_eq!(
+            driver.read(VIRTIO_MMIO_DEVICE_ID),
+            VirtioDevType::Block as u32
+        );
+        assert_ne!(features & (1 << VIRTIO_BLK_F_FLUSH), 0);
+        assert_eq!(features & (1 << VIRTIO_BLK_F_RO), 0);
+        assert_eq!(features & UNSUPPORTED_FEATURES, 0);
+        // Only the synchronous engine completes the requests in order.
+        assert_eq!(
+            features & (1 << VIRTIO_F_IN_ORDER) != 0,
+            io_engine == "sync"
+        );
+
+        // The capacity is reported in sectors.
+        let mut capacity = [0u8; 8];
+        driver.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), DISK_SIZE >> SECTOR_SHIFT);
+
+        // Request layout: header, data and status.
+        let header = DATA_ADDR;
+        let data = DATA_ADDR + 0x1000;
+        let status = DATA_ADDR + 0x2000;
+        let sector = 8u64;
+        let pattern = (0..1024).map(|i| i as u8).collect::<Vec<u8>>();
+
+        // Write two sectors.
+        driver
+            .mem
+            .write_obj(VIRTIO_BLK_T_OUT, GuestAddress(header))
+            .unwrap();
+        driver
+            .mem
+            .write_obj(sector, GuestAddress(header + 8))
+            .unwrap();
+        driver
+            .mem
+            .write_slice(&pattern, GuestAddress(data))
+            .unwrap();
+        driver.mem.write_obj(0xffu8, GuestAddress(status)).unwrap();
+        let head = driver.submit(
+            0,
+            &[
+                (header, 16, false),
+                (data, pattern.len() as u32, false),
+                (status, 1, true),
+            ],
+        );
+        assert_eq!(driver.wait_used(0).unwrap().0, head);
+        assert_eq!(
+            driver.mem.read_obj::<u8>(GuestAddress(status)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+
+        let mut buf = vec![0u8; pattern.len()];
+        disk::open(image.as_path(), Some(image_format), true)
+            .unwrap()
+            .read_at(&mut buf, sector << SECTOR_SHIFT)
+            .unwrap();
+        assert_eq!(buf, pattern);
+
+        // Read the sectors back into a clean buffer.
+        driver
+            .mem
+            .write_obj(VIRTIO_BLK_T_IN, GuestAddress(header))
+            .unwrap();
+        driver
+            .mem
+            .write_slice(&vec![0u8; pattern.len()], GuestAddress(data))
+            .unwrap();
+        driver.mem.write_obj(0xffu8, GuestAddress(status)).unwrap();
+        let head = driver.submit(
+            0,
+            &[
+                (header, 16, false),
+                (data, pattern.len() as u32, true),
+                (status, 1, true),
+            ],
+        );
+        let (id, len) = driver.wait_used(0).unwrap();
+        assert_eq!(id, head);
+        assert_eq!(len, pattern.len() as u32 + 1);
+        assert_eq!(
+            driver.mem.read_obj::<u8>(GuestAddress(status)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+
+        let mut buf = vec![0u8; pattern.len()];
+        driver.mem.read_slice(&mut buf, GuestAddress(data)).unwrap();
+        assert_eq!(buf, pattern);
+
+        // Get the device ID, which holds the zero padded serial number.
+        driver
+            .mem
+            .write_obj(VIRTIO_BLK_T_GET_ID, GuestAddress(header))
+            .unwrap();
+        driver
+            .mem
+            .write_slice(&[0xff; VIRTIO_BLK_ID_BYTES as usize], GuestAddress(data))
+            .unwrap();
+        driver.submit(
+            0,
+            &[
+                (header, 16, false),
+                (data, VIRTIO_BLK_ID_BYTES, true),
+                (status, 1, true),
+            ],
+        );
+        assert_eq!(driver.wait_used(0).unwrap().1, VIRTIO_BLK_ID_BYTES + 1);
+        assert_eq!(
+            driver.mem.read_obj::<u8>(GuestAddress(status)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        let mut id = [0u8; VIRTIO_BLK_ID_BYTES as usize];
+        driver.mem.read_slice(&mut id, GuestAddress(data)).unwrap();
+        assert_eq!(id[..10], *b"bao-disk-0");
+        assert!(id[10..].iter().all(|&b| b == 0));
+
+        // The driver got notified about the used buffers.
+        assert!(driver.dm.wait_irq(MOCK_IO_TIMEOUT));
+        assert_ne!(driver.ack_interrupt() & VIRTIO_MMIO_INT_VRING as u32, 0);
+
+        // Every request got accounted, along with its data bytes.
+        let metrics = block.lock().unwrap().metrics();
+        assert_eq!((metrics.write.requests, metrics.write.bytes), (1, 1024));
+        assert_eq!((metrics.read.requests, metrics.read.bytes), (1, 1024));
+        assert_eq!(metrics.other.requests, 1);
+        assert_eq!(metrics.read.latency.buckets.iter().sum::<u64>(), 1);
+        assert_eq!(
+            metrics.read.errors + metrics.write.errors + metrics.other.errors,
+            0
+        );
+        assert_eq!((metrics.in_flight, metrics.invalid_requests), (0, 0));
+    }
+
+    #[test]
+    fn test_virtio_block_requests() {
+        check_requests("sync", ImageFormat::Raw);
+    }
+
+    #[test]
+    #[ignore = "requires io_uring support of the host kernel"]
+    fn test_virtio_block_io_uring_requests() {
+        check_requests("io_uring", ImageFormat::Raw);
+    }
+
+    #[test]
+    #[ignore = "requires io_uring support of the host kernel"]
+    fn test_virtio_block_io_uring_reset() {
+        let image = disk_image();
+        let mut config = block_config(&image);
+        config.io_engine = Some("io_uring".to_string());
+
+        let (mut driver, block, _) = block_device(&config);
+
+        // Submit a read and reset the device without waiting for it.
+        let header = DATA_ADDR;
+        let data = DATA_ADDR + 0x1000;
+        let status = data + 0x8_0000;
+        driver
+            .mem
+            .write_obj(VIRTIO_BLK_T_IN, GuestAddress(header))
+            .unwrap();
+        driver.submit(
+            0,
+            &[
+                (header, 16, false),
+                (data, 0x8_0000, true),
+                (status, 1, true),
+            ],
+        );
+        driver.write(VIRTIO_MMIO_STATUS, 0);
+        assert_eq!(driver.read(VIRTIO_MMIO_STATUS), 0);
+
+        // The reset waited for the kernel to release the guest buffers.
+        assert_eq!(block.lock().unwrap().metrics().in_flight, 0);
+    }
+
+    #[test]
+    fn test_virtio_block_qcow2_requests() {
+        check_requests("sync", ImageFormat::Qcow2);
+    }
+
+    #[test]
+    fn test_virtio_block_rate_limiter() {
+        let image = disk_image();
+        let mut config = block_config(&image);
+        // One request every 100ms.
+        config.rate_limiter = Some(RateLimiterConfig {
+            bandwidth: None,
+            ops: Some(TokenBucketConfig {
+                size: 1,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+        });
+
+        let (mut driver, _, _) = block_device(&config);
+
+        // Submit two flush requests at once.
+        let header = DATA_ADDR;
+        let status = DATA_ADDR + 0x1000;
+        driver
+            .mem
+            .write_obj(VIRTIO_BLK_T_FLUSH, GuestAddress(header))
+            .unwrap();
+        let start = Instant::now();
+        driver.submit(0, &[(header, 16, false), (status, 1, true)]);
+        driver.submit(0, &[(header, 16, false), (status + 1, 1, true)]);
+
+        // The second one is throttled, rather than dropped, until the bucket is refilled.
+        driver.wait_used(0).unwrap();
+        driver.wait_used(0).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        for addr in [status, status + 1] {
+            assert_eq!(
+                driver.mem.read_obj::<u8>(GuestAddress(addr)).unwrap(),
+                VIRTIO_BLK_S_OK as u8
+            );
+        }
+    }
+
+    #[test]
+    fn test_virtio_block_multiqueue() {
+        for image_format in [ImageFormat::Raw, ImageFormat::Qcow2] {
+            let image = TempFile::new().unwrap();
+            match image_format {
+                ImageFormat::Raw => image.as_file().set_len(DISK_SIZE).unwrap(),
+                ImageFormat::Qcow2 => {
+                    disk::qcow2::create(image.as_file(), DISK_SIZE, None).unwrap()
+                }
+            }
+            let mut config = block_config(&image);
+            config.num_queues = Some(2);
+            config.queue_threads = Some(true);
+
+            let (mut driver, block, features) = block_device(&config);
+            assert_eq!(block.lock().unwrap().queue_threads.len(), 2);
+            assert_ne!(features & (1 << VIRTIO_BLK_F_MQ), 0);
+            assert_eq!(driver.queues.len(), 2);
+
+            // The number of queues is reported in the configuration space.
+            let mut num_queues = [0u8; 2];
+            driver.read_config(34, &mut num_queues);
+            assert_eq!(u16::from_le_bytes(num_queues), 2);
+
+            // Write a sector through the second queue and read it back through the first one.
+            let header = DATA_ADDR;
+            let data = DATA_ADDR + 0x1000;
+            let status = DATA_ADDR + 0x2000;
+            let mut request = |index: usize, request_type: u32| {
+                driver
+                    .mem
+                    .write_obj(request_type, GuestAddress(header))
+                    .unwrap();
+                driver
+                    .mem
+                    .write_obj(8u64, GuestAddress(header + 8))
+                    .unwrap();
+                // Fill the data to write, and clear the buffer to read into.
+                let fill = if request_type == VIRTIO_BLK_T_OUT {
+                    0xaa
+                } else {
+                    0
+                };
+                driver
+                    .mem
+                    .write_slice(&[fill; 0x200], GuestAddress(data))
+                    .unwrap();
+                driver.submit(
+                    index,
+                    &[
+                        (header, 16, false),
+                        (data, 0x200, request_type == VIRTIO_BLK_T_IN),
+                        (status, 1, true),
+                    ],
+                );
+                driver.wait_used(index).unwrap();
+                driver.mem.read_obj::<u8>(GuestAddress(status)).unwrap() as u32
+            };
+
+            assert_eq!(request(1, VIRTIO_BLK_T_OUT), VIRTIO_BLK_S_OK);
+            assert_eq!(request(0, VIRTIO_BLK_T_IN), VIRTIO_BLK_S_OK);
+
+            let mut buf = [0u8; 0x200];
+            driver.mem.read_slice(&mut buf, GuestAddress(data)).unwrap();
+            assert_eq!(buf, [0xaa; 0x200]);
+
+            // Stopping the queue threads drops the queue handlers their event managers held.
+            let mut block = block.lock().unwrap();
+            drop(std::mem::take(&mut block.queue_threads));
+            for handler in block.queue_handlers.iter() {
+                assert_eq!(Arc::strong_count(handler), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_virtio_block_serial() {
+        let image = TempFile::new().unwrap();
+        let mut config = block_config(&image);
+
+        // The default serial number is stable, and differs between devices.
+        let default = serial(&config).unwrap();
+        assert_eq!(default.len(), VIRTIO_BLK_ID_BYTES as usize);
+        assert_eq!(serial(&config).unwrap(), default);
+        config.id += 1;
+        assert_ne!(serial(&config).unwrap(), default);
+
+        config.serial = Some("a".repeat(VIRTIO_BLK_ID_BYTES as usize));
+        assert_eq!(
+            serial(&config).unwrap(),
+            "a".repeat(VIRTIO_BLK_ID_BYTES as usize)
+        );
+        for invalid in [
+            "a".repeat(VIRTIO_BLK_ID_BYTES as usize + 1),
+            "disk 0".to_string(),
+        ] {
+            config.serial = Some(invalid);
+            assert!(matches!(
+                serial(&config),
+                Err(Error::InvalidConfigField("serial", _))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_virtio_block_cache_mode() {
+        let image = TempFile::new().unwrap();
+        let mut config = block_config(&image);
+
+        // The flush feature selects the default cache mode.
+        assert_eq!(cache_mode(&config).unwrap(), CacheMode::Writeback);
+        config.advertise_flush = Some(false);
+        assert_eq!(cache_mode(&config).unwrap(), CacheMode::Writethrough);
+        config.advertise_flush = None;
+        assert_eq!(cache_mode(&config).unwrap(), CacheMode::Writeback);
+
+        // Writethrough devices have no cache to flush.
+        config.cache = Some("writethrough".to_string());
+        let features = VirtioBlock::device_features(&config).unwrap();
+        assert_eq!(features & (1 << VIRTIO_BLK_F_FLUSH), 0);
+        assert_eq!(features & (1 << VIRTIO_BLK_F_CONFIG_WCE), 0);
+        assert_eq!(
+            VirtioBlock::config_space(&config).unwrap()[WRITEBACK_OFFSET],
+            0
+        );
+
+        config.cache = Some("unsafe".to_string());
+        let features = VirtioBlock::device_features(&config).unwrap();
+        assert_ne!(features & (1 << VIRTIO_BLK_F_FLUSH), 0);
+        assert_ne!(features & (1 << VIRTIO_BLK_F_CONFIG_WCE), 0);
+        assert_eq!(
+            VirtioBlock::config_space(&config).unwrap()[WRITEBACK_OFFSET],
+            1
+        );
+
+        // The flush feature must match the cache mode.
+        config.advertise_flush = Some(false);
+        assert!(matches!(
+            cache_mode(&config),
+            Err(Error::InvalidConfigField("advertise_flush", _))
+        ));
+        config.cache = Some("directsync".to_string());
+        assert!(matches!(
+            cache_mode(&config),
+            Err(Error::InvalidConfigField("cache", _))
+        ));
+    }
+
+    #[test]
+    fn test_virtio_block_writeback_toggle() {
+        let image = disk_image();
+        let config = block_config(&image);
+
+        let mut driver = VirtioMmioDriver::new();
+        let block = new_block(&driver, &config).unwrap();
+
+        // Without the feature, the cache mode is read-only.
+        driver.init(u64::MAX & !(1 << VIRTIO_BLK_F_CONFIG_WCE));
+        driver.write_config(WRITEBACK_OFFSET as u64, &[0]);
+        let mut writeback = [0u8];
+        driver.read_config(WRITEBACK_OFFSET as u64, &mut writeback);
+        assert_eq!(writeback, [1]);
+        assert!(block.lock().unwrap().write_cache.is_enabled());
+
+        // The driver switches the cache to writethrough, and back to writeback.
+        driver.init(u64::MAX);
+        driver.write_config(WRITEBACK_OFFSET as u64, &[0]);
+        driver.read_config(WRITEBACK_OFFSET as u64, &mut writeback);
+        assert_eq!(writeback, [0]);
+        assert!(block.lock().unwrap().write_cache.syncs_writes());
+        driver.write_config(WRITEBACK_OFFSET as u64, &[1]);
+        assert!(!block.lock().unwrap().write_cache.syncs_writes());
+
+        // The writes are synced while the cache is in writethrough mode.
+        driver.write_config(WRITEBACK_OFFSET as u64, &[0]);
+        let header = DATA_ADDR;
+        let data = DATA_ADDR + 0x1000;
+        let status = DATA_ADDR + 0x2000;
+        driver
+            .mem
+            .write_obj(VIRTIO_BLK_T_OUT, GuestAddress(header))
+            .unwrap();
+        driver
+            .mem
+            .write_obj(0u64, GuestAddress(header + 8))
+            .unwrap();
+        driver
+            .mem
+            .write_slice(&[0xaa; 0x200], GuestAddress(data))
+            .unwrap();
+        driver.submit(
+            0,
+            &[(header, 16, false), (data, 0x200, false), (status, 1, true)],
+        );
+        driver.wait_used(0).unwrap();
+        assert_eq!(
+            driver.mem.read_obj::<u8>(GuestAddress(status)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+
+        // Resetting the device restores the cache mode it started with.
+        driver.write(VIRTIO_MMIO_STATUS, 0);
+        driver.read_config(WRITEBACK_OFFSET as u64, &mut writeback);
+        assert_eq!(writeback, [1]);
+
+        // Drivers unable to flush get their writes synced.
+        driver.init(u64::MAX & !(1 << VIRTIO_BLK_F_FLUSH));
+        assert!(block.lock().unwrap().write_cache.syncs_writes());
+    }
+
+    #[test]
+    fn test_virtio_block_image_lock() {
+        let image = disk_image();
+        let mut config = block_config(&image);
+
+        let attach = |config: &DeviceConfig| new_block(&VirtioMmioDriver::new(), config);
+
+        // A writable image cannot be attached twice.
+        let block = attach(&config).unwrap();
+        assert!(block.lock().unwrap().image_lock.is_some());
+        config.read_only = Some(true);
+        assert!(matches!(attach(&config), Err(Error::DiskImageLocked(_))));
+        drop(block);
+
+        // Read-only and shared images can.
+        let _reader = attach(&config).unwrap();
+        let _reader = attach(&config).unwrap();
+        config.read_only = Some(false);
+        assert!(matches!(attach(&config), Err(Error::DiskImageLocked(_))));
+        config.shared = Some(true);
+        let _writer = attach(&config).unwrap();
+    }
+
+    #[test]
+    fn test_virtio_block_failed_attach() {
+        let image = disk_image();
+        let mut config = block_config(&image);
+        config.read_only = Some(true);
+
+        let driver = VirtioMmioDriver::new();
+
+        // The configuration is validated before the irqfd is registered.
+        let mut invalid = config.clone();
+        invalid.cache = Some("directsync".to_string());
+        assert!(new_block(&driver, &invalid).is_err());
+        assert_eq!(driver.dm.num_irqfds(), 0);
+
+        // A device failing to register its MMIO region deassigns its irqfd.
+        let _block = new_block(&driver, &config).unwrap();
+        assert!(matches!(
+            new_block(&driver, &config),
+            Err(Error::MmioConfig)
+        ));
+        assert_eq!(driver.dm.num_irqfds(), 1);
+    }
+
+    #[test]
+    fn test_virtio_block_io_engine() {
+        let image = TempFile::new().unwrap();
+        let mut config = block_config(&image);
+        config.io_engine = Some("aio".to_string());
+        assert!(matches!(
+            IoEngine::from_config(&config),
+            Err(Error::InvalidConfigField("io_engine", _))
+        ));
+    }
+
+    #[test]
+    fn test_virtio_block_image_format() {
+        let image = TempFile::new().unwrap();
+        let mut config = block_config(&image);
+
+        // The format is detected from the image content, unless configured.
+        assert_eq!(image_format(&config).unwrap(), ImageFormat::Raw);
+        disk::qcow2::create(image.as_file(), DISK_SIZE, None).unwrap();
+        assert_eq!(image_format(&config).unwrap(), ImageFormat::Qcow2);
+        config.image_format = Some("raw".to_string());
+        assert_eq!(image_format(&config).unwrap(), ImageFormat::Raw);
+
+        // The capacity is the virtual disk size.
+        config.image_format = None;
+        assert_eq!(
+            VirtioBlock::config_space(&config).unwrap()[..8],
+            (DISK_SIZE >> SECTOR_SHIFT).to_le_bytes()
+        );
+
+        config.image_format = Some("vmdk".to_string());
+        assert!(matches!(
+            image_format(&config),
+            Err(Error::InvalidConfigField("image_format", _))
+        ));
+    }
+
+    #[test]
+    fn test_virtio_block_config_space() {
+        let image = disk_image();
+        let mut config = block_config(&image);
+        config.queue_size = Some(128);
+        config.logical_block_size = Some(4096);
+
+        // The logical block size override also raises the physical block size.
+        let bytes = VirtioBlock::config_space(&config).unwrap();
+        assert_eq!(bytes[8..12], MAX_SEGMENT_SIZE.to_le_bytes());
+        assert_eq!(bytes[12..16], 126u32.to_le_bytes());
+        assert_eq!(bytes[16..18], 2u16.to_le_bytes());
+        assert_eq!(bytes[20..24], 4096u32.to_le_bytes());
+
+        config.physical_block_size = Some(0x8000);
+        let bytes = VirtioBlock::config_space(&config).unwrap();
+        assert_eq!(bytes[24], 3);
+        assert_eq!(bytes[26..28], 8u16.to_le_bytes());
+
+        // The block sizes are checked.
+        config.logical_block_size = Some(1000);
+        assert!(matches!(
+            VirtioBlock::config_space(&config),
+            Err(Error::InvalidConfigField("logical_block_size", _))
+        ));
+        config.logical_block_size = Some(4096);
+        config.physical_block_size = Some(2048);
+        assert!(matches!(
+            VirtioBlock::config_space(&config),
+            Err(Error::InvalidConfigField("physical_block_size", _))
+        ));
+
+        // The features are always offered.
+        let features = VirtioBlock::device_features(&config).unwrap();
+        for feature in [
+            VIRTIO_BLK_F_SIZE_MAX,
+            VIRTIO_BLK_F_SEG_MAX,
+            VIRTIO_BLK_F_GEOMETRY,
+            VIRTIO_BLK_F_BLK_SIZE,
+            VIRTIO_BLK_F_TOPOLOGY,
+        ] {
+            assert_ne!(features & (1 << feature), 0);
+        }
+    }
+
+    #[test]
+    fn test_virtio_block_discard_write_zeroes() {
+        let image = disk_image();
+        image
+            .as_file()
+            .write_all_at(&vec![0xaau8; DISK_SIZE as usize], 0)
+            .unwrap();
+        let config = block_config(&image);
+
+        let (mut driver, _, features) = block_device(&config);
+        assert_ne!(features & (1 << VIRTIO_BLK_F_DISCARD), 0);
+        assert_ne!(features & (1 << VIRTIO_BLK_F_WRITE_ZEROES), 0);
+
+        // The limits are reported in the configuration space.
+        let mut max_discard_sectors = [0u8; 4];
+        driver.read_config(36, &mut max_discard_sectors);
+        assert_eq!(u32::from_le_bytes(max_discard_sectors), MAX_DISCARD_SECTORS);
+
+        // Request layout: header, segment and status.
+        let header = DATA_ADDR;
+        let segment = DATA_ADDR + 0x1000;
+        let status = DATA_ADDR + 0x2000;
+        let mut request = |request_type: u32, flags: u32| {
+            driver
+                .mem
+                .write_obj(request_type, GuestAddress(header))
+                .unwrap();
+            driver.mem.write_obj(8u64, GuestAddress(segment)).unwrap();
+            driver
+                .mem
+                .write_obj(2u32, GuestAddress(segment + 8))
+                .unwrap();
+            driver
+                .mem
+                .write_obj(flags, GuestAddress(segment + 12))
+                .unwrap();
+            driver.submit(
+                0,
+                &[(header, 16, false), (segment, 16, false), (status, 1, true)],
+            );
+            driver.wait_used(0).unwrap();
+            driver.mem.read_obj::<u8>(GuestAddress(status)).unwrap() as u32
+        };
+
+        // Zero two sectors.
+        assert_eq!(request(VIRTIO_BLK_T_WRITE_ZEROES, 0), VIRTIO_BLK_S_OK);
+        let mut buf = vec![0u8; 0x600];
+        image.as_file().read_exact_at(&mut buf, 0xe00).unwrap();
+        assert!(buf[..0x200].iter().all(|&b| b == 0xaa));
+        assert!(buf[0x200..0x600].iter().all(|&b| b == 0));
+
+        // Discard requests do not take the unmap flag.
+        assert_eq!(request(VIRTIO_BLK_T_DISCARD, 0), VIRTIO_BLK_S_OK);
+        assert_eq!(
+            request(VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+            VIRTIO_BLK_S_UNSUPP
+        );
+    }
+
+    #[test]
+    fn test_virtio_block_needs_reset() {
+        let image = disk_image();
+        let config = block_config(&image);
+
+        let (driver, _, _) = block_device(&config);
+
+        // Publish an available index the queue cannot hold, which the device cannot recover from.
+        driver
+            .mem
+            .write_obj(0x100u16, GuestAddress(driver.queues[0].avail_ring + 2))
+            .unwrap();
+        driver.kick(0);
+
+        // The driver gets a configuration change interrupt and sees the device needs a reset.
+        assert!(driver.dm.wait_irq(MOCK_IO_TIMEOUT));
+        assert_ne!(driver.ack_interrupt() & VIRTIO_MMIO_INT_CONFIG as u32, 0);
+        assert_ne!(
+            driver.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_NEEDS_RESET,
+            0
+        );
+
+        // Resetting the device clears the error.
+        driver.write(VIRTIO_MMIO_STATUS, 0);
+        assert_eq!(driver.read(VIRTIO_MMIO_STATUS), 0);
+    }
+
+    #[test]
+    fn test_virtio_block_update_capacity() {
+        let image = disk_image();
+        let config = block_config(&image);
+
+        let (mut driver, block, _) = block_device(&config);
+        let generation = driver.read(VIRTIO_MMIO_CONFIG_GENERATION);
+
+        // Grow the disk image and announce it.
+        image.as_file().set_len(2 * DISK_SIZE).unwrap();
+        let capacity = block.lock().unwrap().update_capacity().unwrap();
+        assert_eq!(capacity, (2 * DISK_SIZE) >> SECTOR_SHIFT);
+
+        // The driver gets a configuration change interrupt and reads the new capacity.
+        assert!(driver.dm.wait_irq(MOCK_IO_TIMEOUT));
+        assert_eq!(driver.ack_interrupt(), VIRTIO_MMIO_INT_CONFIG as u32);
+        assert_ne!(driver.read(VIRTIO_MMIO_CONFIG_GENERATION), generation);
+
+        let mut config_capacity = [0u8; 8];
+        driver.read_config(0, &mut config_capacity);
+        assert_eq!(u64::from_le_bytes(config_capacity), capacity);
+
+        // The new sectors are served right away.
+        let header = DATA_ADDR;
+        let data = DATA_ADDR + 0x1000;
+        let status = DATA_ADDR + 0x2000;
+        driver
+            .mem
+            .write_obj(VIRTIO_BLK_T_OUT, GuestAddress(header))
+            .unwrap();
+        driver
+            .mem
+            .write_obj(DISK_SIZE >> SECTOR_SHIFT, GuestAddress(header + 8))
+            .unwrap();
+        driver.submit(
+            0,
+            &[(header, 16, false), (data, 0x200, false), (status, 1, true)],
+        );
+        driver.wait_used(0).unwrap();
+        assert_eq!(
+            driver.mem.read_obj::<u8>(GuestAddress(status)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+    }
+
+    #[test]
+    fn test_virtio_block_resize() {
+        let image = disk_image();
+        let mut config = block_config(&image);
+
+        let (driver, block, _) = block_device(&config);
+
+        // The image file is grown, and the driver notified.
+        let capacity = block.lock().unwrap().resize(2 * DISK_SIZE).unwrap();
+        assert_eq!(capacity, (2 * DISK_SIZE) >> SECTOR_SHIFT);
+        assert_eq!(image.as_file().metadata().unwrap().len(), 2 * DISK_SIZE);
+        assert!(driver.dm.wait_irq(MOCK_IO_TIMEOUT));
+        assert_eq!(driver.ack_interrupt(), VIRTIO_MMIO_INT_CONFIG as u32);
+
+        // Shrinking the disk and partial sectors are refused.
+        for size in [DISK_SIZE, 2 * DISK_SIZE + 1] {
+            assert!(matches!(
+                block.lock().unwrap().resize(size),
+                Err(Error::DiskResize(_))
+            ));
+        }
+        assert_eq!(image.as_file().metadata().unwrap().len(), 2 * DISK_SIZE);
+
+        // Read-only disks are not resized.
+        drop(block);
+        drop(driver);
+        config.read_only = Some(true);
+        let block = new_block(&VirtioMmioDriver::new(), &config).unwrap();
+        assert!(matches!(
+            block.lock().unwrap().resize(4 * DISK_SIZE),
+            Err(Error::DiskResize(_))
+        ));
+    }
+
+    /// Submit a request with a single data buffer (if any), returning its status and used length.
+    fn submit_request(
+        driver: &mut VirtioMmioDriver,
+        request_type: u32,
+        sector: u64,
+        data_len: u32,
+    ) -> (u32, u32) {
+        // Request layout: header, data (if any) and in-header.
+        let header = DATA_ADDR;
+        let data = DATA_ADDR + 0x1000;
+        let in_header = DATA_ADDR + 0x2000;
+        driver
+            .mem
+            .write_obj(request_type, GuestAddress(header))
+            .unwrap();
+        driver
+            .mem
+            .write_obj(sector, GuestAddress(header + 8))
+            .unwrap();
+
+        // The zone appends also return the written sector.
+        let append = request_type == VIRTIO_BLK_T_ZONE_APPEND;
+        let writable = matches!(request_type, VIRTIO_BLK_T_IN | VIRTIO_BLK_T_ZONE_REPORT);
+        let mut buffers = vec![(header, 16, false)];
+        if data_len != 0 {
+            buffers.push((data, data_len, writable));
+        }
+        buffers.push((in_header, if append { 16 } else { 1 }, true));
+        driver.submit(0, &buffers);
+        let (_, used_len) = driver.wait_used(0).unwrap();
+
+        let status_addr = if append { in_header + 8 } else { in_header };
+        let status = driver
+            .mem
+            .read_obj::<u8>(GuestAddress(status_addr))
+            .unwrap();
+        (status as u32, used_len)
+    }
+
+    #[test]
+    fn test_virtio_block_zoned() {
+        let image = disk_image();
+        let mut config = block_config(&image);
+        config.zoned = Some(ZonedConfig {
+            zone_size: Some(0x4_0000),
+            conventional_zones: Some(1),
+            max_open_zones: None,
+            max_active_zones: None,
+        });
+
+        let (mut driver, block, features) = block_device(&config);
+        assert_ne!(features & (1 << VIRTIO_BLK_F_ZONED), 0);
+        assert_eq!(features & (1 << VIRTIO_BLK_F_DISCARD), 0);
+
+        // The zone size is reported in the configuration space.
+        let mut zone_sectors = [0u8; 4];
+        driver.read_config(72, &mut zone_sectors);
+        assert_eq!(u32::from_le_bytes(zone_sectors), 0x200);
+        let mut zoned_model = [0u8; 1];
+        driver.read_config(92, &mut zoned_model);
+        assert_eq!(zoned_model[0], VIRTIO_BLK_Z_HM);
+
+        // Report the zones: one conventional zone, followed by three empty sequential ones.
+        let data = DATA_ADDR + 0x1000;
+        assert_eq!(
+            submit_request(&mut driver, VIRTIO_BLK_T_ZONE_REPORT, 0, 0x200),
+            (VIRTIO_BLK_S_OK, 0x141)
+        );
+        assert_eq!(driver.mem.read_obj::<u64>(GuestAddress(data)).unwrap(), 4);
+        let zone = |driver: &VirtioMmioDriver, index: u64| {
+            let entry = data + 64 * (index + 1);
+            (
+                driver.mem.read_obj::<u64>(GuestAddress(entry + 8)).unwrap(),
+                driver
+                    .mem
+                    .read_obj::<u64>(GuestAddress(entry + 16))
+                    .unwrap(),
+                driver.mem.read_obj::<u8>(GuestAddress(entry + 24)).unwrap(),
+                driver.mem.read_obj::<u8>(GuestAddress(entry + 25)).unwrap(),
+            )
+        };
+        assert_eq!(zone(&driver, 0), (0, 0, 1, 0));
+        assert_eq!(zone(&driver, 1), (0x200, 0x200, 2, 1));
+
+        // Appends return the sector they were written at.
+        for sector in [0x200u64, 0x202] {
+            assert_eq!(
+                submit_request(&mut driver, VIRTIO_BLK_T_ZONE_APPEND, 0x200, 0x400),
+                (VIRTIO_BLK_S_OK, 9)
+            );
+            assert_eq!(
+                driver
+                    .mem
+                    .read_obj::<u64>(GuestAddress(DATA_ADDR + 0x2000))
+                    .unwrap(),
+                sector
+            );
+        }
+
+        // Writes must start at the write pointer.
+        assert_eq!(
+            submit_request(&mut driver, VIRTIO_BLK_T_OUT, 0x200, 0x200).0,
+            VIRTIO_BLK_S_ZONE_UNALIGNED_WP
+        );
+        assert_eq!(
+            submit_request(&mut driver, VIRTIO_BLK_T_OUT, 0x204, 0x200).0,
+            VIRTIO_BLK_S_OK
+        );
+        submit_request(&mut driver, VIRTIO_BLK_T_ZONE_REPORT, 0x200, 0x80);
+        assert_eq!(zone(&driver, 0), (0x200, 0x205, 2, 2));
+
+        // Conventional zones take random writes, but no zone management.
+        assert_eq!(
+            submit_request(&mut driver, VIRTIO_BLK_T_OUT, 8, 0x200).0,
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(
+            submit_request(&mut driver, VIRTIO_BLK_T_ZONE_OPEN, 0, 0).0,
+            VIRTIO_BLK_S_ZONE_INVALID_CMD
+        );
+
+        // The zones are reset through zone management requests only.
+        assert_eq!(
+            submit_request(&mut driver, VIRTIO_BLK_T_DISCARD, 0, 16).0,
+            VIRTIO_BLK_S_UNSUPP
+        );
+        assert_eq!(
+            submit_request(&mut driver, VIRTIO_BLK_T_ZONE_RESET, 0x200, 0).0,
+            VIRTIO_BLK_S_OK
+        );
+        submit_request(&mut driver, VIRTIO_BLK_T_ZONE_REPORT, 0x200, 0x80);
+        assert_eq!(zone(&driver, 0), (0x200, 0x200, 2, 1));
+
+        // Zoned devices are not resized.
+        assert!(matches!(
+            block.lock().unwrap().resize(2 * DISK_SIZE),
+            Err(Error::DiskResize(_))
+        ));
+    }
+
+    #[test]
+    fn test_virtio_block_overlay() {
+        let base = TempFile::new().unwrap();
+        base.as_file()
+            .write_all_at(&vec![0xaau8; DISK_SIZE as usize], 0)
+            .unwrap();
+        let dir = TempDir::new().unwrap();
+        let delta = dir.as_path().join("delta.qcow2");
+        let mut config = block_config(&base);
+        config.overlay = Some(delta.to_str().unwrap().to_string());
+
+        let (mut driver, block, _) = block_device(&config);
+
+        // The delta file takes the writes, while the base image is never written.
+        let data = GuestAddress(DATA_ADDR + 0x1000);
+        let write = |driver: &mut VirtioMmioDriver, byte: u8| {
+            driver.mem.write_slice(&[byte; 0x200], data).unwrap();
+            assert_eq!(
+                submit_request(driver, VIRTIO_BLK_T_OUT, 8, 0x200).0,
+                VIRTIO_BLK_S_OK
+            );
+        };
+        let read = |driver: &mut VirtioMmioDriver| {
+            assert_eq!(
+                submit_request(driver, VIRTIO_BLK_T_IN, 8, 0x400).0,
+                VIRTIO_BLK_S_OK
+            );
+            let mut buf = [0u8; 0x400];
+            driver.mem.read_slice(&mut buf, data).unwrap();
+            (buf[0], buf[0x200])
+        };
+        write(&mut driver, 0x11);
+        assert_eq!(read(&mut driver), (0x11, 0xaa));
+        let mut buf = [0u8; 0x200];
+        base.as_file().read_exact_at(&mut buf, 0x1000).unwrap();
+        assert!(buf.iter().all(|&b| b == 0xaa));
+
+        // The snapshot keeps the disk content at the time it was taken.
+        let snapshot = dir.as_path().join("snapshot.qcow2");
+        block
+            .lock()
+            .unwrap()
+            .snapshot(snapshot.to_str().unwrap())
+            .unwrap();
+        write(&mut driver, 0x22);
+        assert_eq!(read(&mut driver), (0x22, 0xaa));
+        disk::open(&snapshot, Some(ImageFormat::Qcow2), true)
+            .unwrap()
+            .read_at(&mut buf, 0x1000)
+            .unwrap();
+        assert!(buf.iter().all(|&b| b == 0x11));
+
+        // Snapshots do not overwrite existing files.
+        assert!(matches!(
+            block.lock().unwrap().snapshot(snapshot.to_str().unwrap()),
+            Err(Error::DiskSnapshot(_))
+        ));
+        disk::open(&snapshot, Some(ImageFormat::Qcow2), true)
+            .unwrap()
+            .read_at(&mut buf, 0x1000)
+            .unwrap();
+        assert!(buf.iter().all(|&b| b == 0x11));
+        assert_eq!(read(&mut driver), (0x22, 0xaa));
+    }
+
+    #[test]
+    #[ignore = "requires the AF_ALG xts(aes) cipher of the host kernel"]
+    fn test_virtio_block_encryption() {
+        // Format the image, whose payload follows the encryption header.
+        let image = TempFile::new().unwrap();
+        image
+            .as_file()
+            .set_len(CRYPT_HEADER_SIZE + DISK_SIZE)
+            .unwrap();
+        let key_file = TempFile::new().unwrap();
+        key_file
+            .as_file()
+            .write_all_at(&(0..64).collect::<Vec<u8>>(), 0)
+            .unwrap();
+        let key = EncryptionKey::from_file(key_file.as_path()).unwrap();
+        crypt::format(
+            disk::open(image.as_path(), Some(ImageFormat::Raw), false)
+                .unwrap()
+                .as_mut(),
+            &key,
+        )
+        .unwrap();
+
+        let mut config = block_config(&image);
+        config.encryption = Some(EncryptionConfig {
+            key_file: Some(key_file.as_path().to_str().unwrap().to_string()),
+            keyring: None,
+        });
+        let (mut driver, block, _) = block_device(&config);
+        assert_eq!(
+            block.lock().unwrap().capacity.load(Ordering::Acquire),
+            DISK_SIZE >> SECTOR_SHIFT
+        );
+
+        // The guest data is read back, while only its ciphertext reaches the image.
+        let data = GuestAddress(DATA_ADDR + 0x1000);
+        let pattern = [0xaau8; 0x400];
+        driver.mem.write_slice(&pattern, data).unwrap();
+        assert_eq!(
+            submit_request(&mut driver, VIRTIO_BLK_T_OUT, 8, 0x400).0,
+            VIRTIO_BLK_S_OK
+        );
+        driver.mem.write_slice(&[0u8; 0x400], data).unwrap();
+        assert_eq!(
+            submit_request(&mut driver, VIRTIO_BLK_T_IN, 8, 0x400).0,
+            VIRTIO_BLK_S_OK
+        );
+        let mut buf = [0u8; 0x400];
+        driver.mem.read_slice(&mut buf, data).unwrap();
+        assert_eq!(buf, pattern);
+        image
+            .as_file()
+            .read_exact_at(&mut buf, CRYPT_HEADER_SIZE + 0x1000)
+            .unwrap();
+        assert_ne!(buf, pattern);
+
+        // Encrypted images are not resized by the device.
+        assert!(matches!(
+            block.lock().unwrap().resize(2 * DISK_SIZE),
+            Err(Error::DiskResize(_))
+        ));
+        drop(block);
+        drop(driver);
+
+        // The wrong key, conflicting key sources and the io_uring engine are refused.
+        key_file
+            .as_file()
+            .write_all_at(&(64..128).collect::<Vec<u8>>(), 0)
+            .unwrap();
+        let driver = VirtioMmioDriver::new();
+        assert!(matches!(
+            new_block(&driver, &config),
+            Err(Error::DiskImage(_))
+        ));
+        config.encryption.as_mut().unwrap().keyring = Some("bao:disk".to_string());
+        assert!(matches!(
+            new_block(&driver, &config),
+            Err(Error::InvalidConfigField("encryption", _))
+        ));
+        config.encryption.as_mut().unwrap().keyring = None;
+        config.io_engine = Some("io_uring".to_string());
+        assert!(new_block(&driver, &config).is_err());
     }
 }
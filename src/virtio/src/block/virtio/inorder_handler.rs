@@ -1,18 +1,91 @@
+use super::config_space::{MAX_DISCARD_SECTORS, MAX_DISCARD_SEG};
+use super::metrics::{BlockMetrics, BlockOp};
+use super::zoned::{process_zone_request, zone_status, ZoneRequest, VIRTIO_BLK_T_ZONE_APPEND};
+use crate::block::disk::zoned::{ZoneError, ZonedImage};
+use crate::block::disk::{CacheMode, DiskImage, SECTOR_SHIFT};
 use crate::device::SignalUsedQueue;
-use std::fs::File;
+use crate::rate_limiter::RateLimiter;
+use std::io;
 use std::result;
-use virtio_blk::request::Request;
-use virtio_blk::stdio_executor::{self, StdIoBackend};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use virtio_bindings::virtio_blk::{
+    VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
+    VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+};
+use virtio_blk::request::{Request, RequestType};
 use virtio_queue::{DescriptorChain, Queue, QueueOwnedT, QueueT};
 use vm_memory::bitmap::AtomicBitmap;
+use vm_memory::{Bytes, GuestMemoryError};
 
 type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 
+// Size of a discard or write zeroes segment (`struct virtio_blk_discard_write_zeroes`).
+const SEGMENT_SIZE: usize = 16;
+
+/// Device ID returned by `VIRTIO_BLK_T_GET_ID` requests (the serial, zero padded).
+pub type DeviceId = [u8; VIRTIO_BLK_ID_BYTES as usize];
+
+/// Write cache state, shared by the device and its queue handlers.
+///
+/// # Attributes
+///
+/// * `mode` - The host cache mode of the disk image.
+/// * `enabled` - Whether the driver runs the write cache in writeback mode. Otherwise, every write
+///   is flushed before completing.
+#[derive(Clone)]
+pub struct WriteCache {
+    pub mode: CacheMode,
+    enabled: Arc<AtomicBool>,
+}
+
+impl WriteCache {
+    /// Create a new write cache state, enabled if the cache mode holds the writes.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - The host cache mode of the disk image.
+    pub fn new(mode: CacheMode) -> Self {
+        WriteCache {
+            mode,
+            enabled: Arc::new(AtomicBool::new(mode.has_write_cache())),
+        }
+    }
+
+    /// Switch the write cache between writeback (enabled) and writethrough (disabled).
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled
+            .store(enabled && self.mode.has_write_cache(), Ordering::Release);
+    }
+
+    /// Check if the write cache is in writeback mode.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Check if the flush requests reach the backing storage.
+    pub fn flushes(&self) -> bool {
+        self.mode != CacheMode::Unsafe
+    }
+
+    /// Check if every write has to be flushed before completing.
+    pub fn syncs_writes(&self) -> bool {
+        // The writethrough mode opens the image with `O_DSYNC`, which already syncs the writes.
+        self.mode.has_write_cache() && self.flushes() && !self.is_enabled()
+    }
+}
+
 pub struct InOrderQueueHandler<S: SignalUsedQueue> {
     pub driver_notify: S,
     pub mem: GuestMemoryMmap,
     pub queue: Queue,
-    pub disk: StdIoBackend<File>,
+    pub disk: Box<dyn DiskImage>,
+    pub device_id: DeviceId,
+    pub rate_limiter: Option<RateLimiter>,
+    pub write_cache: WriteCache,
+    pub capacity: Arc<AtomicU64>,
+    pub zoned: Option<Arc<Mutex<ZonedImage>>>,
+    pub metrics: Arc<BlockMetrics>,
 }
 
 impl<S> InOrderQueueHandler<S>
@@ -20,15 +93,32 @@ where
     S: SignalUsedQueue,
 {
     /// Process a chain.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing whether the chain was processed (or throttled by the rate limiter).
     fn process_chain(
         &mut self,
         mut chain: DescriptorChain<&GuestMemoryMmap>,
-    ) -> result::Result<(), Error> {
+    ) -> result::Result<bool, Error> {
+        // The zone requests are only served by zoned devices.
+        if self.zoned.is_some() && ZoneRequest::is_zone_request(&chain) {
+            return self.process_zone_chain(chain);
+        }
+
         let used_len = match Request::parse(&mut chain) {
+            // Check if the rate limiter lets the request through.
+            Ok(request)
+                if !rate_limit(self.rate_limiter.as_mut(), &request)
+                    .map_err(Error::RateLimiter)? =>
+            {
+                return Ok(false);
+            }
             // Process the backend request.
-            Ok(request) => self.disk.process_request(chain.memory(), &request)?,
+            Ok(request) => self.process_request(chain.memory(), &request)?,
             Err(e) => {
                 println!("block request parse error: {:?}", e);
+                self.metrics.invalid_request();
                 0
             }
         };
@@ -42,7 +132,196 @@ where
             self.driver_notify.signal_used_queue(0);
         }
 
-        Ok(())
+        Ok(true)
+    }
+
+    /// Process a chain holding a zone request.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing whether the chain was processed (or throttled by the rate limiter).
+    fn process_zone_chain(
+        &mut self,
+        mut chain: DescriptorChain<&GuestMemoryMmap>,
+    ) -> result::Result<bool, Error> {
+        let used_len = match ZoneRequest::parse(&mut chain) {
+            // Check if the rate limiter lets the request through.
+            Ok(request)
+                if !self
+                    .rate_limiter
+                    .as_mut()
+                    .map_or(Ok(true), |l| l.consume(request.data_len()))
+                    .map_err(Error::RateLimiter)? =>
+            {
+                return Ok(false);
+            }
+            // Process the zone request.
+            Ok(request) => {
+                // The zone appends write data, the other requests only manage the zones.
+                let timer = match request.request_type {
+                    VIRTIO_BLK_T_ZONE_APPEND => {
+                        self.metrics.start(BlockOp::Write, request.data_len())
+                    }
+                    _ => self.metrics.start(BlockOp::Other, 0),
+                };
+                let (status, used_len) = process_zone_request(
+                    self.zoned.as_ref().unwrap(),
+                    self.disk.as_mut(),
+                    &self.write_cache,
+                    chain.memory(),
+                    &request,
+                )?;
+                timer.complete(status);
+                used_len
+            }
+            Err(e) => {
+                println!("block zone request parse error: {:?}", e);
+                self.metrics.invalid_request();
+                0
+            }
+        };
+
+        // Add the used descriptor to the queue.
+        self.queue
+            .add_used(chain.memory(), chain.head_index(), used_len)?;
+
+        // Signal the driver, if needed.
+        if self.queue.needs_notification(chain.memory())? {
+            self.driver_notify.signal_used_queue(0);
+        }
+
+        Ok(true)
+    }
+
+    /// Process a request, writing its status to the guest memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - The guest memory.
+    /// * `request` - The request.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of bytes written to the guest memory (status included).
+    fn process_request(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        request: &Request,
+    ) -> result::Result<u32, Error> {
+        // Only the data transfers count towards the bytes.
+        let bytes = match request.request_type() {
+            RequestType::In | RequestType::Out => request.total_data_len() as u64,
+            _ => 0,
+        };
+        let timer = self.metrics.start(request.request_type().into(), bytes);
+
+        let (status, len) = match self.execute(mem, request) {
+            Ok(len) => (VIRTIO_BLK_S_OK, len),
+            Err(Error::Unsupported(request_type)) => {
+                println!("unsupported block request {:?}", request_type);
+                (VIRTIO_BLK_S_UNSUPP, 0)
+            }
+            Err(Error::Disk(e)) => {
+                println!("block request error: {:?}", e);
+                (VIRTIO_BLK_S_IOERR, 0)
+            }
+            Err(Error::Zone(e)) => (zone_status(e), 0),
+            Err(e) => return Err(e),
+        };
+
+        timer.complete(status);
+
+        // Write the request status.
+        mem.write_obj(status as u8, request.status_addr())?;
+
+        Ok(len + 1)
+    }
+
+    /// Execute a request against the disk image.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of data bytes written to the guest memory.
+    fn execute(&mut self, mem: &GuestMemoryMmap, request: &Request) -> result::Result<u32, Error> {
+        match request.request_type() {
+            RequestType::In => {
+                let mut offset = self.data_offset(request)?;
+                for (addr, len) in request.data() {
+                    let mut buf = vec![0u8; *len as usize];
+                    self.disk.read_at(&mut buf, offset).map_err(Error::Disk)?;
+                    mem.write_slice(&buf, *addr)?;
+                    offset += *len as u64;
+                }
+                Ok(request.total_data_len())
+            }
+            RequestType::Out if self.zoned.is_some() => {
+                let offset = self.data_offset(request)?;
+                let len = request.total_data_len() as u64;
+                if len & ((1 << SECTOR_SHIFT) - 1) != 0 {
+                    return Err(Error::Zone(ZoneError::InvalidCommand));
+                }
+
+                // Gather the data, as the zone checks and the write must happen at once.
+                let mut buf = Vec::with_capacity(len as usize);
+                for (addr, len) in request.data() {
+                    let mut chunk = vec![0u8; *len as usize];
+                    mem.read_slice(&mut chunk, *addr)?;
+                    buf.extend_from_slice(&chunk);
+                }
+
+                let disk = self.disk.as_mut();
+                self.zoned
+                    .as_ref()
+                    .unwrap()
+                    .lock()
+                    .unwrap()
+                    .write(request.sector(), len >> SECTOR_SHIFT, false, |_| {
+                        disk.write_at(&buf, offset)
+                    })
+                    .map_err(Error::Zone)?;
+
+                // Without a write cache, the data must be durable once the request completes.
+                if self.write_cache.syncs_writes() {
+                    self.disk.flush().map_err(Error::Disk)?;
+                }
+                Ok(0)
+            }
+            RequestType::Out => {
+                let mut offset = self.data_offset(request)?;
+                for (addr, len) in request.data() {
+                    let mut buf = vec![0u8; *len as usize];
+                    mem.read_slice(&mut buf, *addr)?;
+                    self.disk.write_at(&buf, offset).map_err(Error::Disk)?;
+                    offset += *len as u64;
+                }
+                // Without a write cache, the data must be durable once the request completes.
+                if self.write_cache.syncs_writes() {
+                    self.disk.flush().map_err(Error::Disk)?;
+                }
+                Ok(0)
+            }
+            RequestType::Flush if !self.write_cache.flushes() => Ok(0),
+            RequestType::Flush => self.disk.flush().map(|_| 0).map_err(Error::Disk),
+            // The zones are only reset through zone management requests.
+            RequestType::Discard | RequestType::WriteZeroes if self.zoned.is_some() => {
+                Err(Error::Unsupported(request.request_type()))
+            }
+            RequestType::Discard | RequestType::WriteZeroes => {
+                let capacity = self.capacity.load(Ordering::Acquire);
+                discard_write_zeroes(self.disk.as_mut(), capacity, mem, request).map(|_| 0)
+            }
+            RequestType::GetDeviceID => Ok(write_device_id(mem, request, &self.device_id)?),
+            request_type => Err(Error::Unsupported(request_type)),
+        }
+    }
+
+    /// Get the disk offset of the request data, checking if it fits within the disk.
+    fn data_offset(&self, request: &Request) -> result::Result<u64, Error> {
+        disk_range(
+            self.capacity.load(Ordering::Acquire),
+            request.sector(),
+            request.total_data_len() as u64,
+        )
     }
 
     /// Process the queue.
@@ -51,6 +330,11 @@ where
     ///
     /// * `()` - Ok if the queue was processed successfully.
     pub fn process_queue(&mut self) -> result::Result<(), Error> {
+        // The throttled requests are resumed by the rate limiter.
+        if self.rate_limiter.as_ref().is_some_and(|l| l.is_blocked()) {
+            return Ok(());
+        }
+
         // To see why this is done in a loop, please look at the `Queue::enable_notification`
         // comments in `virtio_queue`.
         loop {
@@ -59,7 +343,11 @@ where
 
             // Process the queue.
             while let Some(chain) = self.queue.iter(&self.mem.clone())?.next() {
-                self.process_chain(chain)?;
+                if !self.process_chain(chain)? {
+                    // Leave the throttled request in the queue until the rate limiter fires.
+                    self.queue.go_to_previous_position();
+                    return Ok(());
+                }
             }
 
             // Enable the notifications.
@@ -70,13 +358,165 @@ where
 
         Ok(())
     }
+
+    /// Resume the requests throttled by the rate limiter, once its timer fired.
+    ///
+    /// # Returns
+    ///
+    /// * `()` - Ok if the queue was processed successfully.
+    pub fn process_rate_limiter(&mut self) -> result::Result<(), Error> {
+        if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+            rate_limiter.event_handler().map_err(Error::RateLimiter)?;
+        }
+
+        self.process_queue()
+    }
+}
+
+/// Consume the rate limiter tokens of a request.
+///
+/// # Arguments
+///
+/// * `rate_limiter` - The rate limiter (if any).
+/// * `request` - The request.
+///
+/// # Returns
+///
+/// An `io::Result` containing whether the request may be served.
+pub(crate) fn rate_limit(
+    rate_limiter: Option<&mut RateLimiter>,
+    request: &Request,
+) -> io::Result<bool> {
+    let Some(rate_limiter) = rate_limiter else {
+        return Ok(true);
+    };
+
+    // Only the data transfers count towards the bandwidth.
+    let bytes = match request.request_type() {
+        RequestType::In | RequestType::Out => request.total_data_len() as u64,
+        _ => 0,
+    };
+
+    rate_limiter.consume(bytes)
+}
+
+/// Get the disk offset of a sector range, checking if it fits within the disk.
+///
+/// # Arguments
+///
+/// * `capacity` - The disk capacity (in sectors).
+/// * `sector` - The first sector of the range.
+/// * `len` - The range length (in bytes).
+///
+/// # Returns
+///
+/// A `Result` containing the disk offset of the range.
+fn disk_range(capacity: u64, sector: u64, len: u64) -> result::Result<u64, Error> {
+    let size = capacity << SECTOR_SHIFT;
+
+    sector
+        .checked_mul(1 << SECTOR_SHIFT)
+        .filter(|offset| offset.checked_add(len).is_some_and(|end| end <= size))
+        .ok_or_else(|| Error::Disk(io::Error::from(io::ErrorKind::InvalidInput)))
+}
+
+/// Execute a discard or write zeroes request.
+///
+/// # Arguments
+///
+/// * `disk` - The disk image.
+/// * `capacity` - The disk capacity (in sectors).
+/// * `mem` - The guest memory.
+/// * `request` - The request, whose data holds the segments to discard or zero.
+///
+/// # Returns
+///
+/// A `Result` containing the result of the operation.
+pub(crate) fn discard_write_zeroes(
+    disk: &mut dyn DiskImage,
+    capacity: u64,
+    mem: &GuestMemoryMmap,
+    request: &Request,
+) -> result::Result<(), Error> {
+    let request_type = request.request_type();
+
+    // Gather the segments.
+    let mut segments = Vec::new();
+    for (addr, len) in request.data() {
+        let mut buf = vec![0u8; *len as usize];
+        mem.read_slice(&mut buf, *addr)?;
+        segments.extend_from_slice(&buf);
+    }
+    if segments.len() % SEGMENT_SIZE != 0
+        || segments.len() / SEGMENT_SIZE > MAX_DISCARD_SEG as usize
+    {
+        return Err(Error::Disk(io::Error::from(io::ErrorKind::InvalidInput)));
+    }
+
+    for segment in segments.chunks_exact(SEGMENT_SIZE) {
+        let sector = u64::from_le_bytes(segment[0..8].try_into().unwrap());
+        let num_sectors = u32::from_le_bytes(segment[8..12].try_into().unwrap());
+        let flags = u32::from_le_bytes(segment[12..16].try_into().unwrap());
+
+        // The unmap flag is only defined for write zeroes requests.
+        let unmap = flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+        if flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0
+            || (matches!(request_type, RequestType::Discard) && unmap)
+        {
+            return Err(Error::Unsupported(request_type));
+        }
+        if num_sectors > MAX_DISCARD_SECTORS {
+            return Err(Error::Disk(io::Error::from(io::ErrorKind::InvalidInput)));
+        }
+
+        let len = (num_sectors as u64) << SECTOR_SHIFT;
+        let offset = disk_range(capacity, sector, len)?;
+        match request_type {
+            RequestType::Discard => disk.discard(offset, len),
+            _ => disk.write_zeroes(offset, len, unmap),
+        }
+        .map_err(Error::Disk)?;
+    }
+
+    Ok(())
+}
+
+/// Execute a get ID request, writing the device ID to the request data.
+///
+/// # Arguments
+///
+/// * `mem` - The guest memory.
+/// * `request` - The request.
+/// * `device_id` - The device ID.
+///
+/// # Returns
+///
+/// A `Result` containing the number of bytes written to the guest memory (the device ID is
+/// truncated to the data length).
+pub(crate) fn write_device_id(
+    mem: &GuestMemoryMmap,
+    request: &Request,
+    device_id: &DeviceId,
+) -> result::Result<u32, GuestMemoryError> {
+    let mut written = 0;
+
+    for (addr, len) in request.data() {
+        let chunk = (*len as usize).min(device_id.len() - written);
+        mem.write_slice(&device_id[written..written + chunk], *addr)?;
+        written += chunk;
+    }
+
+    Ok(written as u32)
 }
 
 #[derive(Debug)]
 pub enum Error {
     GuestMemory(vm_memory::GuestMemoryError),
     Queue(virtio_queue::Error),
-    ProcessRequest(stdio_executor::ProcessReqError),
+    Disk(io::Error),
+    Unsupported(RequestType),
+    RateLimiter(io::Error),
+    Zone(ZoneError),
 }
 
 impl From<vm_memory::GuestMemoryError> for Error {
@@ -90,9 +530,3 @@ impl From<virtio_queue::Error> for Error {
         Error::Queue(e)
     }
 }
-
-impl From<stdio_executor::ProcessReqError> for Error {
-    fn from(e: stdio_executor::ProcessReqError) -> Self {
-        Error::ProcessRequest(e)
-    }
-}
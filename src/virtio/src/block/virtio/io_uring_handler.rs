@@ -0,0 +1,509 @@
+use super::inorder_handler::{
+    self, discard_write_zeroes, rate_limit, write_device_id, DeviceId, WriteCache,
+};
+use super::metrics::{BlockMetrics, RequestTimer};
+use super::zoned::zone_status;
+use crate::block::disk::raw::RawImage;
+use crate::block::disk::SECTOR_SHIFT;
+use crate::device::SignalUsedQueue;
+use crate::rate_limiter::RateLimiter;
+use io_uring::{opcode, squeue, types, IoUring};
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use virtio_bindings::virtio_blk::{VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP};
+use virtio_blk::request::{Request, RequestType};
+use virtio_queue::{DescriptorChain, Queue, QueueOwnedT, QueueT};
+use vm_memory::bitmap::AtomicBitmap;
+use vm_memory::{Bytes, GuestAddress, GuestMemory};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+/// The I/O vectors of an in-flight request.
+///
+/// The vectors point to the guest memory, which stays mapped for the whole device lifetime, and
+/// are only handed to the kernel, so it is safe to move them between threads.
+struct IoVecs(Vec<libc::iovec>);
+
+unsafe impl Send for IoVecs {}
+
+/// A request submitted to the io_uring instance.
+///
+/// # Attributes
+///
+/// * `head_index` - The head descriptor index of the chain.
+/// * `status_addr` - The guest address of the request status.
+/// * `len` - The number of bytes expected to be transferred.
+/// * `used_len` - The number of bytes written to the guest memory on success.
+/// * `timer` - The metrics timer of the request.
+/// * `iovecs` - The I/O vectors of the request (kept alive until its completion).
+struct InFlightRequest {
+    head_index: u16,
+    status_addr: GuestAddress,
+    len: u32,
+    used_len: u32,
+    timer: RequestTimer,
+    _iovecs: IoVecs,
+}
+
+/// Block queue handler backed by io_uring.
+///
+/// The requests are submitted to the kernel as soon as they are popped from the queue, and the
+/// chains are returned to the driver as their completions come in, possibly out of order.
+///
+/// # Attributes
+///
+/// * `driver_notify` - The object used to signal the driver.
+/// * `mem` - The guest memory.
+/// * `queue` - The request queue.
+/// * `disk` - The disk image.
+/// * `device_id` - The device ID.
+/// * `rate_limiter` - The rate limiter (if any).
+/// * `write_cache` - The write cache state.
+/// * `capacity` - The disk capacity (in sectors), updated when the disk is resized.
+/// * `metrics` - The I/O statistics of the device.
+/// * `ring` - The io_uring instance.
+/// * `completion_evt` - The EventFd signalled by the kernel on every completion.
+/// * `in_flight` - The submitted requests, indexed by their io_uring user data.
+/// * `next_user_data` - The user data of the next submitted request.
+pub struct IoUringQueueHandler<S: SignalUsedQueue> {
+    pub driver_notify: S,
+    pub mem: GuestMemoryMmap,
+    pub queue: Queue,
+    pub disk: RawImage,
+    pub device_id: DeviceId,
+    pub rate_limiter: Option<RateLimiter>,
+    pub write_cache: WriteCache,
+    pub capacity: Arc<AtomicU64>,
+    pub metrics: Arc<BlockMetrics>,
+    ring: IoUring,
+    pub completion_evt: EventFd,
+    in_flight: HashMap<u64, InFlightRequest>,
+    next_user_data: u64,
+}
+
+/// Check if io_uring is available on the running kernel.
+///
+/// # Returns
+///
+/// An `io::Result` containing Ok(()) if io_uring is available.
+pub fn probe() -> io::Result<()> {
+    IoUring::new(1).map(|_| ())
+}
+
+impl<S> IoUringQueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    /// Create a new io_uring queue handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `driver_notify` - The object used to signal the driver.
+    /// * `mem` - The guest memory.
+    /// * `queue` - The request queue.
+    /// * `disk` - The disk image.
+    /// * `device_id` - The device ID.
+    /// * `rate_limiter` - The rate limiter (if any).
+    /// * `write_cache` - The write cache state.
+    /// * `capacity` - The disk capacity (in sectors).
+    /// * `metrics` - The I/O statistics of the device.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the new queue handler.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        driver_notify: S,
+        mem: GuestMemoryMmap,
+        queue: Queue,
+        disk: RawImage,
+        device_id: DeviceId,
+        rate_limiter: Option<RateLimiter>,
+        write_cache: WriteCache,
+        capacity: Arc<AtomicU64>,
+        metrics: Arc<BlockMetrics>,
+    ) -> result::Result<Self, Error> {
+        // A chain holds a single request, so the ring never holds more entries than the queue.
+        let ring = IoUring::new(queue.max_size() as u32).map_err(Error::IoUring)?;
+
+        // Get notified about the completions through an EventFd.
+        let completion_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::IoUring)?;
+        ring.submitter()
+            .register_eventfd(completion_evt.as_raw_fd())
+            .map_err(Error::IoUring)?;
+
+        Ok(IoUringQueueHandler {
+            driver_notify,
+            mem,
+            queue,
+            disk,
+            device_id,
+            rate_limiter,
+            write_cache,
+            capacity,
+            metrics,
+            ring,
+            completion_evt,
+            in_flight: HashMap::new(),
+            next_user_data: 0,
+        })
+    }
+
+    /// Translate the data descriptors of a request into I/O vectors.
+    fn iovecs(&self, request: &Request) -> result::Result<Vec<libc::iovec>, Error> {
+        request
+            .data()
+            .iter()
+            .map(|(addr, len)| {
+                let slice = self.mem.get_slice(*addr, *len as usize)?;
+                Ok(libc::iovec {
+                    iov_base: slice.ptr_guard_mut().as_ptr() as *mut libc::c_void,
+                    iov_len: *len as usize,
+                })
+            })
+            .collect()
+    }
+
+    /// Complete a chain, writing the request status and returning it to the driver.
+    fn complete(
+        &mut self,
+        timer: RequestTimer,
+        head_index: u16,
+        status_addr: GuestAddress,
+        status: u32,
+        used_len: u32,
+    ) -> result::Result<(), Error> {
+        timer.complete(status);
+
+        // Write the request status.
+        self.mem.write_obj(status as u8, status_addr)?;
+
+        // Add the used descriptor to the queue (the status byte is also written to the guest).
+        self.queue.add_used(&self.mem, head_index, used_len + 1)?;
+
+        Ok(())
+    }
+
+    /// Process a chain, unless throttled by the rate limiter.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing whether the chain was processed (or throttled by the rate limiter).
+    fn process_chain(
+        &mut self,
+        mut chain: DescriptorChain<&GuestMemoryMmap>,
+    ) -> result::Result<bool, Error> {
+        let head_index = chain.head_index();
+
+        let request = match Request::parse(&mut chain) {
+            Ok(request) => request,
+            Err(e) => {
+                // Without a parsed request there is no status to write.
+                println!("block request parse error: {:?}", e);
+                self.metrics.invalid_request();
+                self.queue.add_used(&self.mem, head_index, 0)?;
+                return Ok(true);
+            }
+        };
+
+        // Check if the rate limiter lets the request through.
+        if !rate_limit(self.rate_limiter.as_mut(), &request).map_err(Error::RateLimiter)? {
+            return Ok(false);
+        }
+
+        self.submit_request(head_index, &request)?;
+
+        Ok(true)
+    }
+
+    /// Submit a request to io_uring, or complete it right away if it cannot be served.
+    fn submit_request(&mut self, head_index: u16, request: &Request) -> result::Result<(), Error> {
+        let status_addr = request.status_addr();
+        let len = request.total_data_len();
+
+        // Only the data transfers count towards the bytes.
+        let bytes = match request.request_type() {
+            RequestType::In | RequestType::Out => len as u64,
+            _ => 0,
+        };
+        let timer = self.metrics.start(request.request_type().into(), bytes);
+
+        // Check if the request fits within the disk.
+        let capacity = self.capacity.load(Ordering::Acquire);
+        let offset = request.sector().wrapping_mul(1 << SECTOR_SHIFT);
+        let in_bounds = request
+            .sector()
+            .checked_mul(1 << SECTOR_SHIFT)
+            .and_then(|offset| offset.checked_add(len as u64))
+            .is_some_and(|end| end <= capacity << SECTOR_SHIFT);
+
+        // Translate the data descriptors of the transfers. A chain that cannot be served is still
+        // returned to the driver, with an I/O error.
+        let iovecs = match request.request_type() {
+            RequestType::In | RequestType::Out if in_bounds => match self.iovecs(request) {
+                Ok(iovecs) => iovecs,
+                Err(e) => {
+                    println!("block request error: {:?}", e);
+                    return self.complete(timer, head_index, status_addr, VIRTIO_BLK_S_IOERR, 0);
+                }
+            },
+            _ => Vec::new(),
+        };
+
+        let fd = types::Fd(self.disk.file().as_raw_fd());
+        let (entry, iovecs, used_len) = match request.request_type() {
+            RequestType::In | RequestType::Out if !in_bounds => {
+                return self.complete(timer, head_index, status_addr, VIRTIO_BLK_S_IOERR, 0);
+            }
+            RequestType::In => {
+                let entry = opcode::Readv::new(fd, iovecs.as_ptr(), iovecs.len() as u32)
+                    .offset(offset)
+                    .build();
+                (entry, iovecs, len)
+            }
+            RequestType::Out => {
+                // Without a write cache, the data must be durable once the request completes.
+                let rw_flags = if self.write_cache.syncs_writes() {
+                    libc::RWF_DSYNC
+                } else {
+                    0
+                };
+                let entry = opcode::Writev::new(fd, iovecs.as_ptr(), iovecs.len() as u32)
+                    .offset(offset)
+                    .rw_flags(rw_flags)
+                    .build();
+                (entry, iovecs, 0)
+            }
+            RequestType::Flush if !self.write_cache.flushes() => {
+                return self.complete(timer, head_index, status_addr, VIRTIO_BLK_S_OK, 0);
+            }
+            RequestType::Flush => {
+                let entry = opcode::Fsync::new(fd)
+                    .flags(types::FsyncFlags::DATASYNC)
+                    .build();
+                (entry, iovecs, 0)
+            }
+            RequestType::Discard | RequestType::WriteZeroes => {
+                // These only update the file metadata, so they are served right away.
+                let status =
+                    match discard_write_zeroes(&mut self.disk, capacity, &self.mem, request) {
+                        Ok(()) => VIRTIO_BLK_S_OK,
+                        Err(inorder_handler::Error::Unsupported(_)) => VIRTIO_BLK_S_UNSUPP,
+                        Err(inorder_handler::Error::Disk(e)) => {
+                            println!("block request error: {:?}", e);
+                            VIRTIO_BLK_S_IOERR
+                        }
+                        Err(inorder_handler::Error::GuestMemory(e)) => return Err(e.into()),
+                        Err(inorder_handler::Error::Queue(e)) => return Err(e.into()),
+                        Err(inorder_handler::Error::RateLimiter(e)) => {
+                            return Err(Error::RateLimiter(e))
+                        }
+                        Err(inorder_handler::Error::Zone(e)) => zone_status(e),
+                    };
+                return self.complete(timer, head_index, status_addr, status, 0);
+            }
+            RequestType::GetDeviceID => {
+                let used_len = write_device_id(&self.mem, request, &self.device_id)?;
+                return self.complete(timer, head_index, status_addr, VIRTIO_BLK_S_OK, used_len);
+            }
+            _ => {
+                return self.complete(timer, head_index, status_addr, VIRTIO_BLK_S_UNSUPP, 0);
+            }
+        };
+
+        // Submit the request.
+        let user_data = self.next_user_data;
+        self.next_user_data = self.next_user_data.wrapping_add(1);
+        if let Err(e) = self.submit(entry.user_data(user_data)) {
+            println!("block request error: {:?}", e);
+            return self.complete(timer, head_index, status_addr, VIRTIO_BLK_S_IOERR, 0);
+        }
+
+        self.in_flight.insert(
+            user_data,
+            InFlightRequest {
+                head_index,
+                status_addr,
+                len,
+                used_len,
+                timer,
+                _iovecs: IoVecs(iovecs),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Push an entry to the submission queue.
+    fn submit(&mut self, entry: squeue::Entry) -> result::Result<(), Error> {
+        // The submission queue is only full if the kernel did not consume the previous entries
+        // yet, so flush them and try again.
+        // SAFETY: the buffers referenced by the entry are kept alive until its completion.
+        if unsafe { self.ring.submission().push(&entry) }.is_err() {
+            self.ring.submit().map_err(Error::IoUring)?;
+            unsafe { self.ring.submission().push(&entry) }
+                .map_err(|_| Error::SubmissionQueueFull)?;
+        }
+
+        Ok(())
+    }
+
+    /// Process the queue, submitting every available request.
+    ///
+    /// # Returns
+    ///
+    /// * `()` - Ok if the queue was processed successfully.
+    pub fn process_queue(&mut self) -> result::Result<(), Error> {
+        // The throttled requests are resumed by the rate limiter.
+        if self.rate_limiter.as_ref().is_some_and(|l| l.is_blocked()) {
+            return Ok(());
+        }
+
+        // To see why this is done in a loop, please look at the `Queue::enable_notification`
+        // comments in `virtio_queue`.
+        'queue: loop {
+            // Disable the notifications.
+            self.queue.disable_notification(&self.mem)?;
+
+            // Process the queue.
+            while let Some(chain) = self.queue.iter(&self.mem.clone())?.next() {
+                if !self.process_chain(chain)? {
+                    // Leave the throttled request in the queue until the rate limiter fires.
+                    self.queue.go_to_previous_position();
+                    break 'queue;
+                }
+            }
+
+            // Enable the notifications.
+            if !self.queue.enable_notification(&self.mem)? {
+                break;
+            }
+        }
+
+        // Hand the requests over to the kernel.
+        self.ring.submit().map_err(Error::IoUring)?;
+
+        // Some requests may have been completed right away (e.g. unsupported ones).
+        self.signal_used_queue()
+    }
+
+    /// Resume the requests throttled by the rate limiter, once its timer fired.
+    ///
+    /// # Returns
+    ///
+    /// * `()` - Ok if the queue was processed successfully.
+    pub fn process_rate_limiter(&mut self) -> result::Result<(), Error> {
+        if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+            rate_limiter.event_handler().map_err(Error::RateLimiter)?;
+        }
+
+        self.process_queue()
+    }
+
+    /// Process the completed requests, returning their chains to the driver.
+    ///
+    /// # Returns
+    ///
+    /// * `()` - Ok if the completions were processed successfully.
+    pub fn process_completions(&mut self) -> result::Result<(), Error> {
+        // Collect the completions first, since the completion queue borrows the ring.
+        let completions = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect::<Vec<_>>();
+
+        // Complete every collected request before reporting the first error, so none of their
+        // chains is lost.
+        let mut completed = Ok(());
+        for (user_data, result) in completions {
+            let Some(request) = self.in_flight.remove(&user_data) else {
+                println!("unexpected io_uring completion {}", user_data);
+                continue;
+            };
+
+            // A short transfer is reported as an I/O error.
+            let (status, used_len) = if result >= 0 && result as u32 == request.len {
+                (VIRTIO_BLK_S_OK, request.used_len)
+            } else {
+                (VIRTIO_BLK_S_IOERR, 0)
+            };
+            completed = completed.and(self.complete(
+                request.timer,
+                request.head_index,
+                request.status_addr,
+                status,
+                used_len,
+            ));
+        }
+
+        // Signal the driver about the chains returned so far.
+        let signalled = self.signal_used_queue();
+        completed.and(signalled)
+    }
+
+    /// Signal the driver, if needed.
+    fn signal_used_queue(&mut self) -> result::Result<(), Error> {
+        if self.queue.needs_notification(&self.mem)? {
+            self.driver_notify.signal_used_queue(0);
+        }
+
+        Ok(())
+    }
+}
+
+impl<S> Drop for IoUringQueueHandler<S>
+where
+    S: SignalUsedQueue,
+{
+    fn drop(&mut self) {
+        // The kernel keeps accessing the guest buffers of the in-flight requests until they
+        // complete, so wait for them before releasing the ring. The handler is only dropped when
+        // the device is reset or torn down, so their chains are not returned to the driver.
+        while !self.in_flight.is_empty() {
+            if let Err(e) = self.ring.submit_and_wait(self.in_flight.len()) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                println!("failed to wait for the io_uring requests: {:?}", e);
+                break;
+            }
+
+            let completed = self
+                .ring
+                .completion()
+                .map(|cqe| cqe.user_data())
+                .collect::<Vec<_>>();
+            for user_data in completed {
+                // Dropping the timer takes the request out of the in-flight ones.
+                self.in_flight.remove(&user_data);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    GuestMemory(vm_memory::GuestMemoryError),
+    Queue(virtio_queue::Error),
+    IoUring(io::Error),
+    SubmissionQueueFull,
+    RateLimiter(io::Error),
+}
+
+impl From<vm_memory::GuestMemoryError> for Error {
+    fn from(e: vm_memory::GuestMemoryError) -> Self {
+        Error::GuestMemory(e)
+    }
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
@@ -0,0 +1,336 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use virtio_bindings::virtio_blk::VIRTIO_BLK_S_OK;
+use virtio_blk::request::RequestType;
+
+/// Upper bounds (in microseconds) of the latency histogram buckets. The requests slower than the
+/// last bound land in an extra overflow bucket.
+pub const LATENCY_BUCKETS_US: [u64; 16] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000,
+    500_000, 1_000_000,
+];
+
+// Number of latency histogram buckets (overflow bucket included).
+const NUM_BUCKETS: usize = LATENCY_BUCKETS_US.len() + 1;
+
+// Number of block operations tracked.
+const NUM_OPS: usize = 6;
+
+/// Block operation, as accounted by the metrics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockOp {
+    Read,
+    Write,
+    Flush,
+    Discard,
+    WriteZeroes,
+    Other,
+}
+
+impl From<RequestType> for BlockOp {
+    fn from(request_type: RequestType) -> Self {
+        match request_type {
+            RequestType::In => BlockOp::Read,
+            RequestType::Out => BlockOp::Write,
+            RequestType::Flush => BlockOp::Flush,
+            RequestType::Discard => BlockOp::Discard,
+            RequestType::WriteZeroes => BlockOp::WriteZeroes,
+            _ => BlockOp::Other,
+        }
+    }
+}
+
+/// Counters of a single block operation.
+#[derive(Default)]
+struct OpCounters {
+    requests: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    latency_buckets: [AtomicU64; NUM_BUCKETS],
+    latency_sum_us: AtomicU64,
+}
+
+impl OpCounters {
+    /// Take a snapshot of the counters.
+    fn snapshot(&self) -> OpMetrics {
+        let mut buckets = [0; NUM_BUCKETS];
+        for (bucket, counter) in buckets.iter_mut().zip(self.latency_buckets.iter()) {
+            *bucket = counter.load(Ordering::Relaxed);
+        }
+
+        OpMetrics {
+            requests: self.requests.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            latency: LatencyHistogram {
+                buckets,
+                sum_us: self.latency_sum_us.load(Ordering::Relaxed),
+            },
+        }
+    }
+}
+
+/// I/O statistics of a block device, shared by the device and its queue handlers.
+///
+/// The counters are cumulative over the device lifetime, so they survive the device resets.
+///
+/// # Attributes
+///
+/// * `ops` - The counters of every block operation, indexed by `BlockOp`.
+/// * `in_flight` - The number of requests being served.
+/// * `invalid_requests` - The number of chains that could not be parsed into a request.
+#[derive(Default)]
+pub struct BlockMetrics {
+    ops: [OpCounters; NUM_OPS],
+    in_flight: AtomicU64,
+    invalid_requests: AtomicU64,
+}
+
+impl BlockMetrics {
+    /// Start accounting a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - The block operation.
+    /// * `bytes` - The number of data bytes transferred by the request.
+    ///
+    /// # Returns
+    ///
+    /// The timer of the request, which records it once completed.
+    pub fn start(self: &Arc<Self>, op: BlockOp, bytes: u64) -> RequestTimer {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+
+        RequestTimer {
+            metrics: self.clone(),
+            op,
+            bytes,
+            start: Instant::now(),
+        }
+    }
+
+    /// Account a chain that could not be parsed into a request.
+    pub fn invalid_request(&self) {
+        self.invalid_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the metrics.
+    pub fn snapshot(&self) -> BlockMetricsSnapshot {
+        BlockMetricsSnapshot {
+            read: self.ops[BlockOp::Read as usize].snapshot(),
+            write: self.ops[BlockOp::Write as usize].snapshot(),
+            flush: self.ops[BlockOp::Flush as usize].snapshot(),
+            discard: self.ops[BlockOp::Discard as usize].snapshot(),
+            write_zeroes: self.ops[BlockOp::WriteZeroes as usize].snapshot(),
+            other: self.ops[BlockOp::Other as usize].snapshot(),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            invalid_requests: self.invalid_requests.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Timer of a request being served.
+///
+/// A timer dropped without being completed (e.g. the request was abandoned by a device reset)
+/// only leaves the in-flight requests.
+///
+/// # Attributes
+///
+/// * `metrics` - The metrics accounting the request.
+/// * `op` - The block operation.
+/// * `bytes` - The number of data bytes transferred by the request.
+/// * `start` - The instant the request started being served.
+#[must_use]
+pub struct RequestTimer {
+    metrics: Arc<BlockMetrics>,
+    op: BlockOp,
+    bytes: u64,
+    start: Instant,
+}
+
+impl RequestTimer {
+    /// Record the completion of the request.
+    ///
+    /// # Arguments
+    ///
+    /// * `status` - The virtio status of the request. The bytes are only accounted on success.
+    pub fn complete(self, status: u32) {
+        let counters = &self.metrics.ops[self.op as usize];
+        let latency_us = self.start.elapsed().as_micros().min(u64::MAX as u128) as u64;
+
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        if status == VIRTIO_BLK_S_OK {
+            counters.bytes.fetch_add(self.bytes, Ordering::Relaxed);
+        } else {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        counters.latency_buckets[latency_bucket(latency_us)].fetch_add(1, Ordering::Relaxed);
+        counters
+            .latency_sum_us
+            .fetch_add(latency_us, Ordering::Relaxed);
+    }
+}
+
+/// Get the latency histogram bucket of a request, the first one whose bound holds its latency.
+///
+/// # Arguments
+///
+/// * `latency_us` - The request latency (in microseconds).
+fn latency_bucket(latency_us: u64) -> usize {
+    LATENCY_BUCKETS_US.partition_point(|&bound| bound < latency_us)
+}
+
+impl Drop for RequestTimer {
+    fn drop(&mut self) {
+        self.metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Latency histogram of a block operation.
+///
+/// # Attributes
+///
+/// * `buckets` - The number of requests per bucket, bounded by `LATENCY_BUCKETS_US` (the last
+///   bucket holds the slower requests).
+/// * `sum_us` - The sum of the request latencies (in microseconds).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub buckets: [u64; NUM_BUCKETS],
+    pub sum_us: u64,
+}
+
+/// Statistics of a block operation.
+///
+/// # Attributes
+///
+/// * `requests` - The number of completed requests (failed ones included).
+/// * `bytes` - The number of data bytes transferred by the successful requests.
+/// * `errors` - The number of failed requests.
+/// * `latency` - The latency histogram of the completed requests.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpMetrics {
+    pub requests: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    pub latency: LatencyHistogram,
+}
+
+/// Snapshot of the I/O statistics of a block device.
+///
+/// # Attributes
+///
+/// * `read` - The statistics of the read requests.
+/// * `write` - The statistics of the write requests (zone appends included).
+/// * `flush` - The statistics of the flush requests.
+/// * `discard` - The statistics of the discard requests.
+/// * `write_zeroes` - The statistics of the write zeroes requests.
+/// * `other` - The statistics of the remaining requests (e.g. get ID or zone management).
+/// * `in_flight` - The number of requests being served.
+/// * `invalid_requests` - The number of chains that could not be parsed into a request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockMetricsSnapshot {
+    pub read: OpMetrics,
+    pub write: OpMetrics,
+    pub flush: OpMetrics,
+    pub discard: OpMetrics,
+    pub write_zeroes: OpMetrics,
+    pub other: OpMetrics,
+    pub in_flight: u64,
+    pub invalid_requests: u64,
+}
+
+impl BlockMetricsSnapshot {
+    /// Serialize the snapshot as a single line JSON object.
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"in_flight\":{},\"invalid_requests\":{},\"latency_bounds_us\":{:?}",
+            self.in_flight, self.invalid_requests, LATENCY_BUCKETS_US
+        );
+
+        for (name, op) in [
+            ("read", &self.read),
+            ("write", &self.write),
+            ("flush", &self.flush),
+            ("discard", &self.discard),
+            ("write_zeroes", &self.write_zeroes),
+            ("other", &self.other),
+        ] {
+            // Writing to a `String` never fails.
+            let _ = write!(
+                json,
+                ",\"{}\":{{\"requests\":{},\"bytes\":{},\"errors\":{},\
+                 \"latency_sum_us\":{},\"latency_buckets\":{:?}}}",
+                name, op.requests, op.bytes, op.errors, op.latency.sum_us, op.latency.buckets
+            );
+        }
+        json.push('}');
+
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtio_bindings::virtio_blk::VIRTIO_BLK_S_IOERR;
+
+    #[test]
+    fn test_block_metrics() {
+        let metrics = Arc::new(BlockMetrics::default());
+
+        // The requests are in flight until completed.
+        let read = metrics.start(BlockOp::from(RequestType::In), 4096);
+        let write = metrics.start(BlockOp::from(RequestType::Out), 512);
+        let flush = metrics.start(BlockOp::from(RequestType::Flush), 0);
+        assert_eq!(metrics.snapshot().in_flight, 3);
+
+        read.complete(VIRTIO_BLK_S_OK);
+        write.complete(VIRTIO_BLK_S_IOERR);
+        // An abandoned request is not accounted.
+        drop(flush);
+        metrics.invalid_request();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.in_flight, 0);
+        assert_eq!(snapshot.invalid_requests, 1);
+        assert_eq!(
+            (
+                snapshot.read.requests,
+                snapshot.read.bytes,
+                snapshot.read.errors
+            ),
+            (1, 4096, 0)
+        );
+        assert_eq!(
+            (
+                snapshot.write.requests,
+                snapshot.write.bytes,
+                snapshot.write.errors
+            ),
+            (1, 0, 1)
+        );
+        assert_eq!(snapshot.flush, OpMetrics::default());
+        assert_eq!(snapshot.read.latency.buckets.iter().sum::<u64>(), 1);
+        assert_eq!(snapshot.write.latency.buckets.iter().sum::<u64>(), 1);
+
+        // The JSON output is a single line, holding every operation.
+        let json = snapshot.to_json();
+        assert!(!json.contains('\n'));
+        assert!(json.starts_with("{\"in_flight\":0,\"invalid_requests\":1,"));
+        assert!(json.contains("\"read\":{\"requests\":1,\"bytes\":4096,\"errors\":0,"));
+        assert!(json.contains("\"write_zeroes\":{\"requests\":0,"));
+        assert!(json.ends_with("]}}"));
+    }
+
+    #[test]
+    fn test_latency_buckets() {
+        // The bounds are inclusive, and the slower requests land in the overflow bucket.
+        assert_eq!(latency_bucket(0), 0);
+        assert_eq!(latency_bucket(10), 0);
+        assert_eq!(latency_bucket(11), 1);
+        assert_eq!(latency_bucket(1_000_000), NUM_BUCKETS - 2);
+        assert_eq!(latency_bucket(1_000_001), NUM_BUCKETS - 1);
+    }
+}
@@ -1,3 +1,7 @@
+pub mod config_space;
 pub mod device;
 pub mod inorder_handler;
+pub mod io_uring_handler;
+pub mod metrics;
 pub mod queue_handler;
+pub mod zoned;
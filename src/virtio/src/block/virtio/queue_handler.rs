@@ -3,9 +3,12 @@ use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
 
 use crate::block::virtio::inorder_handler::InOrderQueueHandler;
-use crate::device::SingleFdSignalQueue;
+use crate::block::virtio::io_uring_handler::IoUringQueueHandler;
+use crate::device::{SignalUsedQueue, SingleFdSignalQueue};
 
 const IOEVENT_DATA: u32 = 0;
+const COMPLETION_DATA: u32 = 1;
+const RATE_LIMITER_DATA: u32 = 2;
 
 // This object simply combines the more generic `InOrderQueueHandler` with a concrete queue
 // signalling implementation based on `EventFd`s, and then also implements `MutEventSubscriber`
@@ -26,6 +29,11 @@ impl MutEventSubscriber for QueueHandler {
         // just to be sure.
         if events.event_set() != EventSet::IN {
             println!("unexpected event_set");
+        } else if events.data() == RATE_LIMITER_DATA {
+            match self.inner.process_rate_limiter() {
+                Ok(()) => error = false,
+                Err(e) => println!("error processing block queue {:?}", e),
+            }
         } else if events.data() != IOEVENT_DATA {
             println!("unexpected events data {}", events.data());
         } else if self.ioeventfd.read().is_err() {
@@ -36,18 +44,100 @@ impl MutEventSubscriber for QueueHandler {
             error = false;
         }
 
+        // The queue can no longer be serviced, so the driver must reset the device.
         if error {
-            ops.remove(events)
-                .expect("Failed to remove fd from event handling loop");
+            self.inner.driver_notify.signal_needs_reset();
+            if let Err(e) = ops.remove(events) {
+                println!("Failed to remove fd from event handling loop: {:?}", e);
+            }
         }
     }
 
     fn init(&mut self, ops: &mut EventOps) {
-        ops.add(Events::with_data(
+        let mut events = vec![Events::with_data(
             &self.ioeventfd,
             IOEVENT_DATA,
             EventSet::IN,
-        ))
-        .expect("Failed to init block queue handler");
+        )];
+        // The throttled requests are resumed by the rate limiter timer.
+        if let Some(rate_limiter) = self.inner.rate_limiter.as_ref() {
+            events.push(Events::with_data(
+                rate_limiter,
+                RATE_LIMITER_DATA,
+                EventSet::IN,
+            ));
+        }
+        for event in events {
+            if let Err(e) = ops.add(event) {
+                println!("Failed to init block queue handler: {:?}", e);
+                self.inner.driver_notify.signal_needs_reset();
+            }
+        }
+    }
+}
+
+// Same as `QueueHandler`, but for the `IoUringQueueHandler`, which is also driven by the
+// `EventFd` signalled by the kernel once the submitted requests complete.
+pub(crate) struct IoUringHandler {
+    pub inner: IoUringQueueHandler<SingleFdSignalQueue>,
+    pub ioeventfd: EventFd,
+}
+
+/// Implement the `MutEventSubscriber` trait for `IoUringHandler` to handle the dispatched
+/// events (Ioeventfds and io_uring completions) from the event manager.
+impl MutEventSubscriber for IoUringHandler {
+    fn process(&mut self, events: Events, ops: &mut EventOps) {
+        let result = if events.event_set() != EventSet::IN {
+            Err(format!("unexpected event_set {:?}", events.event_set()))
+        } else {
+            match events.data() {
+                IOEVENT_DATA => match self.ioeventfd.read() {
+                    Ok(_) => self.inner.process_queue().map_err(|e| format!("{:?}", e)),
+                    Err(e) => Err(format!("ioeventfd read error {:?}", e)),
+                },
+                COMPLETION_DATA => match self.inner.completion_evt.read() {
+                    Ok(_) => self
+                        .inner
+                        .process_completions()
+                        .map_err(|e| format!("{:?}", e)),
+                    Err(e) => Err(format!("completion eventfd read error {:?}", e)),
+                },
+                RATE_LIMITER_DATA => self
+                    .inner
+                    .process_rate_limiter()
+                    .map_err(|e| format!("{:?}", e)),
+                data => Err(format!("unexpected events data {}", data)),
+            }
+        };
+
+        // The queue can no longer be serviced, so the driver must reset the device.
+        if let Err(e) = result {
+            println!("error processing block queue {}", e);
+            self.inner.driver_notify.signal_needs_reset();
+            if let Err(e) = ops.remove(events) {
+                println!("Failed to remove fd from event handling loop: {:?}", e);
+            }
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        let mut events = vec![
+            Events::with_data(&self.ioeventfd, IOEVENT_DATA, EventSet::IN),
+            Events::with_data(&self.inner.completion_evt, COMPLETION_DATA, EventSet::IN),
+        ];
+        // The throttled requests are resumed by the rate limiter timer.
+        if let Some(rate_limiter) = self.inner.rate_limiter.as_ref() {
+            events.push(Events::with_data(
+                rate_limiter,
+                RATE_LIMITER_DATA,
+                EventSet::IN,
+            ));
+        }
+        for event in events {
+            if let Err(e) = ops.add(event) {
+                println!("Failed to init block queue handler: {:?}", e);
+                self.inner.driver_notify.signal_needs_reset();
+            }
+        }
     }
 }
@@ -0,0 +1,338 @@
+use super::config_space::MAX_SEGMENT_SIZE;
+use super::inorder_handler::{Error, WriteCache};
+use crate::block::disk::zoned::{ZoneError, ZoneOp, ZonedImage};
+use crate::block::disk::{DiskImage, SECTOR_SHIFT};
+use std::result;
+use std::sync::Mutex;
+use virtio_bindings::virtio_blk::{VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK};
+use virtio_queue::DescriptorChain;
+use vm_memory::bitmap::AtomicBitmap;
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError};
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+// Zoned block device definitions (see `linux/virtio_blk.h`).
+pub const VIRTIO_BLK_F_ZONED: u32 = 17;
+pub const VIRTIO_BLK_Z_HM: u8 = 1;
+pub const VIRTIO_BLK_T_ZONE_APPEND: u32 = 15;
+pub const VIRTIO_BLK_T_ZONE_REPORT: u32 = 16;
+pub const VIRTIO_BLK_T_ZONE_OPEN: u32 = 18;
+pub const VIRTIO_BLK_T_ZONE_CLOSE: u32 = 20;
+pub const VIRTIO_BLK_T_ZONE_FINISH: u32 = 22;
+pub const VIRTIO_BLK_T_ZONE_RESET: u32 = 24;
+pub const VIRTIO_BLK_T_ZONE_RESET_ALL: u32 = 26;
+pub const VIRTIO_BLK_S_ZONE_INVALID_CMD: u32 = 3;
+pub const VIRTIO_BLK_S_ZONE_UNALIGNED_WP: u32 = 4;
+pub const VIRTIO_BLK_S_ZONE_OPEN_RESOURCE: u32 = 5;
+pub const VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE: u32 = 6;
+
+/// Default zone size of the emulated zones (in bytes).
+pub const DEFAULT_ZONE_SIZE: u64 = 256 << 20;
+
+// Size of the zone report header, and of each zone descriptor (`struct virtio_blk_zone_report`
+// and `struct virtio_blk_zone_descriptor`).
+const ZONE_REPORT_ENTRY_SIZE: usize = 64;
+
+// Size of the request header (`struct virtio_blk_outhdr`).
+const REQUEST_HEADER_SIZE: u32 = 16;
+
+// Size of the in-header of the zone append requests (`append_sector` and `status`).
+const APPEND_IN_HEADER_SIZE: u32 = 9;
+
+/// Zone request.
+///
+/// The zone requests are not parsed by `virtio_blk`, as the zone management ones carry no data,
+/// and the zone append ones return the written sector along with their status.
+///
+/// # Attributes
+///
+/// * `request_type` - The request type (`VIRTIO_BLK_T_ZONE_*`).
+/// * `sector` - The request sector.
+/// * `data` - The address and length of each data buffer.
+/// * `in_header` - The address of the in-header, holding the status (preceded by the written
+///   sector for zone append requests).
+pub struct ZoneRequest {
+    pub request_type: u32,
+    pub sector: u64,
+    pub data: Vec<(GuestAddress, u32)>,
+    pub in_header: GuestAddress,
+}
+
+impl ZoneRequest {
+    /// Check if a chain holds a zone request.
+    ///
+    /// # Arguments
+    ///
+    /// * `chain` - The descriptor chain.
+    pub fn is_zone_request(chain: &DescriptorChain<&GuestMemoryMmap>) -> bool {
+        chain
+            .clone()
+            .next()
+            .and_then(|head| chain.memory().read_obj::<u32>(head.addr()).ok())
+            .is_some_and(|request_type| {
+                matches!(
+                    request_type,
+                    VIRTIO_BLK_T_ZONE_APPEND
+                        | VIRTIO_BLK_T_ZONE_REPORT
+                        | VIRTIO_BLK_T_ZONE_OPEN
+                        | VIRTIO_BLK_T_ZONE_CLOSE
+                        | VIRTIO_BLK_T_ZONE_FINISH
+                        | VIRTIO_BLK_T_ZONE_RESET
+                        | VIRTIO_BLK_T_ZONE_RESET_ALL
+                )
+            })
+    }
+
+    /// Parse a zone request.
+    ///
+    /// # Arguments
+    ///
+    /// * `chain` - The descriptor chain.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the zone request.
+    pub fn parse(
+        chain: &mut DescriptorChain<&GuestMemoryMmap>,
+    ) -> result::Result<Self, ParseError> {
+        let mut descriptors: Vec<_> = chain.by_ref().collect();
+        let mem = chain.memory();
+
+        // The header is read by the device.
+        if descriptors.len() < 2 {
+            return Err(ParseError::ChainTooShort);
+        }
+        let header = descriptors.remove(0);
+        if header.is_write_only() || header.len() < REQUEST_HEADER_SIZE {
+            return Err(ParseError::InvalidDescriptor);
+        }
+        let request_type = mem.read_obj::<u32>(header.addr())?;
+        let sector = mem.read_obj::<u64>(
+            header
+                .addr()
+                .checked_add(8)
+                .ok_or(ParseError::InvalidDescriptor)?,
+        )?;
+
+        // The in-header is written by the device.
+        let in_header = descriptors.pop().unwrap();
+        let in_header_size = match request_type {
+            VIRTIO_BLK_T_ZONE_APPEND => APPEND_IN_HEADER_SIZE,
+            _ => 1,
+        };
+        if !in_header.is_write_only() || in_header.len() < in_header_size {
+            return Err(ParseError::InvalidDescriptor);
+        }
+
+        // The data is written by the driver for zone appends, and by the device for zone reports.
+        let data_valid = match request_type {
+            VIRTIO_BLK_T_ZONE_APPEND => descriptors.iter().all(|desc| !desc.is_write_only()),
+            VIRTIO_BLK_T_ZONE_REPORT => descriptors.iter().all(|desc| desc.is_write_only()),
+            _ => descriptors.is_empty(),
+        };
+        if !data_valid {
+            return Err(ParseError::InvalidDescriptor);
+        }
+
+        Ok(ZoneRequest {
+            request_type,
+            sector,
+            data: descriptors
+                .iter()
+                .map(|desc| (desc.addr(), desc.len()))
+                .collect(),
+            in_header: in_header.addr(),
+        })
+    }
+
+    /// Get the total length of the data buffers (in bytes).
+    pub fn data_len(&self) -> u64 {
+        self.data.iter().map(|(_, len)| *len as u64).sum()
+    }
+}
+
+/// Process a zone request, writing its status to the guest memory.
+///
+/// # Arguments
+///
+/// * `zoned` - The zoned disk image.
+/// * `disk` - The disk image.
+/// * `write_cache` - The write cache state.
+/// * `mem` - The guest memory.
+/// * `request` - The zone request.
+///
+/// # Returns
+///
+/// A `Result` containing the request status and the number of bytes written to the guest memory
+/// (in-header included).
+pub(crate) fn process_zone_request(
+    zoned: &Mutex<ZonedImage>,
+    disk: &mut dyn DiskImage,
+    write_cache: &WriteCache,
+    mem: &GuestMemoryMmap,
+    request: &ZoneRequest,
+) -> result::Result<(u32, u32), Error> {
+    let mut zoned = zoned.lock().unwrap();
+
+    let result = match request.request_type {
+        VIRTIO_BLK_T_ZONE_REPORT => report_zones(&zoned, mem, request),
+        VIRTIO_BLK_T_ZONE_APPEND => append(&mut zoned, disk, write_cache, mem, request),
+        VIRTIO_BLK_T_ZONE_RESET_ALL => zoned.reset_all(disk).map(|_| 0).map_err(Error::Zone),
+        request_type => {
+            let op = match request_type {
+                VIRTIO_BLK_T_ZONE_OPEN => ZoneOp::Open,
+                VIRTIO_BLK_T_ZONE_CLOSE => ZoneOp::Close,
+                VIRTIO_BLK_T_ZONE_FINISH => ZoneOp::Finish,
+                _ => ZoneOp::Reset,
+            };
+            zoned
+                .manage(disk, op, request.sector)
+                .map(|_| 0)
+                .map_err(Error::Zone)
+        }
+    };
+    let (status, len) = match result {
+        Ok(len) => (VIRTIO_BLK_S_OK, len),
+        Err(Error::Zone(e)) => (zone_status(e), 0),
+        Err(e) => return Err(e),
+    };
+
+    // Write the request status, which follows the written sector of zone appends.
+    let (status_addr, in_header_size) = match request.request_type {
+        VIRTIO_BLK_T_ZONE_APPEND => (request.in_header.unchecked_add(8), APPEND_IN_HEADER_SIZE),
+        _ => (request.in_header, 1),
+    };
+    mem.write_obj(status as u8, status_addr)?;
+
+    Ok((status, len + in_header_size))
+}
+
+/// Get the status of a failed zone request.
+///
+/// # Arguments
+///
+/// * `error` - The zone error.
+pub(crate) fn zone_status(error: ZoneError) -> u32 {
+    match error {
+        ZoneError::InvalidCommand => VIRTIO_BLK_S_ZONE_INVALID_CMD,
+        ZoneError::UnalignedWritePointer => VIRTIO_BLK_S_ZONE_UNALIGNED_WP,
+        ZoneError::OpenResource => VIRTIO_BLK_S_ZONE_OPEN_RESOURCE,
+        ZoneError::ActiveResource => VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE,
+        ZoneError::Io(e) => {
+            println!("block zone request error: {:?}", e);
+            VIRTIO_BLK_S_IOERR
+        }
+    }
+}
+
+/// Execute a zone report request, writing the zones to the request data.
+///
+/// # Returns
+///
+/// A `Result` containing the number of data bytes written to the guest memory.
+fn report_zones(
+    zoned: &ZonedImage,
+    mem: &GuestMemoryMmap,
+    request: &ZoneRequest,
+) -> result::Result<u32, Error> {
+    // The report starts with its header, followed by as many zones as the data holds.
+    let entries = request.data_len() as usize / ZONE_REPORT_ENTRY_SIZE;
+    if entries == 0 {
+        return Err(Error::Zone(ZoneError::InvalidCommand));
+    }
+    let zones = zoned.zones_from(request.sector).map_err(Error::Zone)?;
+    let zones = &zones[..zones.len().min(entries - 1)];
+
+    let mut report = vec![0u8; (zones.len() + 1) * ZONE_REPORT_ENTRY_SIZE];
+    report[0..8].copy_from_slice(&(zones.len() as u64).to_le_bytes());
+    for (zone, entry) in zones
+        .iter()
+        .zip(report.chunks_exact_mut(ZONE_REPORT_ENTRY_SIZE).skip(1))
+    {
+        entry[0..8].copy_from_slice(&zone.capacity.to_le_bytes());
+        entry[8..16].copy_from_slice(&zone.start.to_le_bytes());
+        entry[16..24].copy_from_slice(&zone.wp.to_le_bytes());
+        entry[24] = zone.zone_type as u8;
+        entry[25] = zone.condition as u8;
+    }
+
+    // Scatter the report over the data buffers.
+    let mut written = 0;
+    for (addr, len) in request.data.iter() {
+        let chunk = (*len as usize).min(report.len() - written);
+        mem.write_slice(&report[written..written + chunk], *addr)?;
+        written += chunk;
+    }
+
+    Ok(written as u32)
+}
+
+/// Execute a zone append request, writing the first written sector to the in-header.
+///
+/// # Returns
+///
+/// A `Result` containing the number of data bytes written to the guest memory.
+fn append(
+    zoned: &mut ZonedImage,
+    disk: &mut dyn DiskImage,
+    write_cache: &WriteCache,
+    mem: &GuestMemoryMmap,
+    request: &ZoneRequest,
+) -> result::Result<u32, Error> {
+    let len = request.data_len();
+    let num_sectors = len >> SECTOR_SHIFT;
+    if len == 0 || len != num_sectors << SECTOR_SHIFT || num_sectors > max_append_sectors(zoned) {
+        return Err(Error::Zone(ZoneError::InvalidCommand));
+    }
+
+    // Gather the data.
+    let mut buf = Vec::with_capacity(len as usize);
+    for (addr, len) in request.data.iter() {
+        let mut chunk = vec![0u8; *len as usize];
+        mem.read_slice(&mut chunk, *addr)?;
+        buf.extend_from_slice(&chunk);
+    }
+
+    let sector = zoned
+        .write(request.sector, num_sectors, true, |sector| {
+            disk.write_at(&buf, sector << SECTOR_SHIFT)
+        })
+        .map_err(Error::Zone)?;
+
+    // Without a write cache, the data must be durable once the request completes.
+    if write_cache.syncs_writes() {
+        disk.flush().map_err(|e| Error::Zone(ZoneError::Io(e)))?;
+    }
+
+    mem.write_slice(&sector.to_le_bytes(), request.in_header)?;
+
+    Ok(0)
+}
+
+/// Get the maximum number of sectors of a zone append request.
+///
+/// # Arguments
+///
+/// * `zoned` - The zoned disk image.
+pub fn max_append_sectors(zoned: &ZonedImage) -> u64 {
+    zoned
+        .zone_sectors()
+        .min((MAX_SEGMENT_SIZE >> SECTOR_SHIFT) as u64)
+}
+
+/// Zone request parse errors.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The chain lacks the header or the in-header.
+    ChainTooShort,
+    /// A descriptor has an unexpected direction or length.
+    InvalidDescriptor,
+    /// The request header could not be read.
+    GuestMemory(GuestMemoryError),
+}
+
+impl From<GuestMemoryError> for ParseError {
+    fn from(e: GuestMemoryError) -> Self {
+        ParseError::GuestMemory(e)
+    }
+}
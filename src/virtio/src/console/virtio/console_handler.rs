@@ -136,3 +136,77 @@ impl From<ConsoleError> for Error {
         Error::ConsoleError(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::SingleFdSignalQueue;
+    use crate::test_utils::{VirtQueue, VirtioMmioDriver, DATA_ADDR, QUEUE_SIZE};
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicBool, AtomicU8};
+    use vm_memory::{Bytes, GuestAddress};
+    use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+    #[test]
+    fn test_console_queues() {
+        let driver = VirtioMmioDriver::new();
+        let mut input_queue = VirtQueue::new(INPUT_QUEUE_INDEX, QUEUE_SIZE);
+        let mut output_queue = VirtQueue::new(OUTPUT_QUEUE_INDEX, QUEUE_SIZE);
+
+        // The console output is forwarded to a socket, as done by the device.
+        let (socket_out, mut socket_in) = UnixStream::pair().unwrap();
+
+        let mut handler = ConsoleQueueHandler {
+            driver_notify: SingleFdSignalQueue {
+                irqfd: EventFd::new(EFD_NONBLOCK).unwrap(),
+                interrupt_status: Arc::new(AtomicU8::new(0)),
+                needs_reset: Arc::new(AtomicBool::new(false)),
+            },
+            mem: driver.mem.clone(),
+            input_queue: input_queue.to_queue(),
+            output_queue: output_queue.to_queue(),
+            console: Arc::new(Mutex::new(Console::new(socket_out))),
+        };
+
+        // Guest output.
+        let output = b"hello from the guest";
+        driver
+            .mem
+            .write_slice(output, GuestAddress(DATA_ADDR))
+            .unwrap();
+        let head = output_queue.add_chain(&driver.mem, &[(DATA_ADDR, output.len() as u32, false)]);
+        handler.process_output_queue().unwrap();
+        assert_eq!(output_queue.next_used(&driver.mem), Some((head, 0)));
+
+        let mut buf = vec![0u8; output.len()];
+        socket_in.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, output);
+
+        // Guest input.
+        let input = b"hello from the host";
+        let input_addr = DATA_ADDR + 0x1000;
+        let head = input_queue.add_chain(&driver.mem, &[(input_addr, 256, true)]);
+        handler
+            .console
+            .lock()
+            .unwrap()
+            .enqueue_data(&mut input.to_vec())
+            .unwrap();
+        handler.process_input_queue().unwrap();
+        assert_eq!(
+            input_queue.next_used(&driver.mem),
+            Some((head, input.len() as u32))
+        );
+
+        let mut buf = vec![0u8; input.len()];
+        driver
+            .mem
+            .read_slice(&mut buf, GuestAddress(input_addr))
+            .unwrap();
+        assert_eq!(buf, input);
+
+        // The driver got notified about the used buffers.
+        assert!(handler.driver_notify.irqfd.read().unwrap() > 0);
+    }
+}
@@ -1,9 +1,9 @@
 use super::console_handler::ConsoleQueueHandler;
 use super::pty_handler::PtyHandler;
 use super::queue_handler::QueueHandler;
-use crate::device::{SingleFdSignalQueue, Subscriber, VirtioDeviceT};
+use crate::device::{clone_queue, Subscriber, VirtioDeviceT};
 use crate::device::{VirtioDevType, VirtioDeviceCommon};
-use api::device_model::BaoDeviceModel;
+use api::device_model::DeviceModelT;
 use api::error::{Error, Result};
 use api::types::DeviceConfig;
 use event_manager::{
@@ -20,15 +20,20 @@ use vm_device::bus::MmioAddress;
 use vm_device::device_manager::{IoManager, MmioManager};
 use vm_device::MutDeviceMmio;
 
+// Configuration cols and rows are valid.
+const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
+
 /// Virtio console device.
 ///
 /// # Attributes
 ///
 /// * `common` - Virtio common device.
 /// * `endpoint` - The remote subscriber endpoint.
+/// * `sub_ids` - The IDs of the subscribers registered within the `EventManager`.
 pub struct VirtioConsole {
     pub common: VirtioDeviceCommon,
     pub endpoint: RemoteEndpoint<Subscriber>,
+    pub sub_ids: Vec<SubscriberId>,
     pub config: DeviceConfig,
 }
 
@@ -37,30 +42,38 @@ impl VirtioDeviceT for VirtioConsole {
         config: &DeviceConfig,
         device_manager: Arc<Mutex<IoManager>>,
         event_manager: Option<Arc<Mutex<EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>>>>,
-        device_model: Arc<Mutex<BaoDeviceModel>>,
+        device_model: Arc<dyn DeviceModelT>,
     ) -> Result<Arc<Mutex<Self>>> {
         // Extract the generic features and queues.
-        let (common_features, queues) = Self::initialize(&config).unwrap();
+        let (common_features, queues) = Self::initialize(&config)?;
 
         // Update the device features.
-        let device_features = common_features | Self::device_features(&config).unwrap();
+        let device_features = common_features | Self::device_features(&config)?;
 
         // Update the configuration space.
-        let config_space = Self::config_space(&config).unwrap();
+        let config_space = Self::config_space(&config)?;
 
         // Create a VirtioConfig object.
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
         // Create the generic device.
-        let common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg).unwrap();
+        let common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg)?;
+
+        // Extract the VirtioDeviceCommon MMIO range.
+        let range = common_device.mmio.range;
 
         // Create a remote endpoint object, that allows interacting with the VM EventManager from a different thread.
-        let remote_endpoint = event_manager.unwrap().lock().unwrap().remote_endpoint();
+        let remote_endpoint = event_manager
+            .ok_or(Error::EventManagerNotFound)?
+            .lock()
+            .unwrap()
+            .remote_endpoint();
 
         // Create the console device.
         let console = Arc::new(Mutex::new(VirtioConsole {
             common: common_device,
             endpoint: remote_endpoint,
+            sub_ids: Vec::new(),
             config: config.clone(),
         }));
 
@@ -68,18 +81,15 @@ impl VirtioDeviceT for VirtioConsole {
         device_manager
             .lock()
             .unwrap()
-            .register_mmio(
-                console.clone().lock().unwrap().common.mmio.range,
-                console.clone(),
-            )
-            .unwrap();
+            .register_mmio(range, console.clone())
+            .map_err(|_| Error::MmioConfig)?;
 
         // Return the console device.
         Ok(console)
     }
 
     fn device_features(_config: &DeviceConfig) -> Result<u64> {
-        Ok(1 << VIRTIO_F_IN_ORDER)
+        Ok((1 << VIRTIO_F_IN_ORDER) | (1 << VIRTIO_CONSOLE_F_SIZE))
     }
 
     fn config_space(_config: &DeviceConfig) -> Result<Vec<u8>> {
@@ -95,6 +105,26 @@ impl VirtioDeviceT for VirtioConsole {
     }
 }
 
+impl VirtioConsole {
+    /// Update the console size reported to the driver.
+    ///
+    /// # Arguments
+    ///
+    /// * `cols` - The number of columns.
+    /// * `rows` - The number of rows.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    pub fn resize(&mut self, cols: u16, rows: u16) -> Result<()> {
+        // The size is placed at the beginning of the configuration space (cols, then rows).
+        let mut size = Vec::with_capacity(4);
+        size.extend_from_slice(&cols.to_le_bytes());
+        size.extend_from_slice(&rows.to_le_bytes());
+        self.common.update_config_space(0, &size)
+    }
+}
+
 impl Borrow<VirtioConfig<Queue>> for VirtioConsole {
     fn borrow(&self) -> &VirtioConfig<Queue> {
         &self.common.config
@@ -119,27 +149,27 @@ impl VirtioDeviceActions for VirtioConsole {
 
     fn activate(&mut self) -> Result<()> {
         // Create socket to act as console output and forward it to pty
-        let (socket_out, socket_in) = UnixStream::pair().unwrap();
-        socket_in.set_nonblocking(true).unwrap();
+        let (socket_out, socket_in) =
+            UnixStream::pair().map_err(|e| Error::OpenFdFailed("console socket", e))?;
+        socket_in
+            .set_nonblocking(true)
+            .map_err(|e| Error::OpenFdFailed("console socket", e))?;
 
         // Create the backend.
         let console = Arc::new(Mutex::new(Console::new(socket_out)));
 
         // Create the driver notify object.
-        let driver_notify = SingleFdSignalQueue {
-            irqfd: self.common.irqfd.try_clone().unwrap(),
-            interrupt_status: self.common.config.interrupt_status.clone(),
-        };
+        let driver_notify = self.common.driver_notify()?;
 
         // Prepare the activation by calling the generic `prepare_activate` method.
-        let mut ioevents = self.common.prepare_activate().unwrap();
+        let mut ioevents = self.common.prepare_activate()?;
 
         // Create the inner handler.
         let inner = ConsoleQueueHandler {
             driver_notify,
-            mem: self.common.mem(),
-            input_queue: self.common.config.queues.remove(0),
-            output_queue: self.common.config.queues.remove(0),
+            mem: self.common.mem()?,
+            input_queue: clone_queue(&self.common.config.queues[0]),
+            output_queue: clone_queue(&self.common.config.queues[1]),
             console: Arc::clone(&console),
         };
 
@@ -148,19 +178,21 @@ impl VirtioDeviceActions for VirtioConsole {
         let output_ioeventfd = ioevents.remove(0);
         let handler = Arc::new(Mutex::new(QueueHandler {
             inner,
-            input_ioeventfd: input_ioeventfd.try_clone().unwrap(),
+            input_ioeventfd: input_ioeventfd
+                .try_clone()
+                .map_err(Error::EventFdCreateFailed)?,
             output_ioeventfd,
         }));
 
-        // Register the queue handler with the `EventManager`. We could record the `sub_id`
-        // (and/or keep a handler clone) for further interaction (i.e. to remove the subscriber at
-        // a later time, retrieve state, etc).
-        let _sub_id = self
+        // Register the queue handler with the `EventManager`. We record the `sub_id` to remove
+        // the subscriber when the device is reset.
+        let sub_id = self
             .endpoint
             .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
                 Ok(mgr.add_subscriber(handler))
             })
-            .unwrap();
+            .map_err(Error::EventManager)?;
+        self.sub_ids.push(sub_id);
 
         // Create pty handler and register it as a event subscriber
         let pty_handler = Arc::new(Mutex::new(PtyHandler::new(
@@ -168,13 +200,15 @@ impl VirtioDeviceActions for VirtioConsole {
             Arc::clone(&console),
             input_ioeventfd,
             &self.config,
-        )));
+        )?));
 
-        self.endpoint
+        let sub_id = self
+            .endpoint
             .call_blocking(|mgr| -> EvmgrResult<SubscriberId> {
                 Ok(mgr.add_subscriber(pty_handler))
             })
-            .unwrap();
+            .map_err(Error::EventManager)?;
+        self.sub_ids.push(sub_id);
 
         // Set the device as activated.
         self.common.config.device_activated = true;
@@ -183,8 +217,17 @@ impl VirtioDeviceActions for VirtioConsole {
     }
 
     fn reset(&mut self) -> Result<()> {
-        // Not implemented for now.
-        Ok(())
+        // Remove the queue handlers from the `EventManager`.
+        for sub_id in self.sub_ids.drain(..) {
+            self.endpoint
+                .call_blocking(move |mgr| -> EvmgrResult<()> {
+                    mgr.remove_subscriber(sub_id).map(|_| ())
+                })
+                .map_err(Error::EventManager)?;
+        }
+
+        // Reset the generic device.
+        self.common.reset()
     }
 }
 
@@ -199,10 +242,12 @@ impl VirtioMmioDevice for VirtioConsole {
 /// Otherwise we could not register the device within the device manager.
 impl MutDeviceMmio for VirtioConsole {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.common.update_device_status();
         self.read(offset, data);
     }
 
     fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
         self.write(offset, data);
+        self.common.update_device_status();
     }
 }
@@ -5,6 +5,7 @@ use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
+use api::error::{Error, Result};
 use api::types::DeviceConfig;
 use event_manager::{EventOps, Events, MutEventSubscriber};
 use libc::IN_NONBLOCK;
@@ -36,38 +37,44 @@ where
         console: Arc<Mutex<Console<W>>>,
         input_ioeventfd: EventFd,
         config: &DeviceConfig,
-    ) -> Self {
+    ) -> Result<Self> {
         let pty = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .custom_flags(IN_NONBLOCK)
             .open("/dev/ptmx")
-            .unwrap();
+            .map_err(Error::PtyOpenFailed)?;
 
         let pty_name = unsafe {
-            libc::grantpt(pty.as_raw_fd());
-            libc::unlockpt(pty.as_raw_fd());
-            std::ffi::CStr::from_ptr(libc::ptsname(pty.as_raw_fd()))
+            if libc::grantpt(pty.as_raw_fd()) < 0 || libc::unlockpt(pty.as_raw_fd()) < 0 {
+                return Err(Error::PtyOpenFailed(std::io::Error::last_os_error()));
+            }
+            let name = libc::ptsname(pty.as_raw_fd());
+            if name.is_null() {
+                return Err(Error::PtyOpenFailed(std::io::Error::last_os_error()));
+            }
+            std::ffi::CStr::from_ptr(name)
         };
+        let pty_name = pty_name.to_str().map_err(Error::InvalidString)?;
 
         let pty_path = if let Some(pty_alias) = config.pty_alias.clone() {
-            std::os::unix::fs::symlink(pty_name.to_str().unwrap(), pty_alias.as_str())
-                .expect(&format!("Failed to create pty handler alias {}", pty_alias));
+            std::os::unix::fs::symlink(pty_name, pty_alias.as_str())
+                .map_err(Error::PtyOpenFailed)?;
             pty_alias
         } else {
-            String::from(pty_name.to_str().unwrap())
+            String::from(pty_name)
         };
 
         println!("virtio-console device id {} at {}", config.id, pty_path);
 
-        Self {
+        Ok(Self {
             pty,
             pty_path,
             socket,
             console,
             input_ioeventfd,
-        }
+        })
     }
 
     /// Check if the PTY is currently open by any process (e.g., picocom / minicom)
@@ -116,19 +123,21 @@ where
     W: Write + WriteVolatile,
 {
     fn init(&mut self, ops: &mut EventOps) {
-        ops.add(Events::with_data(
+        if let Err(e) = ops.add(Events::with_data(
             &self.pty,
             SOURCE_PTY,
             EventSet::IN | EventSet::EDGE_TRIGGERED,
-        ))
-        .expect("Failed to init pty event");
+        )) {
+            log::error!("Failed to init pty event: {:?}", e);
+        }
 
-        ops.add(Events::with_data(
+        if let Err(e) = ops.add(Events::with_data(
             &self.socket,
             SOURCE_SOCKET,
             EventSet::IN | EventSet::EDGE_TRIGGERED,
-        ))
-        .expect("Failed to init socket event");
+        )) {
+            log::error!("Failed to init socket event: {:?}", e);
+        }
     }
 
     fn process(&mut self, events: Events, ops: &mut EventOps) {
@@ -142,16 +151,21 @@ where
                     // (As the `self.pty.write(&v).unwrap();` line within the `SOURCE_SOCKET` event is always executed upon receiving data from the frontend console
                     // that needs to be written to the backend console, this event should be triggered regardless of the backend console being opened or not.)
                     // In such cases, we must not enqueue the frontend guest console data (output queue) back to the frontend console (receive queue).
-                    if self.is_opened().unwrap().eq(&true) {
-                        self.console.lock().unwrap().enqueue_data(&mut v).unwrap();
-                        self.input_ioeventfd.write(1).unwrap();
+                    if self.is_opened().unwrap_or(false) {
+                        if let Err(e) = self.console.lock().unwrap().enqueue_data(&mut v) {
+                            log::error!("Failed to enqueue the console input: {:?}", e);
+                        } else if let Err(e) = self.input_ioeventfd.write(1) {
+                            log::error!("Failed to kick the input queue: {:?}", e);
+                        }
                     }
                 }
             }
             SOURCE_SOCKET => {
                 while let Ok(n) = self.socket.read(&mut buf) {
                     let v: Vec<_> = buf[..n].iter().cloned().collect();
-                    self.pty.write(&v).unwrap();
+                    if let Err(e) = self.pty.write_all(&v) {
+                        log::error!("Failed to write the console output: {:?}", e);
+                    }
                 }
             }
             _ => {
@@ -159,7 +173,9 @@ where
                     "PtyHandler unexpected event data: {}. Removing event...",
                     events.data()
                 );
-                ops.remove(events).expect("Failed to remove event");
+                if let Err(e) = ops.remove(events) {
+                    log::error!("Failed to remove event: {:?}", e);
+                }
             }
         }
     }
@@ -6,7 +6,7 @@ use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
 
 use crate::console::virtio::console_handler::ConsoleQueueHandler;
-use crate::device::SingleFdSignalQueue;
+use crate::device::{SignalUsedQueue, SingleFdSignalQueue};
 
 pub const INPUT_QUEUE_INDEX: u16 = 0;
 pub const OUTPUT_QUEUE_INDEX: u16 = 1;
@@ -30,12 +30,16 @@ where
 {
     // Helper method that receives an error message to be logged and the `ops` handle
     // which is used to unregister all events.
+    // The queues can no longer be serviced, so the driver is also told to reset the device.
     fn handle_error<S: AsRef<str>>(&self, s: S, ops: &mut EventOps) {
         error!("{}", s.as_ref());
-        ops.remove(Events::empty(&self.input_ioeventfd))
-            .expect("Failed to remove input ioeventfd");
-        ops.remove(Events::empty(&self.output_ioeventfd))
-            .expect("Failed to remove output ioeventfd");
+        self.inner.driver_notify.signal_needs_reset();
+        if let Err(e) = ops.remove(Events::empty(&self.input_ioeventfd)) {
+            error!("Failed to remove input ioeventfd: {:?}", e);
+        }
+        if let Err(e) = ops.remove(Events::empty(&self.output_ioeventfd)) {
+            error!("Failed to remove output ioeventfd: {:?}", e);
+        }
     }
 }
 
@@ -72,18 +76,21 @@ where
     }
 
     fn init(&mut self, ops: &mut EventOps) {
-        ops.add(Events::with_data(
+        if let Err(e) = ops.add(Events::with_data(
             &self.input_ioeventfd,
             INPUT_IOEVENT_DATA,
             EventSet::IN,
-        ))
-        .expect("Failed to init input queue handler");
+        )) {
+            self.handle_error(format!("Failed to init input queue handler {:?}", e), ops);
+            return;
+        }
 
-        ops.add(Events::with_data(
+        if let Err(e) = ops.add(Events::with_data(
             &self.output_ioeventfd,
             OUTPUT_IOEVENT_DATA,
             EventSet::IN,
-        ))
-        .expect("Failed to init output queue handler");
+        )) {
+            self.handle_error(format!("Failed to init output queue handler {:?}", e), ops);
+        }
     }
 }
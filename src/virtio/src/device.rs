@@ -2,14 +2,14 @@ use super::block::virtio::device::VirtioBlock;
 use super::console::virtio::device::VirtioConsole;
 use super::fs::vhost_user::device::VhostUserFs;
 use super::mmio::MmioConfig;
-use super::mmio::VIRTIO_MMIO_INT_VRING;
 use super::mmio::VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET;
+use super::mmio::{VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING};
 use super::net::vhost::device::VhostNet;
 use super::net::virtio::device::VirtioNet;
 use super::vsock::vhost::device::VhostVsockDevice;
 use super::vsock::vhost_user::device::VhostUserVsock;
-use api::defines::BAO_IOEVENTFD_FLAG_DATAMATCH;
-use api::device_model::BaoDeviceModel;
+use api::defines::{BAO_IOEVENTFD_FLAG_DATAMATCH, BAO_IOEVENTFD_FLAG_DEASSIGN};
+use api::device_model::DeviceModelT;
 use api::error::{Error, Result};
 use api::types::DeviceConfig;
 use event_manager::{EventManager, MutEventSubscriber};
@@ -18,20 +18,33 @@ use std::fmt::{self, Debug};
 use std::fs::File;
 use std::os::fd::AsRawFd;
 use std::os::unix::io::{FromRawFd, RawFd};
-use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
 use vhost_user_frontend::{GuestMemoryMmap, GuestRegionMmap};
 use virtio_device::VirtioConfig;
 use virtio_queue::{Queue, QueueT};
 use vm_device::device_manager::IoManager;
-use vm_memory::{guest_memory::FileOffset, GuestAddress, MmapRegion};
+use vm_memory::{guest_memory::FileOffset, Address, Bytes, GuestAddress, MmapRegion};
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
-use virtio_bindings::virtio_config::{VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1};
+use virtio_bindings::virtio_config::{
+    VIRTIO_CONFIG_S_DRIVER_OK, VIRTIO_CONFIG_S_NEEDS_RESET, VIRTIO_F_IOMMU_PLATFORM,
+    VIRTIO_F_RING_PACKED, VIRTIO_F_VERSION_1,
+};
 
 /// Type alias for the subscriber.
 pub type Subscriber = Arc<Mutex<dyn MutEventSubscriber + Send>>;
 
+/// Maximum size of a split virtqueue.
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+
+/// Features never offered to the driver, even if a vhost-user backend supports them.
+///
+/// The queues are always set up as split virtqueues, since `virtio_queue` does not implement the
+/// packed layout (`VIRTIO_F_RING_PACKED`), and the vring state handed to the backends is read
+/// from them.
+pub const UNSUPPORTED_FEATURES: u64 = 1 << VIRTIO_F_RING_PACKED;
+
 // Clippy thinks that values of the enum are too different in size.
 #[allow(clippy::large_enum_variant)]
 /// Virtio device type abstraction to pack all possible devices into one enum.
@@ -55,12 +68,21 @@ pub enum VirtioDeviceType {
 /// * `irqfd` - The interrupt file descriptor.
 /// * `device_model` - The device model.
 /// * `regions` - The memory regions of the device.
+/// * `memory` - The guest memory built from the memory regions.
+/// * `ioeventfds` - The ioeventfds registered during the device activation.
+/// * `needs_reset` - Whether the device experienced an unrecoverable error.
+/// * `backend_used_idx` - The used ring index of each queue served by a vhost or vhost-user
+///   backend, as of the last interrupt status read.
 pub struct VirtioDeviceCommon {
     pub config: VirtioConfig<Queue>,
     pub mmio: MmioConfig,
     pub irqfd: EventFd,
-    pub device_model: Arc<Mutex<BaoDeviceModel>>,
+    pub device_model: Arc<dyn DeviceModelT>,
     pub regions: Vec<GuestRegionMmap>,
+    pub memory: Option<GuestMemoryMmap>,
+    pub ioeventfds: Vec<EventFd>,
+    pub needs_reset: Arc<AtomicBool>,
+    backend_used_idx: Mutex<Vec<u16>>,
 }
 
 impl VirtioDeviceCommon {
@@ -77,25 +99,43 @@ impl VirtioDeviceCommon {
     /// A `Result` containing the new device.
     pub fn new(
         config: &DeviceConfig,
-        device_model: Arc<Mutex<BaoDeviceModel>>,
+        device_model: Arc<dyn DeviceModelT>,
         virtio: VirtioConfig<Queue>,
     ) -> Result<Self> {
+        // Extract the device model fields.
+        let dm = device_model.info();
+
+        // Each device may use its own shared memory slice (defaults to the device model one),
+        // while the interrupt is shared by all the devices of the frontend VM, since the irqfd
+        // is always bound to the device model interrupt.
+        let shmem_addr = config.shmem_addr.unwrap_or(dm.shmem_addr);
+        let shmem_size = config.shmem_size.unwrap_or(dm.shmem_size);
+        if let Some(irq) = config.irq.filter(|&irq| irq != dm.irq) {
+            return Err(Error::InvalidConfigField("irq", irq.to_string()));
+        }
+
         // Create the MMIO configuration.
-        let mmio = MmioConfig::new(
-            config.mmio_addr,
-            0x200,
-            device_model.lock().unwrap().clone().irq,
-        )
-        .unwrap();
+        let mmio =
+            MmioConfig::new(config.mmio_addr, 0x200, dm.irq).map_err(|_| Error::MmioConfig)?;
 
         // Create a new EventFd for the interrupt (irqfd).
-        let irqfd = EventFd::new(0).unwrap();
+        let irqfd = EventFd::new(0).map_err(Error::EventFdCreateFailed)?;
 
-        // Extract the device model fields.
-        let file =
-            unsafe { File::from_raw_fd(device_model.lock().unwrap().clone().devmodel_fd as RawFd) };
-        let shemem_addr = device_model.lock().unwrap().clone().shmem_addr;
-        let shemem_size = device_model.lock().unwrap().clone().shmem_size;
+        // Duplicate the device model file descriptor, since the file takes ownership of it and
+        // the same frontend device model may be shared by several devices.
+        let fd = unsafe { libc::dup(dm.fd as RawFd) };
+        if fd < 0 {
+            return Err(Error::OpenFdFailed(
+                "devmodel_fd",
+                std::io::Error::last_os_error(),
+            ));
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        // The slice must start within the device model shared memory.
+        let mmap_offset = shmem_addr
+            .checked_sub(dm.shmem_addr)
+            .ok_or(Error::InvalidShmemSlice(config.mmio_addr))?;
 
         // Create the device object.
         let mut device = VirtioDeviceCommon {
@@ -104,22 +144,19 @@ impl VirtioDeviceCommon {
             irqfd: irqfd,
             device_model,
             regions: Vec::new(),
+            memory: None,
+            ioeventfds: Vec::new(),
+            needs_reset: Arc::new(AtomicBool::new(false)),
+            backend_used_idx: Mutex::new(Vec::new()),
         };
 
         // Map the region.
-        // The mmap_offset is set to 0 because the base address of Bao's shared memory driver is
+        // The mmap_offset is relative to the base address of Bao's shared memory driver, which is
         // already defined statically in the backend device tree.
-        device
-            .map_region(0, file, shemem_addr, shemem_size as usize)
-            .unwrap();
+        device.map_region(mmap_offset, file, shmem_addr, shmem_size as usize)?;
 
         // Register the Irqfd (Host to Guest notification).
-        device
-            .device_model
-            .lock()
-            .unwrap()
-            .register_irqfd(&device.irqfd)
-            .unwrap();
+        device.device_model.register_irqfd(&device.irqfd)?;
 
         // Return the device object.
         Ok(device)
@@ -131,7 +168,7 @@ impl VirtioDeviceCommon {
     /// # Returns
     ///
     /// A `Result` containing the event file descriptors.
-    pub fn prepare_activate(&self) -> Result<Vec<EventFd>> {
+    pub fn prepare_activate(&mut self) -> Result<Vec<EventFd>> {
         // Check if the device has already been activated.
         if self.config.device_activated {
             return Err(Error::DeviceAlreadyActivated);
@@ -142,6 +179,11 @@ impl VirtioDeviceCommon {
             return Err(Error::DeviceBadFeatures(self.config.driver_features));
         }
 
+        // The queues can only be served with the split layout.
+        if self.config.driver_features & (1 << VIRTIO_F_RING_PACKED) != 0 {
+            return Err(Error::PackedRingNotSupported);
+        }
+
         // Create an empty vector to store all event file descriptors.
         let mut ioevents = Vec::new();
 
@@ -150,21 +192,21 @@ impl VirtioDeviceCommon {
         // additional queues on top of the defaults).
         for (i, _queue) in self.config.queues.iter().enumerate() {
             // Create a new EventFd for the queue (Ioeventfd -> Guest to Host notification).
-            let fd = EventFd::new(EFD_NONBLOCK).unwrap();
+            let fd = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreateFailed)?;
 
             // Register the queue event fd.
-            self.device_model
-                .lock()
-                .unwrap()
-                .register_ioeventfd(
-                    fd.as_raw_fd() as u32,
-                    BAO_IOEVENTFD_FLAG_DATAMATCH,
-                    self.mmio.range.base().0 + VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET,
-                    // The maximum number of queues should fit within an `u16` according to the
-                    // standard, so the conversion below is always expected to succeed.
-                    i as u64,
-                )
-                .unwrap();
+            self.device_model.register_ioeventfd(
+                fd.as_raw_fd() as u32,
+                BAO_IOEVENTFD_FLAG_DATAMATCH,
+                self.mmio.range.base().0 + VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET,
+                // The maximum number of queues should fit within an `u16` according to the
+                // standard, so the conversion below is always expected to succeed.
+                i as u64,
+            )?;
+
+            // Keep a copy of the event fd to deassign it on reset.
+            self.ioeventfds
+                .push(fd.try_clone().map_err(Error::EventFdCreateFailed)?);
 
             ioevents.push(fd);
         }
@@ -172,6 +214,45 @@ impl VirtioDeviceCommon {
         Ok(ioevents)
     }
 
+    /// Perform common steps for device reset, restoring the device to its initial state
+    /// so that the driver can bring it up again.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    pub fn reset(&mut self) -> Result<()> {
+        // Deassign the ioeventfds registered during the activation.
+        for (i, fd) in self.ioeventfds.drain(..).enumerate() {
+            self.device_model.register_ioeventfd(
+                fd.as_raw_fd() as u32,
+                BAO_IOEVENTFD_FLAG_DATAMATCH | BAO_IOEVENTFD_FLAG_DEASSIGN,
+                self.mmio.range.base().0 + VIRTIO_MMIO_QUEUE_NOTIFY_OFFSET,
+                i as u64,
+            )?;
+        }
+
+        // Restore the queues to their initial state.
+        for queue in self.config.queues.iter_mut() {
+            queue.reset();
+        }
+
+        // Clear the negotiated features and the selectors.
+        self.config.driver_features = 0;
+        self.config.device_features_select = 0;
+        self.config.driver_features_select = 0;
+        self.config.queue_select = 0;
+
+        // Clear any pending interrupt and error condition.
+        self.config.interrupt_status.store(0, Ordering::SeqCst);
+        self.needs_reset.store(false, Ordering::SeqCst);
+        self.backend_used_idx.lock().unwrap().clear();
+
+        // Set the device as not activated.
+        self.config.device_activated = false;
+
+        Ok(())
+    }
+
     /// Method to map a region.
     ///
     /// # Arguments
@@ -194,7 +275,7 @@ impl VirtioDeviceCommon {
         // Create a mmap region with proper permissions.
         let mmap_region = match MmapRegion::build(
             Some(FileOffset::new(file, mmap_offset)),
-            base_addr as usize + size as usize,
+            size,
             PROT_READ | PROT_WRITE,
             MAP_SHARED,
         ) {
@@ -225,13 +306,239 @@ impl VirtioDeviceCommon {
     ///
     /// # Returns
     ///
-    /// * `GuestMemoryMmap` - Guest memory mmap.
-    pub fn mem(&mut self) -> GuestMemoryMmap {
-        // Create a new GuestMemoryMmap from the regions without removing them.
-        GuestMemoryMmap::from_regions(self.regions.drain(..).collect()).unwrap()
+    /// * `Result<GuestMemoryMmap>` - A Result containing the guest memory mmap.
+    pub fn mem(&mut self) -> Result<GuestMemoryMmap> {
+        // Create the GuestMemoryMmap from the regions the first time, since the regions are
+        // moved into it, and hand out clones of it afterwards (e.g. after a device reset).
+        if self.memory.is_none() {
+            let memory = GuestMemoryMmap::from_regions(self.regions.drain(..).collect())
+                .map_err(|_| Error::MmapGuestMemoryFailed)?;
+            self.memory = Some(memory);
+        }
+
+        Ok(self.memory.clone().unwrap())
+    }
+
+    /// Create the object used by the queue handlers to signal the driver.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the driver notify object.
+    pub fn driver_notify(&self) -> Result<SingleFdSignalQueue> {
+        Ok(SingleFdSignalQueue {
+            irqfd: self.irqfd.try_clone().map_err(Error::EventFdCreateFailed)?,
+            interrupt_status: self.config.interrupt_status.clone(),
+            needs_reset: self.needs_reset.clone(),
+        })
+    }
+
+    /// Flag the device as needing a reset and notify the driver about it through a
+    /// configuration change interrupt.
+    pub fn set_needs_reset(&self) {
+        if !self.needs_reset.swap(true, Ordering::SeqCst) {
+            signal_config_interrupt(&self.irqfd, &self.config.interrupt_status);
+        }
+    }
+
+    /// Reflect any unrecoverable error in the device status, setting the
+    /// `DEVICE_NEEDS_RESET` bit.
+    ///
+    /// # Note
+    ///
+    /// This method must be called around every driver access to the device registers, since the
+    /// errors may be raised asynchronously (e.g. by the queue handlers). A device that failed
+    /// to activate after the driver set the `DRIVER_OK` bit also needs to be reset.
+    pub fn update_device_status(&mut self) {
+        let status = self.config.device_status as u32;
+        if status & VIRTIO_CONFIG_S_DRIVER_OK != 0 && !self.config.device_activated {
+            self.set_needs_reset();
+        }
+
+        if self.needs_reset.load(Ordering::SeqCst) {
+            self.config.device_status |= VIRTIO_CONFIG_S_NEEDS_RESET as u8;
+        }
+    }
+
+    /// Update a slice of the device configuration space and notify the driver about it.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset within the configuration space.
+    /// * `data` - The new contents of the slice.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    pub fn update_config_space(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        // Check if the slice fits within the configuration space.
+        let end = offset
+            .checked_add(data.len())
+            .filter(|end| *end <= self.config.config_space.len())
+            .ok_or(Error::InvalidConfigSpaceAccess(offset, data.len()))?;
+
+        self.config.config_space[offset..end].copy_from_slice(data);
+        self.signal_config_change();
+
+        Ok(())
+    }
+
+    /// Notify the driver about a change of the device configuration space.
+    ///
+    /// # Note
+    ///
+    /// The configuration generation is always bumped, so the driver detects the change even if
+    /// it races with a multi-field read. The interrupt is only raised once the driver is up,
+    /// since it reads the whole configuration space during the initialization anyway.
+    pub fn signal_config_change(&mut self) {
+        self.config.config_generation = self.config.config_generation.wrapping_add(1);
+
+        if self.config.device_status as u32 & VIRTIO_CONFIG_S_DRIVER_OK != 0 {
+            signal_config_interrupt(&self.irqfd, &self.config.interrupt_status);
+        }
+    }
+
+    /// Report the interrupt status of a device served by a vhost or vhost-user backend.
+    ///
+    /// # Returns
+    ///
+    /// The interrupt status.
+    ///
+    /// # Note
+    ///
+    /// The backend sends the used buffer notifications straight through the irqfd, bypassing
+    /// the VMM, and the interrupt is shared by all the devices of the frontend VM. The `VRING` bit
+    /// is therefore only reported once the backend moved the used index of one of the queues
+    /// forward, so the other devices raising the interrupt do not show up as this one. The
+    /// configuration changes go through the VMM, which sets the `CONFIG` bit on its own.
+    pub fn backend_interrupt_status(&self) -> &Arc<AtomicU8> {
+        if let Some(memory) = self.memory.as_ref() {
+            let mut last_used_idx = self.backend_used_idx.lock().unwrap();
+            last_used_idx.resize(self.config.queues.len(), 0);
+
+            for (queue, last) in self.config.queues.iter().zip(last_used_idx.iter_mut()) {
+                if !queue.ready() {
+                    continue;
+                }
+
+                // The used index follows the flags of the used ring.
+                let used_idx = memory.load::<u16>(
+                    GuestAddress(queue.used_ring()).unchecked_add(2),
+                    Ordering::Acquire,
+                );
+                if let Ok(used_idx) = used_idx {
+                    if used_idx != *last {
+                        *last = used_idx;
+                        self.config
+                            .interrupt_status
+                            .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+                    }
+                }
+            }
+        }
+
+        &self.config.interrupt_status
+    }
+}
+
+impl Drop for VirtioDeviceCommon {
+    fn drop(&mut self) {
+        // Deassign the irqfd, so a device failing to build (or torn down) does not leave it bound
+        // to the device model interrupt. The irqfd is not registered yet if the construction
+        // failed early, so the errors are ignored.
+        let _ = self.device_model.unregister_irqfd(&self.irqfd);
     }
 }
 
+/// Raise a configuration change interrupt.
+///
+/// # Arguments
+///
+/// * `irqfd` - The EventFd to be used for signalling.
+/// * `interrupt_status` - The interrupt status to be updated.
+pub(crate) fn signal_config_interrupt(irqfd: &EventFd, interrupt_status: &AtomicU8) {
+    interrupt_status.fetch_or(VIRTIO_MMIO_INT_CONFIG, Ordering::SeqCst);
+    if let Err(e) = irqfd.write(1) {
+        println!("Failed to signal the configuration change: {:?}", e);
+    }
+}
+
+/// Resolve and check the number of queues and the queue size of a device.
+///
+/// # Arguments
+///
+/// * `config` - The device configuration.
+/// * `device_type` - The device type.
+///
+/// # Returns
+///
+/// A `Result` containing the number of queues and the queue size.
+pub(crate) fn queue_config(
+    config: &DeviceConfig,
+    device_type: VirtioDevType,
+) -> Result<(usize, u16)> {
+    let (default_num, default_size) = device_type.queue_num_and_size();
+    let queue_num = config.num_queues.unwrap_or(default_num);
+    let queue_size = config.queue_size.unwrap_or(default_size as u16);
+
+    // Check the number of queues against the ones the device type is able to serve.
+    let (min_num, max_num) = device_type.queue_num_range();
+    if queue_num < min_num || queue_num > max_num {
+        return Err(Error::InvalidConfigField(
+            "num_queues",
+            format!("{} (expected {} to {})", queue_num, min_num, max_num),
+        ));
+    }
+
+    // The split virtqueue size must be a power of 2, up to 32768.
+    if !queue_size.is_power_of_two() || queue_size > MAX_QUEUE_SIZE {
+        return Err(Error::InvalidConfigField(
+            "queue_size",
+            format!(
+                "{} (expected a power of 2 up to {})",
+                queue_size, MAX_QUEUE_SIZE
+            ),
+        ));
+    }
+
+    // The rings are placed by the driver within the device shared memory, which must also
+    // leave room for the buffers.
+    if let Some(shmem_size) = config.shmem_size {
+        let rings_size = queue_num as u64 * split_ring_size(queue_size);
+        if rings_size >= shmem_size {
+            return Err(Error::InvalidConfigField(
+                "queue_size",
+                format!(
+                    "{} queues of {} entries need {:#x} bytes out of {:#x} bytes of shared memory",
+                    queue_num, queue_size, rings_size, shmem_size
+                ),
+            ));
+        }
+    }
+
+    Ok((queue_num, queue_size))
+}
+
+/// Compute the guest memory taken by a split virtqueue.
+///
+/// # Arguments
+///
+/// * `queue_size` - The queue size.
+///
+/// # Returns
+///
+/// The size (in bytes) of the descriptor table, the available ring and the used ring.
+fn split_ring_size(queue_size: u16) -> u64 {
+    let queue_size = queue_size as u64;
+
+    // Each part has its own alignment requirement (16, 2 and 4 bytes respectively), which is
+    // accounted for by padding every part to 16 bytes.
+    let desc_table = 16 * queue_size;
+    let avail_ring = (6 + 2 * queue_size + 15) & !15;
+    let used_ring = (6 + 8 * queue_size + 15) & !15;
+
+    desc_table + avail_ring + used_ring
+}
+
 /// Trait to model the common virtio device operations.
 /// Each virtio device type should implement this trait.
 pub trait VirtioDeviceT {
@@ -248,20 +555,20 @@ pub trait VirtioDeviceT {
         // Extract the device type.
         let device_type = VirtioDevType::from(config.device_type.as_str());
 
-        // Extract the number of queues and queue size for the device type.
-        let (queue_num, queue_size) = device_type.queue_num_and_size();
+        // Extract the number of queues and queue size (defaults to the device type ones).
+        let (queue_num, queue_size) = queue_config(config, device_type)?;
 
         // Create the queues.
         let mut queues = Vec::with_capacity(queue_num);
         for _ in 0..queue_num {
-            queues.push(Queue::new(queue_size as u16));
+            queues.push(Queue::new(queue_size));
         }
 
         // Convert the vector of Result<Queue, virtio_queue::Error> to a vector of Queue.
         let queues_converted: Vec<Queue> = queues
             .into_iter()
             .collect::<std::result::Result<Vec<_>, _>>()
-            .unwrap();
+            .map_err(|_| Error::QueueCreateFailed)?;
 
         // Define the generic device features.
         let device_features = 1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_F_IOMMU_PLATFORM;
@@ -285,7 +592,7 @@ pub trait VirtioDeviceT {
         config: &DeviceConfig,
         device_manager: Arc<Mutex<IoManager>>,
         event_manager: Option<Arc<Mutex<EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>>>>,
-        device_model: Arc<Mutex<BaoDeviceModel>>,
+        device_model: Arc<dyn DeviceModelT>,
     ) -> Result<Arc<Mutex<Self>>>;
 
     /// Returns the specific device features.
@@ -316,6 +623,10 @@ pub trait VirtioDeviceT {
 pub trait SignalUsedQueue {
     /// Signals the driver about used events for the specified queue.
     fn signal_used_queue(&self, index: u16);
+
+    /// Signals the driver that the device experienced an unrecoverable error and needs
+    /// to be reset.
+    fn signal_needs_reset(&self);
 }
 
 /// Uses a single irqfd as the basis of signalling any queue (useful for the MMIO transport,
@@ -325,9 +636,11 @@ pub trait SignalUsedQueue {
 ///
 /// * `irqfd` - The EventFd to be used for signalling.
 /// * `interrupt_status` - The interrupt status to be used for signalling.
+/// * `needs_reset` - The device error flag, reflected in the device status.
 pub struct SingleFdSignalQueue {
     pub irqfd: EventFd,
     pub interrupt_status: Arc<AtomicU8>,
+    pub needs_reset: Arc<AtomicBool>,
 }
 
 impl SignalUsedQueue for SingleFdSignalQueue {
@@ -338,9 +651,16 @@ impl SignalUsedQueue for SingleFdSignalQueue {
             .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
 
         // Write to the eventfd to signal the queue.
-        self.irqfd
-            .write(1)
-            .expect("Failed write to eventfd when signalling queue");
+        if let Err(e) = self.irqfd.write(1) {
+            println!("Failed to signal the queue: {:?}", e);
+        }
+    }
+
+    /// Signals the driver that the device needs to be reset.
+    fn signal_needs_reset(&self) {
+        if !self.needs_reset.swap(true, Ordering::SeqCst) {
+            signal_config_interrupt(&self.irqfd, &self.interrupt_status);
+        }
     }
 }
 
@@ -494,6 +814,22 @@ impl VirtioDevType {
             _ => (0, 0),
         }
     }
+
+    /// Returns the minimum and maximum number of queues the device type can be served with.
+    pub fn queue_num_range(&self) -> (usize, usize) {
+        match *self {
+            // The file system device has a high priority queue followed by the request queues,
+            // whose number is up to the vhost-user backend.
+            VirtioDevType::Fs => (2, u16::MAX as usize),
+            // The block device serves any number of request queues (`VIRTIO_BLK_F_MQ`).
+            VirtioDevType::Block => (1, u16::MAX as usize),
+            // The remaining devices have a fixed set of queues.
+            _ => {
+                let (queue_num, _) = self.queue_num_and_size();
+                (queue_num, queue_num)
+            }
+        }
+    }
 }
 
 /// Virtio data plane types.
@@ -576,3 +912,162 @@ pub fn clone_queue(queue: &Queue) -> Queue {
 
     q
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{device_config, MMIO_IRQ, SHMEM_ADDR, SHMEM_SIZE};
+    use api::mock::MockDeviceModel;
+    use vm_memory::{GuestMemory, GuestMemoryRegion};
+
+    #[test]
+    fn test_shmem_slice() {
+        let dm = Arc::new(MockDeviceModel::new(0, SHMEM_ADDR, SHMEM_SIZE, MMIO_IRQ).unwrap());
+        let slice_addr = SHMEM_ADDR + SHMEM_SIZE / 2;
+        let slice_size = SHMEM_SIZE / 4;
+
+        // The device maps exactly its slice, at the matching offset of the shared memory.
+        let mut config = device_config("block");
+        config.shmem_addr = Some(slice_addr);
+        config.shmem_size = Some(slice_size);
+        let virtio = VirtioConfig::new(0, Vec::new(), Vec::new());
+        let mut common = VirtioDeviceCommon::new(&config, dm.clone(), virtio).unwrap();
+        let mem = common.mem().unwrap();
+        assert_eq!(mem.num_regions(), 1);
+        assert_eq!(mem.iter().next().unwrap().len(), slice_size);
+        assert_eq!(
+            mem.iter().next().unwrap().start_addr(),
+            GuestAddress(slice_addr)
+        );
+
+        dm.write_guest(slice_addr + 0x10, &[1, 2, 3, 4]).unwrap();
+        let mut buf = [0u8; 4];
+        mem.read_slice(&mut buf, GuestAddress(slice_addr + 0x10))
+            .unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert!(mem
+            .read_slice(&mut buf, GuestAddress(slice_addr + slice_size))
+            .is_err());
+
+        // The slice cannot start before the shared memory.
+        config.shmem_addr = Some(SHMEM_ADDR - 0x1000);
+        let virtio = VirtioConfig::new(0, Vec::new(), Vec::new());
+        assert!(matches!(
+            VirtioDeviceCommon::new(&config, dm, virtio),
+            Err(Error::InvalidShmemSlice(_))
+        ));
+    }
+
+    #[test]
+    fn test_irqfd_deassigned_on_drop() {
+        let dm = Arc::new(MockDeviceModel::new(0, SHMEM_ADDR, SHMEM_SIZE, MMIO_IRQ).unwrap());
+
+        // The irqfd stays bound for the lifetime of the device.
+        let virtio = VirtioConfig::new(0, Vec::new(), Vec::new());
+        let common = VirtioDeviceCommon::new(&device_config("block"), dm.clone(), virtio).unwrap();
+        assert_eq!(dm.num_irqfds(), 1);
+
+        drop(common);
+        assert_eq!(dm.num_irqfds(), 0);
+    }
+
+    #[test]
+    fn test_shared_irq() {
+        let dm = Arc::new(MockDeviceModel::new(0, SHMEM_ADDR, SHMEM_SIZE, MMIO_IRQ).unwrap());
+
+        // The devices may only state the frontend interrupt.
+        let mut config = device_config("block");
+        config.irq = Some(MMIO_IRQ);
+        let virtio = VirtioConfig::new(0, Vec::new(), Vec::new());
+        assert!(VirtioDeviceCommon::new(&config, dm.clone(), virtio).is_ok());
+
+        config.irq = Some(MMIO_IRQ + 1);
+        let virtio = VirtioConfig::new(0, Vec::new(), Vec::new());
+        assert!(matches!(
+            VirtioDeviceCommon::new(&config, dm, virtio),
+            Err(Error::InvalidConfigField("irq", _))
+        ));
+    }
+
+    #[test]
+    fn test_backend_interrupt_status() {
+        let dm = Arc::new(MockDeviceModel::new(0, SHMEM_ADDR, SHMEM_SIZE, MMIO_IRQ).unwrap());
+        let used_ring = SHMEM_ADDR + 0x1000;
+
+        let mut queue = Queue::new(16).unwrap();
+        queue
+            .try_set_used_ring_address(GuestAddress(used_ring))
+            .unwrap();
+        queue.set_ready(true);
+        let virtio = VirtioConfig::new(0, vec![queue], Vec::new());
+        let mut common =
+            VirtioDeviceCommon::new(&device_config("vsock"), dm.clone(), virtio).unwrap();
+        common.mem().unwrap();
+
+        // The shared interrupt raised for another device is not reported as this one.
+        assert_eq!(common.backend_interrupt_status().load(Ordering::SeqCst), 0);
+
+        // The backend moving the used index forward is, once.
+        dm.write_guest(used_ring + 2, &1u16.to_le_bytes()).unwrap();
+        assert_eq!(
+            common.backend_interrupt_status().swap(0, Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING
+        );
+        assert_eq!(common.backend_interrupt_status().load(Ordering::SeqCst), 0);
+
+        // The used indexes start over after a reset.
+        common.reset().unwrap();
+        dm.write_guest(used_ring + 2, &0u16.to_le_bytes()).unwrap();
+        let queue = &mut common.config.queues[0];
+        queue
+            .try_set_used_ring_address(GuestAddress(used_ring))
+            .unwrap();
+        queue.set_ready(true);
+        assert_eq!(common.backend_interrupt_status().load(Ordering::SeqCst), 0);
+        dm.write_guest(used_ring + 2, &1u16.to_le_bytes()).unwrap();
+        assert_eq!(
+            common.backend_interrupt_status().load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_VRING
+        );
+    }
+
+    #[test]
+    fn test_queue_config() {
+        // The device type defaults apply unless overridden.
+        let mut config = device_config("net");
+        assert_eq!(
+            queue_config(&config, VirtioDevType::Net).unwrap(),
+            (2, 1024)
+        );
+        config.queue_size = Some(128);
+        assert_eq!(queue_config(&config, VirtioDevType::Net).unwrap(), (2, 128));
+
+        // The queue size must be a power of 2, up to the spec limit.
+        for queue_size in [0, 100, u16::MAX] {
+            config.queue_size = Some(queue_size);
+            assert!(queue_config(&config, VirtioDevType::Net).is_err());
+        }
+        config.queue_size = None;
+
+        // The net device has a fixed set of queues, while the file system device may have extra
+        // request queues.
+        config.num_queues = Some(4);
+        assert!(queue_config(&config, VirtioDevType::Net).is_err());
+        assert_eq!(queue_config(&config, VirtioDevType::Fs).unwrap(), (4, 1024));
+        assert_eq!(
+            queue_config(&config, VirtioDevType::Block).unwrap(),
+            (4, 256)
+        );
+        config.num_queues = Some(1);
+        assert!(queue_config(&config, VirtioDevType::Fs).is_err());
+        config.num_queues = Some(0);
+        assert!(queue_config(&config, VirtioDevType::Block).is_err());
+        config.num_queues = None;
+
+        // The rings must fit in the shared memory.
+        config.shmem_size = Some(2 * split_ring_size(1024));
+        assert!(queue_config(&config, VirtioDevType::Net).is_err());
+        config.queue_size = Some(256);
+        assert_eq!(queue_config(&config, VirtioDevType::Net).unwrap(), (2, 256));
+    }
+}
@@ -1,16 +1,15 @@
 use crate::device::clone_queue;
-use crate::device::{SingleFdSignalQueue, VirtioDeviceT};
-use crate::device::{VirtioDevType, VirtioDeviceCommon};
-use crate::mmio::VIRTIO_MMIO_INT_VRING;
-use api::device_model::BaoDeviceModel;
+use crate::device::VirtioDeviceT;
+use crate::device::{VirtioDevType, VirtioDeviceCommon, UNSUPPORTED_FEATURES};
+use crate::vhost_user::VHOST_USER_PROTOCOL_FEATURES;
+use api::device_model::DeviceModelT;
 use api::error::{Error, Result};
 use api::types::DeviceConfig;
 use event_manager::{EventManager, MutEventSubscriber};
 use seccompiler::SeccompAction;
 use std::borrow::{Borrow, BorrowMut};
-use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::atomic::AtomicU8;
 use std::sync::{Arc, Mutex};
-use vhost::vhost_user::message::VhostUserProtocolFeatures;
 use vhost_user_frontend::{
     Generic as VhostUserCommon, VhostUserConfig, VirtioDevice,
     VirtioDeviceType as VhostUserDeviceType,
@@ -41,19 +40,25 @@ impl VirtioDeviceT for VhostUserFs {
         config: &DeviceConfig,
         device_manager: Arc<Mutex<IoManager>>,
         _event_manager: Option<Arc<Mutex<EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>>>>,
-        device_model: Arc<Mutex<BaoDeviceModel>>,
+        device_model: Arc<dyn DeviceModelT>,
     ) -> Result<Arc<Mutex<Self>>> {
         // Extract the generic features and queues.
-        let (common_features, queues) = Self::initialize(&config).unwrap();
+        let (common_features, queues) = Self::initialize(&config)?;
 
         // Update the configuration space.
-        let config_space = Self::config_space(&config).unwrap();
+        let config_space = Self::config_space(&config)?;
+
+        // Extract the vhost-user socket path.
+        let socket_path = config
+            .socket_path
+            .clone()
+            .ok_or(Error::MissingConfigField("socket_path"))?;
 
         // Create the vhost-user configuration.
         let vu_cfg = VhostUserConfig {
             socket: format!(
                 "{}{}{}.sock",
-                config.socket_path.as_ref().unwrap(),
+                socket_path,
                 VirtioDevType::from(VirtioDevType::Fs).to_string(),
                 config.id
             ),
@@ -71,7 +76,7 @@ impl VirtioDeviceT for VhostUserFs {
         let vhost_user = VhostUserCommon::new(
             vu_cfg,
             SeccompAction::Allow,
-            EventFd::new(EFD_NONBLOCK).unwrap(),
+            EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreateFailed)?,
             VhostUserDeviceType::Fs,
         )
         .map_err(Error::VhostFrontendError)?;
@@ -82,15 +87,15 @@ impl VirtioDeviceT for VhostUserFs {
         );
 
         // Update the device features since we have the vhost-user backend now.
-        let device_features = Self::device_features(&config).unwrap()
-            | common_features
-            | vhost_user.device_features();
+        let device_features =
+            (Self::device_features(&config)? | common_features | vhost_user.device_features())
+                & !UNSUPPORTED_FEATURES;
 
         // Create a VirtioConfig object.
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
         // Create the generic device.
-        let common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg).unwrap();
+        let common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg)?;
 
         // Extract the VirtioDeviceCommon MMIO range.
         let range = common_device.mmio.range;
@@ -99,7 +104,7 @@ impl VirtioDeviceT for VhostUserFs {
         let fs = Arc::new(Mutex::new(VhostUserFs {
             vhost_user: Mutex::new(vhost_user),
             virtio: common_device,
-            socket_path: config.socket_path.clone().unwrap(),
+            socket_path,
         }));
 
         // Register the MMIO device within the device manager with the specified range.
@@ -107,7 +112,7 @@ impl VirtioDeviceT for VhostUserFs {
             .lock()
             .unwrap()
             .register_mmio(range, fs.clone())
-            .unwrap();
+            .map_err(|_| Error::MmioConfig)?;
 
         // Return the fs device.
         Ok(fs)
@@ -150,13 +155,10 @@ impl VirtioDeviceActions for VhostUserFs {
     // For that reasosn, it is the right place to perform the device initialization.
     fn activate(&mut self) -> Result<()> {
         // Setup the ioeventfds by calling the generic `prepare_activate` method.
-        let ioevents = self.virtio.prepare_activate().unwrap();
+        let ioevents = self.virtio.prepare_activate()?;
 
         // Create the driver notify object.
-        let driver_notify = SingleFdSignalQueue {
-            irqfd: self.virtio.irqfd.try_clone().unwrap(),
-            interrupt_status: self.virtio.config.interrupt_status.clone(),
-        };
+        let driver_notify = self.virtio.driver_notify()?;
 
         // Format the queues and ioevents into a Vec<(usize, Queue, EventFd)>.
         let queues = self
@@ -174,11 +176,11 @@ impl VirtioDeviceActions for VhostUserFs {
             .lock()
             .unwrap()
             .activate(
-                GuestMemoryAtomic::new(self.virtio.mem()),
+                GuestMemoryAtomic::new(self.virtio.mem()?),
                 Arc::new(driver_notify),
                 queues,
             )
-            .unwrap();
+            .map_err(Error::VhostFrontendActivateError)?;
 
         // Set the device as activated.
         self.virtio.config.device_activated = true;
@@ -187,8 +189,17 @@ impl VirtioDeviceActions for VhostUserFs {
     }
 
     fn reset(&mut self) -> Result<()> {
-        // Not implemented for now.
-        Ok(())
+        // The driver also resets the device before the first activation, in which case there
+        // is no backend to stop.
+        if !self.virtio.config.device_activated {
+            return self.virtio.reset();
+        }
+
+        // Stop the vhost-user backend device.
+        self.vhost_user.lock().unwrap().reset();
+
+        // Reset the generic device.
+        self.virtio.reset()
     }
 
     // This method is called when the driver wants to read information from the device configuration space.
@@ -217,14 +228,16 @@ impl VirtioDeviceActions for VhostUserFs {
     // features with the backend device. Otherwise, the device is not prepared to support, for example,
     // multiple queues and configuration space reads and writes.
     fn negotiate_driver_features(&mut self) {
-        self.vhost_user
-            .lock()
-            .unwrap()
-            .negotiate_features(
-                self.virtio.config.driver_features,
-                VhostUserProtocolFeatures::empty(),
-            )
-            .unwrap();
+        let result = self.vhost_user.lock().unwrap().negotiate_features(
+            self.virtio.config.driver_features,
+            VHOST_USER_PROTOCOL_FEATURES,
+        );
+
+        // The backend cannot serve the driver without the negotiated features.
+        if let Err(e) = result {
+            println!("Failed to negotiate the vhost-user features: {:?}", e);
+            self.virtio.set_needs_reset();
+        }
     }
 
     // This method is called when the driver needs to read the interrupt status from the device.
@@ -232,12 +245,7 @@ impl VirtioDeviceActions for VhostUserFs {
     // dedicated logic to update the interrupt status accordingly (Used Buffer Notification or Configuration Change Notification).
     // Note: If the device is implemented in the VMM, the interrupt status can be managed and updated directly by the device.
     fn interrupt_status(&self) -> &Arc<AtomicU8> {
-        // We assume that all the interrupts are Used Buffer Notifications.
-        self.virtio
-            .config
-            .interrupt_status
-            .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
-        &self.virtio.config.interrupt_status
+        self.virtio.backend_interrupt_status()
     }
 }
 
@@ -253,10 +261,12 @@ impl VirtioMmioDevice for VhostUserFs {
 /// Otherwise we could not register the device within the device manager.
 impl MutDeviceMmio for VhostUserFs {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.virtio.update_device_status();
         self.read(offset, data);
     }
 
     fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
         self.write(offset, data);
+        self.virtio.update_device_status();
     }
 }
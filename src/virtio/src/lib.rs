@@ -4,6 +4,9 @@ pub mod device;
 pub mod fs;
 pub mod mmio;
 pub mod net;
+pub mod rate_limiter;
+#[cfg(test)]
+mod test_utils;
 pub mod vhost;
 pub mod vhost_user;
 pub mod vsock;
@@ -1,13 +1,12 @@
 use crate::device::clone_queue;
 use crate::device::VirtioDeviceT;
 use crate::device::{VirtioDevType, VirtioDeviceCommon};
-use crate::mmio::VIRTIO_MMIO_INT_VRING;
 use crate::net::utils::mac_address_to_bytes;
 use crate::net::virtio::bindings;
 use crate::net::virtio::tap::Tap;
 use crate::net::virtio::VIRTIO_NET_HDR_SIZE;
 use crate::vhost::{VhostKernelCommon, VHOST_FEATURES};
-use api::device_model::BaoDeviceModel;
+use api::device_model::DeviceModelT;
 use api::error::{Error, Result};
 use api::types::DeviceConfig;
 use event_manager::{EventManager, MutEventSubscriber};
@@ -51,35 +50,38 @@ impl VirtioDeviceT for VhostNet {
         config: &DeviceConfig,
         device_manager: Arc<Mutex<IoManager>>,
         _event_manager: Option<Arc<Mutex<EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>>>>,
-        device_model: Arc<Mutex<BaoDeviceModel>>,
+        device_model: Arc<dyn DeviceModelT>,
     ) -> Result<Arc<Mutex<Self>>> {
         // Extract the generic features and queues.
-        let (common_features, queues) = Self::initialize(&config).unwrap();
+        let (common_features, queues) = Self::initialize(&config)?;
 
         // Extract the device features.
-        let device_features = Self::device_features(&config).unwrap();
+        let device_features = Self::device_features(&config)?;
 
         // Update the configuration space.
-        let config_space = Self::config_space(&config).unwrap();
+        let config_space = Self::config_space(&config)?;
 
         // Create a VirtioConfig object.
         let virtio_cfg = VirtioConfig::new(common_features | device_features, queues, config_space);
 
         // Create the generic device.
-        let mut common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg).unwrap();
+        let common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg)?;
 
         // Extract the VirtioDeviceCommon MMIO range.
         let range = common_device.mmio.range;
 
         // Create the Net kernel device.
-        let net_kernel = Net::new(Arc::new(common_device.mem())).unwrap();
+        let net_kernel = Net::new(Arc::new(common_device.mem()?)).map_err(Error::VhostBackend)?;
 
         // Create the net device.
         let net = Arc::new(Mutex::new(VhostNet {
             virtio: common_device,
-            vhost: VhostKernelCommon::new(device_features).unwrap(),
+            vhost: VhostKernelCommon::new(device_features)?,
             net: net_kernel,
-            tap_name: config.tap_name.clone().unwrap(),
+            tap_name: config
+                .tap_name
+                .clone()
+                .ok_or(Error::MissingConfigField("tap_name"))?,
         }));
 
         // Register the MMIO device within the device manager with the specified range.
@@ -87,7 +89,7 @@ impl VirtioDeviceT for VhostNet {
             .lock()
             .unwrap()
             .register_mmio(range, net.clone())
-            .unwrap();
+            .map_err(|_| Error::MmioConfig)?;
 
         // Return the net device.
         Ok(net)
@@ -110,8 +112,9 @@ impl VirtioDeviceT for VhostNet {
 
         // Extract the mac address.
         let mut mac_addr = Vec::new();
-        if config.mac_addr.is_some() {
-            mac_addr = mac_address_to_bytes(config.mac_addr.clone().unwrap().as_str()).unwrap();
+        if let Some(mac) = config.mac_addr.as_ref() {
+            mac_addr = mac_address_to_bytes(mac)
+                .ok_or_else(|| Error::InvalidConfigField("mac_addr", mac.clone()))?;
         }
 
         // Retrieve the mac address from the device configuration space.
@@ -161,7 +164,7 @@ impl VirtioDeviceActions for VhostNet {
         tap.set_vnet_hdr_size(VIRTIO_NET_HDR_SIZE as i32)?;
 
         // Setup the ioeventfds by calling the generic `prepare_activate` method.
-        let ioevents = self.virtio.prepare_activate().unwrap();
+        let ioevents = self.virtio.prepare_activate()?;
 
         // Format the queues and ioevents into a Vec<(usize, Queue, EventFd)>.
         let queues = self
@@ -175,20 +178,20 @@ impl VirtioDeviceActions for VhostNet {
             .collect::<Vec<_>>();
 
         // Set the current process as the owner of the file descriptor.
-        self.net.set_owner().unwrap();
+        self.net.set_owner().map_err(Error::VhostBackend)?;
 
         // Get the device features.
-        let supported_backend_features = self.net.get_features().unwrap();
+        let supported_backend_features = self.net.get_features().map_err(Error::VhostBackend)?;
 
         // Set the device features.
         self.net
             .set_features(self.vhost.features() & supported_backend_features)
-            .unwrap();
+            .map_err(Error::VhostBackend)?;
 
         // Update the memory table.
         self.net
-            .set_mem_table(self.vhost.memory(self.net.mem()).unwrap().as_slice())
-            .unwrap();
+            .set_mem_table(self.vhost.memory(self.net.mem())?.as_slice())
+            .map_err(Error::VhostBackend)?;
 
         // Set the vring.
         let mem = self.net.mem();
@@ -196,7 +199,9 @@ impl VirtioDeviceActions for VhostNet {
 
         for (queue_index, queue, ioeventfd) in queues.iter() {
             // Set the vring num.
-            self.net.set_vring_num(*queue_index, queue.size()).unwrap();
+            self.net
+                .set_vring_num(*queue_index, queue.size())
+                .map_err(Error::VhostBackend)?;
 
             let config_data = VringConfigData {
                 queue_max_size: queue.max_size(),
@@ -209,28 +214,37 @@ impl VirtioDeviceActions for VhostNet {
             };
 
             // Set the vring base.
+            let avail_idx = queue
+                .avail_idx(mem_aux, Ordering::Acquire)
+                .map_err(|_| Error::MmapGuestMemoryFailed)?;
             self.net
-                .set_vring_base(
-                    *queue_index,
-                    queue.avail_idx(mem_aux, Ordering::Acquire).unwrap().0,
-                )
-                .unwrap();
+                .set_vring_base(*queue_index, avail_idx.0)
+                .map_err(Error::VhostBackend)?;
 
             // Set the vring address.
-            self.net.set_vring_addr(*queue_index, &config_data).unwrap();
+            self.net
+                .set_vring_addr(*queue_index, &config_data)
+                .map_err(Error::VhostBackend)?;
 
             // Set the vring call.
+            let irqfd = self
+                .virtio
+                .irqfd
+                .try_clone()
+                .map_err(Error::EventFdCreateFailed)?;
             self.net
-                .set_vring_call(*queue_index, &self.virtio.irqfd.try_clone().unwrap())
-                .unwrap();
+                .set_vring_call(*queue_index, &irqfd)
+                .map_err(Error::VhostBackend)?;
 
             // Set the vring kick.
-            self.net.set_vring_kick(*queue_index, ioeventfd).unwrap();
+            self.net
+                .set_vring_kick(*queue_index, ioeventfd)
+                .map_err(Error::VhostBackend)?;
 
             // Set the backend.
             self.net
                 .set_backend(*queue_index, Some(&tap.tap_file))
-                .unwrap();
+                .map_err(Error::VhostBackend)?;
         }
 
         // Set the device as activated.
@@ -240,8 +254,24 @@ impl VirtioDeviceActions for VhostNet {
     }
 
     fn reset(&mut self) -> Result<()> {
-        // Not implemented for now.
-        Ok(())
+        // The driver also resets the device before the first activation, in which case there
+        // is no backend to stop.
+        if !self.virtio.config.device_activated {
+            return self.virtio.reset();
+        }
+
+        // Detach the tap device from every queue.
+        for queue_index in 0..self.virtio.config.queues.len() {
+            self.net
+                .set_backend(queue_index, None)
+                .map_err(Error::VhostBackend)?;
+        }
+
+        // Release the ownership of the vhost device, so it can be set up again on activation.
+        self.net.reset_owner().map_err(Error::VhostBackend)?;
+
+        // Reset the generic device.
+        self.virtio.reset()
     }
 
     // This method is called when the driver needs to read the interrupt status from the device.
@@ -249,12 +279,7 @@ impl VirtioDeviceActions for VhostNet {
     // dedicated logic to update the interrupt status accordingly (Used Buffer Notification or Configuration Change Notification).
     // Note: If the device is implemented in the VMM, the interrupt status can be managed and updated directly by the device.
     fn interrupt_status(&self) -> &Arc<AtomicU8> {
-        // We assume that all the interrupts are Used Buffer Notifications.
-        self.virtio
-            .config
-            .interrupt_status
-            .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
-        &self.virtio.config.interrupt_status
+        self.virtio.backend_interrupt_status()
     }
 }
 
@@ -270,10 +295,12 @@ impl VirtioMmioDevice for VhostNet {
 /// Otherwise we could not register the device within the device manager.
 impl MutDeviceMmio for VhostNet {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.virtio.update_device_status();
         self.read(offset, data);
     }
 
     fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
         self.write(offset, data);
+        self.virtio.update_device_status();
     }
 }
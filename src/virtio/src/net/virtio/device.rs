@@ -3,11 +3,11 @@ use super::queue_handler::QueueHandler;
 use super::simple_handler::SimpleHandler;
 use super::tap::Tap;
 use crate::device::clone_queue;
-use crate::device::{SingleFdSignalQueue, Subscriber, VirtioDeviceT};
+use crate::device::{Subscriber, VirtioDeviceT};
 use crate::device::{VirtioDevType, VirtioDeviceCommon};
 use crate::net::utils::mac_address_to_bytes;
 use crate::net::virtio::VIRTIO_NET_HDR_SIZE;
-use api::device_model::BaoDeviceModel;
+use api::device_model::DeviceModelT;
 use api::error::{Error, Result};
 use api::types::DeviceConfig;
 use event_manager::{
@@ -19,7 +19,7 @@ use virtio_bindings::virtio_config::VIRTIO_F_IN_ORDER;
 use virtio_bindings::virtio_net::{
     VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_TSO6,
     VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO,
-    VIRTIO_NET_F_MAC,
+    VIRTIO_NET_F_MAC, VIRTIO_NET_F_STATUS, VIRTIO_NET_S_LINK_UP,
 };
 use virtio_device::{VirtioConfig, VirtioDeviceActions, VirtioDeviceType, VirtioMmioDevice};
 use virtio_queue::Queue;
@@ -29,16 +29,21 @@ use vm_device::MutDeviceMmio;
 
 const VIRTIO_F_RING_EVENT_IDX: u64 = 29;
 
+// Offset of the link status within the configuration space (right after the mac address).
+const VIRTIO_NET_CONFIG_STATUS_OFFSET: usize = 6;
+
 /// Virtio net device.
 ///
 /// # Attributes
 ///
 /// * `common` - Virtio common device.
 /// * `endpoint` - The remote subscriber endpoint.
+/// * `sub_ids` - The IDs of the subscribers registered within the `EventManager`.
 /// * `tap_name` - Name of the tap device.
 pub struct VirtioNet {
     pub common: VirtioDeviceCommon,
     pub endpoint: RemoteEndpoint<Subscriber>,
+    pub sub_ids: Vec<SubscriberId>,
     pub tap_name: String,
 }
 
@@ -47,39 +52,50 @@ impl VirtioDeviceT for VirtioNet {
         config: &DeviceConfig,
         device_manager: Arc<Mutex<IoManager>>,
         event_manager: Option<Arc<Mutex<EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>>>>,
-        device_model: Arc<Mutex<BaoDeviceModel>>,
+        device_model: Arc<dyn DeviceModelT>,
     ) -> Result<Arc<Mutex<Self>>> {
         // Extract the generic features and queues.
-        let (common_features, queues) = Self::initialize(&config).unwrap();
+        let (common_features, queues) = Self::initialize(&config)?;
 
         // Update the device features.
-        let device_features = common_features | Self::device_features(&config).unwrap();
+        let device_features = common_features | Self::device_features(&config)?;
 
         // Update the configuration space.
-        let config_space = Self::config_space(&config).unwrap();
+        let config_space = Self::config_space(&config)?;
 
         // Create a VirtioConfig object.
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
         // Create the generic device.
-        let common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg).unwrap();
+        let common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg)?;
+
+        // Extract the VirtioDeviceCommon MMIO range.
+        let range = common_device.mmio.range;
 
         // Create a remote endpoint object, that allows interacting with the VM EventManager from a different thread.
-        let remote_endpoint = event_manager.unwrap().lock().unwrap().remote_endpoint();
+        let remote_endpoint = event_manager
+            .ok_or(Error::EventManagerNotFound)?
+            .lock()
+            .unwrap()
+            .remote_endpoint();
 
         // Create the net device.
         let net = Arc::new(Mutex::new(VirtioNet {
             common: common_device,
             endpoint: remote_endpoint,
-            tap_name: config.tap_name.clone().unwrap(),
+            sub_ids: Vec::new(),
+            tap_name: config
+                .tap_name
+                .clone()
+                .ok_or(Error::MissingConfigField("tap_name"))?,
         }));
 
         // Register the MMIO device within the device manager with the specified range.
         device_manager
             .lock()
             .unwrap()
-            .register_mmio(net.clone().lock().unwrap().common.mmio.range, net.clone())
-            .unwrap();
+            .register_mmio(range, net.clone())
+            .map_err(|_| Error::MmioConfig)?;
 
         // Return the net device.
         Ok(net)
@@ -95,7 +111,8 @@ impl VirtioDeviceT for VirtioNet {
             | (1 << VIRTIO_NET_F_GUEST_UFO)
             | (1 << VIRTIO_NET_F_HOST_TSO4)
             | (1 << VIRTIO_NET_F_HOST_TSO6)
-            | (1 << VIRTIO_NET_F_HOST_UFO);
+            | (1 << VIRTIO_NET_F_HOST_UFO)
+            | (1 << VIRTIO_NET_F_STATUS);
 
         // Set the mac address feature if a mac address is provided.
         if config.mac_addr.is_some() {
@@ -107,17 +124,38 @@ impl VirtioDeviceT for VirtioNet {
 
     fn config_space(config: &DeviceConfig) -> Result<Vec<u8>> {
         // TODO: Maybe we will need in the future to support setting other fields in the
-        // configuration space. For now, we only need the mac address.
+        // configuration space. For now, we only need the mac address and the link status.
         // Info: https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-2230004
 
-        // Extract the mac address.
-        let mut mac_addr = Vec::new();
-        if config.mac_addr.is_some() {
-            mac_addr = mac_address_to_bytes(config.mac_addr.clone().unwrap().as_str()).unwrap();
+        // Extract the mac address (the field is still present if no mac address is provided,
+        // as the link status follows it).
+        let mut config_space = vec![0u8; VIRTIO_NET_CONFIG_STATUS_OFFSET];
+        if let Some(mac) = config.mac_addr.as_ref() {
+            config_space = mac_address_to_bytes(mac)
+                .ok_or_else(|| Error::InvalidConfigField("mac_addr", mac.clone()))?;
         }
 
-        // Retrieve the mac address from the device configuration space.
-        Ok(mac_addr)
+        // The link starts up.
+        config_space.extend_from_slice(&(VIRTIO_NET_S_LINK_UP as u16).to_le_bytes());
+
+        Ok(config_space)
+    }
+}
+
+impl VirtioNet {
+    /// Update the link status reported to the driver.
+    ///
+    /// # Arguments
+    ///
+    /// * `up` - Whether the link is up.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    pub fn set_link_up(&mut self, up: bool) -> Result<()> {
+        let status = if up { VIRTIO_NET_S_LINK_UP as u16 } else { 0 };
+        self.common
+            .update_config_space(VIRTIO_NET_CONFIG_STATUS_OFFSET, &status.to_le_bytes())
     }
 }
 
@@ -161,10 +199,7 @@ impl VirtioDeviceActions for VirtioNet {
         tap.set_vnet_hdr_size(VIRTIO_NET_HDR_SIZE as i32)?;
 
         // Create the driver notify object.
-        let driver_notify = SingleFdSignalQueue {
-            irqfd: self.common.irqfd.try_clone().unwrap(),
-            interrupt_status: self.common.config.interrupt_status.clone(),
-        };
+        let driver_notify = self.common.driver_notify()?;
 
         // Prepare the activation by calling the generic `prepare_activate` method.
         let mut ioevents = self.common.prepare_activate()?;
@@ -172,7 +207,7 @@ impl VirtioDeviceActions for VirtioNet {
         // Create the inner handler.
         let rxq = clone_queue(&self.common.config.queues[0]);
         let txq = clone_queue(&self.common.config.queues[1]);
-        let inner = SimpleHandler::new(driver_notify, rxq, txq, tap, self.common.mem());
+        let inner = SimpleHandler::new(driver_notify, rxq, txq, tap, self.common.mem()?);
 
         // Create the queue handler.
         let handler = Arc::new(Mutex::new(QueueHandler {
@@ -181,15 +216,15 @@ impl VirtioDeviceActions for VirtioNet {
             tx_ioevent: ioevents.remove(0),
         }));
 
-        // Register the queue handler with the `EventManager`. We could record the `sub_id`
-        // (and/or keep a handler clone) for further interaction (i.e. to remove the subscriber at
-        // a later time, retrieve state, etc).
-        let _sub_id = self
+        // Register the queue handler with the `EventManager`. We record the `sub_id` to remove
+        // the subscriber when the device is reset.
+        let sub_id = self
             .endpoint
             .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
                 Ok(mgr.add_subscriber(handler))
             })
-            .unwrap();
+            .map_err(Error::EventManager)?;
+        self.sub_ids.push(sub_id);
 
         // Set the device as activated.
         self.common.config.device_activated = true;
@@ -198,8 +233,17 @@ impl VirtioDeviceActions for VirtioNet {
     }
 
     fn reset(&mut self) -> Result<()> {
-        // Not implemented for now.
-        Ok(())
+        // Remove the queue handlers from the `EventManager`.
+        for sub_id in self.sub_ids.drain(..) {
+            self.endpoint
+                .call_blocking(move |mgr| -> EvmgrResult<()> {
+                    mgr.remove_subscriber(sub_id).map(|_| ())
+                })
+                .map_err(Error::EventManager)?;
+        }
+
+        // Reset the generic device.
+        self.common.reset()
     }
 }
 
@@ -214,10 +258,12 @@ impl VirtioMmioDevice for VirtioNet {
 /// Otherwise we could not register the device within the device manager.
 impl MutDeviceMmio for VirtioNet {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.common.update_device_status();
         self.read(offset, data);
     }
 
     fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
         self.write(offset, data);
+        self.common.update_device_status();
     }
 }
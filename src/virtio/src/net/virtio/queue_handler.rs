@@ -3,7 +3,7 @@ use log::error;
 use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
 
-use crate::device::SingleFdSignalQueue;
+use crate::device::{SignalUsedQueue, SingleFdSignalQueue};
 
 use super::simple_handler::SimpleHandler;
 
@@ -24,15 +24,20 @@ pub struct QueueHandler {
 
 impl QueueHandler {
     // Helper method that receives an error message to be logged and the `ops` handle
-    // which is used to unregister all events.
+    // which is used to unregister all events. The queues can no longer be serviced, so the
+    // driver is also told to reset the device.
     fn handle_error<S: AsRef<str>>(&self, s: S, ops: &mut EventOps) {
         error!("{}", s.as_ref());
-        ops.remove(Events::empty(&self.rx_ioevent))
-            .expect("Failed to remove rx ioevent");
-        ops.remove(Events::empty(&self.tx_ioevent))
-            .expect("Failed to remove tx ioevent");
-        ops.remove(Events::empty(&self.inner.tap))
-            .expect("Failed to remove tap event");
+        self.inner.driver_notify.signal_needs_reset();
+        if let Err(e) = ops.remove(Events::empty(&self.rx_ioevent)) {
+            error!("Failed to remove rx ioevent: {:?}", e);
+        }
+        if let Err(e) = ops.remove(Events::empty(&self.tx_ioevent)) {
+            error!("Failed to remove tx ioevent: {:?}", e);
+        }
+        if let Err(e) = ops.remove(Events::empty(&self.inner.tap)) {
+            error!("Failed to remove tap event: {:?}", e);
+        }
     }
 }
 
@@ -72,25 +77,21 @@ impl MutEventSubscriber for QueueHandler {
     }
 
     fn init(&mut self, ops: &mut EventOps) {
-        ops.add(Events::with_data(
-            &self.inner.tap,
-            TAPFD_DATA,
-            EventSet::IN | EventSet::EDGE_TRIGGERED,
-        ))
-        .expect("Unable to add tapfd");
+        let events = [
+            Events::with_data(
+                &self.inner.tap,
+                TAPFD_DATA,
+                EventSet::IN | EventSet::EDGE_TRIGGERED,
+            ),
+            Events::with_data(&self.rx_ioevent, RX_IOEVENT_DATA, EventSet::IN),
+            Events::with_data(&self.tx_ioevent, TX_IOEVENT_DATA, EventSet::IN),
+        ];
 
-        ops.add(Events::with_data(
-            &self.rx_ioevent,
-            RX_IOEVENT_DATA,
-            EventSet::IN,
-        ))
-        .expect("Unable to add rxfd");
-
-        ops.add(Events::with_data(
-            &self.tx_ioevent,
-            TX_IOEVENT_DATA,
-            EventSet::IN,
-        ))
-        .expect("Unable to add txfd");
+        for event in events {
+            if let Err(e) = ops.add(event) {
+                self.handle_error(format!("Unable to add event {:?}", e), ops);
+                return;
+            }
+        }
     }
 }
@@ -191,3 +191,90 @@ impl<S: SignalUsedQueue> SimpleHandler<S> {
         self.process_tap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::SingleFdSignalQueue;
+    use crate::mmio::VIRTIO_MMIO_INT_VRING;
+    use crate::test_utils::{VirtQueue, VirtioMmioDriver, DATA_ADDR, QUEUE_SIZE};
+    use std::fs::File;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixDatagram;
+    use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+    use std::sync::Arc;
+    use vm_memory::GuestAddress;
+    use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+    // Size of the `virtio_net_hdr_v1` header prepended to every frame.
+    const VNET_HDR_LEN: usize = 12;
+
+    #[test]
+    fn test_simple_handler_frames() {
+        let driver = VirtioMmioDriver::new();
+        let mut rxq = VirtQueue::new(RXQ_INDEX, QUEUE_SIZE);
+        let mut txq = VirtQueue::new(TXQ_INDEX, QUEUE_SIZE);
+
+        // Back the tap with a datagram socket, which preserves the frame boundaries.
+        let (tap_socket, peer) = UnixDatagram::pair().unwrap();
+        tap_socket.set_nonblocking(true).unwrap();
+        let tap = Tap {
+            tap_file: File::from(OwnedFd::from(tap_socket)),
+            if_name: [0u8; 16],
+        };
+
+        let driver_notify = SingleFdSignalQueue {
+            irqfd: EventFd::new(EFD_NONBLOCK).unwrap(),
+            interrupt_status: Arc::new(AtomicU8::new(0)),
+            needs_reset: Arc::new(AtomicBool::new(false)),
+        };
+        let mut handler = SimpleHandler::new(
+            driver_notify,
+            rxq.to_queue(),
+            txq.to_queue(),
+            tap,
+            driver.mem.clone(),
+        );
+
+        let mut frame = vec![0u8; VNET_HDR_LEN];
+        frame.extend((0..64).map(|i| i as u8));
+
+        // Transmit a frame.
+        driver
+            .mem
+            .write_slice(&frame, GuestAddress(DATA_ADDR))
+            .unwrap();
+        let head = txq.add_chain(&driver.mem, &[(DATA_ADDR, frame.len() as u32, false)]);
+        handler.process_txq().unwrap();
+        assert_eq!(txq.next_used(&driver.mem), Some((head, 0)));
+
+        let mut buf = vec![0u8; MAX_BUFFER_SIZE];
+        let len = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], frame.as_slice());
+
+        // Receive a frame.
+        let rx_addr = DATA_ADDR + 0x1000;
+        let head = rxq.add_chain(&driver.mem, &[(rx_addr, 2048, true)]);
+        peer.send(&frame).unwrap();
+        handler.process_rxq().unwrap();
+        assert_eq!(rxq.next_used(&driver.mem), Some((head, frame.len() as u32)));
+
+        let mut buf = vec![0u8; frame.len()];
+        driver
+            .mem
+            .read_slice(&mut buf, GuestAddress(rx_addr))
+            .unwrap();
+        assert_eq!(buf, frame);
+
+        // The driver got notified about the used buffers.
+        assert!(handler.driver_notify.irqfd.read().unwrap() > 0);
+        assert_ne!(
+            handler
+                .driver_notify
+                .interrupt_status
+                .load(Ordering::SeqCst)
+                & VIRTIO_MMIO_INT_VRING,
+            0
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use api::error::{Error, Result};
+use api::types::{RateLimiterConfig, TokenBucketConfig};
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use vmm_sys_util::timerfd::TimerFd;
+
+// Shortest time a throttled user waits for, so the timer is never disarmed by a zero duration.
+const MIN_WAIT: Duration = Duration::from_micros(100);
+
+/// Token bucket, refilled at a constant rate.
+///
+/// # Attributes
+///
+/// * `size` - The bucket size (in tokens).
+/// * `one_time_burst` - The initial extra budget left (in tokens), which is not refilled.
+/// * `refill_time` - The time to refill the bucket from empty (in nanoseconds).
+/// * `budget` - The tokens left in the bucket.
+/// * `last_update` - The time the budget was last refilled.
+#[derive(Debug)]
+pub struct TokenBucket {
+    size: u64,
+    one_time_burst: u64,
+    refill_time: u64,
+    budget: u64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    /// Create a new token bucket, initially full.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The bucket size (in tokens).
+    /// * `one_time_burst` - The initial extra budget (in tokens), which is not refilled.
+    /// * `refill_time` - The time to refill the bucket from empty.
+    ///
+    /// # Returns
+    ///
+    /// The token bucket, or `None` if the size or the refill time is zero.
+    pub fn new(size: u64, one_time_burst: u64, refill_time: Duration) -> Option<Self> {
+        let refill_time = refill_time.as_nanos().min(u64::MAX as u128) as u64;
+        if size == 0 || refill_time == 0 {
+            return None;
+        }
+
+        Some(TokenBucket {
+            size,
+            one_time_burst,
+            refill_time,
+            budget: size,
+            last_update: Instant::now(),
+        })
+    }
+
+    /// Refill the bucket with the tokens accumulated since the last update.
+    fn refill(&mut self) {
+        let elapsed = self.last_update.elapsed().as_nanos();
+        let tokens = elapsed * self.size as u128 / self.refill_time as u128;
+        if tokens == 0 {
+            return;
+        }
+
+        if self.budget as u128 + tokens >= self.size as u128 {
+            self.budget = self.size;
+            self.last_update = Instant::now();
+        } else {
+            // Only move forward by the time the new tokens took, so the remainder keeps
+            // accumulating towards the next token.
+            self.budget += tokens as u64;
+            self.last_update += Duration::from_nanos(
+                (tokens * self.refill_time as u128 / self.size as u128) as u64,
+            );
+        }
+    }
+
+    /// Get the time to wait for, before the tokens can be consumed.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - The number of tokens.
+    ///
+    /// # Returns
+    ///
+    /// The time to wait for (zero if the tokens are available right away).
+    ///
+    /// # Note
+    ///
+    /// Consuming more tokens than the bucket size only requires a full bucket, which is drained.
+    pub fn wait_time(&mut self, tokens: u64) -> Duration {
+        self.refill();
+
+        let needed = tokens.saturating_sub(self.one_time_burst).min(self.size);
+        if needed <= self.budget {
+            return Duration::ZERO;
+        }
+
+        // Round up, so the tokens are available once the time has elapsed.
+        let missing = (needed - self.budget) as u128;
+        let wait = (missing * self.refill_time as u128).div_ceil(self.size as u128);
+        Duration::from_nanos(wait.min(u64::MAX as u128) as u64)
+            .saturating_sub(self.last_update.elapsed())
+            .max(MIN_WAIT)
+    }
+
+    /// Consume tokens, which must be available (see `wait_time`).
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - The number of tokens.
+    pub fn consume(&mut self, tokens: u64) {
+        // The one time burst is spent first.
+        let burst = tokens.min(self.one_time_burst);
+        self.one_time_burst -= burst;
+        self.budget = self.budget.saturating_sub(tokens - burst);
+    }
+
+    /// Get the tokens left in the bucket (the one time burst included).
+    pub fn budget(&self) -> u64 {
+        self.budget + self.one_time_burst
+    }
+}
+
+/// The token buckets of a rate limiter.
+///
+/// # Attributes
+///
+/// * `bandwidth` - The bandwidth bucket, in bytes (unlimited if `None`).
+/// * `ops` - The operations bucket, in requests (unlimited if `None`).
+#[derive(Debug)]
+struct Buckets {
+    bandwidth: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+}
+
+/// Rate limiter, made of a bandwidth and an operations token bucket.
+///
+/// Every request consumes its size from the bandwidth bucket and one token from the operations
+/// bucket. If any of them runs out, the request is throttled and the timer fires once the tokens
+/// are available again. The clones of a rate limiter share its buckets, each one with its own
+/// timer, so a device may throttle all of its queues together.
+///
+/// # Attributes
+///
+/// * `buckets` - The token buckets.
+/// * `timer` - The timer signalled once a throttled user may retry.
+/// * `blocked` - Whether the user is throttled until the timer fires.
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<Buckets>>,
+    timer: TimerFd,
+    blocked: bool,
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter.
+    ///
+    /// # Arguments
+    ///
+    /// * `bandwidth` - The bandwidth bucket, in bytes (unlimited if `None`).
+    /// * `ops` - The operations bucket, in requests (unlimited if `None`).
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the rate limiter.
+    pub fn new(bandwidth: Option<TokenBucket>, ops: Option<TokenBucket>) -> io::Result<Self> {
+        Ok(RateLimiter {
+            buckets: Arc::new(Mutex::new(Buckets { bandwidth, ops })),
+            timer: TimerFd::new()?,
+            blocked: false,
+        })
+    }
+
+    /// Create a new rate limiter from its configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The rate limiter configuration.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the rate limiter.
+    pub fn from_config(config: &RateLimiterConfig) -> Result<Self> {
+        let bucket = |config: Option<&TokenBucketConfig>| {
+            config
+                .map(|config| {
+                    TokenBucket::new(
+                        config.size,
+                        config.one_time_burst.unwrap_or(0),
+                        Duration::from_millis(config.refill_time),
+                    )
+                    .ok_or_else(|| {
+                        Error::InvalidConfigField(
+                            "rate_limiter",
+                            format!("{:?} (expected a non zero size and refill time)", config),
+                        )
+                    })
+                })
+                .transpose()
+        };
+
+        RateLimiter::new(
+            bucket(config.bandwidth.as_ref())?,
+            bucket(config.ops.as_ref())?,
+        )
+        .map_err(Error::TimerFdCreateFailed)
+    }
+
+    /// Create a new rate limiter sharing the token buckets, with its own timer.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the rate limiter.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(RateLimiter {
+            buckets: self.buckets.clone(),
+            timer: TimerFd::new()?,
+            blocked: false,
+        })
+    }
+
+    /// Consume the tokens of a request, unless throttled.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The size of the request (in bytes).
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing whether the request may go on. Otherwise, the timer is armed
+    /// and the request must be retried once it fires.
+    pub fn consume(&mut self, bytes: u64) -> io::Result<bool> {
+        if self.blocked {
+            return Ok(false);
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets { bandwidth, ops } = &mut *buckets;
+
+        // Only consume the tokens if both buckets have enough of them.
+        let wait = longest_wait(
+            bandwidth.as_mut().map(|bucket| bucket.wait_time(bytes)),
+            ops.as_mut().map(|bucket| bucket.wait_time(1)),
+        );
+        if !wait.is_zero() {
+            self.timer.reset(wait, None)?;
+            self.blocked = true;
+            return Ok(false);
+        }
+
+        if let Some(bucket) = bandwidth.as_mut() {
+            bucket.consume(bytes);
+        }
+        if let Some(bucket) = ops.as_mut() {
+            bucket.consume(1);
+        }
+
+        Ok(true)
+    }
+
+    /// Check if the user is throttled until the timer fires.
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    /// Handle the timer expiration, after which the throttled requests may be retried.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the result of the operation.
+    pub fn event_handler(&mut self) -> io::Result<()> {
+        self.timer.wait()?;
+        self.blocked = false;
+
+        Ok(())
+    }
+}
+
+impl AsRawFd for RateLimiter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+/// Get the longest of the wait times (zero if there are none).
+fn longest_wait(a: Option<Duration>, b: Option<Duration>) -> Duration {
+    a.unwrap_or_default().max(b.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_token_bucket() {
+        assert!(TokenBucket::new(0, 0, Duration::from_millis(100)).is_none());
+        assert!(TokenBucket::new(1000, 0, Duration::ZERO).is_none());
+
+        // The bucket starts full, and the one time burst is spent first.
+        let mut bucket = TokenBucket::new(1000, 500, Duration::from_millis(100)).unwrap();
+        assert_eq!(bucket.budget(), 1500);
+        assert!(bucket.wait_time(1500).is_zero());
+        bucket.consume(600);
+        assert_eq!(bucket.budget(), 900);
+
+        // Running out of tokens takes a refill.
+        bucket.consume(900);
+        let wait = bucket.wait_time(500);
+        assert!(!wait.is_zero() && wait <= Duration::from_millis(50));
+        thread::sleep(wait);
+        assert!(bucket.wait_time(500).is_zero());
+
+        // Oversized requests only need a full bucket.
+        thread::sleep(Duration::from_millis(100));
+        assert!(bucket.wait_time(5000).is_zero());
+        bucket.consume(5000);
+        assert_eq!(bucket.budget(), 0);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let config = RateLimiterConfig {
+            bandwidth: Some(TokenBucketConfig {
+                size: 0x1000,
+                one_time_burst: None,
+                refill_time: 100,
+            }),
+            ops: Some(TokenBucketConfig {
+                size: 2,
+                one_time_burst: None,
+                refill_time: 200,
+            }),
+        };
+        let mut limiter = RateLimiter::from_config(&config).unwrap();
+        let mut clone = limiter.try_clone().unwrap();
+
+        // The clones share the buckets.
+        assert!(limiter.consume(0x800).unwrap());
+        assert!(clone.consume(0x800).unwrap());
+        assert!(!limiter.consume(0x200).unwrap());
+        assert!(limiter.is_blocked());
+        assert!(!clone.is_blocked());
+
+        // The throttled user is unblocked by its timer.
+        limiter.event_handler().unwrap();
+        assert!(!limiter.is_blocked());
+
+        // The size and the refill time must not be zero.
+        let mut config = config;
+        config.ops.as_mut().unwrap().refill_time = 0;
+        assert!(matches!(
+            RateLimiter::from_config(&config),
+            Err(Error::InvalidConfigField("rate_limiter", _))
+        ));
+    }
+}
@@ -0,0 +1,626 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Test utilities.
+//!
+//! In-process virtio-mmio guest driver, running on top of the mock device model, used to bring
+//! up the devices and to push buffers through their queues end to end.
+
+use crate::block::virtio::device::VirtioBlock;
+use crate::device::{Subscriber, VirtioDeviceT};
+use api::error::Result;
+use api::mock::{MockDeviceModel, MOCK_IO_TIMEOUT};
+use api::types::DeviceConfig;
+use event_manager::EventManager;
+use libc::{MAP_SHARED, PROT_READ, PROT_WRITE};
+use std::sync::atomic::{fence, AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use virtio_bindings::virtio_config::{
+    VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER, VIRTIO_CONFIG_S_DRIVER_OK,
+    VIRTIO_CONFIG_S_FEATURES_OK,
+};
+use virtio_bindings::virtio_mmio::{
+    VIRTIO_MMIO_CONFIG, VIRTIO_MMIO_DEVICE_FEATURES, VIRTIO_MMIO_DEVICE_FEATURES_SEL,
+    VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_MMIO_DRIVER_FEATURES_SEL, VIRTIO_MMIO_INTERRUPT_ACK,
+    VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_AVAIL_HIGH,
+    VIRTIO_MMIO_QUEUE_AVAIL_LOW, VIRTIO_MMIO_QUEUE_DESC_HIGH, VIRTIO_MMIO_QUEUE_DESC_LOW,
+    VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_NUM_MAX,
+    VIRTIO_MMIO_QUEUE_READY, VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_QUEUE_USED_HIGH,
+    VIRTIO_MMIO_QUEUE_USED_LOW, VIRTIO_MMIO_STATUS, VIRTIO_MMIO_VERSION,
+};
+use virtio_bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+use virtio_queue::{Queue, QueueT};
+use vm_device::bus::MmioAddress;
+use vm_device::device_manager::IoManager;
+use vm_memory::bitmap::AtomicBitmap;
+use vm_memory::{guest_memory::FileOffset, Bytes, GuestAddress, MmapRegion};
+use vmm_sys_util::tempfile::TempFile;
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+type GuestRegionMmap = vm_memory::GuestRegionMmap<AtomicBitmap>;
+
+/// Guest address of the shared memory (not at zero, so the slice offsets are exercised).
+pub const SHMEM_ADDR: u64 = 0x5000_0000;
+/// Size of the shared memory.
+pub const SHMEM_SIZE: u64 = 0x10_0000;
+/// Guest address of the first queue rings (each queue uses a 4 KiB page).
+pub const RING_ADDR: u64 = SHMEM_ADDR + 0x1000;
+/// Guest address of the data buffers.
+pub const DATA_ADDR: u64 = SHMEM_ADDR + 0x1_0000;
+/// MMIO address of the device.
+pub const MMIO_ADDR: u64 = 0xa003e00;
+/// Interrupt of the device.
+pub const MMIO_IRQ: u32 = 47;
+/// Size of the queues set up by the driver.
+pub const QUEUE_SIZE: u16 = 16;
+/// Size of the disk images created by `disk_image`.
+pub const DISK_SIZE: u64 = 0x10_0000;
+
+/// Magic value ("virt" string) exposed by every virtio-mmio device.
+const MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
+/// Version of the virtio-mmio transport (modern devices only).
+const MMIO_VERSION: u32 = 2;
+
+/// Build a device configuration with the device specific options unset.
+///
+/// # Arguments
+///
+/// * `device_type` - The device type.
+///
+/// # Returns
+///
+/// The device configuration.
+pub fn device_config(device_type: &str) -> DeviceConfig {
+    DeviceConfig {
+        id: 0,
+        device_type: device_type.to_string(),
+        mmio_addr: MMIO_ADDR,
+        data_plane: "virtio".to_string(),
+        shmem_addr: None,
+        shmem_size: None,
+        irq: None,
+        num_queues: None,
+        queue_size: None,
+        file_path: None,
+        read_only: None,
+        root_device: None,
+        advertise_flush: None,
+        io_engine: None,
+        image_format: None,
+        logical_block_size: None,
+        physical_block_size: None,
+        queue_threads: None,
+        serial: None,
+        rate_limiter: None,
+        cache: None,
+        shared: None,
+        overlay: None,
+        zoned: None,
+        encryption: None,
+        tap_name: None,
+        mac_addr: None,
+        guest_cid: None,
+        socket_path: None,
+        pty_alias: None,
+    }
+}
+
+/// Build a block device configuration backed by the given image.
+///
+/// # Arguments
+///
+/// * `image` - The disk image.
+///
+/// # Returns
+///
+/// The device configuration.
+pub fn block_config(image: &TempFile) -> DeviceConfig {
+    let mut config = device_config("block");
+    config.file_path = Some(image.as_path().to_str().unwrap().to_string());
+    config.read_only = Some(false);
+    config.root_device = Some(false);
+    config.advertise_flush = Some(true);
+    config
+}
+
+/// Create a raw disk image of `DISK_SIZE` bytes.
+///
+/// # Returns
+///
+/// The disk image, removed when dropped.
+pub fn disk_image() -> TempFile {
+    let image = TempFile::new().unwrap();
+    image.as_file().set_len(DISK_SIZE).unwrap();
+    image
+}
+
+/// Create a block device on top of the given driver.
+///
+/// # Arguments
+///
+/// * `driver` - The driver the device is attached to.
+/// * `config` - The device configuration.
+///
+/// # Returns
+///
+/// The block device, or the error returned while creating it.
+pub fn new_block(
+    driver: &VirtioMmioDriver,
+    config: &DeviceConfig,
+) -> Result<Arc<Mutex<VirtioBlock>>> {
+    VirtioBlock::new(
+        config,
+        driver.device_manager.clone(),
+        Some(driver.event_manager.clone()),
+        driver.dm.clone(),
+    )
+}
+
+/// Create a block device and bring it up, accepting every feature it offers.
+///
+/// # Arguments
+///
+/// * `config` - The device configuration.
+///
+/// # Returns
+///
+/// The driver, the block device and the negotiated features.
+pub fn block_device(config: &DeviceConfig) -> (VirtioMmioDriver, Arc<Mutex<VirtioBlock>>, u64) {
+    let mut driver = VirtioMmioDriver::new();
+    let block = new_block(&driver, config).unwrap();
+    let features = driver.init(u64::MAX);
+    (driver, block, features)
+}
+
+/// Map the shared memory of the mock device model, as seen by the guest.
+///
+/// # Arguments
+///
+/// * `dm` - The mock device model.
+///
+/// # Returns
+///
+/// The guest memory.
+pub fn guest_memory(dm: &MockDeviceModel) -> GuestMemoryMmap {
+    let file = dm.shmem_file().try_clone().unwrap();
+    let region = MmapRegion::build(
+        Some(FileOffset::new(file, 0)),
+        SHMEM_SIZE as usize,
+        PROT_READ | PROT_WRITE,
+        MAP_SHARED,
+    )
+    .unwrap();
+
+    GuestMemoryMmap::from_regions(vec![
+        GuestRegionMmap::new(region, GuestAddress(SHMEM_ADDR)).unwrap()
+    ])
+    .unwrap()
+}
+
+/// Driver side of a split virtqueue.
+///
+/// # Attributes
+///
+/// * `size` - The queue size.
+/// * `desc_table` - The guest address of the descriptor table.
+/// * `avail_ring` - The guest address of the available ring.
+/// * `used_ring` - The guest address of the used ring.
+/// * `next_desc` - The next free descriptor.
+/// * `next_avail` - The next available ring index.
+/// * `next_used` - The next used ring index to be consumed.
+pub struct VirtQueue {
+    pub size: u16,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+    next_desc: u16,
+    next_avail: u16,
+    next_used: u16,
+}
+
+impl VirtQueue {
+    /// Create a new virtqueue, laid out in the ring page of the given queue index.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The queue index.
+    /// * `size` - The queue size.
+    ///
+    /// # Returns
+    ///
+    /// The virtqueue.
+    pub fn new(index: u16, size: u16) -> Self {
+        let desc_table = RING_ADDR + index as u64 * 0x1000;
+        let avail_ring = desc_table + 16 * size as u64;
+        // The used ring must be 4 bytes aligned.
+        let used_ring = (avail_ring + 6 + 2 * size as u64 + 3) & !3;
+
+        VirtQueue {
+            size,
+            desc_table,
+            avail_ring,
+            used_ring,
+            next_desc: 0,
+            next_avail: 0,
+            next_used: 0,
+        }
+    }
+
+    /// Build the device side queue, as configured by the driver, to drive the queue
+    /// handlers directly.
+    ///
+    /// # Returns
+    ///
+    /// The device side queue.
+    pub fn to_queue(&self) -> Queue {
+        let mut queue = Queue::new(self.size).unwrap();
+        queue.set_size(self.size);
+        queue
+            .try_set_desc_table_address(GuestAddress(self.desc_table))
+            .unwrap();
+        queue
+            .try_set_avail_ring_address(GuestAddress(self.avail_ring))
+            .unwrap();
+        queue
+            .try_set_used_ring_address(GuestAddress(self.used_ring))
+            .unwrap();
+        queue.set_ready(true);
+        queue
+    }
+
+    /// Add a descriptor chain to the available ring.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - The guest memory.
+    /// * `buffers` - The address, length and device writable flag of each buffer.
+    ///
+    /// # Returns
+    ///
+    /// The head descriptor index of the chain.
+    pub fn add_chain(&mut self, mem: &GuestMemoryMmap, buffers: &[(u64, u32, bool)]) -> u16 {
+        let head = self.next_desc;
+
+        // Fill the descriptor table.
+        for (i, &(addr, len, write)) in buffers.iter().enumerate() {
+            let desc = self.desc_table + 16 * self.next_desc as u64;
+            self.next_desc = (self.next_desc + 1) % self.size;
+
+            let mut flags = 0;
+            if i + 1 < buffers.len() {
+                flags |= VRING_DESC_F_NEXT as u16;
+            }
+            if write {
+                flags |= VRING_DESC_F_WRITE as u16;
+            }
+
+            mem.write_obj(addr, GuestAddress(desc)).unwrap();
+            mem.write_obj(len, GuestAddress(desc + 8)).unwrap();
+            mem.write_obj(flags, GuestAddress(desc + 12)).unwrap();
+            mem.write_obj(self.next_desc, GuestAddress(desc + 14))
+                .unwrap();
+        }
+
+        // Publish the chain, making sure the ring entry is visible before the index.
+        let entry = self.avail_ring + 4 + 2 * (self.next_avail % self.size) as u64;
+        mem.write_obj(head, GuestAddress(entry)).unwrap();
+        self.next_avail = self.next_avail.wrapping_add(1);
+        fence(Ordering::SeqCst);
+        mem.write_obj(self.next_avail, GuestAddress(self.avail_ring + 2))
+            .unwrap();
+
+        head
+    }
+
+    /// Consume the next used ring entry, if any.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - The guest memory.
+    ///
+    /// # Returns
+    ///
+    /// The head descriptor index and the written length of the used chain.
+    pub fn next_used(&mut self, mem: &GuestMemoryMmap) -> Option<(u16, u32)> {
+        let idx: u16 = mem.read_obj(GuestAddress(self.used_ring + 2)).unwrap();
+        if idx == self.next_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+
+        let entry = self.used_ring + 4 + 8 * (self.next_used % self.size) as u64;
+        let id: u32 = mem.read_obj(GuestAddress(entry)).unwrap();
+        let len: u32 = mem.read_obj(GuestAddress(entry + 4)).unwrap();
+        self.next_used = self.next_used.wrapping_add(1);
+
+        Some((id as u16, len))
+    }
+
+    /// Wait for the next used ring entry.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem` - The guest memory.
+    /// * `timeout` - The maximum time to wait.
+    ///
+    /// # Returns
+    ///
+    /// The head descriptor index and the written length of the used chain.
+    pub fn wait_used(&mut self, mem: &GuestMemoryMmap, timeout: Duration) -> Option<(u16, u32)> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(used) = self.next_used(mem) {
+                return Some(used);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// In-process virtio-mmio guest driver.
+///
+/// # Attributes
+///
+/// * `dm` - The mock device model.
+/// * `device_manager` - The device manager the device is registered within.
+/// * `event_manager` - The event manager running the device handlers.
+/// * `mem` - The guest memory.
+/// * `queues` - The queues set up by the driver.
+/// * `stop` - Whether the event manager loop should stop.
+/// * `event_loop` - The thread running the event manager loop.
+pub struct VirtioMmioDriver {
+    pub dm: Arc<MockDeviceModel>,
+    pub device_manager: Arc<Mutex<IoManager>>,
+    pub event_manager: Arc<Mutex<EventManager<Subscriber>>>,
+    pub mem: GuestMemoryMmap,
+    pub queues: Vec<VirtQueue>,
+    stop: Arc<AtomicBool>,
+    event_loop: Option<JoinHandle<()>>,
+}
+
+impl VirtioMmioDriver {
+    /// Create a new driver, with no device attached yet.
+    ///
+    /// # Returns
+    ///
+    /// The driver.
+    pub fn new() -> Self {
+        let dm = Arc::new(MockDeviceModel::new(0, SHMEM_ADDR, SHMEM_SIZE, MMIO_IRQ).unwrap());
+        let mem = guest_memory(&dm);
+
+        VirtioMmioDriver {
+            dm,
+            device_manager: Arc::new(Mutex::new(IoManager::new())),
+            event_manager: Arc::new(Mutex::new(EventManager::new().unwrap())),
+            mem,
+            queues: Vec::new(),
+            stop: Arc::new(AtomicBool::new(false)),
+            event_loop: None,
+        }
+    }
+
+    /// Read a 32-bit device register.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The register offset.
+    ///
+    /// # Returns
+    ///
+    /// The register value.
+    pub fn read(&self, offset: u32) -> u32 {
+        let mut data = [0u8; 4];
+        self.device_manager
+            .lock()
+            .unwrap()
+            .mmio_read(MmioAddress(MMIO_ADDR + offset as u64), &mut data)
+            .unwrap();
+        u32::from_le_bytes(data)
+    }
+
+    /// Write a 32-bit device register.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The register offset.
+    /// * `value` - The register value.
+    pub fn write(&self, offset: u32, value: u32) {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .mmio_write(MmioAddress(MMIO_ADDR + offset as u64), &value.to_le_bytes())
+            .unwrap();
+    }
+
+    /// Read the device configuration space.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset within the configuration space.
+    /// * `data` - The buffer to fill.
+    pub fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .mmio_read(
+                MmioAddress(MMIO_ADDR + VIRTIO_MMIO_CONFIG as u64 + offset),
+                data,
+            )
+            .unwrap();
+    }
+
+    /// Write the device configuration space.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset within the configuration space.
+    /// * `data` - The data to write.
+    pub fn write_config(&self, offset: u64, data: &[u8]) {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .mmio_write(
+                MmioAddress(MMIO_ADDR + VIRTIO_MMIO_CONFIG as u64 + offset),
+                data,
+            )
+            .unwrap();
+    }
+
+    /// Bring up the device, following the virtio-mmio driver initialization sequence.
+    ///
+    /// # Arguments
+    ///
+    /// * `features` - The features supported by the driver.
+    ///
+    /// # Returns
+    ///
+    /// The negotiated features.
+    pub fn init(&mut self, features: u64) -> u64 {
+        // Check the device identification.
+        assert_eq!(self.read(VIRTIO_MMIO_MAGIC_VALUE), MMIO_MAGIC_VALUE);
+        assert_eq!(self.read(VIRTIO_MMIO_VERSION), MMIO_VERSION);
+
+        // Reset the device and acknowledge it.
+        let mut status = 0;
+        self.write(VIRTIO_MMIO_STATUS, status);
+        status |= VIRTIO_CONFIG_S_ACKNOWLEDGE;
+        self.write(VIRTIO_MMIO_STATUS, status);
+        status |= VIRTIO_CONFIG_S_DRIVER;
+        self.write(VIRTIO_MMIO_STATUS, status);
+
+        // Negotiate the features.
+        let mut device_features = 0;
+        for sel in 0..2 {
+            self.write(VIRTIO_MMIO_DEVICE_FEATURES_SEL, sel);
+            device_features |= (self.read(VIRTIO_MMIO_DEVICE_FEATURES) as u64) << (32 * sel);
+        }
+        let features = device_features & features;
+        for sel in 0..2 {
+            self.write(VIRTIO_MMIO_DRIVER_FEATURES_SEL, sel);
+            self.write(VIRTIO_MMIO_DRIVER_FEATURES, (features >> (32 * sel)) as u32);
+        }
+        status |= VIRTIO_CONFIG_S_FEATURES_OK;
+        self.write(VIRTIO_MMIO_STATUS, status);
+        assert_ne!(
+            self.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FEATURES_OK,
+            0
+        );
+
+        // Set up every queue exposed by the device.
+        self.queues.clear();
+        for index in 0.. {
+            self.write(VIRTIO_MMIO_QUEUE_SEL, index as u32);
+            let max_size = self.read(VIRTIO_MMIO_QUEUE_NUM_MAX);
+            if max_size == 0 {
+                break;
+            }
+
+            let queue = VirtQueue::new(index, QUEUE_SIZE.min(max_size as u16));
+            self.write(VIRTIO_MMIO_QUEUE_NUM, queue.size as u32);
+            self.write(VIRTIO_MMIO_QUEUE_DESC_LOW, queue.desc_table as u32);
+            self.write(VIRTIO_MMIO_QUEUE_DESC_HIGH, (queue.desc_table >> 32) as u32);
+            self.write(VIRTIO_MMIO_QUEUE_AVAIL_LOW, queue.avail_ring as u32);
+            self.write(
+                VIRTIO_MMIO_QUEUE_AVAIL_HIGH,
+                (queue.avail_ring >> 32) as u32,
+            );
+            self.write(VIRTIO_MMIO_QUEUE_USED_LOW, queue.used_ring as u32);
+            self.write(VIRTIO_MMIO_QUEUE_USED_HIGH, (queue.used_ring >> 32) as u32);
+            self.write(VIRTIO_MMIO_QUEUE_READY, 1);
+            self.queues.push(queue);
+        }
+
+        // The device registers its handlers within the event manager on activation, so the
+        // event manager loop must be running by then.
+        self.start_event_loop();
+
+        // Activate the device.
+        status |= VIRTIO_CONFIG_S_DRIVER_OK;
+        self.write(VIRTIO_MMIO_STATUS, status);
+
+        features
+    }
+
+    /// Add a descriptor chain to a queue and notify the device through its ioeventfd.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The queue index.
+    /// * `buffers` - The address, length and device writable flag of each buffer.
+    ///
+    /// # Returns
+    ///
+    /// The head descriptor index of the chain.
+    pub fn submit(&mut self, index: usize, buffers: &[(u64, u32, bool)]) -> u16 {
+        let head = self.queues[index].add_chain(&self.mem, buffers);
+        self.kick(index);
+        head
+    }
+
+    /// Notify the device about new buffers on a queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The queue index.
+    pub fn kick(&self, index: usize) {
+        // The write is consumed by the queue ioeventfd, never reaching the I/O client.
+        self.dm
+            .mmio_write(MMIO_ADDR + VIRTIO_MMIO_QUEUE_NOTIFY as u64, 4, index as u64)
+            .unwrap();
+    }
+
+    /// Wait for the device to return a chain on a queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The queue index.
+    ///
+    /// # Returns
+    ///
+    /// The head descriptor index and the written length of the used chain.
+    pub fn wait_used(&mut self, index: usize) -> Option<(u16, u32)> {
+        self.queues[index].wait_used(&self.mem, MOCK_IO_TIMEOUT)
+    }
+
+    /// Acknowledge the pending interrupts.
+    ///
+    /// # Returns
+    ///
+    /// The acknowledged interrupt status.
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(VIRTIO_MMIO_INTERRUPT_STATUS);
+        self.write(VIRTIO_MMIO_INTERRUPT_ACK, status);
+        status
+    }
+
+    /// Run the event manager loop on a separate thread.
+    fn start_event_loop(&mut self) {
+        if self.event_loop.is_some() {
+            return;
+        }
+
+        let event_manager = self.event_manager.clone();
+        let stop = self.stop.clone();
+        self.event_loop = Some(thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                event_manager.lock().unwrap().run_with_timeout(10).unwrap();
+            }
+        }));
+    }
+}
+
+impl Drop for VirtioMmioDriver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(event_loop) = self.event_loop.take() {
+            event_loop.join().unwrap();
+        }
+    }
+}
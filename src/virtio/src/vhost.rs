@@ -1,4 +1,4 @@
-use api::error::Result;
+use api::error::{Error, Result};
 use std::sync::Arc;
 use vhost::VhostUserMemoryRegionInfo;
 use vhost_user_frontend::GuestMemoryMmap;
@@ -27,7 +27,7 @@ impl VhostKernelCommon {
                 Ok(region) => region,
                 Err(e) => {
                     println!("Failed to create memory region: {:?}", e);
-                    panic!("Failed to create memory region: {:?}", e);
+                    return Err(Error::VhostUserMemoryRegion);
                 }
             };
             regions.push(region);
@@ -35,7 +35,7 @@ impl VhostKernelCommon {
 
         if regions.is_empty() {
             println!("No memory regions found");
-            panic!("No memory regions found");
+            return Err(Error::VhostUserMemoryRegion);
         }
 
         Ok(regions)
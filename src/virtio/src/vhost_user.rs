@@ -1,32 +1,56 @@
 use super::device::SingleFdSignalQueue;
+use super::mmio::{VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING};
 use std::io::Result as IoResult;
+use std::sync::atomic::Ordering;
+use vhost::vhost_user::message::VhostUserProtocolFeatures;
 use vhost_user_frontend::{VirtioInterrupt, VirtioInterruptType};
 use vmm_sys_util::eventfd::EventFd;
 
+/// Protocol features requested from the vhost-user backends.
+///
+/// The `CONFIG` feature lets the frontend access the backend configuration space, while the
+/// `BACKEND_REQ` feature opens the backend request channel, through which the backend announces
+/// configuration changes.
+pub const VHOST_USER_PROTOCOL_FEATURES: VhostUserProtocolFeatures =
+    VhostUserProtocolFeatures::CONFIG.union(VhostUserProtocolFeatures::BACKEND_REQ);
+
 impl VirtioInterrupt for SingleFdSignalQueue {
     /// Implementation of the trigger method of the VirtioInterrupt trait for BaoInterrupt.
     ///
     /// # Arguments
     ///
-    /// * `_int_type` - The type of the interrupt (Used Buffer or Configuration Change Notification).
+    /// * `int_type` - The type of the interrupt (Used Buffer or Configuration Change Notification).
     ///
     /// # Return
     ///
     /// * `IoResult<()>` - An IoResult containing Ok(()) on success, or an Error on failure.
-    fn trigger(&self, _int_type: VirtioInterruptType) -> IoResult<()> {
-        Ok(())
+    fn trigger(&self, int_type: VirtioInterruptType) -> IoResult<()> {
+        // Set the interrupt status.
+        let status = match int_type {
+            VirtioInterruptType::Config => VIRTIO_MMIO_INT_CONFIG,
+            VirtioInterruptType::Queue(_) => VIRTIO_MMIO_INT_VRING,
+        };
+        self.interrupt_status.fetch_or(status, Ordering::SeqCst);
+
+        // Write to the eventfd to signal the driver.
+        self.irqfd.write(1)
     }
 
     /// Implementation of the notifier method of the VirtioInterrupt trait for BaoInterrupt.
     ///
     /// # Arguments
     ///
-    /// * `_int_type` - The type of the interrupt (Used Buffer or Configuration Change Notification).
+    /// * `int_type` - The type of the interrupt (Used Buffer or Configuration Change Notification).
     ///
     /// # Return
     ///
     /// * `Option<EventFd>` - An Option containing the EventFd associated with the interrupt.
-    fn notifier(&self, _int_type: VirtioInterruptType) -> Option<EventFd> {
-        Some(self.irqfd.try_clone().unwrap())
+    fn notifier(&self, int_type: VirtioInterruptType) -> Option<EventFd> {
+        match int_type {
+            // The configuration changes announced by the backend must go through `trigger`,
+            // otherwise the driver would not find the `CONFIG` bit in the interrupt status.
+            VirtioInterruptType::Config => None,
+            VirtioInterruptType::Queue(_) => self.irqfd.try_clone().ok(),
+        }
     }
 }
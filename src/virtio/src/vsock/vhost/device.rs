@@ -1,9 +1,8 @@
 use crate::device::clone_queue;
 use crate::device::VirtioDeviceT;
 use crate::device::{VirtioDevType, VirtioDeviceCommon};
-use crate::mmio::VIRTIO_MMIO_INT_VRING;
 use crate::vhost::{VhostKernelCommon, VHOST_FEATURES};
-use api::device_model::BaoDeviceModel;
+use api::device_model::DeviceModelT;
 use api::error::{Error, Result};
 use api::types::DeviceConfig;
 use event_manager::{EventManager, MutEventSubscriber};
@@ -46,35 +45,38 @@ impl VirtioDeviceT for VhostVsockDevice {
         config: &DeviceConfig,
         device_manager: Arc<Mutex<IoManager>>,
         _event_manager: Option<Arc<Mutex<EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>>>>,
-        device_model: Arc<Mutex<BaoDeviceModel>>,
+        device_model: Arc<dyn DeviceModelT>,
     ) -> Result<Arc<Mutex<Self>>> {
         // Extract the generic features and queues.
-        let (common_features, queues) = Self::initialize(&config).unwrap();
+        let (common_features, queues) = Self::initialize(&config)?;
 
         // Extract the device features.
-        let device_features = Self::device_features(&config).unwrap();
+        let device_features = Self::device_features(&config)?;
 
         // Update the configuration space.
-        let config_space = Self::config_space(&config).unwrap();
+        let config_space = Self::config_space(&config)?;
 
         // Create a VirtioConfig object.
         let virtio_cfg = VirtioConfig::new(common_features | device_features, queues, config_space);
 
         // Create the generic device.
-        let mut common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg).unwrap();
+        let common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg)?;
 
         // Extract the VirtioDeviceCommon MMIO range.
         let range = common_device.mmio.range;
 
         // Create the Vsock kernel device.
-        let vsock_kernel = Vsock::new(Arc::new(common_device.mem())).unwrap();
+        let vsock_kernel =
+            Vsock::new(Arc::new(common_device.mem()?)).map_err(Error::VhostBackend)?;
 
         // Create the vsock device.
         let vsock = Arc::new(Mutex::new(VhostVsockDevice {
             virtio: common_device,
-            vhost: VhostKernelCommon::new(device_features).unwrap(),
+            vhost: VhostKernelCommon::new(device_features)?,
             vsock: vsock_kernel,
-            guest_cid: config.guest_cid.unwrap() as u32,
+            guest_cid: config
+                .guest_cid
+                .ok_or(Error::MissingConfigField("guest_cid"))? as u32,
         }));
 
         // Register the MMIO device within the device manager with the specified range.
@@ -82,7 +84,7 @@ impl VirtioDeviceT for VhostVsockDevice {
             .lock()
             .unwrap()
             .register_mmio(range, vsock.clone())
-            .unwrap();
+            .map_err(|_| Error::MmioConfig)?;
 
         // Return the vosck device.
         Ok(vsock)
@@ -94,7 +96,10 @@ impl VirtioDeviceT for VhostVsockDevice {
 
     fn config_space(config: &DeviceConfig) -> Result<Vec<u8>> {
         // Retrieve the guest CID from the device configuration space.
-        Ok(config.guest_cid.unwrap().to_le_bytes().to_vec())
+        let guest_cid = config
+            .guest_cid
+            .ok_or(Error::MissingConfigField("guest_cid"))?;
+        Ok(guest_cid.to_le_bytes().to_vec())
     }
 }
 
@@ -124,7 +129,7 @@ impl VirtioDeviceActions for VhostVsockDevice {
     // For that reasosn, it is the right place to perform the device initialization.
     fn activate(&mut self) -> Result<()> {
         // Setup the ioeventfds by calling the generic `prepare_activate` method.
-        let ioevents = self.virtio.prepare_activate().unwrap();
+        let ioevents = self.virtio.prepare_activate()?;
 
         // Format the queues and ioevents into a Vec<(usize, Queue, EventFd)>.
         let queues = self
@@ -139,20 +144,20 @@ impl VirtioDeviceActions for VhostVsockDevice {
             .collect::<Vec<_>>();
 
         // Set the current process as the owner of the file descriptor.
-        self.vsock.set_owner().unwrap();
+        self.vsock.set_owner().map_err(Error::VhostBackend)?;
 
         // Get the device features.
-        let supported_backend_features = self.vsock.get_features().unwrap();
+        let supported_backend_features = self.vsock.get_features().map_err(Error::VhostBackend)?;
 
         // Set the device features.
         self.vsock
             .set_features(self.vhost.features() & supported_backend_features)
-            .unwrap();
+            .map_err(Error::VhostBackend)?;
 
         // Update the memory table.
         self.vsock
-            .set_mem_table(self.vhost.memory(self.vsock.mem()).unwrap().as_slice())
-            .unwrap();
+            .set_mem_table(self.vhost.memory(self.vsock.mem())?.as_slice())
+            .map_err(Error::VhostBackend)?;
 
         // Set the vring.
         let mem = self.vsock.mem();
@@ -162,7 +167,7 @@ impl VirtioDeviceActions for VhostVsockDevice {
             // Set the vring num.
             self.vsock
                 .set_vring_num(*queue_index, queue.size())
-                .unwrap();
+                .map_err(Error::VhostBackend)?;
 
             let config_data = VringConfigData {
                 queue_max_size: queue.max_size(),
@@ -175,32 +180,41 @@ impl VirtioDeviceActions for VhostVsockDevice {
             };
 
             // Set the vring base.
+            let avail_idx = queue
+                .avail_idx(mem_aux, Ordering::Acquire)
+                .map_err(|_| Error::MmapGuestMemoryFailed)?;
             self.vsock
-                .set_vring_base(
-                    *queue_index,
-                    queue.avail_idx(mem_aux, Ordering::Acquire).unwrap().0,
-                )
-                .unwrap();
+                .set_vring_base(*queue_index, avail_idx.0)
+                .map_err(Error::VhostBackend)?;
 
             // Set the vring address.
             self.vsock
                 .set_vring_addr(*queue_index, &config_data)
-                .unwrap();
+                .map_err(Error::VhostBackend)?;
 
             // Set the vring call.
+            let irqfd = self
+                .virtio
+                .irqfd
+                .try_clone()
+                .map_err(Error::EventFdCreateFailed)?;
             self.vsock
-                .set_vring_call(*queue_index, &self.virtio.irqfd.try_clone().unwrap())
-                .unwrap();
+                .set_vring_call(*queue_index, &irqfd)
+                .map_err(Error::VhostBackend)?;
 
             // Set the vring kick.
-            self.vsock.set_vring_kick(*queue_index, ioeventfd).unwrap();
+            self.vsock
+                .set_vring_kick(*queue_index, ioeventfd)
+                .map_err(Error::VhostBackend)?;
         }
 
         // Set the guest CID.
-        self.vsock.set_guest_cid(self.guest_cid as u64).unwrap();
+        self.vsock
+            .set_guest_cid(self.guest_cid as u64)
+            .map_err(Error::VhostBackend)?;
 
         // Start the vsock device.
-        self.vsock.start().unwrap();
+        self.vsock.start().map_err(Error::VhostBackend)?;
 
         // Set the device as activated.
         self.virtio.config.device_activated = true;
@@ -209,8 +223,20 @@ impl VirtioDeviceActions for VhostVsockDevice {
     }
 
     fn reset(&mut self) -> Result<()> {
-        // Not implemented for now.
-        Ok(())
+        // The driver also resets the device before the first activation, in which case there
+        // is no backend to stop.
+        if !self.virtio.config.device_activated {
+            return self.virtio.reset();
+        }
+
+        // Stop the vsock device.
+        self.vsock.stop().map_err(Error::VhostBackend)?;
+
+        // Release the ownership of the vhost device, so it can be set up again on activation.
+        self.vsock.reset_owner().map_err(Error::VhostBackend)?;
+
+        // Reset the generic device.
+        self.virtio.reset()
     }
 
     // This method is called when the driver needs to read the interrupt status from the device.
@@ -218,12 +244,7 @@ impl VirtioDeviceActions for VhostVsockDevice {
     // dedicated logic to update the interrupt status accordingly (Used Buffer Notification or Configuration Change Notification).
     // Note: If the device is implemented in the VMM, the interrupt status can be managed and updated directly by the device.
     fn interrupt_status(&self) -> &Arc<AtomicU8> {
-        // We assume that all the interrupts are Used Buffer Notifications.
-        self.virtio
-            .config
-            .interrupt_status
-            .fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
-        &self.virtio.config.interrupt_status
+        self.virtio.backend_interrupt_status()
     }
 }
 
@@ -239,10 +260,12 @@ impl VirtioMmioDevice for VhostVsockDevice {
 /// Otherwise we could not register the device within the device manager.
 impl MutDeviceMmio for VhostVsockDevice {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.virtio.update_device_status();
         self.read(offset, data);
     }
 
     fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
         self.write(offset, data);
+        self.virtio.update_device_status();
     }
 }
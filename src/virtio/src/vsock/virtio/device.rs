@@ -1,9 +1,9 @@
 use super::packet_handler::VsockPacketHandler;
 use super::queue_handler::QueueHandler;
 use crate::device::clone_queue;
-use crate::device::{SingleFdSignalQueue, Subscriber, VirtioDeviceT};
+use crate::device::{Subscriber, VirtioDeviceT};
 use crate::device::{VirtioDevType, VirtioDeviceCommon};
-use api::device_model::BaoDeviceModel;
+use api::device_model::DeviceModelT;
 use api::error::{Error, Result};
 use api::types::DeviceConfig;
 use event_manager::{
@@ -23,10 +23,12 @@ use vm_device::MutDeviceMmio;
 ///
 /// * `common` - Virtio common device.
 /// * `endpoint` - The remote subscriber endpoint.
+/// * `sub_ids` - The IDs of the subscribers registered within the `EventManager`.
 /// * `guest_cid` - The guest CID.
 pub struct VirtioVsock {
     pub common: VirtioDeviceCommon,
     pub endpoint: RemoteEndpoint<Subscriber>,
+    pub sub_ids: Vec<SubscriberId>,
     pub guest_cid: u64,
 }
 
@@ -35,42 +37,49 @@ impl VirtioDeviceT for VirtioVsock {
         config: &DeviceConfig,
         device_manager: Arc<Mutex<IoManager>>,
         event_manager: Option<Arc<Mutex<EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>>>>,
-        device_model: Arc<Mutex<BaoDeviceModel>>,
+        device_model: Arc<dyn DeviceModelT>,
     ) -> Result<Arc<Mutex<Self>>> {
         // Extract the generic features and queues.
-        let (common_features, queues) = Self::initialize(&config).unwrap();
+        let (common_features, queues) = Self::initialize(&config)?;
 
         // Update the device features.
-        let device_features = common_features | Self::device_features(&config).unwrap();
+        let device_features = common_features | Self::device_features(&config)?;
 
         // Update the configuration space.
-        let config_space = Self::config_space(&config).unwrap();
+        let config_space = Self::config_space(&config)?;
 
         // Create a VirtioConfig object.
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
         // Create the generic device.
-        let common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg).unwrap();
+        let common_device = VirtioDeviceCommon::new(config, device_model, virtio_cfg)?;
+
+        // Extract the VirtioDeviceCommon MMIO range.
+        let range = common_device.mmio.range;
 
         // Create a remote endpoint object, that allows interacting with the VM EventManager from a different thread.
-        let remote_endpoint = event_manager.unwrap().lock().unwrap().remote_endpoint();
+        let remote_endpoint = event_manager
+            .ok_or(Error::EventManagerNotFound)?
+            .lock()
+            .unwrap()
+            .remote_endpoint();
 
         // Create the vsock device.
         let vsock = Arc::new(Mutex::new(VirtioVsock {
             common: common_device,
             endpoint: remote_endpoint,
-            guest_cid: config.guest_cid.unwrap(),
+            sub_ids: Vec::new(),
+            guest_cid: config
+                .guest_cid
+                .ok_or(Error::MissingConfigField("guest_cid"))?,
         }));
 
         // Register the MMIO device within the device manager with the specified range.
         device_manager
             .lock()
             .unwrap()
-            .register_mmio(
-                vsock.clone().lock().unwrap().common.mmio.range,
-                vsock.clone(),
-            )
-            .unwrap();
+            .register_mmio(range, vsock.clone())
+            .map_err(|_| Error::MmioConfig)?;
 
         // Return the vsock device.
         Ok(vsock)
@@ -109,13 +118,10 @@ impl VirtioDeviceActions for VirtioVsock {
 
     fn activate(&mut self) -> Result<()> {
         // Create the driver notify object.
-        let driver_notify = SingleFdSignalQueue {
-            irqfd: self.common.irqfd.try_clone().unwrap(),
-            interrupt_status: self.common.config.interrupt_status.clone(),
-        };
+        let driver_notify = self.common.driver_notify()?;
 
         // Prepare the activation by calling the generic `prepare_activate` method.
-        let ioevents = self.common.prepare_activate().unwrap();
+        let ioevents = self.common.prepare_activate()?;
 
         // Clone the queues.
         let queues = self
@@ -129,7 +135,7 @@ impl VirtioDeviceActions for VirtioVsock {
         // Create the inner handler.
         let inner = VsockPacketHandler {
             driver_notify,
-            mem: self.common.mem(),
+            mem: self.common.mem()?,
             queues: queues,
         };
 
@@ -139,15 +145,15 @@ impl VirtioDeviceActions for VirtioVsock {
             ioeventfd: ioevents,
         }));
 
-        // Register the queue handler with the `EventManager`. We could record the `sub_id`
-        // (and/or keep a handler clone) for further interaction (i.e. to remove the subscriber at
-        // a later time, retrieve state, etc).
-        let _sub_id = self
+        // Register the queue handler with the `EventManager`. We record the `sub_id` to remove
+        // the subscriber when the device is reset.
+        let sub_id = self
             .endpoint
             .call_blocking(move |mgr| -> EvmgrResult<SubscriberId> {
                 Ok(mgr.add_subscriber(handler))
             })
-            .unwrap();
+            .map_err(Error::EventManager)?;
+        self.sub_ids.push(sub_id);
 
         // Set the device as activated.
         self.common.config.device_activated = true;
@@ -156,8 +162,17 @@ impl VirtioDeviceActions for VirtioVsock {
     }
 
     fn reset(&mut self) -> Result<()> {
-        // Not implemented for now.
-        Ok(())
+        // Remove the queue handlers from the `EventManager`.
+        for sub_id in self.sub_ids.drain(..) {
+            self.endpoint
+                .call_blocking(move |mgr| -> EvmgrResult<()> {
+                    mgr.remove_subscriber(sub_id).map(|_| ())
+                })
+                .map_err(Error::EventManager)?;
+        }
+
+        // Reset the generic device.
+        self.common.reset()
     }
 }
 
@@ -172,10 +187,12 @@ impl VirtioMmioDevice for VirtioVsock {
 /// Otherwise we could not register the device within the device manager.
 impl MutDeviceMmio for VirtioVsock {
     fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        self.common.update_device_status();
         self.read(offset, data);
     }
 
     fn mmio_write(&mut self, _base: MmioAddress, offset: u64, data: &[u8]) {
         self.write(offset, data);
+        self.common.update_device_status();
     }
 }
@@ -1,7 +1,7 @@
 use crate::device::SignalUsedQueue;
-use api::error::{Error, Result};
+use std::result;
 use virtio_queue::{DescriptorChain, Queue, QueueOwnedT, QueueT};
-use virtio_vsock::packet::VsockPacket;
+use virtio_vsock::packet::{Error as PacketError, VsockPacket};
 use vm_memory::bitmap::AtomicBitmap;
 
 type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
@@ -28,13 +28,12 @@ where
         &mut self,
         mut chain: DescriptorChain<&GuestMemoryMmap>,
         queue_index: usize,
-    ) -> Result<()> {
+    ) -> result::Result<(), Error> {
         let vsock_packet;
         match queue_index {
             RX_VIRTQ => {
                 vsock_packet =
-                    VsockPacket::from_rx_virtq_chain(&self.mem, &mut chain, MAX_PKT_BUF_SIZE)
-                        .unwrap();
+                    VsockPacket::from_rx_virtq_chain(&self.mem, &mut chain, MAX_PKT_BUF_SIZE)?;
                 /*
                 // Write data to the packet, using the setters.
                 vsock_packet.set_src_cid(SRC_CID)
@@ -54,28 +53,23 @@ where
             }
             TX_VIRTQ => {
                 vsock_packet =
-                    VsockPacket::from_rx_virtq_chain(&self.mem, &mut chain, MAX_PKT_BUF_SIZE)
-                        .unwrap();
+                    VsockPacket::from_tx_virtq_chain(&self.mem, &mut chain, MAX_PKT_BUF_SIZE)?;
                 if vsock_packet.op() == OP_RW {
                     // Send the packet payload to the backend.
                 }
             }
-            _ => {
-                println!("Invalid queue index: {}", queue_index);
-                return Err(Error::DeviceNotFound);
-            }
+            _ => return Err(Error::InvalidQueue(queue_index)),
         }
 
         // Add the used descriptor to the queue.
-        self.queues[queue_index]
-            .add_used(chain.memory(), chain.head_index(), vsock_packet.len())
-            .unwrap();
+        self.queues[queue_index].add_used(
+            chain.memory(),
+            chain.head_index(),
+            vsock_packet.len(),
+        )?;
 
         // Signal the driver, if needed.
-        if self.queues[queue_index]
-            .needs_notification(chain.memory())
-            .unwrap()
-        {
+        if self.queues[queue_index].needs_notification(chain.memory())? {
             self.driver_notify.signal_used_queue(0);
         }
 
@@ -91,29 +85,24 @@ where
     /// # Returns
     ///
     /// * `()` - Ok if the queue was processed successfully.
-    pub fn process_queue(&mut self, queue_index: usize) -> Result<()> {
+    pub fn process_queue(&mut self, queue_index: usize) -> result::Result<(), Error> {
+        if queue_index >= self.queues.len() {
+            return Err(Error::InvalidQueue(queue_index));
+        }
+
         // To see why this is done in a loop, please look at the `Queue::enable_notification`
         // comments in `virtio_queue`.
         loop {
             // Disable the notifications.
-            self.queues[queue_index]
-                .disable_notification(&self.mem)
-                .unwrap();
+            self.queues[queue_index].disable_notification(&self.mem)?;
 
             // Process the queue.
-            while let Some(chain) = self.queues[queue_index]
-                .iter(&self.mem.clone())
-                .unwrap()
-                .next()
-            {
+            while let Some(chain) = self.queues[queue_index].iter(&self.mem.clone())?.next() {
                 self.process_chain(chain, queue_index)?;
             }
 
             // Enable the notifications.
-            if !self.queues[queue_index]
-                .enable_notification(&self.mem)
-                .unwrap()
-            {
+            if !self.queues[queue_index].enable_notification(&self.mem)? {
                 break;
             }
         }
@@ -121,3 +110,98 @@ where
         Ok(())
     }
 }
+
+#[derive(Debug)]
+pub enum Error {
+    Queue(virtio_queue::Error),
+    Packet(PacketError),
+    InvalidQueue(usize),
+}
+
+impl From<virtio_queue::Error> for Error {
+    fn from(e: virtio_queue::Error) -> Self {
+        Error::Queue(e)
+    }
+}
+
+impl From<PacketError> for Error {
+    fn from(e: PacketError) -> Self {
+        Error::Packet(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::SingleFdSignalQueue;
+    use crate::test_utils::{VirtQueue, VirtioMmioDriver, DATA_ADDR, QUEUE_SIZE};
+    use std::sync::atomic::{AtomicBool, AtomicU8};
+    use std::sync::Arc;
+    use vm_memory::{Bytes, GuestAddress};
+    use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+    // Size of the `virtio_vsock_hdr` header.
+    const HDR_LEN: u32 = 44;
+    // Offsets of the `len` and `op` fields within the header.
+    const HDR_LEN_OFFSET: u64 = 24;
+    const HDR_OP_OFFSET: u64 = 30;
+
+    #[test]
+    fn test_vsock_packets() {
+        let driver = VirtioMmioDriver::new();
+        let mut rxq = VirtQueue::new(RX_VIRTQ as u16, QUEUE_SIZE);
+        let mut txq = VirtQueue::new(TX_VIRTQ as u16, QUEUE_SIZE);
+        let evq = VirtQueue::new(2, QUEUE_SIZE);
+
+        let mut handler = VsockPacketHandler {
+            driver_notify: SingleFdSignalQueue {
+                irqfd: EventFd::new(EFD_NONBLOCK).unwrap(),
+                interrupt_status: Arc::new(AtomicU8::new(0)),
+                needs_reset: Arc::new(AtomicBool::new(false)),
+            },
+            mem: driver.mem.clone(),
+            queues: vec![rxq.to_queue(), txq.to_queue(), evq.to_queue()],
+        };
+
+        // Transmit a RW packet, with the header and the payload on separate descriptors.
+        let payload = b"ping";
+        let hdr = DATA_ADDR;
+        let data = DATA_ADDR + 0x100;
+        driver
+            .mem
+            .write_slice(&[0u8; HDR_LEN as usize], GuestAddress(hdr))
+            .unwrap();
+        driver
+            .mem
+            .write_obj(payload.len() as u32, GuestAddress(hdr + HDR_LEN_OFFSET))
+            .unwrap();
+        driver
+            .mem
+            .write_obj(OP_RW, GuestAddress(hdr + HDR_OP_OFFSET))
+            .unwrap();
+        driver.mem.write_slice(payload, GuestAddress(data)).unwrap();
+        let head = txq.add_chain(
+            &driver.mem,
+            &[(hdr, HDR_LEN, false), (data, payload.len() as u32, false)],
+        );
+        handler.process_queue(TX_VIRTQ).unwrap();
+        assert_eq!(
+            txq.next_used(&driver.mem),
+            Some((head, payload.len() as u32))
+        );
+
+        // Hand a receive buffer over to the device.
+        let head = rxq.add_chain(
+            &driver.mem,
+            &[
+                (DATA_ADDR + 0x1000, HDR_LEN, true),
+                (DATA_ADDR + 0x1100, 0x1000, true),
+            ],
+        );
+        handler.process_queue(RX_VIRTQ).unwrap();
+        assert_eq!(rxq.next_used(&driver.mem).map(|used| used.0), Some(head));
+
+        // The driver got notified about the used buffers.
+        assert!(handler.driver_notify.irqfd.read().unwrap() > 0);
+    }
+}
@@ -1,5 +1,5 @@
 use super::packet_handler::VsockPacketHandler;
-use crate::device::SingleFdSignalQueue;
+use crate::device::{SignalUsedQueue, SingleFdSignalQueue};
 use event_manager::{EventOps, Events, MutEventSubscriber};
 use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
@@ -25,22 +25,29 @@ impl MutEventSubscriber for QueueHandler {
             println!("unexpected event_set");
         } else if events.data() as usize >= self.ioeventfd.len() {
             println!("unexpected events data {}", events.data());
+        } else if self.ioeventfd[events.data() as usize].read().is_err() {
+            println!("ioeventfd read error")
         } else if let Err(e) = self.inner.process_queue(events.data() as usize) {
-            println!("error processing block queue {:?}", e);
+            println!("error processing vsock queue {:?}", e);
         } else {
             error = false;
         }
 
+        // The queue can no longer be serviced, so the driver must reset the device.
         if error {
-            ops.remove(events)
-                .expect("Failed to remove fd from event handling loop");
+            self.inner.driver_notify.signal_needs_reset();
+            if let Err(e) = ops.remove(events) {
+                println!("Failed to remove fd from event handling loop: {:?}", e);
+            }
         }
     }
 
     fn init(&mut self, ops: &mut EventOps) {
-        for (index, ioeventfd) in self.ioeventfd.drain(..).enumerate() {
-            ops.add(Events::with_data(&ioeventfd, index as u32, EventSet::IN))
-                .expect("Failed to init block queue handler");
+        for (index, ioeventfd) in self.ioeventfd.iter().enumerate() {
+            if let Err(e) = ops.add(Events::with_data(ioeventfd, index as u32, EventSet::IN)) {
+                println!("Failed to init vsock queue handler: {:?}", e);
+                self.inner.driver_notify.signal_needs_reset();
+            }
         }
     }
 }
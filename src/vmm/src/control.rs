@@ -0,0 +1,182 @@
+use api::error::{Error, Result};
+use api::types::ControlRequest;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::{self, Builder};
+
+use super::vm::Vm;
+
+/// Control server, serving the management requests sent to a Unix socket.
+///
+/// Every request is a single line holding a YAML (or JSON) mapping, e.g.
+/// `{command: block_resize, id: 0, mmio_addr: 0xa003e00, size: 2147483648}`, and is answered
+/// by a single line, either `ok` (followed by the returned data, if any) or `error: <reason>`.
+///
+/// # Attributes
+///
+/// * `listener` - The control socket.
+/// * `vms` - The VMs targeted by the control requests.
+pub struct ControlServer {
+    listener: UnixListener,
+    vms: Vec<Arc<Vm>>,
+}
+
+impl ControlServer {
+    /// Create a new control server.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the control socket.
+    /// * `vms` - The VMs targeted by the control requests.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the control server.
+    pub fn new<P: AsRef<Path>>(path: P, vms: Vec<Arc<Vm>>) -> Result<Self> {
+        // Remove the socket left behind by a previous instance (but nothing else).
+        if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            fs::remove_file(&path).map_err(Error::ControlSocket)?;
+        }
+
+        // Bind the control socket, which only the owner may connect to.
+        let listener = UnixListener::bind(&path).map_err(Error::ControlSocket)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+            .map_err(Error::ControlSocket)?;
+
+        Ok(ControlServer { listener, vms })
+    }
+
+    /// Run the control server.
+    ///
+    /// # Note
+    ///
+    /// Every connection is served on its own thread, so an idle management client does not hold
+    /// back the other ones.
+    pub fn run(&self) {
+        thread::scope(|scope| {
+            for stream in self.listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("Control connection failed: {:?}", e);
+                        continue;
+                    }
+                };
+
+                let spawned = Builder::new()
+                    .name("vmm_control_conn".to_string())
+                    .spawn_scoped(scope, move || {
+                        if let Err(e) = self.serve(stream) {
+                            println!("Control connection failed: {:?}", e);
+                        }
+                    });
+                if let Err(e) = spawned {
+                    println!("Failed to spawn the control connection thread: {:?}", e);
+                }
+            }
+        });
+    }
+
+    /// Serve the control requests of a connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The connection.
+    ///
+    /// # Returns
+    ///
+    /// An `io::Result` containing the result of the operation.
+    fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match self.handle(&line) {
+                Ok(None) => writeln!(writer, "ok")?,
+                Ok(Some(data)) => writeln!(writer, "ok {}", data)?,
+                Err(e) => writeln!(writer, "error: {}", e)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle a control request.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The control request.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the data returned by the request (if any).
+    fn handle(&self, line: &str) -> Result<Option<String>> {
+        // Parse the request.
+        let request = ControlRequest::parse(line)?;
+
+        // Forward the request to the targeted VM.
+        self.vms
+            .iter()
+            .find(|vm| vm.id as u32 == request.id())
+            .ok_or(Error::VmNotFound(request.id()))?
+            .control(&request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::mock::MockDeviceModel;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_control_server() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("control.sock");
+
+        let dm = Arc::new(MockDeviceModel::new(0, 0, 0x10_0000, 47).unwrap());
+        let vm = Arc::new(Vm::new(dm, Vec::new()).unwrap());
+
+        // A stale socket is replaced.
+        drop(UnixListener::bind(&path).unwrap());
+        let server = ControlServer::new(&path, vec![vm]).unwrap();
+        thread::spawn(move || server.run());
+
+        // The socket is only accessible to its owner.
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        // An idle connection does not hold back the other ones.
+        let _idle = UnixStream::connect(&path).unwrap();
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request = |line: &str| {
+            writeln!(writer, "{}", line).unwrap();
+            let mut reply = String::new();
+            reader.read_line(&mut reply).unwrap();
+            reply
+        };
+
+        // The requests are answered line by line, in YAML or JSON.
+        assert!(
+            request("{command: net_link, id: 0, mmio_addr: 0xa003e00, up: true}")
+                .starts_with("error: No device at MMIO address 0xa003e00 of VM 0")
+        );
+        assert!(
+            request(r#"{"command": "net_link", "id": 1, "mmio_addr": 0, "up": true}"#)
+                .starts_with("error: No VM 1")
+        );
+        assert!(request("{command: reboot, id: 0}").starts_with("error: Invalid control request"));
+    }
+}
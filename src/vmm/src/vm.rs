@@ -1,9 +1,11 @@
 use api::defines::{BAO_IO_READ, BAO_IO_WRITE};
-use api::device_model::BaoDeviceModel;
+use api::device_model::DeviceModelT;
 use api::error::{Error, Result};
-use api::types::DeviceConfig;
+use api::types::{BaoIoRequest, ControlRequest, DeviceConfig};
 use event_manager::{EventManager, MutEventSubscriber};
-use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::Builder;
 use virtio::block::virtio::device::VirtioBlock;
 use virtio::console::virtio::device::VirtioConsole;
 use virtio::device::VirtioDeviceT;
@@ -16,20 +18,24 @@ use virtio::vsock::vhost_user::device::VhostUserVsock;
 use vm_device::bus::MmioAddress;
 use vm_device::device_manager::{IoManager, MmioManager};
 
+/// Page size used to align the shared memory slices of the devices.
+const PAGE_SIZE: u64 = 0x1000;
+
 /// Vm abstraction.
 ///
 /// # Attributes
 ///
 /// * `id` - The ID of the VM.
 /// * `device_model` - The device model.
-/// * `devices` - The list of devices.
-/// * `device_manager` - The device manager responsible for providing methods for device registration, as well as for dispatching read and write requests.
+/// * `devices` - The devices, indexed by MMIO address.
+/// * `device_manager` - The device manager responsible for dispatching read and write requests. It
+///   only needs shared access, so the requests are serialized by the lock of each device.
 /// * `event_manager` - The event manager responsible for handling and dispatch the device events.
 pub struct Vm {
     pub id: u16,
-    device_model: Arc<Mutex<BaoDeviceModel>>,
-    devices: Vec<VirtioDeviceType>,
-    device_manager: Arc<Mutex<IoManager>>,
+    device_model: Arc<dyn DeviceModelT>,
+    devices: BTreeMap<u64, VirtioDeviceType>,
+    device_manager: IoManager,
     pub event_manager: Option<Arc<Mutex<EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>>>>,
 }
 
@@ -38,18 +44,18 @@ impl Vm {
     ///
     /// # Arguments
     ///
-    /// * `fd` - The file descriptor for the VMM.
-    /// * `config` - The device configuration.
+    /// * `device_model` - The device model of the frontend VM.
+    /// * `configs` - The configuration of every device served to the frontend VM.
     ///
     /// # Returns
     ///
     /// A `Result` containing the result of the operation.
-    pub fn new(fd: i32, config: DeviceConfig) -> Result<Self> {
-        // Create the device manager.
+    pub fn new(device_model: Arc<dyn DeviceModelT>, configs: Vec<DeviceConfig>) -> Result<Self> {
+        // Create the device manager, shared with the devices while they register themselves.
         let device_manager = Arc::new(Mutex::new(IoManager::new()));
 
-        // Create the event manager if the data plane is virtio.
-        let event_manager = if config.data_plane == "virtio" {
+        // Create the event manager if any of the devices relies on the virtio data plane.
+        let event_manager = if configs.iter().any(|config| config.data_plane == "virtio") {
             Some(Arc::new(Mutex::new(
                 EventManager::<Arc<Mutex<dyn MutEventSubscriber + Send>>>::new()
                     .map_err(Error::EventManager)?,
@@ -58,51 +64,134 @@ impl Vm {
             None
         };
 
+        // Assign a shared memory slice to every device.
+        let configs = Self::assign_shmem_slices(device_model.as_ref(), configs)?;
+
+        // Create the devices.
+        let mut devices = BTreeMap::new();
+        for config in configs.iter() {
+            let device = Self::create_device(
+                config,
+                device_manager.clone(),
+                event_manager.clone(),
+                device_model.clone(),
+            )?;
+            devices.insert(config.mmio_addr, device);
+        }
+
+        // Take the device manager back, as the devices do not hold on to it once registered.
+        let device_manager = Arc::try_unwrap(device_manager)
+            .map_err(|_| Error::DeviceManagerInUse)?
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+
         // Create the VM.
-        let mut vm = Vm {
-            id: config.id as u16,
-            device_model: Arc::new(Mutex::new(
-                BaoDeviceModel::new(fd, config.id as u16).unwrap(),
-            )),
-            devices: Vec::new(),
+        Ok(Vm {
+            id: device_model.info().id as u16,
+            device_model,
+            devices,
             device_manager,
             event_manager,
+        })
+    }
+
+    /// Assign a slice of the frontend shared memory to every device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_model` - The device model.
+    /// * `configs` - The configuration of every device served to the frontend VM.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the device configurations with the shared memory fields resolved.
+    ///
+    /// # Note
+    ///
+    /// Devices that do not specify the `shmem_addr` and `shmem_size` fields are given the slot
+    /// matching their position within the configuration list, after evenly splitting the
+    /// shared memory in page aligned slots (a single device keeps the whole shared memory).
+    /// The resulting slices must fit within the shared memory and must not overlap.
+    fn assign_shmem_slices(
+        device_model: &dyn DeviceModelT,
+        mut configs: Vec<DeviceConfig>,
+    ) -> Result<Vec<DeviceConfig>> {
+        // Extract the device model fields.
+        let dm_info = device_model.info();
+
+        // Compute the size of each slot.
+        let slot_size = match configs.len() {
+            0 | 1 => dm_info.shmem_size,
+            n => (dm_info.shmem_size / n as u64) & !(PAGE_SIZE - 1),
         };
 
-        // Add the device.
-        // FIXME: For now one VM can have only one device.
-        vm.add_device(&config).unwrap();
+        // Resolve the shared memory slice of each device.
+        for (i, config) in configs.iter_mut().enumerate() {
+            let addr = config
+                .shmem_addr
+                .unwrap_or(dm_info.shmem_addr + i as u64 * slot_size);
+            let size = config.shmem_size.unwrap_or(slot_size);
+
+            // Check if the slice is page aligned and fits within the shared memory.
+            let fits = addr >= dm_info.shmem_addr
+                && (addr - dm_info.shmem_addr) % PAGE_SIZE == 0
+                && size > 0
+                && addr
+                    .checked_add(size)
+                    .is_some_and(|end| end <= dm_info.shmem_addr + dm_info.shmem_size);
+            if !fits {
+                return Err(Error::InvalidShmemSlice(config.mmio_addr));
+            }
+
+            config.shmem_addr = Some(addr);
+            config.shmem_size = Some(size);
+        }
+
+        // Check if any of the slices overlap.
+        for (i, a) in configs.iter().enumerate() {
+            for b in configs.iter().skip(i + 1) {
+                let (a_start, a_size) = (a.shmem_addr.unwrap(), a.shmem_size.unwrap());
+                let (b_start, b_size) = (b.shmem_addr.unwrap(), b.shmem_size.unwrap());
+                if a_start < b_start + b_size && b_start < a_start + a_size {
+                    return Err(Error::InvalidShmemSlice(b.mmio_addr));
+                }
+            }
+        }
 
-        Ok(vm)
+        Ok(configs)
     }
 
-    /// Add a new device.
+    /// Create a new device.
     ///
     /// # Arguments
     ///
     /// * `config` - The device configuration.
+    /// * `device_manager` - The device manager the device registers its MMIO region to.
+    /// * `event_manager` - The event manager (if any).
+    /// * `device_model` - The device model.
     ///
     /// # Returns
     ///
-    /// A `Result` containing the result of the operation.
-    fn add_device(&mut self, config: &DeviceConfig) -> Result<()> {
+    /// A `Result` containing the device.
+    fn create_device(
+        config: &DeviceConfig,
+        device_manager: Arc<Mutex<IoManager>>,
+        event_manager: Option<Arc<Mutex<EventManager<Arc<Mutex<dyn MutEventSubscriber + Send>>>>>>,
+        device_model: Arc<dyn DeviceModelT>,
+    ) -> Result<VirtioDeviceType> {
         // Extract the device type.
         let device_type = VirtioDevType::from(config.device_type.as_str());
 
         // Extract the data plane.
         let data_plane = VirtioDataPlane::from(config.data_plane.as_str());
 
-        // Clone the device manager, event manager and device model.
-        let device_manager = self.device_manager.clone();
-        let event_manager = self.event_manager.clone();
-        let device_model = self.device_model.clone();
-
-        let device = match device_type {
+        match device_type {
             // Block device.
             VirtioDevType::Block => match data_plane {
-                VirtioDataPlane::Virtio => Ok(VirtioDeviceType::VirtioBlock(
-                    VirtioBlock::new(config, device_manager, event_manager, device_model).unwrap(),
-                )),
+                VirtioDataPlane::Virtio => {
+                    VirtioBlock::new(config, device_manager, event_manager, device_model)
+                        .map(VirtioDeviceType::VirtioBlock)
+                }
                 _ => Err(Error::WrongDeviceConfiguration(
                     VirtioDevType::to_string(&device_type),
                     VirtioDataPlane::to_string(&data_plane),
@@ -110,9 +199,10 @@ impl Vm {
             },
             // Virtual Filesystem device.
             VirtioDevType::Fs => match data_plane {
-                VirtioDataPlane::VhostUser => Ok(VirtioDeviceType::VhostUserFs(
-                    VhostUserFs::new(config, device_manager, event_manager, device_model).unwrap(),
-                )),
+                VirtioDataPlane::VhostUser => {
+                    VhostUserFs::new(config, device_manager, event_manager, device_model)
+                        .map(VirtioDeviceType::VhostUserFs)
+                }
                 _ => Err(Error::WrongDeviceConfiguration(
                     VirtioDevType::to_string(&device_type),
                     VirtioDataPlane::to_string(&data_plane),
@@ -120,14 +210,14 @@ impl Vm {
             },
             // Vsock device.
             VirtioDevType::Vsock => match data_plane {
-                VirtioDataPlane::Vhost => Ok(VirtioDeviceType::VhostVsock(
+                VirtioDataPlane::Vhost => {
                     VhostVsockDevice::new(config, device_manager, event_manager, device_model)
-                        .unwrap(),
-                )),
-                VirtioDataPlane::VhostUser => Ok(VirtioDeviceType::VhostUserVsock(
+                        .map(VirtioDeviceType::VhostVsock)
+                }
+                VirtioDataPlane::VhostUser => {
                     VhostUserVsock::new(config, device_manager, event_manager, device_model)
-                        .unwrap(),
-                )),
+                        .map(VirtioDeviceType::VhostUserVsock)
+                }
                 _ => Err(Error::WrongDeviceConfiguration(
                     VirtioDevType::to_string(&device_type),
                     VirtioDataPlane::to_string(&data_plane),
@@ -135,12 +225,14 @@ impl Vm {
             },
             // Network device.
             VirtioDevType::Net => match data_plane {
-                VirtioDataPlane::Virtio => Ok(VirtioDeviceType::VirtioNet(
-                    VirtioNet::new(config, device_manager, event_manager, device_model).unwrap(),
-                )),
-                VirtioDataPlane::Vhost => Ok(VirtioDeviceType::VhostNet(
-                    VhostNet::new(config, device_manager, event_manager, device_model).unwrap(),
-                )),
+                VirtioDataPlane::Virtio => {
+                    VirtioNet::new(config, device_manager, event_manager, device_model)
+                        .map(VirtioDeviceType::VirtioNet)
+                }
+                VirtioDataPlane::Vhost => {
+                    VhostNet::new(config, device_manager, event_manager, device_model)
+                        .map(VirtioDeviceType::VhostNet)
+                }
                 _ => Err(Error::WrongDeviceConfiguration(
                     VirtioDevType::to_string(&device_type),
                     VirtioDataPlane::to_string(&data_plane),
@@ -148,10 +240,10 @@ impl Vm {
             },
             // Console device.
             VirtioDevType::Console => match data_plane {
-                VirtioDataPlane::Virtio => Ok(VirtioDeviceType::VirtioConsole(
+                VirtioDataPlane::Virtio => {
                     VirtioConsole::new(config, device_manager, event_manager, device_model)
-                        .unwrap(),
-                )),
+                        .map(VirtioDeviceType::VirtioConsole)
+                }
                 _ => Err(Error::WrongDeviceConfiguration(
                     VirtioDevType::to_string(&device_type),
                     VirtioDataPlane::to_string(&data_plane),
@@ -163,78 +255,161 @@ impl Vm {
                 VirtioDataPlane::to_string(&data_plane),
             )),
         }
-        .unwrap();
+    }
 
-        // Push the device to the list of devices.
-        self.devices.push(device);
+    /// Execute a control request on one of the devices.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The control request.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the data returned by the request (if any).
+    pub fn control(&self, request: &ControlRequest) -> Result<Option<String>> {
+        // Look up the targeted device.
+        let device = self
+            .devices
+            .get(&request.mmio_addr())
+            .ok_or(Error::DeviceNotFound(self.id as u32, request.mmio_addr()))?;
 
-        Ok(())
+        // Execute the request, if it applies to the device type.
+        match (request, device) {
+            (ControlRequest::BlockResize { size, .. }, VirtioDeviceType::VirtioBlock(block)) => {
+                let mut block = block.lock().unwrap();
+                match size {
+                    Some(size) => block.resize(*size),
+                    None => block.update_capacity(),
+                }
+                .map(|_| None)
+            }
+            (ControlRequest::BlockSnapshot { path, .. }, VirtioDeviceType::VirtioBlock(block)) => {
+                block.lock().unwrap().snapshot(path).map(|_| None)
+            }
+            (ControlRequest::BlockMetrics { .. }, VirtioDeviceType::VirtioBlock(block)) => {
+                Ok(Some(block.lock().unwrap().metrics().to_json()))
+            }
+            (
+                ControlRequest::ConsoleResize { cols, rows, .. },
+                VirtioDeviceType::VirtioConsole(console),
+            ) => console.lock().unwrap().resize(*cols, *rows).map(|_| None),
+            (ControlRequest::NetLink { up, .. }, VirtioDeviceType::VirtioNet(net)) => {
+                net.lock().unwrap().set_link_up(*up).map(|_| None)
+            }
+            _ => Err(Error::InvalidControlRequest(format!(
+                "{:?} does not apply to the device at {:#x}",
+                request,
+                request.mmio_addr()
+            ))),
+        }
     }
 
     /// Run the I/O events.
     ///
+    /// # Arguments
+    ///
+    /// * `workers` - Number of threads serving the I/O requests.
+    ///
     /// # Returns
     ///
     /// A `Result` containing the result of the operation.
-    pub fn run_io(self: Arc<Self>) -> Result<()> {
+    ///
+    /// # Note
+    ///
+    /// Each worker attaches, dispatches and completes its own I/O requests, so no request is
+    /// handed over between threads. The dispatch only locks the targeted device, so the workers
+    /// serve different devices at once.
+    pub fn run_io(self: Arc<Self>, workers: usize) -> Result<()> {
+        if workers <= 1 {
+            return self.serve_io_requests();
+        }
+
+        // Create the worker threads.
+        let mut handles = Vec::new();
+        for i in 0..workers {
+            let vm = self.clone();
+            let name = format!("vm_{}_io_{}", self.id, i);
+            handles.push((
+                name.clone(),
+                Builder::new()
+                    .name(name.clone())
+                    .spawn(move || vm.serve_io_requests())
+                    .map_err(|e| Error::ThreadSpawnFailed(name, e))?,
+            ));
+        }
+
+        // Report the reason the workers stopped.
+        let mut result = Ok(());
+        for (name, handle) in handles {
+            let stopped = handle
+                .join()
+                .map_err(|_| Error::ThreadJoinFailed(name))
+                .and_then(|stopped| stopped);
+            result = result.and(stopped);
+        }
+
+        result
+    }
+
+    /// Serve the I/O requests, one at a time.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the error that stopped the I/O loop.
+    fn serve_io_requests(&self) -> Result<()> {
         loop {
             //Attach the I/O client.
-            let mut req = match self.device_model.lock().unwrap().attach_io_client() {
-                Ok(req) => req,
-                Err(err) => {
-                    return Err(err);
-                }
-            };
+            let mut req = self.device_model.attach_io_client()?;
 
-            //Call the device manager to dispatch the I/O request
-            let mut data = (req.value as u32).to_le_bytes();
-
-            match req.op {
-                BAO_IO_WRITE => {
-                    match self
-                        .device_manager
-                        .lock()
-                        .unwrap()
-                        .mmio_write(MmioAddress(req.addr), &mut data)
-                    {
-                        Ok(()) => {}
-                        Err(_err) => {
-                            println!("Invalid Mmio write operation: {:?}", req);
-                            return Err(Error::InvalidMmioOperation("write"));
-                        }
-                    }
-                }
-                BAO_IO_READ => {
-                    match self
-                        .device_manager
-                        .lock()
-                        .unwrap()
-                        .mmio_read(MmioAddress(req.addr), &mut data)
-                    {
-                        Ok(()) => {}
-                        Err(_err) => {
-                            println!("Invalid Mmio read operation: {:?}", req);
-                            return Err(Error::InvalidMmioOperation("read"));
-                        }
-                    }
-                }
-                _ => {
-                    println!("Invalid I/O request direction: {:?}", req.op);
-                    return Err(Error::InvalidIoReqDirection(req.op));
-                }
+            // Dispatch the I/O request. Errors are bound to the request itself, so they
+            // are reported without tearing down the I/O loop.
+            if let Err(err) = Self::handle_io_request(&self.device_manager, &mut req) {
+                println!("Failed to handle I/O request {:?}: {}", req, err);
             }
 
-            // Update the req.value with the data.
-            req.value = u32::from_le_bytes(data) as u64;
-
             // Notify the I/O client that the I/O request has been completed
-            match self.device_model.lock().unwrap().notify_io_completed(req) {
-                Ok(()) => {}
-                Err(err) => {
-                    return Err(err);
-                }
+            self.device_model.notify_io_completed(req)?;
+        }
+    }
+
+    /// Handle an I/O request by dispatching it to the device manager.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_manager` - The device manager.
+    /// * `req` - The I/O request (the value is updated on reads).
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the result of the operation.
+    fn handle_io_request(device_manager: &IoManager, req: &mut BaoIoRequest) -> Result<()> {
+        // Size the data buffer from the access width (1, 2, 4 or 8 bytes).
+        let len = match req.access_width {
+            1 | 2 | 4 | 8 => req.access_width as usize,
+            width => return Err(Error::InvalidAccessWidth(width)),
+        };
+        let mut buf = req.value.to_le_bytes();
+        let data = &mut buf[..len];
+
+        //Call the device manager to dispatch the I/O request
+        match req.op {
+            BAO_IO_WRITE => device_manager
+                .mmio_write(MmioAddress(req.addr), data)
+                .map_err(|_| Error::InvalidMmioOperation("write"))?,
+            BAO_IO_READ => {
+                // Start from a clean buffer, so the upper bytes are zero on narrow reads.
+                data.fill(0);
+                device_manager
+                    .mmio_read(MmioAddress(req.addr), data)
+                    .map_err(|_| Error::InvalidMmioOperation("read"))?
             }
+            op => return Err(Error::InvalidIoReqDirection(op)),
         }
+
+        // Update the req.value with the data.
+        req.value = u64::from_le_bytes(buf);
+
+        Ok(())
     }
 
     /// Run the event manager.
@@ -246,25 +421,270 @@ impl Vm {
     /// and to dispatch the respective I/O events to the associated
     /// device.
     pub fn run_event_manager(self: Arc<Self>) {
+        // Only the VMs serving virtio data plane devices own an event manager.
+        let Some(event_manager) = self.event_manager.as_ref() else {
+            return;
+        };
+
         loop {
-            self.event_manager
-                .as_ref()
-                .unwrap()
-                .lock()
-                .unwrap()
-                .run()
-                .unwrap();
+            if let Err(e) = event_manager.lock().unwrap().run() {
+                println!("VM {} event manager failed: {:?}", self.id, e);
+                return;
+            }
         }
     }
 }
 
-// Implementing `Send` trait unsafely for `Vm`.
-// This indicates it's considered safe to transfer `Vm` instances between threads,
-// enabling transferring ownership of a `Vm` instance between threads.
-// As the `Vm` instance is protected by a Mutex, it's safe to transfer ownership of it between threads.
-unsafe impl Send for Vm {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::mock::MockDeviceModel;
+    use std::thread;
+    use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryRegion};
+    use vmm_sys_util::tempfile::TempFile;
+
+    const SHMEM_ADDR: u64 = 0x5000_0000;
+    const SHMEM_SIZE: u64 = 0x10_0000;
+    const MMIO_ADDR: u64 = 0xa003e00;
+
+    fn block_config(file_path: &str) -> DeviceConfig {
+        DeviceConfig {
+            id: 0,
+            device_type: "block".to_string(),
+            mmio_addr: MMIO_ADDR,
+            data_plane: "virtio".to_string(),
+            shmem_addr: None,
+            shmem_size: None,
+            irq: None,
+            num_queues: None,
+            queue_size: None,
+            file_path: Some(file_path.to_string()),
+            read_only: Some(true),
+            root_device: Some(false),
+            advertise_flush: Some(false),
+            io_engine: None,
+            image_format: None,
+            logical_block_size: None,
+            physical_block_size: None,
+            queue_threads: None,
+            serial: None,
+            rate_limiter: None,
+            cache: None,
+            shared: None,
+            overlay: None,
+            zoned: None,
+            encryption: None,
+            tap_name: None,
+            mac_addr: None,
+            guest_cid: None,
+            socket_path: None,
+            pty_alias: None,
+        }
+    }
+
+    #[test]
+    fn test_vm_io_requests() {
+        let image = TempFile::new().unwrap();
+        image.as_file().set_len(0x10_0000).unwrap();
+        let config = block_config(image.as_path().to_str().unwrap());
+
+        // Exercise both the inline dispatch and the worker threads.
+        for workers in [1, 2] {
+            let dm = Arc::new(MockDeviceModel::new(0, SHMEM_ADDR, SHMEM_SIZE, 47).unwrap());
+            let vm = Arc::new(Vm::new(dm.clone(), vec![config.clone()]).unwrap());
+            let io = thread::spawn(move || vm.run_io(workers));
+
+            // Identify the device, as a guest driver would do.
+            assert_eq!(dm.mmio_read(MMIO_ADDR, 4).unwrap(), 0x7472_6976);
+            assert_eq!(dm.mmio_read(MMIO_ADDR + 0x4, 4).unwrap(), 2);
+            assert_eq!(dm.mmio_read(MMIO_ADDR + 0x8, 4).unwrap(), 2);
 
-// Implementing `Sync` trait unsafely for `Vm`.
-// This indicates it's considered safe to share references of `Vm` between threads.
-// As the `Vm` instance is protected by a Mutex, it's safe to share references of it between threads.
-unsafe impl Sync for Vm {}
+            // Requests with an invalid access width are still completed.
+            assert_eq!(dm.mmio_read(MMIO_ADDR, 3).unwrap(), 0);
+
+            // The I/O loop stops once the device model goes away.
+            dm.shutdown();
+            assert!(io.join().unwrap().is_err());
+        }
+    }
+
+    #[test]
+    fn test_vm_io_workers() {
+        let images = [TempFile::new().unwrap(), TempFile::new().unwrap()];
+        let configs = images
+            .iter()
+            .enumerate()
+            .map(|(i, image)| {
+                image.as_file().set_len(0x10_0000).unwrap();
+                let mut config = block_config(image.as_path().to_str().unwrap());
+                config.mmio_addr = MMIO_ADDR + i as u64 * 0x200;
+                config
+            })
+            .collect::<Vec<_>>();
+
+        let dm = Arc::new(MockDeviceModel::new(0, SHMEM_ADDR, SHMEM_SIZE, 47).unwrap());
+        let vm = Arc::new(Vm::new(dm.clone(), configs).unwrap());
+        let Some(VirtioDeviceType::VirtioBlock(block)) = vm.devices.get(&MMIO_ADDR) else {
+            panic!("missing block device");
+        };
+        let block = block.clone();
+        let io = thread::spawn(move || vm.run_io(2));
+
+        // Stall a worker on the first device.
+        let guard = block.lock().unwrap();
+        let stalled = {
+            let dm = dm.clone();
+            thread::spawn(move || dm.mmio_read(MMIO_ADDR, 4))
+        };
+
+        // The other worker keeps serving the second device meanwhile.
+        assert_eq!(dm.mmio_read(MMIO_ADDR + 0x200, 4).unwrap(), 0x7472_6976);
+
+        drop(guard);
+        assert_eq!(stalled.join().unwrap().unwrap(), 0x7472_6976);
+
+        dm.shutdown();
+        assert!(io.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_vm_shmem_slices() {
+        let dm = Arc::new(MockDeviceModel::new(0, SHMEM_ADDR, SHMEM_SIZE, 47).unwrap());
+        let images = [TempFile::new().unwrap(), TempFile::new().unwrap()];
+        let configs = images
+            .iter()
+            .enumerate()
+            .map(|(i, image)| {
+                image.as_file().set_len(0x10_0000).unwrap();
+                let mut config = block_config(image.as_path().to_str().unwrap());
+                config.mmio_addr = MMIO_ADDR + i as u64 * 0x200;
+                config
+            })
+            .collect::<Vec<_>>();
+
+        // The shared memory is evenly split between the devices, following their order.
+        let slices = Vm::assign_shmem_slices(dm.as_ref(), configs.clone())
+            .unwrap()
+            .iter()
+            .map(|config| (config.shmem_addr.unwrap(), config.shmem_size.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            slices,
+            [
+                (SHMEM_ADDR, SHMEM_SIZE / 2),
+                (SHMEM_ADDR + SHMEM_SIZE / 2, SHMEM_SIZE / 2)
+            ]
+        );
+
+        // The slices must be page aligned, fit within the shared memory and not overlap.
+        for (addr, size) in [
+            (SHMEM_ADDR + 0x800, 0x1000),
+            (SHMEM_ADDR - 0x1000, 0x1000),
+            (SHMEM_ADDR + SHMEM_SIZE - 0x1000, 0x2000),
+            (SHMEM_ADDR + 0x1000, 0x1000),
+        ] {
+            let mut configs = configs.clone();
+            configs[1].shmem_addr = Some(addr);
+            configs[1].shmem_size = Some(size);
+            assert!(matches!(
+                Vm::assign_shmem_slices(dm.as_ref(), configs),
+                Err(Error::InvalidShmemSlice(_))
+            ));
+        }
+
+        // Each device maps its own slice, backed by the matching offset of the shared memory.
+        let vm = Vm::new(dm.clone(), configs).unwrap();
+        for (i, (addr, size)) in slices.into_iter().enumerate() {
+            let Some(VirtioDeviceType::VirtioBlock(block)) =
+                vm.devices.get(&(MMIO_ADDR + i as u64 * 0x200))
+            else {
+                panic!("missing block device {}", i);
+            };
+            let mem = block.lock().unwrap().common.mem().unwrap();
+            let region = mem.iter().next().unwrap();
+            assert_eq!(
+                (region.start_addr(), region.len()),
+                (GuestAddress(addr), size)
+            );
+
+            dm.write_guest(addr, &[i as u8 + 1]).unwrap();
+            assert_eq!(mem.read_obj::<u8>(GuestAddress(addr)).unwrap(), i as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn test_vm_control() {
+        let image = TempFile::new().unwrap();
+        image.as_file().set_len(0x10_0000).unwrap();
+        let mut config = block_config(image.as_path().to_str().unwrap());
+        config.read_only = Some(false);
+
+        let dm = Arc::new(MockDeviceModel::new(0, SHMEM_ADDR, SHMEM_SIZE, 47).unwrap());
+        let vm = Vm::new(dm, vec![config]).unwrap();
+
+        // Grow the disk image.
+        let request = ControlRequest::parse(&format!(
+            "{{command: block_resize, id: 0, mmio_addr: {:#x}, size: 2097152}}",
+            MMIO_ADDR
+        ))
+        .unwrap();
+        assert_eq!(vm.control(&request).unwrap(), None);
+        assert_eq!(image.as_file().metadata().unwrap().len(), 0x20_0000);
+
+        // Pick up the size of a disk image grown by someone else.
+        image.as_file().set_len(0x40_0000).unwrap();
+        vm.control(&ControlRequest::BlockResize {
+            id: 0,
+            mmio_addr: MMIO_ADDR,
+            size: None,
+        })
+        .unwrap();
+
+        // Shrinking is rejected.
+        assert!(matches!(
+            vm.control(&ControlRequest::BlockResize {
+                id: 0,
+                mmio_addr: MMIO_ADDR,
+                size: Some(0x10_0000),
+            }),
+            Err(Error::DiskResize(_))
+        ));
+
+        // Only overlays are snapshotted.
+        let request = ControlRequest::parse(&format!(
+            "{{command: block_snapshot, id: 0, mmio_addr: {:#x}, path: /tmp/snapshot.qcow2}}",
+            MMIO_ADDR
+        ))
+        .unwrap();
+        assert!(matches!(vm.control(&request), Err(Error::DiskSnapshot(_))));
+
+        // The I/O statistics are returned as JSON, while the other requests return nothing.
+        let request = ControlRequest::parse(&format!(
+            "{{command: block_metrics, id: 0, mmio_addr: {:#x}}}",
+            MMIO_ADDR
+        ))
+        .unwrap();
+        let metrics = vm.control(&request).unwrap().unwrap();
+        assert!(metrics.starts_with("{\"in_flight\":0,\"invalid_requests\":0,"));
+        assert!(metrics.contains("\"read\":{\"requests\":0,"));
+
+        // The request must match the device type.
+        assert!(matches!(
+            vm.control(&ControlRequest::NetLink {
+                id: 0,
+                mmio_addr: MMIO_ADDR,
+                up: false,
+            }),
+            Err(Error::InvalidControlRequest(_))
+        ));
+        assert!(matches!(
+            vm.control(&ControlRequest::ConsoleResize {
+                id: 0,
+                mmio_addr: MMIO_ADDR + 0x200,
+                cols: 80,
+                rows: 24,
+            }),
+            Err(Error::DeviceNotFound(0, _))
+        ));
+    }
+}
@@ -1,11 +1,14 @@
 use api::defines::BAO_IO_DISPATCHER_DEV_NODE;
+use api::device_model::BaoDeviceModel;
 use api::error::{Error, Result};
-use api::types::VMMConfig;
+use api::types::{DeviceConfig, VMMConfig};
+use std::collections::BTreeMap;
 use std::fs::OpenOptions;
 use std::os::fd::AsRawFd;
 use std::sync::{Arc, Mutex};
 use std::thread::{Builder, JoinHandle};
 
+use super::control::ControlServer;
 use super::vm::Vm;
 
 /// VMM abstraction.
@@ -15,10 +18,14 @@ use super::vm::Vm;
 /// * `fd` - The file descriptor for the VMM (e.g. /dev/bao-io-dispatcher).
 /// * `vms` - The list of VMs.
 /// * `vcpus` - The list of vCPUs/threads.
+/// * `io_workers` - Number of threads dispatching the I/O requests of each VM.
+/// * `control_socket` - Path of the Unix socket serving the control requests.
 pub struct Vmm {
     fd: i32,
     vms: Mutex<Vec<Arc<Vm>>>,
     vcpus: Mutex<Vec<JoinHandle<()>>>,
+    io_workers: usize,
+    control_socket: Option<String>,
 }
 
 impl TryFrom<VMMConfig> for Vmm {
@@ -48,11 +55,20 @@ impl TryFrom<VMMConfig> for Vmm {
             fd: fd.as_raw_fd(),
             vms: Mutex::new(Vec::new()),
             vcpus: Mutex::new(Vec::new()),
+            io_workers: config.io_workers.unwrap_or(1),
+            control_socket: config.control_socket,
         };
 
-        // Create all VMs.
+        // Group the devices by frontend VM, since all devices of a frontend share its device model.
+        let mut frontends: BTreeMap<u32, Vec<DeviceConfig>> = BTreeMap::new();
         for config in config.devices {
-            let vm = Vm::new(vmm.fd, config).unwrap();
+            frontends.entry(config.id).or_default().push(config);
+        }
+
+        // Create all VMs.
+        for (id, configs) in frontends {
+            let device_model = BaoDeviceModel::new(vmm.fd, id as u16)?;
+            let vm = Vm::new(Arc::new(device_model), configs)?;
 
             // Add the VM to the VMM list.
             vmm.vms.lock().unwrap().push(Arc::new(vm));
@@ -69,16 +85,30 @@ impl Vmm {
     ///
     /// A `Result` containing the result of the operation.
     pub fn run(&self) -> Result<()> {
+        // Create a new thread to serve the control requests, if requested.
+        if let Some(path) = self.control_socket.as_ref() {
+            let server = ControlServer::new(path, self.vms.lock().unwrap().clone())?;
+            self.vcpus.lock().unwrap().push(
+                Builder::new()
+                    .name("vmm_control".to_string())
+                    .spawn(move || server.run())
+                    .map_err(|e| Error::ThreadSpawnFailed("vmm_control".to_string(), e))?,
+            );
+        }
+
         for vm in self.vms.lock().unwrap().drain(..) {
             // Create a new vCPU/thread to run the I/O events.
             let vm_io = vm.clone();
+            let workers = self.io_workers;
             self.vcpus.lock().unwrap().push(
                 Builder::new()
                     .name(format!("vm_{}_io", vm_io.id))
                     .spawn(move || {
-                        vm_io.run_io().unwrap();
+                        if let Err(e) = vm_io.run_io(workers) {
+                            println!("VM {} stopped serving I/O requests: {}", vm_io.id, e);
+                        }
                     })
-                    .unwrap(),
+                    .map_err(|e| Error::ThreadSpawnFailed(format!("vm_{}_io", vm.id), e))?,
             );
 
             // Create a new vCPU/thread to run the VM event manager.
@@ -90,7 +120,7 @@ impl Vmm {
                         .spawn(move || {
                             vm_evm.run_event_manager();
                         })
-                        .unwrap(),
+                        .map_err(|e| Error::ThreadSpawnFailed(format!("vm_{}_evm", vm.id), e))?,
                 );
             }
         }
@@ -103,8 +133,8 @@ impl Drop for Vmm {
     fn drop(&mut self) {
         // Loops until all handles are popped from the vcpus vector
         while let Some(handle) = self.vcpus.lock().unwrap().pop() {
-            // Joins the thread represented by the handle
-            handle.join().unwrap();
+            // Joins the thread represented by the handle (a panicked thread has already reported itself)
+            let _ = handle.join();
         }
     }
 }